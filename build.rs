@@ -2,17 +2,29 @@ use std::process::Command;
 
 fn main() {
     let mut config = prost_build::Config::new();
-    config.bytes(&["."]);
-    config.type_attribute(".", "#[derive(PartialOrd)]");
+    config.bytes(["."]);
+    // prost 生成的 enum 已经实现了 PartialOrd，只需要给 message 和 oneof 加上
+    config.message_attribute(".", "#[derive(PartialOrd)]");
+    config.enum_attribute(".abi.CommandRequest.request_data", "#[derive(PartialOrd)]");
+    config.enum_attribute(".abi.Value.value", "#[derive(PartialOrd)]");
+    config.enum_attribute(".abi.RaftEntry.data", "#[derive(PartialOrd)]");
+    config.enum_attribute(".abi.RaftMessage.body", "#[derive(PartialOrd)]");
     config
         .out_dir("src/pb")
         .compile_protos(&["abi.proto"], &["."])
         .unwrap();
+    // gRPC 的 service 定义在 kv.proto 中，message 直接使用 abi.rs 里生成好的
+    // tonic-build 会把 import 的 abi.proto 也生成一遍，所以输出到 OUT_DIR，避免覆盖 src/pb/abi.rs
+    tonic_build::configure()
+        .extern_path(".abi", "crate::pb::abi")
+        .compile(&["kv.proto"], &["."])
+        .unwrap();
     Command::new("cargo")
-        .args(&["fmt", "--", "src/*.rs"])
+        .args(["fmt", "--", "src/*.rs"])
         .status()
         .expect("cargo fmt failed");
 
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=abi.proto");
+    println!("cargo:rerun-if-changed=kv.proto");
 }
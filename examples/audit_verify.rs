@@ -0,0 +1,44 @@
+use std::{env, process};
+
+use kv::{AuditIssue, AuditLog};
+
+// 校验审计日志有没有被修改或者删除：cargo run --example audit_verify -- audit.log
+fn main() -> anyhow::Result<()> {
+    let path = match env::args().nth(1) {
+        Some(v) => v,
+        None => {
+            eprintln!("Usage: audit_verify <audit log>");
+            process::exit(2);
+        }
+    };
+
+    let issues = AuditLog::verify(&path)?;
+    if issues.is_empty() {
+        println!("{}: OK", path);
+        return Ok(());
+    }
+
+    for issue in &issues {
+        match issue {
+            AuditIssue::Malformed { line } => println!("line {}: malformed record", line),
+            AuditIssue::Gap {
+                line,
+                expected,
+                found,
+            } => println!(
+                "line {}: expected seq {} but found {}, records are missing",
+                line, expected, found
+            ),
+            AuditIssue::BrokenChain { line, seq } => {
+                println!(
+                    "line {}: record {} does not follow the previous one",
+                    line, seq
+                )
+            }
+            AuditIssue::Tampered { line, seq } => {
+                println!("line {}: record {} has been modified", line, seq)
+            }
+        }
+    }
+    process::exit(1);
+}
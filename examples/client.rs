@@ -1,28 +1,28 @@
 use anyhow::Result;
-use async_prost::AsyncProstStream;
-use futures::prelude::*;
-use kv::{CommandRequest, CommandResponse};
-use tokio::net::TcpStream;
+use kv::{CommandRequest, CommandResponse, FrameStream, KvStream};
 use tracing::info;
 
 // 这段代码连接服务器的 9527 端口，发送一个 HSET 命令出去，然后等待服务器的响应。
+// 也可以指定其它地址，比如 unix:///tmp/kv.sock 连接 Unix domain socket：
+// cargo run --example client -- unix:///tmp/kv.sock
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
-    let addr = "127.0.0.1:9527";
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:9527".into());
 
-    let stream = TcpStream::connect(addr).await?;
+    let stream = KvStream::connect(&addr).await?;
 
-    let mut client =
-        AsyncProstStream::<_, CommandResponse, CommandRequest, _>::from(stream).for_async();
+    let mut client = FrameStream::new(stream);
 
     // let cmd = CommandRequest::new_hset("table1", "hello", "world!".into());
     let cmd = CommandRequest::new_hget("table1", "hello");
 
-    client.send(cmd).await?;
+    client.send(&cmd).await?;
 
-    if let Some(Ok(data)) = client.next().await {
+    if let Some(data) = client.recv::<CommandResponse>().await? {
         info!("Got response {:?}", data);
     }
 
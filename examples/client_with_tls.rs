@@ -0,0 +1,61 @@
+use anyhow::Result;
+use kv::{ClientConfig, CommandRequest, ProstClientStream, TlsClientConnector, YamuxClient};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tracing::info;
+
+// 先运行 cargo run --example gen_cert 生成证书，按照配置文件（默认是 examples/client_with_tls.toml）
+// 用客户端证书连接 server_with_tls
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "examples/client_with_tls.toml".into());
+    let config = ClientConfig::load(&path)?;
+
+    let stream = TcpStream::connect(&config.general.addr).await?;
+    match &config.tls {
+        Some(tls) => {
+            let connector = TlsClientConnector::from_config(tls)?;
+            run(connector.connect(stream).await?, &config).await
+        }
+        None => run(stream, &config).await,
+    }
+}
+
+async fn run<S>(stream: S, config: &ClientConfig) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let max_frame_size = config.general.max_frame_size;
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".into());
+    let data = match &config.yamux {
+        Some(yamux) => {
+            let mut client = YamuxClient::new(stream, yamux);
+            let mut stream = client
+                .open_stream()
+                .await?
+                .with_max_frame_size(max_frame_size);
+            stream.execute(cmd).await?
+        }
+        None => {
+            let client = match &config.compression {
+                Some(compression) => {
+                    ProstClientStream::with_compression(stream, compression).await?
+                }
+                None => ProstClientStream::new(stream),
+            };
+            client
+                .with_max_frame_size(max_frame_size)
+                .execute(cmd)
+                .await?
+        }
+    };
+    info!("Got response {:?}", data);
+
+    Ok(())
+}
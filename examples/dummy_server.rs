@@ -1,6 +1,4 @@
-use async_prost::AsyncProstStream;
-use futures::prelude::*;
-use kv::{CommandRequest, CommandResponse};
+use kv::{CommandRequest, CommandResponse, FrameStream};
 use tokio::net::TcpListener;
 use tracing::info;
 
@@ -16,15 +14,16 @@ async fn main() -> anyhow::Result<()> {
         let (stream, addr) = listener.accept().await?;
         info!("Client {:?} connected", addr);
         tokio::spawn(async move {
-            let mut stream =
-                AsyncProstStream::<_, CommandRequest, CommandResponse, _>::from(stream).for_async();
+            let mut stream = FrameStream::new(stream);
 
-            while let Some(Ok(msg)) = stream.next().await {
+            while let Ok(Some(msg)) = stream.recv::<CommandRequest>().await {
                 info!("Got a new command: {:?}", msg);
-                let mut resp = CommandResponse::default();
-                resp.status = 404;
-                resp.message = "Not found".to_string();
-                stream.send(resp).await.unwrap();
+                let resp = CommandResponse {
+                    status: 404,
+                    message: "Not found".to_string(),
+                    ..Default::default()
+                };
+                stream.send(&resp).await.unwrap();
             }
             info!("Client {:?} disconnected", addr);
         });
@@ -0,0 +1,45 @@
+use std::fs;
+
+use anyhow::Result;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+};
+
+// 生成一套本地测试用的证书：自签名 CA，以及它签发的 server / client 证书，写入 fixtures 目录
+fn main() -> Result<()> {
+    let mut params = CertificateParams::new(vec![]);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "Acme KV CA");
+    let ca = Certificate::from_params(params)?;
+
+    let mut params = CertificateParams::new(vec!["kvserver.acme.inc".into()]);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "kvserver.acme.inc");
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    let server = Certificate::from_params(params)?;
+
+    let mut params = CertificateParams::new(vec![]);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "awesome-device-id");
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let client = Certificate::from_params(params)?;
+
+    fs::create_dir_all("fixtures")?;
+    fs::write("fixtures/ca.pem", ca.serialize_pem()?)?;
+    fs::write(
+        "fixtures/server.pem",
+        server.serialize_pem_with_signer(&ca)?,
+    )?;
+    fs::write("fixtures/server.key", server.serialize_private_key_pem())?;
+    fs::write(
+        "fixtures/client.pem",
+        client.serialize_pem_with_signer(&ca)?,
+    )?;
+    fs::write("fixtures/client.key", client.serialize_private_key_pem())?;
+
+    Ok(())
+}
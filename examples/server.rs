@@ -1,39 +1,41 @@
-use async_prost::AsyncProstStream;
-use futures::prelude::*;
-use kv::{CommandRequest, CommandResponse, MemTable, Service, ServiceInner};
+use std::sync::Arc;
+
+use kv::{serve_metrics, MemTable, Metrics, Server, ServerConfig, ServiceInner};
 use tokio::net::TcpListener;
-use tracing::info;
+use tracing::{info, warn};
 
-// 在这段代码里，服务器监听 9527 端口，对任何客户端的请求，一律返回 status = 404，message 是 “Not found” 的响应。
+// 在这段代码里，服务器按照配置文件（默认是 examples/server.toml）监听端口，
+// 用 Service 处理客户端的请求；9528 端口的 /metrics 提供 Prometheus 指标。
+// 收到 SIGTERM/SIGINT 时优雅地关闭
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
 
-    let addr = "127.0.0.1:9527";
-    let listener = TcpListener::bind(addr).await?;
-    info!("Start listening on {}", addr);
-
-    let service: Service = ServiceInner::new(MemTable::new()).into();
-
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        info!("Client {:?} connected", addr);
-        let svc = service.clone();
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "examples/server.toml".into());
+    let config = ServerConfig::load(&path)?;
 
-        tokio::spawn(async move {
-            let mut stream =
-                AsyncProstStream::<_, CommandRequest, CommandResponse, _>::from(stream).for_async();
+    let metrics = Arc::new(Metrics::new());
+    let inner = ServiceInner::new(MemTable::new())
+        .metrics(metrics.clone())
+        .fn_connected(|e| info!("Client {:?} connected", e.peer))
+        .fn_disconnected(|e| {
+            info!(
+                "Client {:?} disconnected after {:?}, {} requests",
+                e.peer, e.duration, e.requests
+            )
+        })
+        .fn_decode_error(|e| warn!("Failed to decode from {:?}: {}", e.peer, e.error));
+    let server = Server::from_config(&config, inner).await?;
 
-            while let Some(Ok(msg)) = stream.next().await {
-                info!("Got a new command: {:?}", msg);
-                let resp = svc.execute(msg);
+    let metrics_addr = "127.0.0.1:9528";
+    let metrics_listener = TcpListener::bind(metrics_addr).await?;
+    info!("Serving metrics on http://{}/metrics", metrics_addr);
+    tokio::spawn(serve_metrics(metrics_listener, metrics, server.service()));
 
-                // let mut resp = CommandResponse::default();
-                // resp.status = 404;
-                // resp.message = "Not found".to_string();
-                stream.send(resp).await.unwrap();
-            }
-            info!("Client {:?} disconnected", addr);
-        });
-    }
+    server.handle().shutdown_on_signal();
+    let report = server.run().await;
+    info!("Shutdown: {:?}", report);
+    Ok(())
 }
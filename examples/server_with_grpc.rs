@@ -0,0 +1,31 @@
+use kv::{serve_grpc, MemTable, ProstServerStream, Service, ServiceInner, Session};
+use tokio::net::TcpListener;
+use tracing::info;
+
+// 9527 端口使用 protobuf 协议，50051 端口提供 gRPC 服务，两个端口共享同一个 Service：
+// grpcurl -plaintext -import-path . -proto kv.proto -d '{"hget": {"table": "t1", "key": "k1"}}' 127.0.0.1:50051 kv.Kv/Execute
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let service: Service = ServiceInner::new(MemTable::new()).into();
+
+    let grpc_addr = "127.0.0.1:50051";
+    let grpc_listener = TcpListener::bind(grpc_addr).await?;
+    info!("Start listening gRPC on {}", grpc_addr);
+    tokio::spawn(serve_grpc(grpc_listener, service.clone(), None));
+
+    let addr = "127.0.0.1:9527";
+    let listener = TcpListener::bind(addr).await?;
+    info!("Start listening on {}", addr);
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let svc = service.clone();
+        tokio::spawn(async move {
+            ProstServerStream::new(stream, svc)
+                .with_session(Session::new(Some(addr)))
+                .process()
+                .await
+        });
+    }
+}
@@ -0,0 +1,32 @@
+use kv::{serve_http, MemTable, ProstServerStream, Service, ServiceInner, Session};
+use tokio::net::TcpListener;
+use tracing::info;
+
+// 9527 端口使用 protobuf 协议，8080 端口提供 REST 接口，两个端口共享同一个 Service：
+// curl -X PUT -d '"alice"' http://127.0.0.1:8080/tables/user/keys/name
+// curl http://127.0.0.1:8080/tables/user?limit=10
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let service: Service = ServiceInner::new(MemTable::new()).into();
+
+    let http_addr = "127.0.0.1:8080";
+    let http_listener = TcpListener::bind(http_addr).await?;
+    info!("Start listening HTTP on {}", http_addr);
+    tokio::spawn(serve_http(http_listener, service.clone(), None));
+
+    let addr = "127.0.0.1:9527";
+    let listener = TcpListener::bind(addr).await?;
+    info!("Start listening on {}", addr);
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let svc = service.clone();
+        tokio::spawn(async move {
+            ProstServerStream::new(stream, svc)
+                .with_session(Session::new(Some(addr)))
+                .process()
+                .await
+        });
+    }
+}
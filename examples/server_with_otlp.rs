@@ -0,0 +1,39 @@
+use std::env;
+
+use kv::{MemTable, OtlpExporter, ProstServerStream, Service, ServiceInner, Session};
+use tokio::net::TcpListener;
+use tracing::info;
+use tracing_subscriber::{fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt};
+
+// 把每个请求的 span 输出到终端（包括耗时），同时导出到 OpenTelemetry collector
+// cargo run --example server_with_otlp --features otlp
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let endpoint =
+        env::var("OTEL_EXPORTER_OTLP_ENDPOINT").unwrap_or_else(|_| "http://localhost:4318".into());
+    let exporter = OtlpExporter::new(&endpoint, "kv-server")?;
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_span_events(FmtSpan::CLOSE))
+        .with(exporter.layer())
+        .init();
+
+    let addr = "127.0.0.1:9527";
+    let listener = TcpListener::bind(addr).await?;
+    info!(
+        "Start listening on {}, exporting spans to {}",
+        addr, endpoint
+    );
+
+    let service: Service = ServiceInner::new(MemTable::new()).into();
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let svc = service.clone();
+
+        tokio::spawn(async move {
+            ProstServerStream::new(stream, svc)
+                .with_session(Session::new(Some(addr)))
+                .process()
+                .await
+        });
+    }
+}
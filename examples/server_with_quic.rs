@@ -0,0 +1,24 @@
+use kv::{
+    bind_quic, serve_quic, MemTable, ServerTlsConfig, Service, ServiceInner, TlsServerAcceptor,
+};
+use tracing::info;
+
+// 先运行 cargo run --example gen_cert 生成证书，服务器在 UDP 9528 端口上提供 QUIC 服务，要求客户端提供证书（mTLS）
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let addr = "127.0.0.1:9528";
+    let config = ServerTlsConfig {
+        cert: "fixtures/server.pem".into(),
+        key: "fixtures/server.key".into(),
+        ca: Some("fixtures/ca.pem".into()),
+    };
+    let acceptor = TlsServerAcceptor::from_config(&config)?;
+    let service: Service = ServiceInner::new(MemTable::new()).into();
+
+    let endpoint = bind_quic(addr.parse()?, &acceptor)?;
+    info!("Start listening on {} (QUIC)", addr);
+    serve_quic(endpoint, service).await?;
+    Ok(())
+}
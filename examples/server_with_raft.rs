@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use kv::{RaftPeer, RaftServer, RaftServerConfig, Server, Service, ServiceInner, SledDb};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+// 三个节点的 Raft 集群，在三个终端里分别运行 cargo run --example server_with_raft -- 1（2、3）
+// 节点 n 在 952n 端口接收客户端请求，在 962n 端口和其它节点交换 Raft 消息，数据保存在 /tmp/kvraft/n
+// 写命令发给 follower 时返回 307 和 leader 的地址
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let id: u64 = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "1".into())
+        .parse()?;
+    let peers = (1..=3)
+        .map(|n| RaftPeer {
+            id: n,
+            raft_addr: format!("127.0.0.1:962{}", n),
+            addr: format!("127.0.0.1:952{}", n),
+        })
+        .collect();
+    let dir = format!("/tmp/kvraft/{}", id);
+    let config = RaftServerConfig::new(id, format!("{}/raft", dir), peers);
+    let addr = format!("127.0.0.1:952{}", id);
+
+    let shutdown = CancellationToken::new();
+    let raft = RaftServer::new(config).with_shutdown(shutdown.clone());
+    let service: Service<SledDb> = ServiceInner::new(SledDb::new(format!("{}/data", dir)))
+        .raft(raft.handle())
+        .into();
+    raft.start(service.clone()).await?;
+
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Raft node {} listening on {}", id, addr);
+    let server = Server::new(listener, service).with_shutdown_timeout(Duration::from_secs(5));
+    server.handle().shutdown_on_signal();
+    let report = server.run().await;
+    shutdown.cancel();
+    info!("Shutdown: {:?}", report);
+    report.flush?;
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use kv::{serve_resp, MemTable, ProstServerStream, Service, ServiceInner, Session};
+use tokio::net::TcpListener;
+use tracing::info;
+
+// 9527 端口使用 protobuf 协议，6379 端口使用 Redis 的 RESP 协议，两个端口共享同一个 Service：
+// redis-cli -p 6379 hset user:1 name alice
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let service: Service = ServiceInner::new(MemTable::new()).into();
+
+    let resp_addr = "127.0.0.1:6379";
+    let resp_listener = TcpListener::bind(resp_addr).await?;
+    info!("Start listening RESP on {}", resp_addr);
+    tokio::spawn(serve_resp(resp_listener, service.clone(), None));
+
+    let addr = "127.0.0.1:9527";
+    let listener = TcpListener::bind(addr).await?;
+    info!("Start listening on {}", addr);
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let svc = service.clone();
+        tokio::spawn(async move {
+            ProstServerStream::new(stream, svc)
+                .with_session(Session::new(Some(addr)))
+                .process()
+                .await
+        });
+    }
+}
@@ -1,10 +1,11 @@
-use async_prost::AsyncProstStream;
-use futures::prelude::*;
-use kv::{CommandRequest, CommandResponse, MemTable, Service, ServiceInner, SledDb};
+use std::time::Duration;
+
+use kv::{Server, Service, ServiceInner, SledDb};
 use tokio::net::TcpListener;
-use tracing::info;
+use tracing::{info, warn};
 
-// 在这段代码里，服务器监听 9527 端口，对任何客户端的请求，一律返回 status = 404，message 是 “Not found” 的响应。
+// 在这段代码里，服务器监听 9527 端口，使用 sled 持久化数据，并修改返回给客户端的 message
+// 收到 SIGTERM/SIGINT 时等待正在处理的命令完成，然后 flush sled
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
@@ -18,27 +19,14 @@ async fn main() -> anyhow::Result<()> {
             "" => res.message = "altered. Original message is empty.".into(),
             s => res.message = format!("altered: {}", s),
         })
+        .fn_after_send(|e| info!("Sent to {:?} in {:?}", e.peer, e.elapsed))
+        .fn_send_error(|e| warn!("Failed to send to {:?}: {}", e.peer, e.error))
         .into();
 
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        info!("Client {:?} connected", addr);
-        let svc = service.clone();
-
-        tokio::spawn(async move {
-            let mut stream =
-                AsyncProstStream::<_, CommandRequest, CommandResponse, _>::from(stream).for_async();
-
-            while let Some(Ok(msg)) = stream.next().await {
-                info!("Got a new command: {:?}", msg);
-                let resp = svc.execute(msg);
-
-                // let mut resp = CommandResponse::default();
-                // resp.status = 404;
-                // resp.message = "Not found".to_string();
-                stream.send(resp).await.unwrap();
-            }
-            info!("Client {:?} disconnected", addr);
-        });
-    }
+    let server = Server::new(listener, service).with_shutdown_timeout(Duration::from_secs(5));
+    server.handle().shutdown_on_signal();
+    let report = server.run().await;
+    info!("Shutdown: {:?}", report);
+    report.flush?;
+    Ok(())
 }
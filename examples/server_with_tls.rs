@@ -0,0 +1,44 @@
+use kv::{
+    peer_identity, MemTable, ProstServerStream, ServerTlsConfig, Service, ServiceInner, Session,
+    TlsServerAcceptor,
+};
+use tokio::net::TcpListener;
+use tracing::info;
+
+// 先运行 cargo run --example gen_cert 生成证书，服务器监听 9527 端口，要求客户端提供证书（mTLS）
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let addr = "127.0.0.1:9527";
+    let config = ServerTlsConfig {
+        cert: "fixtures/server.pem".into(),
+        key: "fixtures/server.key".into(),
+        ca: Some("fixtures/ca.pem".into()),
+    };
+    let acceptor = TlsServerAcceptor::from_config(&config)?;
+    let service: Service = ServiceInner::new(MemTable::new()).into();
+
+    let listener = TcpListener::bind(addr).await?;
+    info!("Start listening on {}", addr);
+
+    loop {
+        let tls = acceptor.clone();
+        let (stream, addr) = listener.accept().await?;
+        info!("Client {:?} connected", addr);
+        let svc = service.clone();
+
+        tokio::spawn(async move {
+            let stream = tls.accept(stream).await?;
+            let identity = peer_identity(&stream);
+            info!("Client {:?} identity: {:?}", addr, identity);
+            let session = Session::new(Some(addr)).with_user(identity);
+            ProstServerStream::new(stream, svc)
+                .with_session(session)
+                .process()
+                .await?;
+            info!("Client {:?} disconnected", addr);
+            Ok::<(), kv::KvError>(())
+        });
+    }
+}
@@ -0,0 +1,42 @@
+use std::{collections::BTreeSet, env, process};
+
+use kv::{ClientConfig, KvError, ShardedClient};
+use tokio::net::TcpStream;
+
+// 增加或删除节点之后迁移 key，原来的节点和 vnodes 来自客户端配置文件的 [shard]：
+// cargo run --example shard_rebalance -- client.toml 127.0.0.1:9527,127.0.0.1:9528,127.0.0.1:9529
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        eprintln!("Usage: shard_rebalance <client config> <new nodes>");
+        eprintln!("       nodes are comma separated addresses");
+        process::exit(2);
+    }
+    let shard = ClientConfig::load(&args[0])?
+        .shard
+        .ok_or_else(|| KvError::ConfigError("[shard] is not configured".into()))?;
+    let old: BTreeSet<String> = shard.nodes.into_iter().collect();
+    let new = split(&args[1]);
+
+    // 连接所有节点，然后把不在新的节点列表里的节点从哈希环上删除，它们的 key 会全部迁移走
+    let mut client = ShardedClient::new(shard.vnodes);
+    for addr in old.union(&new) {
+        client.add_node(addr.as_str(), TcpStream::connect(addr).await?);
+    }
+    for addr in old.difference(&new) {
+        client.remove_node(addr);
+    }
+
+    let moved = client.rebalance().await?;
+    println!("Moved {} keys", moved);
+    Ok(())
+}
+
+fn split(nodes: &str) -> BTreeSet<String> {
+    nodes
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
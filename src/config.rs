@@ -0,0 +1,1070 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Compression, KvError, DEFAULT_MAX_FRAME_SIZE};
+
+/// 服务器端配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ServerConfig {
+    pub general: GeneralConfig,
+    /// 配置了 unix 之后，在 TCP 地址之外额外监听一个 Unix domain socket，协议相同
+    pub unix: Option<UnixSocketConfig>,
+    pub tls: Option<ServerTlsConfig>,
+    /// 配置了 auth 之后，客户端必须先认证，并且只能访问 ACL 允许的 table
+    pub auth: Option<AuthConfig>,
+    /// 限流配置
+    pub rate_limit: Option<RateLimitConfig>,
+    /// 慢命令日志配置，不配置时使用缺省值
+    pub slowlog: Option<SlowlogConfig>,
+    /// Pub/Sub 配置，不配置时使用缺省值
+    pub pubsub: Option<PubsubConfig>,
+    /// Watch 配置，不配置时使用缺省值
+    pub watch: Option<WatchConfig>,
+    /// 变更日志（CDC）配置，不配置时不记录变更
+    pub cdc: Option<CdcConfig>,
+    /// 配置了 replication 之后，服务器作为 follower 从 leader 复制数据，并拒绝所有写命令
+    pub replication: Option<ReplicationConfig>,
+    /// 配置了 resp 之后，额外监听一个端口，使用 Redis 的 RESP 协议
+    pub resp: Option<RespConfig>,
+    /// 配置了 http 之后，额外监听一个端口，提供 JSON 格式的 REST 接口
+    pub http: Option<HttpConfig>,
+    /// 配置了 grpc 之后，额外监听一个端口，提供 kv.proto 中定义的 gRPC 服务
+    pub grpc: Option<GrpcConfig>,
+    /// 客户端请求压缩时允许使用的算法，不配置时允许所有算法
+    pub compression: Option<CompressionConfig>,
+    /// 配置了 yamux 之后，每个连接都使用 yamux 多路复用
+    pub yamux: Option<YamuxConfig>,
+    /// 配置了 quic 之后，额外监听一个 UDP 端口提供 QUIC 服务，证书使用 [tls] 中的配置
+    pub quic: Option<QuicConfig>,
+    /// 配置了 raft 之后，服务器作为 Raft 集群的成员运行，写命令需要多数节点确认
+    pub raft: Option<RaftServerConfig>,
+}
+
+/// 客户端配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ClientConfig {
+    pub general: GeneralConfig,
+    pub tls: Option<ClientTlsConfig>,
+    /// 配置了分片就用一致性哈希把请求分发到多个服务器，此时忽略 general.addr
+    pub shard: Option<ShardConfig>,
+    /// 配置了 compression 之后，连接建立时和服务器协商压缩算法
+    pub compression: Option<CompressionConfig>,
+    /// 配置了 yamux 之后，每个连接都使用 yamux 多路复用
+    pub yamux: Option<YamuxConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct GeneralConfig {
+    /// 服务器端是监听的 TCP 地址；客户端可以用 unix://path 连接 Unix domain socket
+    pub addr: String,
+    /// 最大的 frame 大小（解压之后），超过的 frame 会导致连接断开
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+}
+
+fn default_max_frame_size() -> usize {
+    DEFAULT_MAX_FRAME_SIZE
+}
+
+/// Unix domain socket 的监听配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UnixSocketConfig {
+    /// socket 文件的路径，比如 /var/run/kv.sock
+    pub path: String,
+    /// socket 文件的权限，缺省只有服务器进程的用户可以连接
+    #[serde(default = "default_unix_mode")]
+    pub mode: u32,
+}
+
+fn default_unix_mode() -> u32 {
+    0o600
+}
+
+/// RESP 协议的监听配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RespConfig {
+    /// 监听的地址，比如 127.0.0.1:6379
+    pub addr: String,
+}
+
+/// REST 网关的监听配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HttpConfig {
+    /// 监听的地址，比如 127.0.0.1:8080
+    pub addr: String,
+}
+
+/// gRPC 服务的监听配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GrpcConfig {
+    /// 监听的地址，比如 127.0.0.1:50051
+    pub addr: String,
+}
+
+/// QUIC 服务的监听配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QuicConfig {
+    /// 监听的 UDP 地址，比如 127.0.0.1:9528
+    pub addr: String,
+}
+
+/// frame 压缩的配置
+///
+/// 客户端按照偏好顺序列出算法，服务器选择第一个自己也允许的算法；algorithms 为空时不压缩。
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CompressionConfig {
+    #[serde(default = "default_compression_algorithms")]
+    pub algorithms: Vec<Compression>,
+    /// payload 超过这个字节数才压缩
+    #[serde(default = "default_compression_threshold")]
+    pub threshold: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            algorithms: default_compression_algorithms(),
+            threshold: default_compression_threshold(),
+        }
+    }
+}
+
+fn default_compression_algorithms() -> Vec<Compression> {
+    Compression::ALL.to_vec()
+}
+
+fn default_compression_threshold() -> usize {
+    1024
+}
+
+/// yamux 多路复用的配置，服务器和客户端需要同时开启
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct YamuxConfig {
+    /// 一个连接上最多的逻辑 stream 数
+    #[serde(default = "default_yamux_max_streams")]
+    pub max_streams: usize,
+    /// 每个逻辑 stream 的接收窗口，最小 256KB
+    #[serde(default = "default_yamux_receive_window")]
+    pub receive_window: u32,
+}
+
+impl Default for YamuxConfig {
+    fn default() -> Self {
+        Self {
+            max_streams: default_yamux_max_streams(),
+            receive_window: default_yamux_receive_window(),
+        }
+    }
+}
+
+fn default_yamux_max_streams() -> usize {
+    256
+}
+
+fn default_yamux_receive_window() -> u32 {
+    256 * 1024
+}
+
+/// 服务器端 TLS 配置，都是 PEM 文件的路径
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ServerTlsConfig {
+    pub cert: String,
+    pub key: String,
+    /// 配置了 CA 就会开启双向认证（mTLS），客户端必须提供由这个 CA 签发的证书
+    pub ca: Option<String>,
+}
+
+/// 客户端 TLS 配置，都是 PEM 文件的路径
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ClientTlsConfig {
+    /// 服务器证书里的域名
+    pub domain: String,
+    /// 客户端证书，双向认证时使用
+    pub identity: Option<ClientIdentity>,
+    /// 自签名的 CA，不提供的话使用缺省的根证书
+    pub ca: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ClientIdentity {
+    pub cert: String,
+    pub key: String,
+}
+
+/// 客户端分片配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ShardConfig {
+    /// 所有服务器的地址
+    pub nodes: Vec<String>,
+    /// 每个服务器在哈希环上的虚拟节点数，越多数据分布越均匀
+    #[serde(default = "default_shard_vnodes")]
+    pub vnodes: usize,
+}
+
+fn default_shard_vnodes() -> usize {
+    160
+}
+
+/// 认证和访问控制配置
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+    #[serde(default)]
+    pub acl: Vec<AclRule>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct UserConfig {
+    pub name: String,
+    /// argon2 哈希后的密码（PHC 字符串格式）
+    pub password: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TokenConfig {
+    /// token 的 id，客户端发送 `id.secret`，服务器按 id 找到 token 之后只验证这一个哈希
+    pub id: String,
+    /// token 对应的用户
+    pub user: String,
+    /// argon2 哈希后的 secret（PHC 字符串格式）
+    pub token: String,
+}
+
+/// 一条 ACL 规则：user 和 table 都支持 `*` 通配符
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct AclRule {
+    pub user: String,
+    pub table: String,
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    /// admin 包含 read 和 write
+    Admin,
+}
+
+/// 令牌桶限流配置，每一类都是可选的
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct RateLimitConfig {
+    /// 整个服务器共享的限额
+    pub global: Option<BucketConfig>,
+    /// 每个连接的限额
+    pub per_connection: Option<BucketConfig>,
+    /// 每个认证用户的限额（同一个用户的多个连接共享）
+    pub per_user: Option<BucketConfig>,
+    /// 每种命令的限额，key 是命令名，比如 "hgetall"
+    #[serde(default)]
+    pub per_command: HashMap<String, BucketConfig>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BucketConfig {
+    /// 每秒补充的令牌数
+    pub rate: f64,
+    /// 桶的容量，也就是允许的突发请求数
+    pub burst: u32,
+}
+
+/// 慢命令日志配置
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SlowlogConfig {
+    /// 执行时间超过这个阈值（微秒）的命令会被记录下来，0 表示记录所有命令
+    #[serde(default = "default_slowlog_threshold")]
+    pub threshold_us: u64,
+    /// 最多保留的条数，超出后丢弃最老的记录
+    #[serde(default = "default_slowlog_capacity")]
+    pub capacity: usize,
+}
+
+impl Default for SlowlogConfig {
+    fn default() -> Self {
+        Self {
+            threshold_us: default_slowlog_threshold(),
+            capacity: default_slowlog_capacity(),
+        }
+    }
+}
+
+fn default_slowlog_threshold() -> u64 {
+    10_000
+}
+
+fn default_slowlog_capacity() -> usize {
+    128
+}
+
+/// Pub/Sub 配置
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PubsubConfig {
+    /// 每个订阅者最多缓存的消息数
+    #[serde(default = "default_pubsub_buffer")]
+    pub buffer: usize,
+    /// 订阅者的缓存满了之后怎么处理
+    #[serde(default)]
+    pub slow_consumer: SlowConsumerPolicy,
+}
+
+/// 订阅者处理消息太慢，缓存满了之后的处理策略
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SlowConsumerPolicy {
+    /// 丢弃新的消息，订阅者会错过这些消息
+    Drop,
+    /// 断开订阅者的连接，订阅者重连后需要重新订阅
+    #[default]
+    Disconnect,
+}
+
+impl Default for PubsubConfig {
+    fn default() -> Self {
+        Self {
+            buffer: default_pubsub_buffer(),
+            slow_consumer: SlowConsumerPolicy::default(),
+        }
+    }
+}
+
+fn default_pubsub_buffer() -> usize {
+    128
+}
+
+/// Watch 配置
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WatchConfig {
+    /// 每个 watcher 最多缓存的事件数
+    #[serde(default = "default_pubsub_buffer")]
+    pub buffer: usize,
+    /// 保留最近的多少个事件，用于 watcher 断线重连之后补发
+    #[serde(default = "default_watch_backlog")]
+    pub backlog: usize,
+    /// watcher 的缓存满了之后怎么处理
+    #[serde(default)]
+    pub slow_consumer: SlowConsumerPolicy,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            buffer: default_pubsub_buffer(),
+            backlog: default_watch_backlog(),
+            slow_consumer: SlowConsumerPolicy::default(),
+        }
+    }
+}
+
+fn default_watch_backlog() -> usize {
+    1024
+}
+
+/// 变更日志（CDC）配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CdcConfig {
+    /// 存放 segment 文件的目录
+    pub path: String,
+    /// 单个 segment 文件的最大字节数，超出后写入新的 segment
+    #[serde(default = "default_cdc_segment_bytes")]
+    pub segment_bytes: u64,
+    /// 所有 segment 的总大小超过它之后，删除最旧的 segment
+    pub retention_bytes: Option<u64>,
+    /// 删除最后一次写入在这么多秒之前的 segment
+    pub retention_secs: Option<u64>,
+    /// 每次写入之后都调用 fsync，关闭后机器掉电可能丢失最近的记录
+    #[serde(default = "default_cdc_fsync")]
+    pub fsync: bool,
+}
+
+impl CdcConfig {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            segment_bytes: default_cdc_segment_bytes(),
+            retention_bytes: None,
+            retention_secs: None,
+            fsync: default_cdc_fsync(),
+        }
+    }
+}
+
+fn default_cdc_segment_bytes() -> u64 {
+    4 * 1024 * 1024
+}
+
+fn default_cdc_fsync() -> bool {
+    true
+}
+
+/// follower 的复制配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ReplicationConfig {
+    /// leader 的地址，比如 127.0.0.1:9527；leader 需要开启变更日志
+    pub leader: String,
+    /// leader 开启了认证时使用的用户名和密码，用户需要对所有 table（`*`）有读权限
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// 没有新的变更时，隔多久再向 leader 查询一次
+    #[serde(default = "default_replication_poll_ms")]
+    pub poll_interval_ms: u64,
+    /// 和 leader 的连接断开之后，隔多久重连
+    #[serde(default = "default_replication_retry_ms")]
+    pub retry_interval_ms: u64,
+    /// 每次最多读取的变更数，也是加载快照时每页的记录数
+    #[serde(default = "default_replication_batch")]
+    pub batch: u32,
+}
+
+impl ReplicationConfig {
+    pub fn new(leader: impl Into<String>) -> Self {
+        Self {
+            leader: leader.into(),
+            username: None,
+            password: None,
+            poll_interval_ms: default_replication_poll_ms(),
+            retry_interval_ms: default_replication_retry_ms(),
+            batch: default_replication_batch(),
+        }
+    }
+}
+
+fn default_replication_poll_ms() -> u64 {
+    100
+}
+
+fn default_replication_retry_ms() -> u64 {
+    1000
+}
+
+fn default_replication_batch() -> u32 {
+    1000
+}
+
+/// Raft 节点的配置，时间都以 `RaftNode::tick` 的次数计算
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RaftConfig {
+    /// 选举超时，实际的超时在 [election_ticks, 2 * election_ticks) 之间随机选择
+    #[serde(default = "default_raft_election_ticks")]
+    pub election_ticks: u64,
+    /// leader 发送心跳的间隔，需要远小于 election_ticks
+    #[serde(default = "default_raft_heartbeat_ticks")]
+    pub heartbeat_ticks: u64,
+    /// 上次快照之后应用了这么多条目，就生成新的快照并压缩日志
+    #[serde(default = "default_raft_snapshot_entries")]
+    pub snapshot_entries: u64,
+    /// 每个 Append 消息最多携带的条目数
+    #[serde(default = "default_raft_max_append_entries")]
+    pub max_append_entries: usize,
+    /// 给落后的 follower 发送快照时，每个消息携带的字节数
+    #[serde(default = "default_raft_snapshot_chunk_bytes")]
+    pub snapshot_chunk_bytes: usize,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            election_ticks: default_raft_election_ticks(),
+            heartbeat_ticks: default_raft_heartbeat_ticks(),
+            snapshot_entries: default_raft_snapshot_entries(),
+            max_append_entries: default_raft_max_append_entries(),
+            snapshot_chunk_bytes: default_raft_snapshot_chunk_bytes(),
+        }
+    }
+}
+
+fn default_raft_election_ticks() -> u64 {
+    10
+}
+
+fn default_raft_heartbeat_ticks() -> u64 {
+    2
+}
+
+fn default_raft_snapshot_entries() -> u64 {
+    10_000
+}
+
+fn default_raft_max_append_entries() -> usize {
+    100
+}
+
+fn default_raft_snapshot_chunk_bytes() -> usize {
+    1024 * 1024
+}
+
+/// Raft 集群中的一个节点
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RaftPeer {
+    /// 节点的 id，从 1 开始
+    pub id: u64,
+    /// 节点之间交换 Raft 消息的地址
+    pub raft_addr: String,
+    /// 客户端访问的地址，写命令发到 follower 时返回这个地址让客户端重定向
+    pub addr: String,
+}
+
+/// 以 Raft 集群的成员运行的配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RaftServerConfig {
+    /// 自己的 id，需要在 peers 中
+    pub id: u64,
+    /// 保存 term、投票、日志和快照的目录
+    pub dir: String,
+    /// 集群中所有可能成为成员的节点，包括自己；成员变更只改变投票成员，节点的地址需要事先配置在这里
+    pub peers: Vec<RaftPeer>,
+    /// 第一次启动时的投票成员，为空时是 peers 中的所有节点；已经有快照时使用快照中的成员
+    #[serde(default)]
+    pub voters: Vec<u64>,
+    /// 每隔多久调用一次 `RaftNode::tick`
+    #[serde(default = "default_raft_tick_ms")]
+    pub tick_ms: u64,
+    /// 等待写命令提交或者读命令确认的最长时间，超时的写命令之后仍然可能被应用
+    #[serde(default = "default_raft_request_timeout_ms")]
+    pub request_timeout_ms: u64,
+    #[serde(default)]
+    pub raft: RaftConfig,
+}
+
+impl RaftServerConfig {
+    pub fn new(id: u64, dir: impl Into<String>, peers: Vec<RaftPeer>) -> Self {
+        Self {
+            id,
+            dir: dir.into(),
+            peers,
+            voters: Vec::new(),
+            tick_ms: default_raft_tick_ms(),
+            request_timeout_ms: default_raft_request_timeout_ms(),
+            raft: RaftConfig::default(),
+        }
+    }
+}
+
+fn default_raft_tick_ms() -> u64 {
+    100
+}
+
+fn default_raft_request_timeout_ms() -> u64 {
+    5000
+}
+
+impl ServerConfig {
+    /// 从 toml 文件加载配置
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        load_toml(path)
+    }
+}
+
+impl ClientConfig {
+    /// 从 toml 文件加载配置
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        load_toml(path)
+    }
+}
+
+fn load_toml<T: for<'de> Deserialize<'de>>(path: impl AsRef<Path>) -> Result<T, KvError> {
+    let content = fs::read_to_string(path)?;
+    toml::from_str(&content).map_err(|e| KvError::ConfigError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [tls]
+            cert = "fixtures/server.pem"
+            key = "fixtures/server.key"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.general.addr, "127.0.0.1:9527");
+        assert_eq!(config.general.max_frame_size, DEFAULT_MAX_FRAME_SIZE);
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.cert, "fixtures/server.pem");
+        assert_eq!(tls.ca, None);
+        assert_eq!(config.auth, None);
+        assert_eq!(config.rate_limit, None);
+        assert_eq!(config.slowlog, None);
+    }
+
+    #[test]
+    fn slowlog_config_should_use_defaults() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [slowlog]
+            threshold_us = 500
+            "#,
+        )
+        .unwrap();
+
+        let slowlog = config.slowlog.unwrap();
+        assert_eq!(slowlog.threshold_us, 500);
+        assert_eq!(slowlog.capacity, SlowlogConfig::default().capacity);
+    }
+
+    #[test]
+    fn pubsub_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [pubsub]
+            slow_consumer = "drop"
+            "#,
+        )
+        .unwrap();
+
+        let pubsub = config.pubsub.unwrap();
+        assert_eq!(pubsub.buffer, 128);
+        assert_eq!(pubsub.slow_consumer, SlowConsumerPolicy::Drop);
+    }
+
+    #[test]
+    fn watch_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [watch]
+            backlog = 10000
+            "#,
+        )
+        .unwrap();
+
+        let watch = config.watch.unwrap();
+        assert_eq!(watch.backlog, 10000);
+        assert_eq!(watch.buffer, 128);
+        assert_eq!(watch.slow_consumer, SlowConsumerPolicy::Disconnect);
+    }
+
+    #[test]
+    fn cdc_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [cdc]
+            path = "/tmp/kv/changes"
+            retention_secs = 86400
+            "#,
+        )
+        .unwrap();
+
+        let cdc = config.cdc.unwrap();
+        assert_eq!(cdc.path, "/tmp/kv/changes");
+        assert_eq!(cdc.segment_bytes, 4 * 1024 * 1024);
+        assert_eq!(cdc.retention_bytes, None);
+        assert_eq!(cdc.retention_secs, Some(86400));
+        assert!(cdc.fsync);
+    }
+
+    #[test]
+    fn replication_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9537"
+
+            [replication]
+            leader = "127.0.0.1:9527"
+            username = "replica"
+            password = "replica-password"
+            "#,
+        )
+        .unwrap();
+
+        let replication = config.replication.unwrap();
+        assert_eq!(replication.leader, "127.0.0.1:9527");
+        assert_eq!(replication.username.as_deref(), Some("replica"));
+        assert_eq!(replication.poll_interval_ms, 100);
+        assert_eq!(replication.batch, 1000);
+    }
+
+    #[test]
+    fn resp_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [resp]
+            addr = "127.0.0.1:6379"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.resp.unwrap().addr, "127.0.0.1:6379");
+    }
+
+    #[test]
+    fn unix_socket_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [unix]
+            path = "/var/run/kv.sock"
+            mode = 0o660
+            "#,
+        )
+        .unwrap();
+        let unix = config.unix.unwrap();
+        assert_eq!(unix.path, "/var/run/kv.sock");
+        assert_eq!(unix.mode, 0o660);
+
+        let config: ClientConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "unix:///var/run/kv.sock"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.general.addr, "unix:///var/run/kv.sock");
+    }
+
+    #[test]
+    fn compression_config_should_be_loaded() {
+        let config: ClientConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [compression]
+            algorithms = ["zstd", "lz4"]
+            "#,
+        )
+        .unwrap();
+        let compression = config.compression.unwrap();
+        assert_eq!(
+            compression.algorithms,
+            vec![Compression::Zstd, Compression::Lz4]
+        );
+        assert_eq!(compression.threshold, 1024);
+
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [compression]
+            threshold = 4096
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.compression.unwrap(),
+            CompressionConfig {
+                algorithms: Compression::ALL.to_vec(),
+                threshold: 4096,
+            }
+        );
+    }
+
+    #[test]
+    fn quic_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [tls]
+            cert = "fixtures/server.pem"
+            key = "fixtures/server.key"
+
+            [quic]
+            addr = "127.0.0.1:9528"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.quic.unwrap().addr, "127.0.0.1:9528");
+    }
+
+    #[test]
+    fn yamux_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [yamux]
+            max_streams = 16
+            "#,
+        )
+        .unwrap();
+        let yamux = config.yamux.unwrap();
+        assert_eq!(yamux.max_streams, 16);
+        assert_eq!(yamux.receive_window, 256 * 1024);
+
+        let config: ClientConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [yamux]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.yamux.unwrap(), YamuxConfig::default());
+    }
+
+    #[test]
+    fn http_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [http]
+            addr = "127.0.0.1:8080"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.http.unwrap().addr, "127.0.0.1:8080");
+    }
+
+    #[test]
+    fn grpc_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [grpc]
+            addr = "127.0.0.1:50051"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.grpc.unwrap().addr, "127.0.0.1:50051");
+    }
+
+    #[test]
+    fn rate_limit_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [rate_limit.per_connection]
+            rate = 100.0
+            burst = 200
+
+            [rate_limit.per_command.hgetall]
+            rate = 1.0
+            burst = 5
+            "#,
+        )
+        .unwrap();
+
+        let rate_limit = config.rate_limit.unwrap();
+        assert_eq!(rate_limit.global, None);
+        assert_eq!(rate_limit.per_connection.unwrap().burst, 200);
+        assert_eq!(rate_limit.per_command["hgetall"].rate, 1.0);
+    }
+
+    #[test]
+    fn auth_config_should_be_loaded() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [[auth.users]]
+            name = "alice"
+            password = "$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHQ$aGFzaA"
+
+            [[auth.acl]]
+            user = "alice"
+            table = "user_*"
+            permissions = ["read", "write"]
+            "#,
+        )
+        .unwrap();
+
+        let auth = config.auth.unwrap();
+        assert_eq!(auth.users[0].name, "alice");
+        assert!(auth.tokens.is_empty());
+        assert_eq!(
+            auth.acl[0].permissions,
+            vec![Permission::Read, Permission::Write]
+        );
+    }
+
+    #[test]
+    fn client_config_should_be_loaded() {
+        let config: ClientConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [tls]
+            domain = "kvserver.acme.inc"
+            ca = "fixtures/ca.pem"
+
+            [tls.identity]
+            cert = "fixtures/client.pem"
+            key = "fixtures/client.key"
+            "#,
+        )
+        .unwrap();
+
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.domain, "kvserver.acme.inc");
+        assert_eq!(tls.identity.unwrap().key, "fixtures/client.key");
+        assert_eq!(config.shard, None);
+    }
+
+    #[test]
+    fn client_shard_config_should_be_loaded() {
+        let config: ClientConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [shard]
+            nodes = ["10.0.0.1:9527", "10.0.0.2:9527"]
+            "#,
+        )
+        .unwrap();
+
+        let shard = config.shard.unwrap();
+        assert_eq!(shard.nodes.len(), 2);
+        assert_eq!(shard.vnodes, 160);
+    }
+
+    #[test]
+    fn config_should_round_trip_through_toml() {
+        let server: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [unix]
+            path = "/var/run/kv.sock"
+            mode = 0o660
+
+            [tls]
+            cert = "fixtures/server.pem"
+            key = "fixtures/server.key"
+            ca = "fixtures/ca.pem"
+
+            [[auth.users]]
+            name = "alice"
+            password = "$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHQ$aGFzaA"
+
+            [[auth.acl]]
+            user = "alice"
+            table = "user_*"
+            permissions = ["read", "write"]
+
+            [rate_limit.per_connection]
+            rate = 100.0
+            burst = 200
+
+            [rate_limit.per_command.hgetall]
+            rate = 1.0
+            burst = 5
+
+            [slowlog]
+            threshold_us = 500
+
+            [pubsub]
+            slow_consumer = "drop"
+
+            [watch]
+            backlog = 10000
+
+            [cdc]
+            path = "/tmp/kv/changes"
+            retention_secs = 86400
+
+            [replication]
+            leader = "127.0.0.1:9537"
+            username = "replica"
+            password = "replica-password"
+
+            [resp]
+            addr = "127.0.0.1:6379"
+
+            [http]
+            addr = "127.0.0.1:8080"
+
+            [grpc]
+            addr = "127.0.0.1:50051"
+
+            [compression]
+            algorithms = ["zstd", "lz4"]
+
+            [yamux]
+            max_streams = 16
+
+            [quic]
+            addr = "127.0.0.1:9528"
+
+            [raft]
+            id = 1
+            dir = "/tmp/kv/raft"
+
+            [[raft.peers]]
+            id = 1
+            raft_addr = "127.0.0.1:9627"
+            addr = "127.0.0.1:9527"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(server.general.max_frame_size, DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(
+            server.slowlog.unwrap().capacity,
+            SlowlogConfig::default().capacity
+        );
+        // 先转换成 toml::Value，序列化时普通的值排在 table 之前
+        let encoded = toml::Value::try_from(&server).unwrap().to_string();
+        assert_eq!(toml::from_str::<ServerConfig>(&encoded).unwrap(), server);
+
+        let client: ClientConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "unix:///var/run/kv.sock"
+
+            [tls]
+            domain = "kvserver.acme.inc"
+            ca = "fixtures/ca.pem"
+
+            [tls.identity]
+            cert = "fixtures/client.pem"
+            key = "fixtures/client.key"
+
+            [shard]
+            nodes = ["10.0.0.1:9527", "10.0.0.2:9527"]
+
+            [compression]
+            threshold = 4096
+
+            [yamux]
+            "#,
+        )
+        .unwrap();
+        let encoded = toml::Value::try_from(&client).unwrap().to_string();
+        assert_eq!(toml::from_str::<ClientConfig>(&encoded).unwrap(), client);
+    }
+}
@@ -11,6 +11,9 @@ pub enum KvError {
     #[error("Not found for table: {0}, key: {1}")]
     NotFound(String, String),
 
+    #[error("Value has changed for table: {0}, key: {1}")]
+    Conflict(String, String),
+
     #[error("Fail to encode protobuf message")]
     EncodeError(#[from] prost::EncodeError),
 
@@ -19,4 +22,44 @@ pub enum KvError {
 
     #[error("Failed to access sled db")]
     SledError(#[from] sled::Error),
+
+    #[error("I/O error: {0}")]
+    IoError(String),
+
+    #[error("Failed to parse certificate: {0}")]
+    CertificateParseError(String),
+
+    #[error("TLS error: {0}")]
+    TlsError(#[from] tokio_rustls::rustls::Error),
+
+    #[error("Failed to load config: {0}")]
+    ConfigError(String),
+
+    #[error("Unauthenticated: {0}")]
+    Unauthenticated(String),
+
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("Too many requests for {0}, retry after {1}ms")]
+    RateLimited(String, u64),
+
+    #[error("History before {1} has been truncated, requested {0}")]
+    HistoryTruncated(u64, u64),
+
+    #[error("This server is a follower, send writes to the leader {0}")]
+    NotLeader(String),
+
+    #[error("Protocol error: {0}")]
+    ProtocolError(String),
+
+    #[error("Server is shutting down")]
+    ShuttingDown,
+}
+
+// std::io::Error 没有实现 PartialEq，所以只保留它的描述信息
+impl From<std::io::Error> for KvError {
+    fn from(e: std::io::Error) -> Self {
+        Self::IoError(e.to_string())
+    }
 }
@@ -1,9 +1,25 @@
+mod config;
 mod error;
+mod metrics;
+mod network;
 mod pb;
+mod raft;
+mod replication;
 mod service;
+mod shard;
 mod storage;
+#[cfg(feature = "otlp")]
+mod telemetry;
 
+pub use config::*;
 pub use error::KvError;
+pub use metrics::*;
+pub use network::*;
 pub use pb::abi::*;
+pub use raft::*;
+pub use replication::*;
 pub use service::*;
+pub use shard::*;
 pub use storage::*;
+#[cfg(feature = "otlp")]
+pub use telemetry::*;
@@ -0,0 +1,405 @@
+use std::{
+    convert::Infallible,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use hyper::{server::conn::Http, service::service_fn, Body, Method, Request, Response, StatusCode};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use prost::Message;
+use tokio::{net::TcpListener, sync::Mutex, task};
+use tracing::warn;
+
+use crate::{
+    CommandRequest, CommandResponse, KvError, Middleware, Next, Service, Session, Storage,
+};
+
+/// 存储的统计需要遍历所有数据，缺省缓存 30 秒
+pub const DEFAULT_STORAGE_STATS_TTL: Duration = Duration::from_secs(30);
+
+/// 服务器和存储的 Prometheus metrics
+///
+/// 作为中间件统计每个命令的次数、耗时和响应状态码；存储相关的指标在抓取时从 Storage 中读取，
+/// 读取在 blocking 线程池中进行，结果缓存一段时间，避免每次抓取都遍历所有数据。
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    responses: IntCounterVec,
+    connections: IntGauge,
+    bytes_received: IntCounter,
+    bytes_sent: IntCounter,
+    table_keys: IntGaugeVec,
+    table_bytes: IntGaugeVec,
+    disk_bytes: IntGauge,
+    memory_bytes: IntGauge,
+    replication_connected: IntGauge,
+    replication_leader_offset: IntGauge,
+    replication_applied_offset: IntGauge,
+    replication_lag: IntGauge,
+    storage_stats_ttl: Duration,
+    /// 上次读取存储统计的时间，读取期间持有锁，同时抓取时只读取一次
+    storage_refreshed: Mutex<Option<Instant>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new("kv_requests_total", "Number of commands received"),
+            &["command"],
+        )
+        .unwrap();
+        let latency = HistogramVec::new(
+            HistogramOpts::new(
+                "kv_request_duration_seconds",
+                "Time spent on executing commands",
+            ),
+            &["command"],
+        )
+        .unwrap();
+        let responses = IntCounterVec::new(
+            Opts::new("kv_responses_total", "Number of responses by status code"),
+            &["status"],
+        )
+        .unwrap();
+        let connections =
+            IntGauge::new("kv_connections_active", "Number of connected clients").unwrap();
+        let bytes_received = IntCounter::new(
+            "kv_received_bytes_total",
+            "Encoded size of all received commands",
+        )
+        .unwrap();
+        let bytes_sent =
+            IntCounter::new("kv_sent_bytes_total", "Encoded size of all sent responses").unwrap();
+        let table_keys = IntGaugeVec::new(
+            Opts::new("kv_storage_keys", "Number of keys in each table"),
+            &["table"],
+        )
+        .unwrap();
+        let table_bytes = IntGaugeVec::new(
+            Opts::new("kv_storage_table_bytes", "Approximate size of each table"),
+            &["table"],
+        )
+        .unwrap();
+        let disk_bytes =
+            IntGauge::new("kv_storage_disk_bytes", "Disk space used by the storage").unwrap();
+        let memory_bytes = IntGauge::new(
+            "kv_storage_memory_bytes",
+            "Approximate memory used by the storage",
+        )
+        .unwrap();
+        let replication_connected = IntGauge::new(
+            "kv_replication_connected",
+            "Whether the follower is connected to the leader",
+        )
+        .unwrap();
+        let replication_leader_offset = IntGauge::new(
+            "kv_replication_leader_offset",
+            "Latest change log offset of the leader",
+        )
+        .unwrap();
+        let replication_applied_offset = IntGauge::new(
+            "kv_replication_applied_offset",
+            "Last change log offset applied by the follower",
+        )
+        .unwrap();
+        let replication_lag = IntGauge::new(
+            "kv_replication_lag",
+            "Number of changes the follower is behind the leader",
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        registry.register(Box::new(responses.clone())).unwrap();
+        registry.register(Box::new(connections.clone())).unwrap();
+        registry.register(Box::new(bytes_received.clone())).unwrap();
+        registry.register(Box::new(bytes_sent.clone())).unwrap();
+        registry.register(Box::new(table_keys.clone())).unwrap();
+        registry.register(Box::new(table_bytes.clone())).unwrap();
+        registry.register(Box::new(disk_bytes.clone())).unwrap();
+        registry.register(Box::new(memory_bytes.clone())).unwrap();
+        registry
+            .register(Box::new(replication_connected.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(replication_leader_offset.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(replication_applied_offset.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(replication_lag.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            requests,
+            latency,
+            responses,
+            connections,
+            bytes_received,
+            bytes_sent,
+            table_keys,
+            table_bytes,
+            disk_bytes,
+            memory_bytes,
+            replication_connected,
+            replication_leader_offset,
+            replication_applied_offset,
+            replication_lag,
+            storage_stats_ttl: DEFAULT_STORAGE_STATS_TTL,
+            storage_refreshed: Mutex::new(None),
+        }
+    }
+
+    /// 设置存储统计的缓存时间，0 表示每次抓取都重新读取
+    pub fn with_storage_stats_ttl(mut self, ttl: Duration) -> Self {
+        self.storage_stats_ttl = ttl;
+        self
+    }
+
+    /// 注册自定义的指标，它们会和内置的指标一起导出
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections.inc();
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections.dec();
+    }
+
+    /// 更新存储和复制相关的指标，然后按 Prometheus 文本格式输出所有指标
+    pub async fn render<Store>(&self, service: &Service<Store>) -> Result<String, KvError>
+    where
+        Store: Storage + Send + Sync + 'static,
+    {
+        self.refresh_storage(service).await?;
+
+        if let Some(replication) = service.replication() {
+            let connected = replication.connected.load(Ordering::Relaxed);
+            self.replication_connected.set(connected as i64);
+            let leader_offset = replication.leader_offset.load(Ordering::Relaxed);
+            self.replication_leader_offset.set(leader_offset as i64);
+            let applied_offset = replication.applied_offset.load(Ordering::Relaxed);
+            self.replication_applied_offset.set(applied_offset as i64);
+            self.replication_lag.set(replication.lag() as i64);
+        }
+
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .map_err(|e| KvError::Internal(e.to_string()))?;
+        String::from_utf8(buf).map_err(|e| KvError::Internal(e.to_string()))
+    }
+
+    /// 缓存过期时在 blocking 线程池里读取存储的统计，更新存储相关的指标
+    async fn refresh_storage<Store>(&self, service: &Service<Store>) -> Result<(), KvError>
+    where
+        Store: Storage + Send + Sync + 'static,
+    {
+        let mut refreshed = self.storage_refreshed.lock().await;
+        if refreshed.is_some_and(|t| t.elapsed() < self.storage_stats_ttl) {
+            return Ok(());
+        }
+
+        let service = service.clone();
+        let stats = task::spawn_blocking(move || service.storage_stats())
+            .await
+            .map_err(|e| KvError::Internal(e.to_string()))??;
+        // table 可能已经不存在了，先清空再设置
+        self.table_keys.reset();
+        self.table_bytes.reset();
+        for table in &stats.tables {
+            let labels = [table.name.as_str()];
+            self.table_keys
+                .with_label_values(&labels)
+                .set(table.keys as i64);
+            self.table_bytes
+                .with_label_values(&labels)
+                .set(table.bytes as i64);
+        }
+        self.disk_bytes.set(stats.disk_bytes.unwrap_or(0) as i64);
+        self.memory_bytes
+            .set(stats.memory_bytes.unwrap_or(0) as i64);
+        *refreshed = Some(Instant::now());
+        Ok(())
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for Metrics {
+    fn call(&self, cmd: CommandRequest, session: &mut Session, next: Next) -> CommandResponse {
+        let command = cmd.request_data.as_ref().map_or("unknown", |v| v.name());
+        self.bytes_received.inc_by(cmd.encoded_len() as u64);
+
+        let start = Instant::now();
+        let res = next.run(cmd, session);
+        let elapsed = start.elapsed();
+
+        self.requests.with_label_values(&[command]).inc();
+        self.latency
+            .with_label_values(&[command])
+            .observe(elapsed.as_secs_f64());
+        self.responses
+            .with_label_values(&[&res.status.to_string()])
+            .inc();
+        self.bytes_sent.inc_by(res.encoded_len() as u64);
+        res
+    }
+}
+
+/// 在 listener 上提供 HTTP 接口，GET /metrics 返回 Prometheus 文本格式的指标
+pub async fn serve_metrics<Store>(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    service: Service<Store>,
+) -> Result<(), KvError>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let metrics = metrics.clone();
+        let service = service.clone();
+
+        tokio::spawn(async move {
+            let handler = service_fn(move |req: Request<Body>| {
+                let metrics = metrics.clone();
+                let service = service.clone();
+                async move { Ok::<_, Infallible>(handle_request(&req, &metrics, &service).await) }
+            });
+            if let Err(e) = Http::new()
+                .http1_only(true)
+                .serve_connection(stream, handler)
+                .await
+            {
+                warn!("Failed to serve metrics to {:?}: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_request<Store>(
+    req: &Request<Body>,
+    metrics: &Metrics,
+    service: &Service<Store>,
+) -> Response<Body>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        return response(StatusCode::NOT_FOUND, "Not found".into());
+    }
+
+    match metrics.render(service).await {
+        Ok(body) => response(StatusCode::OK, body),
+        Err(e) => response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+fn response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::{MemTable, ServiceInner};
+
+    #[tokio::test]
+    async fn metrics_should_count_requests() {
+        let metrics = Arc::new(Metrics::new());
+        let service: Service = ServiceInner::new(MemTable::new())
+            .metrics(metrics.clone())
+            .into();
+
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        service.execute(CommandRequest::new_hset("t1", "k2", "v2".into()));
+        service.execute(CommandRequest::new_hget("t1", "k3"));
+
+        let text = metrics.render(&service).await.unwrap();
+        assert!(text.contains(r#"kv_requests_total{command="hset"} 2"#));
+        assert!(text.contains(r#"kv_requests_total{command="hget"} 1"#));
+        assert!(text.contains(r#"kv_request_duration_seconds_count{command="hset"} 2"#));
+        assert!(text.contains(r#"kv_responses_total{status="200"} 2"#));
+        assert!(text.contains(r#"kv_responses_total{status="404"} 1"#));
+        assert!(text.contains(r#"kv_storage_keys{table="t1"} 2"#));
+        assert!(text.contains("kv_storage_memory_bytes"));
+    }
+
+    #[tokio::test]
+    async fn storage_stats_should_be_cached() {
+        let metrics = Arc::new(Metrics::new());
+        let service: Service = ServiceInner::new(MemTable::new())
+            .metrics(metrics.clone())
+            .into();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let text = metrics.render(&service).await.unwrap();
+        assert!(text.contains(r#"kv_storage_keys{table="t1"} 1"#));
+
+        // 缓存没有过期时不会重新读取存储
+        service.execute(CommandRequest::new_hset("t1", "k2", "v2".into()));
+        let text = metrics.render(&service).await.unwrap();
+        assert!(text.contains(r#"kv_storage_keys{table="t1"} 1"#));
+
+        let metrics = Metrics::new().with_storage_stats_ttl(Duration::ZERO);
+        let text = metrics.render(&service).await.unwrap();
+        assert!(text.contains(r#"kv_storage_keys{table="t1"} 2"#));
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_should_work() -> Result<()> {
+        let metrics = Arc::new(Metrics::new());
+        let service: Service = ServiceInner::new(MemTable::new())
+            .metrics(metrics.clone())
+            .into();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve_metrics(listener, metrics, service));
+
+        let res = http_get(addr, "/metrics").await?;
+        assert!(res.starts_with("HTTP/1.1 200 OK"));
+        assert!(res.contains(r#"kv_requests_total{command="hset"} 1"#));
+
+        let res = http_get(addr, "/").await?;
+        assert!(res.starts_with("HTTP/1.1 404 Not Found"));
+        Ok(())
+    }
+
+    async fn http_get(addr: std::net::SocketAddr, path: &str) -> Result<String> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let req = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(req.as_bytes()).await?;
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).await?;
+        Ok(buf)
+    }
+}
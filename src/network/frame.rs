@@ -0,0 +1,566 @@
+use std::{
+    borrow::Cow,
+    io::{Read, Write},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{CommandRequest, CommandResponse, KvError, RaftMessage};
+
+/// frame 头部是 4 字节的大端整数
+///
+/// ```text
+/// | 1 bit 压缩 | 1 bit 握手 | 2 bits 版本 | 28 bits payload 长度 |
+/// ```
+const HEADER_LEN: usize = 4;
+/// 头部的最高位表示 payload 是压缩过的，使用握手时协商的算法
+const COMPRESSED_BIT: u32 = 1 << 31;
+/// 头部的次高位表示这是一个握手 frame
+const HANDSHAKE_BIT: u32 = 1 << 30;
+const VERSION_SHIFT: u32 = 28;
+const VERSION_MASK: u32 = 0b11 << VERSION_SHIFT;
+/// 目前的版本号是 0，不压缩时和只有长度头的 frame 兼容
+const VERSION: u32 = 0;
+/// 头部剩下的 28 位是 payload 的长度
+const LEN_MASK: u32 = (1 << VERSION_SHIFT) - 1;
+/// 缺省的最大 frame 大小（解压之后）
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// frame 的压缩算法，在连接建立时协商
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    pub const ALL: [Compression; 3] = [Compression::Gzip, Compression::Lz4, Compression::Zstd];
+
+    /// 握手 frame 中使用的编号
+    fn id(self) -> u8 {
+        match self {
+            Compression::Gzip => 1,
+            Compression::Lz4 => 2,
+            Compression::Zstd => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.id() == id)
+    }
+
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>, KvError> {
+        match self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            Compression::Zstd => Ok(zstd::encode_all(data, 1)?),
+        }
+    }
+
+    /// 解压时最多输出 max 字节，避免很小的 frame 解压出巨大的数据
+    pub fn decompress(self, data: &[u8], max: usize) -> Result<Vec<u8>, KvError> {
+        let mut buf = Vec::new();
+        match self {
+            Compression::Gzip => {
+                GzDecoder::new(data)
+                    .take(max as u64 + 1)
+                    .read_to_end(&mut buf)?;
+            }
+            Compression::Lz4 => {
+                // lz4_flex 按照开头 4 字节（小端）记录的长度分配内存，先检查它
+                let size = data
+                    .get(..4)
+                    .map(|v| u32::from_le_bytes([v[0], v[1], v[2], v[3]]) as usize)
+                    .ok_or_else(|| error("lz4 frame is too short"))?;
+                if size > max {
+                    return Err(error("decompressed frame is too large"));
+                }
+                buf = lz4_flex::decompress_size_prepended(data)
+                    .map_err(|e| error(&format!("invalid lz4 frame: {}", e)))?;
+            }
+            Compression::Zstd => {
+                zstd::Decoder::new(data)?
+                    .take(max as u64 + 1)
+                    .read_to_end(&mut buf)?;
+            }
+        }
+        if buf.len() > max {
+            return Err(error("decompressed frame is too large"));
+        }
+        Ok(buf)
+    }
+}
+
+/// 编解码 frame 时使用的参数
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameOptions {
+    /// 超过这个大小（解压之后）的 frame 直接报错，不会读取或者分配内存
+    pub max_frame_size: usize,
+    /// 协商好的压缩算法
+    pub compression: Option<Compression>,
+    /// payload 超过这个字节数才压缩
+    pub threshold: usize,
+}
+
+impl Default for FrameOptions {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            compression: None,
+            threshold: usize::MAX,
+        }
+    }
+}
+
+/// 把 protobuf message 编码成带长度头的 frame，或者从 frame 中解码出 message
+pub trait FrameCoder: Message + Default + Sized {
+    /// 把 message 编码成一个 frame，追加到 buf 后面
+    fn encode_frame(&self, options: &FrameOptions, buf: &mut BytesMut) -> Result<(), KvError> {
+        encode_payload(&self.encode_to_vec(), false, options, buf)
+    }
+
+    /// 从 buf 开头解码出一个 frame 并把它从 buf 中移除，数据不完整时返回 None
+    fn decode_frame(options: &FrameOptions, buf: &mut BytesMut) -> Result<Option<Self>, KvError> {
+        match decode_payload(options, buf)? {
+            Some(frame) if frame.handshake => Err(error("unexpected handshake frame")),
+            Some(frame) => Ok(Some(Self::decode(frame.payload)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl FrameCoder for CommandRequest {}
+impl FrameCoder for CommandResponse {}
+impl FrameCoder for RaftMessage {}
+
+/// 从连接中读到的一个 frame，payload 已经解压
+#[derive(Debug)]
+pub(crate) struct Frame {
+    pub handshake: bool,
+    pub payload: Bytes,
+}
+
+/// 在任何 AsyncRead + AsyncWrite（TCP、TLS、内存中的 duplex 等）上读写 frame
+///
+/// 协商了压缩算法之后自动压缩和解压。
+pub struct FrameStream<S> {
+    stream: S,
+    buf: BytesMut,
+    options: FrameOptions,
+}
+
+impl<S> FrameStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buf: BytesMut::new(),
+            options: FrameOptions::default(),
+        }
+    }
+
+    /// 设置最大的 frame 大小，对发送和接收都有效
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.options.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn compression(&self) -> Option<Compression> {
+        self.options.compression
+    }
+
+    pub(crate) fn set_compression(&mut self, compression: Option<Compression>, threshold: usize) {
+        self.options.compression = compression;
+        self.options.threshold = threshold;
+    }
+
+    /// 发送一个 message
+    pub async fn send<M: FrameCoder>(&mut self, msg: &M) -> Result<(), KvError> {
+        let mut buf = BytesMut::new();
+        msg.encode_frame(&self.options, &mut buf)?;
+        self.write_all(&buf).await
+    }
+
+    /// 接收一个 message，连接正常关闭时返回 None
+    pub async fn recv<M: FrameCoder>(&mut self) -> Result<Option<M>, KvError> {
+        loop {
+            if let Some(msg) = M::decode_frame(&self.options, &mut self.buf)? {
+                return Ok(Some(msg));
+            }
+            if !self.fill_buf().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// 读取下一个 frame，连接正常关闭时返回 None
+    ///
+    /// 没读完的数据保存在 buf 里，所以可以在 select! 中使用，被取消也不会丢数据。
+    pub(crate) async fn read_frame(&mut self) -> Result<Option<Frame>, KvError> {
+        loop {
+            if let Some(frame) = decode_payload(&self.options, &mut self.buf)? {
+                return Ok(Some(frame));
+            }
+            if !self.fill_buf().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// 关闭写的一端，对方读完剩下的 frame 之后会读到连接关闭
+    pub(crate) async fn shutdown(&mut self) -> Result<(), KvError> {
+        Ok(self.stream.shutdown().await?)
+    }
+
+    pub(crate) async fn write_frame(
+        &mut self,
+        handshake: bool,
+        payload: &[u8],
+    ) -> Result<(), KvError> {
+        let mut buf = BytesMut::new();
+        encode_payload(payload, handshake, &self.options, &mut buf)?;
+        self.write_all(&buf).await
+    }
+
+    /// 从连接读取更多的数据，连接在两个 frame 之间关闭时返回 false
+    async fn fill_buf(&mut self) -> Result<bool, KvError> {
+        if self.stream.read_buf(&mut self.buf).await? > 0 {
+            return Ok(true);
+        }
+        if self.buf.is_empty() {
+            return Ok(false);
+        }
+        Err(KvError::IoError(
+            "connection closed in the middle of a frame".into(),
+        ))
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> Result<(), KvError> {
+        self.stream.write_all(buf).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// 客户端发起握手，按顺序列出希望使用的算法，返回服务器选择的算法
+    pub(crate) async fn handshake(
+        &mut self,
+        algorithms: &[Compression],
+        threshold: usize,
+    ) -> Result<Option<Compression>, KvError> {
+        let ids: Vec<u8> = algorithms.iter().map(|c| c.id()).collect();
+        self.write_frame(true, &ids).await?;
+        let frame = match self.read_frame().await? {
+            Some(frame) if frame.handshake => frame,
+            Some(_) => return Err(error("expect a handshake frame")),
+            None => {
+                return Err(KvError::IoError(
+                    "connection closed during handshake".into(),
+                ))
+            }
+        };
+        let compression = match frame.payload.first() {
+            Some(id) => {
+                Some(Compression::from_id(*id).ok_or_else(|| error("unknown compression"))?)
+            }
+            None => None,
+        };
+        self.set_compression(compression, threshold);
+        Ok(compression)
+    }
+
+    /// 服务器回应握手，选择客户端列出的第一个自己也支持的算法
+    pub(crate) async fn accept_handshake(
+        &mut self,
+        frame: &Frame,
+        algorithms: &[Compression],
+        threshold: usize,
+    ) -> Result<Option<Compression>, KvError> {
+        let compression = frame
+            .payload
+            .iter()
+            .filter_map(|id| Compression::from_id(*id))
+            .find(|c| algorithms.contains(c));
+        let reply: Vec<u8> = compression.iter().map(|c| c.id()).collect();
+        self.write_frame(true, &reply).await?;
+        self.set_compression(compression, threshold);
+        Ok(compression)
+    }
+}
+
+fn encode_payload(
+    payload: &[u8],
+    handshake: bool,
+    options: &FrameOptions,
+    buf: &mut BytesMut,
+) -> Result<(), KvError> {
+    if payload.len() > options.max_frame_size {
+        return Err(error(&format!(
+            "frame of {} bytes exceeds the limit of {} bytes",
+            payload.len(),
+            options.max_frame_size
+        )));
+    }
+    let mut header = VERSION << VERSION_SHIFT;
+    let payload = match options.compression {
+        Some(compression) if !handshake && payload.len() > options.threshold => {
+            // 压缩之后没有变小就直接发送原始数据
+            let compressed = compression.compress(payload)?;
+            if compressed.len() < payload.len() {
+                header |= COMPRESSED_BIT;
+                Cow::Owned(compressed)
+            } else {
+                Cow::Borrowed(payload)
+            }
+        }
+        _ => Cow::Borrowed(payload),
+    };
+    if payload.len() > LEN_MASK as usize {
+        return Err(error("frame is too large"));
+    }
+    header |= payload.len() as u32;
+    if handshake {
+        header |= HANDSHAKE_BIT;
+    }
+
+    buf.reserve(HEADER_LEN + payload.len());
+    buf.put_u32(header);
+    buf.extend_from_slice(&payload);
+    Ok(())
+}
+
+fn decode_payload(options: &FrameOptions, buf: &mut BytesMut) -> Result<Option<Frame>, KvError> {
+    if buf.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    let header = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let version = (header & VERSION_MASK) >> VERSION_SHIFT;
+    if version != VERSION {
+        return Err(error(&format!("unsupported frame version {}", version)));
+    }
+    let len = (header & LEN_MASK) as usize;
+    // 在读取 payload 之前检查长度，避免为不合法的 frame 分配内存
+    if len > options.max_frame_size {
+        return Err(error(&format!(
+            "frame of {} bytes exceeds the limit of {} bytes",
+            len, options.max_frame_size
+        )));
+    }
+    if buf.len() < HEADER_LEN + len {
+        buf.reserve(HEADER_LEN + len - buf.len());
+        return Ok(None);
+    }
+
+    buf.advance(HEADER_LEN);
+    let mut payload = buf.split_to(len).freeze();
+    if header & COMPRESSED_BIT != 0 {
+        let compression = options
+            .compression
+            .ok_or_else(|| error("got a compressed frame without negotiating compression"))?;
+        payload = compression
+            .decompress(&payload, options.max_frame_size)?
+            .into();
+    }
+    Ok(Some(Frame {
+        handshake: header & HANDSHAKE_BIT != 0,
+        payload,
+    }))
+}
+
+fn error(msg: &str) -> KvError {
+    KvError::ProtocolError(msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{CommandRequest, CommandResponse, Kvpair, Value};
+
+    #[test]
+    fn frame_coder_should_handle_partial_frames() -> Result<()> {
+        let options = FrameOptions::default();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let mut buf = BytesMut::new();
+        cmd.encode_frame(&options, &mut buf)?;
+        cmd.encode_frame(&options, &mut buf)?;
+        let len = buf.len() / 2;
+        assert_eq!(&buf[..4], &(len as u32 - 4).to_be_bytes());
+
+        // 数据不完整时不消耗 buf
+        let mut partial = BytesMut::from(&buf[..len - 1]);
+        assert_eq!(CommandRequest::decode_frame(&options, &mut partial)?, None);
+        assert_eq!(partial.len(), len - 1);
+
+        assert_eq!(
+            CommandRequest::decode_frame(&options, &mut buf)?,
+            Some(cmd.clone())
+        );
+        assert_eq!(CommandRequest::decode_frame(&options, &mut buf)?, Some(cmd));
+        assert!(buf.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn frame_coder_should_reject_bad_headers() {
+        let options = FrameOptions {
+            max_frame_size: 16,
+            ..Default::default()
+        };
+
+        // 不认识的版本
+        let mut buf = BytesMut::from(&[0x10, 0, 0, 1, 0][..]);
+        assert!(CommandRequest::decode_frame(&options, &mut buf).is_err());
+
+        // 只看到头部就可以发现超过了最大的 frame 大小
+        let mut buf = BytesMut::from(&[0, 0, 0, 17][..]);
+        assert!(CommandRequest::decode_frame(&options, &mut buf).is_err());
+
+        let res = CommandResponse {
+            message: "x".repeat(100),
+            ..Default::default()
+        };
+        assert!(res.encode_frame(&options, &mut BytesMut::new()).is_err());
+
+        let mut buf = BytesMut::from(&[0x40, 0, 0, 0][..]);
+        assert!(CommandResponse::decode_frame(&options, &mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn frame_stream_should_work_over_duplex() -> Result<()> {
+        let (client, server) = tokio::io::duplex(16);
+        let (mut client, mut server) = (FrameStream::new(client), FrameStream::new(server));
+
+        let handle = tokio::spawn(async move {
+            while let Some(cmd) = server.recv::<CommandRequest>().await.unwrap() {
+                let res = CommandResponse {
+                    status: 200,
+                    message: format!("{:?}", cmd.request_data.is_some()),
+                    ..Default::default()
+                };
+                server.send(&res).await.unwrap();
+            }
+        });
+
+        client
+            .send(&CommandRequest::new_hget(
+                "table",
+                "a long key that spans reads",
+            ))
+            .await?;
+        let res = client.recv::<CommandResponse>().await?.unwrap();
+        assert_eq!(res.message, "true");
+        drop(client);
+        handle.await?;
+        Ok(())
+    }
+
+    #[test]
+    fn every_codec_should_round_trip() -> Result<()> {
+        let data = b"hello world ".repeat(1000);
+        for compression in Compression::ALL {
+            let compressed = compression.compress(&data)?;
+            assert!(compressed.len() < data.len(), "{:?}", compression);
+            assert_eq!(compression.decompress(&compressed, data.len())?, data);
+            // 超过上限的数据不会被解压出来
+            assert!(compression.decompress(&compressed, data.len() - 1).is_err());
+            assert!(compression.decompress(b"garbage", data.len()).is_err());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn frames_should_round_trip_with_every_codec() -> Result<()> {
+        for compression in Compression::ALL {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let (mut client, mut server) = (FrameStream::new(client), FrameStream::new(server));
+
+            let handle = tokio::spawn(async move {
+                let frame = server.read_frame().await.unwrap().unwrap();
+                assert!(frame.handshake);
+                let chosen = server
+                    .accept_handshake(&frame, &Compression::ALL, 100)
+                    .await
+                    .unwrap();
+                assert_eq!(chosen, Some(compression));
+
+                // 把收到的请求里的 pairs 原样放在响应里发回去
+                let frame = server.read_frame().await.unwrap().unwrap();
+                let cmd = CommandRequest::decode(frame.payload).unwrap();
+                let pairs = match cmd.request_data {
+                    Some(crate::command_request::RequestData::Hmset(v)) => v.pairs,
+                    _ => panic!("expect hmset"),
+                };
+                let res = CommandResponse {
+                    status: 200,
+                    pairs,
+                    ..Default::default()
+                };
+                server.send(&res).await.unwrap();
+            });
+
+            let chosen = client.handshake(&[compression], 100).await?;
+            assert_eq!(chosen, Some(compression));
+            let pairs: Vec<_> = (0..100)
+                .map(|i| Kvpair::new(format!("k{}", i), Value::from("v".repeat(100))))
+                .collect();
+            client
+                .send(&CommandRequest::new_hmset("t1", pairs.clone()))
+                .await?;
+            let frame = client.read_frame().await?.unwrap();
+            let res = CommandResponse::decode(frame.payload)?;
+            assert_eq!(res.pairs, pairs);
+            handle.await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn small_frames_should_not_be_compressed() -> Result<()> {
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut client = FrameStream::new(client);
+        client.set_compression(Some(Compression::Zstd), 100);
+
+        client.write_frame(false, b"short").await?;
+        client.write_frame(false, &[b'a'; 200]).await?;
+        drop(client);
+
+        let mut buf = Vec::new();
+        server.read_to_end(&mut buf).await?;
+        assert_eq!(&buf[..9], b"\0\0\0\x05short");
+        let header = u32::from_be_bytes([buf[9], buf[10], buf[11], buf[12]]);
+        assert_ne!(header & COMPRESSED_BIT, 0);
+        assert!(((header & LEN_MASK) as usize) < 200);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake_should_fall_back_to_no_compression() -> Result<()> {
+        let (client, server) = tokio::io::duplex(1024);
+        let (mut client, mut server) = (FrameStream::new(client), FrameStream::new(server));
+
+        let handle = tokio::spawn(async move {
+            let frame = server.read_frame().await.unwrap().unwrap();
+            server
+                .accept_handshake(&frame, &[Compression::Gzip], 0)
+                .await
+                .unwrap()
+        });
+        let chosen = client.handshake(&[Compression::Zstd], 0).await?;
+        assert_eq!(chosen, None);
+        assert_eq!(handle.await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,436 @@
+use std::{convert::Infallible, pin::Pin, sync::Arc, time::Instant};
+
+use futures::{stream, Stream};
+use hyper::{server::conn::Http, service::service_fn, Body};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::mpsc,
+};
+use tokio_util::sync::CancellationToken;
+use tonic::{codegen::Service as _, Request, Response, Status};
+use tracing::warn;
+
+use super::{http::parse_authorization, recv_push, tls::accept_stream, ConnectionEvents};
+use crate::{
+    command_request::RequestData, pb::kv::kv_server::Kv, CommandRequest, CommandResponse, KvError,
+    MemTable, Service, Session, Storage, TlsServerAcceptor,
+};
+
+pub use crate::pb::kv::{kv_client::KvClient, kv_server::KvServer};
+
+/// Stream 返回 HGETALL 的结果时，每个响应最多包含的 pair 数
+const SCAN_CHUNK_SIZE: usize = 100;
+/// Stream 推送消息时，等待客户端读取的消息数
+const STREAM_BUFFER: usize = 16;
+/// gRPC 使用 HTTP/2，TLS 握手时协商 h2
+pub(crate) const ALPN_GRPC: &str = "h2";
+
+type ResponseStream = Pin<Box<dyn Stream<Item = Result<CommandResponse, Status>> + Send>>;
+
+/// 把 Service 包装成 kv.proto 中定义的 gRPC 服务
+///
+/// 命令执行的结果放在 CommandResponse 的 status 里，gRPC 的 status 总是 OK。
+pub struct GrpcService<Store: Storage = MemTable> {
+    service: Service<Store>,
+    /// 服务器关闭时被取消，订阅和 watch 的 Stream 通知客户端之后结束
+    shutdown: CancellationToken,
+}
+
+impl<Store: Storage> GrpcService<Store> {
+    pub fn new(service: Service<Store>) -> Self {
+        Self {
+            service,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// token 被取消时，正在推送消息的 Stream 发送 503 之后结束
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+}
+
+/// 在 listener 上提供 gRPC 服务，配置了 TLS 时先完成握手
+///
+/// 同一个连接上的 RPC 共享连接的 Session，所以连接级别的限流对所有 RPC 有效。
+pub async fn serve_grpc<Store>(
+    listener: TcpListener,
+    service: Service<Store>,
+    tls: Option<TlsServerAcceptor>,
+) -> Result<(), KvError>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    let tls = tls.map(|tls| tls.with_alpn(&[ALPN_GRPC]));
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let (service, tls) = (service.clone(), tls.clone());
+        tokio::spawn(async move {
+            match accept_stream(stream, addr, tls.as_ref()).await {
+                Ok((stream, session)) => {
+                    serve_connection(stream, service, session, CancellationToken::new()).await
+                }
+                Err(e) => warn!("Failed to accept gRPC client {:?}: {}", addr, e),
+            }
+        });
+    }
+}
+
+/// 处理一个 HTTP/2 连接上的所有 RPC，shutdown 被取消时，处理完正在处理的 RPC 之后关闭连接
+pub(crate) async fn serve_connection<S, Store>(
+    stream: S,
+    service: Service<Store>,
+    session: Session,
+    shutdown: CancellationToken,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    Store: Storage + Send + Sync + 'static,
+{
+    let peer = session.peer;
+    let events = Arc::new(ConnectionEvents::new(service.clone(), peer));
+    let info = GrpcConnectInfo { session, events };
+    let server = KvServer::new(GrpcService::new(service).with_shutdown(shutdown.clone()));
+    let handler = service_fn(move |mut req: hyper::Request<Body>| {
+        req.extensions_mut().insert(info.clone());
+        let mut server = server.clone();
+        async move { Ok::<_, Infallible>(server.call(req).await.unwrap_or_else(|e| match e {})) }
+    });
+    let conn = Http::new()
+        .http2_only(true)
+        .serve_connection(stream, handler);
+    tokio::pin!(conn);
+    let result = tokio::select! {
+        result = &mut conn => result,
+        _ = shutdown.cancelled() => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+    if let Err(e) = result {
+        warn!("Failed to serve gRPC to {:?}: {}", peer, e);
+    }
+}
+
+/// 连接的 Session 和事件，放进这个连接上每个请求的 extensions
+struct GrpcConnectInfo<Store: Storage> {
+    session: Session,
+    events: Arc<ConnectionEvents<Store>>,
+}
+
+impl<Store: Storage> Clone for GrpcConnectInfo<Store> {
+    fn clone(&self) -> Self {
+        Self {
+            session: self.session.clone(),
+            events: self.events.clone(),
+        }
+    }
+}
+
+impl<Store: Storage + Send + Sync + 'static> GrpcService<Store> {
+    /// 每个 RPC 使用连接的 Session 的副本，metadata 里有 authorization 时先在这个副本里认证
+    ///
+    /// 不是通过 serve_grpc 提供服务（比如交给 tonic 的 Server）时没有连接的信息，每个 RPC 使用独立的 Session。
+    async fn session<T>(&self, req: &Request<T>) -> Result<Session, Box<CommandResponse>> {
+        let mut session = match req.extensions().get::<GrpcConnectInfo<Store>>() {
+            Some(info) => info.session.clone(),
+            None => Session::new(req.remote_addr()),
+        };
+        if let Some(value) = req.metadata().get("authorization") {
+            let res = match value.to_str() {
+                Ok(v) => match parse_authorization(v) {
+                    Ok(cmd) => self.service.execute_async(cmd, &mut session).await,
+                    Err(e) => e.into(),
+                },
+                Err(_) => KvError::Unauthenticated("invalid authorization metadata".into()).into(),
+            };
+            if !(200..300).contains(&res.status) {
+                return Err(Box::new(res));
+            }
+        }
+        Ok(session)
+    }
+
+    /// 订阅和 watch 之后，把推送的消息转发给客户端，直到客户端取消或者来不及读取
+    fn forward(&self, res: CommandResponse, session: Session) -> ResponseStream {
+        let (service, shutdown) = (self.service.clone(), self.shutdown.clone());
+        let mut push = service.pubsub().take_receiver(session.id);
+        let mut events = service.watcher().take_receiver(session.id);
+        if push.is_none() && events.is_none() {
+            return once(res);
+        }
+
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        tokio::spawn(async move {
+            if tx.send(Ok(res)).await.is_ok() {
+                loop {
+                    let msg = tokio::select! {
+                        msg = recv_push(&mut push) => msg,
+                        msg = recv_push(&mut events) => msg,
+                        _ = tx.closed() => break,
+                        _ = shutdown.cancelled() => {
+                            tx.send(Ok(KvError::ShuttingDown.into())).await.ok();
+                            break;
+                        }
+                    };
+                    let msg = match msg {
+                        Some(msg) => msg,
+                        None => {
+                            warn!(
+                                "Client {:?} is too slow to receive messages, closing stream",
+                                session.peer
+                            );
+                            let error =
+                                KvError::Internal("Client is too slow to receive messages".into());
+                            tx.send(Ok(error.into())).await.ok();
+                            break;
+                        }
+                    };
+                    if tx.send(Ok(msg)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            service.pubsub().remove(session.id);
+            service.watcher().remove(session.id);
+        });
+
+        Box::pin(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|item| (item, rx))
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl<Store: Storage + Send + Sync + 'static> Kv for GrpcService<Store> {
+    async fn execute(
+        &self,
+        request: Request<CommandRequest>,
+    ) -> Result<Response<CommandResponse>, Status> {
+        let start = Instant::now();
+        let events = self.connection_events(&request);
+        let res = self.execute_request(request).await;
+        if let Some(events) = events {
+            events.sent(Ok(()), start).ok();
+        }
+        Ok(Response::new(res))
+    }
+
+    type StreamStream = ResponseStream;
+
+    async fn stream(
+        &self,
+        request: Request<CommandRequest>,
+    ) -> Result<Response<Self::StreamStream>, Status> {
+        let start = Instant::now();
+        let events = self.connection_events(&request);
+        let res = self.stream_request(request).await;
+        if let Some(events) = events {
+            events.sent(Ok(()), start).ok();
+        }
+        Ok(Response::new(res))
+    }
+}
+
+impl<Store: Storage + Send + Sync + 'static> GrpcService<Store> {
+    /// 通过 serve_grpc 提供服务时，请求所在连接的事件；响应交给 tonic 之后调用它的 `sent`
+    fn connection_events<T>(&self, req: &Request<T>) -> Option<Arc<ConnectionEvents<Store>>> {
+        req.extensions()
+            .get::<GrpcConnectInfo<Store>>()
+            .map(|info| info.events.clone())
+    }
+
+    async fn execute_request(&self, request: Request<CommandRequest>) -> CommandResponse {
+        let mut session = match self.session(&request).await {
+            Ok(session) => session,
+            Err(res) => return *res,
+        };
+        let cmd = request.into_inner();
+        // 一次性的调用没法接收推送的消息
+        if let Some(RequestData::Subscribe(_) | RequestData::Watch(_)) = cmd.request_data {
+            let error = KvError::InvalidCommand("subscribe and watch require Stream".into());
+            return error.into();
+        }
+        self.service.execute_with(cmd, &mut session)
+    }
+
+    async fn stream_request(&self, request: Request<CommandRequest>) -> ResponseStream {
+        let mut session = match self.session(&request).await {
+            Ok(session) => session,
+            Err(res) => return once(*res),
+        };
+        let cmd = request.into_inner();
+        let is_scan = matches!(cmd.request_data, Some(RequestData::Hgetall(_)));
+        // 推送的消息按照 session id 分发，所以每个订阅的 Stream 需要自己的 Session
+        if let Some(RequestData::Subscribe(_) | RequestData::Watch(_)) = cmd.request_data {
+            session = Session::new(session.peer).with_user(session.user);
+        }
+        let res = self.service.execute_with(cmd, &mut session);
+
+        if is_scan && res.pairs.len() > SCAN_CHUNK_SIZE {
+            let chunks: Vec<_> = res
+                .pairs
+                .chunks(SCAN_CHUNK_SIZE)
+                .map(|pairs| CommandResponse {
+                    status: res.status,
+                    pairs: pairs.to_vec(),
+                    ..Default::default()
+                })
+                .collect();
+            return Box::pin(stream::iter(chunks.into_iter().map(Ok)));
+        }
+        self.forward(res, session)
+    }
+}
+
+fn once(res: CommandResponse) -> ResponseStream {
+    Box::pin(stream::once(async move { Ok(res) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use anyhow::Result;
+    use futures::StreamExt;
+    use tokio::net::TcpStream;
+    use tonic::transport::{Channel, Endpoint};
+    use tower::service_fn;
+
+    use super::*;
+    use crate::{
+        assert_res_ok,
+        network::tls::test_utils::{generate_certs, DOMAIN},
+        BucketConfig, MemTable, RateLimitConfig, RateLimiter, ServiceInner, TlsClientConnector,
+        Value,
+    };
+
+    #[tokio::test]
+    async fn grpc_execute_should_work() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let mut client = start_server(service).await?;
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute(cmd).await?.into_inner();
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res.into_inner(), &["v1".into()], &[]);
+
+        let res = client.execute(CommandRequest::new_subscribe(["c1"], Vec::<&str>::new()));
+        assert_eq!(res.await?.into_inner().status, 400);
+
+        // authorization 不合法时，命令不会执行
+        let mut req = Request::new(CommandRequest::new_hget("t1", "k1"));
+        req.metadata_mut()
+            .insert("authorization", "Unknown x".parse()?);
+        assert_eq!(client.execute(req).await?.into_inner().status, 401);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn grpc_stream_should_return_chunks_and_pushes() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        for i in 0..250i64 {
+            service.execute(CommandRequest::new_hset("t1", format!("k{}", i), i.into()));
+        }
+        let mut client = start_server(service.clone()).await?;
+
+        let res = client.stream(CommandRequest::new_hget_all("t1")).await?;
+        let chunks: Vec<_> = res.into_inner().collect().await;
+        let sizes: Vec<_> = chunks
+            .iter()
+            .map(|r| r.as_ref().unwrap().pairs.len())
+            .collect();
+        assert_eq!(sizes, vec![100, 100, 50]);
+
+        let cmd = CommandRequest::new_subscribe(["news"], Vec::<&str>::new());
+        let mut stream = client.stream(cmd).await?.into_inner();
+        assert_eq!(stream.next().await.unwrap()?.status, 200);
+
+        let res = client.execute(CommandRequest::new_publish("news", "hello".into()));
+        assert_res_ok(res.await?.into_inner(), &[1.into()], &[]);
+        let msg = stream.next().await.unwrap()?.pubsub.unwrap();
+        assert_eq!(msg.channel, "news");
+        assert_eq!(msg.data, Some("hello".into()));
+
+        // 客户端取消之后，订阅会被清理
+        drop(stream);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let res = client.execute(CommandRequest::new_publish("news", "bye".into()));
+        assert_res_ok(res.await?.into_inner(), &[0.into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rpcs_on_one_connection_should_share_the_connection_limit() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(RateLimiter::new(RateLimitConfig {
+                per_connection: Some(BucketConfig {
+                    rate: 0.001,
+                    burst: 1,
+                }),
+                ..Default::default()
+            }))
+            .into();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr: SocketAddr = listener.local_addr()?;
+        tokio::spawn(serve_grpc(listener, service.clone(), None));
+
+        // 同一个连接上的第二个 RPC 被限流
+        let mut client = KvClient::connect(format!("http://{}", addr)).await?;
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.into_inner().status, 404);
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.into_inner().status, 429);
+
+        // 新的连接有自己的限额
+        let mut client = KvClient::connect(format!("http://{}", addr)).await?;
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.into_inner().status, 404);
+        let res = service.execute(CommandRequest::new_info(["clients"]));
+        let total = res
+            .pairs
+            .iter()
+            .find(|p| p.key == "clients.total_connections");
+        assert_eq!(total.and_then(|p| p.value.clone()), Some(2.into()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn grpc_with_tls_should_work() -> Result<()> {
+        let certs = generate_certs("client1");
+        let acceptor = TlsServerAcceptor::new(&certs.server_cert, &certs.server_key, None)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr: SocketAddr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(serve_grpc(listener, service, Some(acceptor)));
+
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&certs.ca))?.with_alpn(&["h2"]);
+        let channel = Endpoint::from_shared(format!("http://{}", addr))?
+            .connect_with_connector(service_fn(move |_| {
+                let connector = connector.clone();
+                async move {
+                    let stream = TcpStream::connect(addr).await?;
+                    connector.connect(stream).await
+                }
+            }))
+            .await?;
+        let mut client = KvClient::new(channel);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute(cmd).await?.into_inner();
+        assert_res_ok(res, &[Value::default()], &[]);
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res.into_inner(), &["v1".into()], &[]);
+        Ok(())
+    }
+
+    async fn start_server(service: Service) -> Result<KvClient<Channel>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr: SocketAddr = listener.local_addr()?;
+        tokio::spawn(serve_grpc(listener, service, None));
+        Ok(KvClient::connect(format!("http://{}", addr)).await?)
+    }
+}
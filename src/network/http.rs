@@ -0,0 +1,663 @@
+use std::{convert::Infallible, sync::Arc, time::Instant};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{
+    body::HttpBody, header, server::conn::Http, service::service_fn, Body, Method, Request,
+    Response, StatusCode,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Number};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use super::{tls::accept_stream, ConnectionEvents};
+use crate::{
+    value, CommandRequest, CommandResponse, KvError, Service, Session, Storage, TlsServerAcceptor,
+    Value,
+};
+
+/// HTTPS 通过 ALPN 协商的协议
+pub(crate) const ALPN_HTTP: &str = "http/1.1";
+/// 请求 body 最大 1MB
+const MAX_BODY_LEN: usize = 1024 * 1024;
+/// GET /tables/{t} 缺省每页返回的 key 数
+const DEFAULT_PAGE_SIZE: usize = 100;
+/// GET /tables/{t} 每页最多返回的 key 数
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// 在 listener 上提供 REST 接口，把 HTTP 请求翻译成 CommandRequest 交给 Service 执行
+///
+/// - GET/PUT/DELETE /tables/{t}/keys/{k}：读取、写入、删除一个 key，PUT 的 body 是 JSON 格式的值
+/// - GET /tables/{t}?limit=&after=：按 key 排序分页返回整个表，after 是上一页返回的 next
+/// - POST /batch：body 是 {"requests": [{"op": "get|put|delete", "table", "key", "value"}]}
+///
+/// 值的 JSON 格式：string、整数、浮点数、bool 直接对应，二进制是 {"binary": "<base64>"}。
+/// HTTP status 就是 CommandResponse 的 status，出错时 body 是 {"error": message}。
+/// 认证使用 Authorization 头，Bearer 是 token，Basic 是用户名和密码。
+///
+/// 配置了 TLS 时使用 HTTPS，双向认证时客户端证书中的身份就是请求的用户。
+pub async fn serve_http<Store>(
+    listener: TcpListener,
+    service: Service<Store>,
+    tls: Option<TlsServerAcceptor>,
+) -> Result<(), KvError>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    let tls = tls.map(|tls| tls.with_alpn(&[ALPN_HTTP]));
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let (service, tls) = (service.clone(), tls.clone());
+        tokio::spawn(async move {
+            match accept_stream(stream, addr, tls.as_ref()).await {
+                Ok((stream, session)) => {
+                    serve_connection(stream, service, session, CancellationToken::new()).await
+                }
+                Err(e) => warn!("Failed to accept HTTP client {:?}: {}", addr, e),
+            }
+        });
+    }
+}
+
+/// 处理一个 HTTP 连接上的所有请求
+///
+/// 请求都使用连接的 Session id，所以 per_connection 限流同样按连接计算；认证的结果不在请求之间共享，
+/// 每个请求都要带上自己的 Authorization 头。shutdown 被取消时，处理完正在处理的请求之后关闭连接。
+pub(crate) async fn serve_connection<S, Store>(
+    stream: S,
+    service: Service<Store>,
+    session: Session,
+    shutdown: CancellationToken,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    Store: Storage + Send + Sync + 'static,
+{
+    let peer = session.peer;
+    let events = Arc::new(ConnectionEvents::new(service.clone(), peer));
+    let handler = service_fn(move |req: Request<Body>| {
+        let (service, session, events) = (service.clone(), session.clone(), events.clone());
+        async move {
+            let start = Instant::now();
+            let res = handle_request(req, &service, session).await;
+            // 响应由 hyper 在之后写入，写入失败时连接会断开，这里只统计处理的时间
+            events.sent(Ok(()), start).ok();
+            Ok::<_, Infallible>(res)
+        }
+    });
+    let conn = Http::new()
+        .http1_only(true)
+        .serve_connection(stream, handler);
+    tokio::pin!(conn);
+    let result = tokio::select! {
+        result = &mut conn => result,
+        _ = shutdown.cancelled() => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+    if let Err(e) = result {
+        warn!("Failed to serve HTTP to {:?}: {}", peer, e);
+    }
+}
+
+/// POST /batch 中的一个操作
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchOp {
+    Get {
+        table: String,
+        key: String,
+    },
+    Put {
+        table: String,
+        key: String,
+        value: serde_json::Value,
+    },
+    Delete {
+        table: String,
+        key: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    requests: Vec<BatchOp>,
+}
+
+async fn handle_request<Store: Storage + Send + Sync + 'static>(
+    req: Request<Body>,
+    service: &Service<Store>,
+    mut session: Session,
+) -> Response<Body> {
+    // session 是连接的 Session 的副本，需要认证时通过 Authorization 头在这个副本里先认证
+    if let Some(cmd) = auth_command(&req) {
+        let res = match cmd {
+            Ok(cmd) => service.execute_async(cmd, &mut session).await,
+            Err(e) => e.into(),
+        };
+        if !is_success(&res) {
+            return error_response(&res);
+        }
+    }
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let query = req.uri().query().unwrap_or_default().to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    let result = match (&method, segments.as_slice()) {
+        (&Method::GET, ["tables", table, "keys", key]) => {
+            let (table, key) = match (decode(table), decode(key)) {
+                (Ok(table), Ok(key)) => (table, key),
+                (Err(e), _) | (_, Err(e)) => return error_response(&e.into()),
+            };
+            let cmd = CommandRequest::new_hget(table, key);
+            let res = service.execute_async(cmd, &mut session).await;
+            value_response(res, "value")
+        }
+        (&Method::PUT, ["tables", table, "keys", key]) => {
+            match (decode(table), decode(key), read_json(req.into_body()).await) {
+                (Ok(table), Ok(key), Ok(value)) => match json_to_value(value) {
+                    Ok(value) => {
+                        let cmd = CommandRequest::new_hset(table, key, value);
+                        value_response(service.execute_async(cmd, &mut session).await, "old_value")
+                    }
+                    Err(e) => Err(Box::new(e.into())),
+                },
+                (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => Err(Box::new(e.into())),
+            }
+        }
+        (&Method::DELETE, ["tables", table, "keys", key]) => {
+            let (table, key) = match (decode(table), decode(key)) {
+                (Ok(table), Ok(key)) => (table, key),
+                (Err(e), _) | (_, Err(e)) => return error_response(&e.into()),
+            };
+            let cmd = CommandRequest::new_hdel(table, key);
+            let res = service.execute_async(cmd, &mut session).await;
+            value_response(res, "old_value")
+        }
+        (&Method::GET, ["tables", table]) => match (decode(table), parse_query(&query)) {
+            (Ok(table), Ok(query)) => {
+                // 多读一个 key，用来判断是否还有下一页
+                let limit = query.limit();
+                let after = query.after.unwrap_or_default();
+                let cmd = CommandRequest::new_hget_range(table, after, limit as u32 + 1);
+                page_response(service.execute_async(cmd, &mut session).await, limit)
+            }
+            (Err(e), _) | (_, Err(e)) => Err(Box::new(e.into())),
+        },
+        (&Method::POST, ["batch"]) => match read_json(req.into_body()).await {
+            Ok(body) => match serde_json::from_value::<BatchRequest>(body) {
+                Ok(batch) => Ok(batch_response(batch, service, &mut session).await),
+                Err(e) => Err(Box::new(KvError::InvalidCommand(e.to_string()).into())),
+            },
+            Err(e) => Err(Box::new(e.into())),
+        },
+        _ => {
+            return json_response(
+                StatusCode::NOT_FOUND,
+                json!({ "error": format!("No route for {} {}", method, path) }),
+            )
+        }
+    };
+
+    match result {
+        Ok(body) => json_response(StatusCode::OK, body),
+        Err(res) => error_response(&res),
+    }
+}
+
+/// 把 Authorization 头翻译成 Auth 命令，没有这个头时返回 None
+fn auth_command(req: &Request<Body>) -> Option<Result<CommandRequest, KvError>> {
+    let value = req.headers().get(header::AUTHORIZATION)?;
+    Some(match value.to_str() {
+        Ok(v) => parse_authorization(v),
+        Err(_) => Err(invalid_authorization()),
+    })
+}
+
+/// 解析 Authorization 的值，Bearer 是 token，Basic 是 base64 编码的用户名和密码
+pub(crate) fn parse_authorization(value: &str) -> Result<CommandRequest, KvError> {
+    match value.split_once(' ') {
+        Some(("Bearer", token)) => Ok(CommandRequest::new_auth_token(token.trim())),
+        Some(("Basic", credentials)) => STANDARD
+            .decode(credentials.trim())
+            .ok()
+            .and_then(|v| String::from_utf8(v).ok())
+            .and_then(|v| {
+                v.split_once(':')
+                    .map(|(user, pass)| CommandRequest::new_auth(user, pass))
+            })
+            .ok_or_else(invalid_authorization),
+        _ => Err(invalid_authorization()),
+    }
+}
+
+fn invalid_authorization() -> KvError {
+    KvError::Unauthenticated("invalid Authorization header".into())
+}
+
+/// 单个值的响应：{name: value}
+fn value_response(
+    res: CommandResponse,
+    name: &str,
+) -> Result<serde_json::Value, Box<CommandResponse>> {
+    if !is_success(&res) {
+        return Err(Box::new(res));
+    }
+    let value = res.values.first().map(value_to_json).unwrap_or_default();
+    let mut body = Map::new();
+    body.insert(name.into(), value);
+    Ok(body.into())
+}
+
+/// 分页查询的参数
+#[derive(Debug, Default, PartialEq)]
+struct PageQuery {
+    limit: Option<usize>,
+    after: Option<String>,
+}
+
+impl PageQuery {
+    fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+}
+
+/// 整个表的分页响应：{"items": [{"key", "value"}], "next": 下一页的 after，没有下一页时为 null}
+///
+/// res 是按 key 排序的最多 limit + 1 个 kv pair，多出来的一个说明还有下一页。
+fn page_response(
+    res: CommandResponse,
+    limit: usize,
+) -> Result<serde_json::Value, Box<CommandResponse>> {
+    if !is_success(&res) {
+        return Err(Box::new(res));
+    }
+    let mut items = res.pairs;
+    let next = match items.len() > limit {
+        true => {
+            items.truncate(limit);
+            items.last().map(|p| json!(p.key))
+        }
+        false => None,
+    };
+
+    let items: Vec<_> = items
+        .into_iter()
+        .map(|p| json!({ "key": p.key, "value": p.value.as_ref().map(value_to_json) }))
+        .collect();
+    Ok(json!({ "items": items, "next": next }))
+}
+
+/// 依次执行 batch 里的每个操作，每个操作有自己的 status，整个请求总是返回 200
+async fn batch_response<Store: Storage + Send + Sync + 'static>(
+    batch: BatchRequest,
+    service: &Service<Store>,
+    session: &mut Session,
+) -> serde_json::Value {
+    let mut responses = Vec::with_capacity(batch.requests.len());
+    for op in batch.requests {
+        let (cmd, name) = match op {
+            BatchOp::Get { table, key } => (Ok(CommandRequest::new_hget(table, key)), "value"),
+            BatchOp::Put { table, key, value } => (
+                json_to_value(value).map(|v| CommandRequest::new_hset(table, key, v)),
+                "old_value",
+            ),
+            BatchOp::Delete { table, key } => {
+                (Ok(CommandRequest::new_hdel(table, key)), "old_value")
+            }
+        };
+        let res = match cmd {
+            Ok(cmd) => service.execute_async(cmd, session).await,
+            Err(e) => e.into(),
+        };
+        let status = res.status;
+        let mut body = match value_response(res, name) {
+            Ok(body) => body,
+            Err(res) => json!({ "error": res.message }),
+        };
+        body["status"] = json!(status);
+        responses.push(body);
+    }
+    json!({ "responses": responses })
+}
+
+async fn read_json(mut body: Body) -> Result<serde_json::Value, KvError> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| KvError::IoError(e.to_string()))?;
+        if buf.len() + chunk.len() > MAX_BODY_LEN {
+            return Err(KvError::InvalidCommand(format!(
+                "request body exceeds {} bytes",
+                MAX_BODY_LEN
+            )));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    serde_json::from_slice(&buf).map_err(|e| KvError::InvalidCommand(e.to_string()))
+}
+
+/// JSON 转换成 Value：string、整数、浮点数、bool 直接对应，{"binary": "<base64>"} 是二进制
+fn json_to_value(json: serde_json::Value) -> Result<Value, KvError> {
+    let value = match json {
+        serde_json::Value::String(s) => value::Value::String(s),
+        serde_json::Value::Bool(b) => value::Value::Bool(b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => value::Value::Integer(i),
+            None => value::Value::Float(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::Object(obj) => match obj.get("binary") {
+            Some(serde_json::Value::String(data)) if obj.len() == 1 => STANDARD
+                .decode(data)
+                .map(|v| value::Value::Binary(v.into()))
+                .map_err(|e| KvError::InvalidCommand(format!("invalid base64: {}", e)))?,
+            _ => return Err(unsupported(&obj.into())),
+        },
+        other => return Err(unsupported(&other)),
+    };
+    Ok(Value { value: Some(value) })
+}
+
+fn value_to_json(value: &Value) -> serde_json::Value {
+    match &value.value {
+        Some(value::Value::String(s)) => json!(s),
+        Some(value::Value::Integer(i)) => json!(i),
+        Some(value::Value::Float(f)) => Number::from_f64(*f)
+            .map(serde_json::Value::Number)
+            .unwrap_or_default(),
+        Some(value::Value::Bool(b)) => json!(b),
+        Some(value::Value::Binary(data)) => json!({ "binary": STANDARD.encode(data) }),
+        None => serde_json::Value::Null,
+    }
+}
+
+fn unsupported(json: &serde_json::Value) -> KvError {
+    KvError::InvalidCommand(format!("unsupported value: {}", json))
+}
+
+fn parse_query(query: &str) -> Result<PageQuery, KvError> {
+    let mut result = PageQuery::default();
+    for pair in query.split('&').filter(|v| !v.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = decode(&value.replace('+', " "))?;
+        match name {
+            "limit" => {
+                let limit = value
+                    .parse()
+                    .map_err(|_| KvError::InvalidCommand(format!("invalid limit: {}", value)))?;
+                result.limit = Some(limit);
+            }
+            "after" => result.after = Some(value),
+            _ => {}
+        }
+    }
+    Ok(result)
+}
+
+/// 解码 URL 中 %XX 形式的字符
+fn decode(s: &str) -> Result<String, KvError> {
+    let invalid = || KvError::InvalidCommand(format!("invalid percent-encoding: {}", s));
+    let bytes = s.as_bytes();
+    let mut buf = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).ok_or_else(invalid)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            buf.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            i += 3;
+        } else {
+            buf.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(buf).map_err(|_| invalid())
+}
+
+fn is_success(res: &CommandResponse) -> bool {
+    (200..300).contains(&res.status)
+}
+
+fn error_response(res: &CommandResponse) -> Response<Body> {
+    let status =
+        StatusCode::from_u16(res.status as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut response = json_response(status, json!({ "error": res.message }));
+    // follower 拒绝写命令时，把 leader 的地址告诉客户端
+    if status == StatusCode::TEMPORARY_REDIRECT {
+        if let Some(value::Value::String(leader)) =
+            res.values.first().and_then(|v| v.value.as_ref())
+        {
+            if let Ok(leader) = leader.parse() {
+                response.headers_mut().insert("X-Kv-Leader", leader);
+            }
+        }
+    }
+    response
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::{
+        network::tls::test_utils::{generate_certs, DOMAIN},
+        BucketConfig, MemTable, RateLimitConfig, RateLimiter, ServiceInner, TlsClientConnector,
+    };
+
+    #[test]
+    fn json_should_be_converted_to_value_and_back() {
+        let cases = [
+            json!("hello"),
+            json!(42),
+            json!(1.5),
+            json!(true),
+            json!({ "binary": "AAEC" }),
+        ];
+        for case in cases {
+            let value = json_to_value(case.clone()).unwrap();
+            assert_eq!(value_to_json(&value), case);
+        }
+        assert_eq!(
+            json_to_value(json!({ "binary": "AAEC" })).unwrap(),
+            Value {
+                value: Some(value::Value::Binary(vec![0, 1, 2].into()))
+            }
+        );
+        assert!(json_to_value(json!(null)).is_err());
+        assert!(json_to_value(json!([1])).is_err());
+        assert!(json_to_value(json!({ "binary": "!!" })).is_err());
+        assert_eq!(decode("a%2Fb%20c").unwrap(), "a/b c");
+        assert!(decode("a%2").is_err());
+    }
+
+    #[tokio::test]
+    async fn rest_endpoints_should_work() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let addr = start_server(service).await?;
+
+        let (status, body) =
+            http(addr, "PUT", "/tables/t1/keys/k1", r#"{"binary":"AAEC"}"#).await?;
+        assert_eq!(status, 200);
+        assert_eq!(body, json!({ "old_value": null }));
+
+        let (status, body) = http(addr, "PUT", "/tables/t1/keys/k%201", "10").await?;
+        assert_eq!((status, body), (200, json!({ "old_value": null })));
+
+        let (status, body) = http(addr, "GET", "/tables/t1/keys/k1", "").await?;
+        assert_eq!(
+            (status, body),
+            (200, json!({ "value": { "binary": "AAEC" } }))
+        );
+
+        let (status, body) = http(addr, "GET", "/tables/t1/keys/k%201", "").await?;
+        assert_eq!((status, body), (200, json!({ "value": 10 })));
+
+        let (status, body) = http(addr, "DELETE", "/tables/t1/keys/k%201", "").await?;
+        assert_eq!((status, body), (200, json!({ "old_value": 10 })));
+
+        let (status, body) = http(addr, "GET", "/tables/t1/keys/k%201", "").await?;
+        assert_eq!(status, 404);
+        assert!(body["error"].as_str().unwrap().contains("Not found"));
+
+        let (status, _) = http(addr, "PUT", "/tables/t1/keys/k1", "null").await?;
+        assert_eq!(status, 400);
+        let (status, _) = http(addr, "GET", "/nothing", "").await?;
+        assert_eq!(status, 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn table_should_be_paginated_and_batch_should_work() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        for i in 0..5i64 {
+            service.execute(CommandRequest::new_hset("t1", format!("k{}", i), i.into()));
+        }
+        let addr = start_server(service).await?;
+
+        let (status, body) = http(addr, "GET", "/tables/t1?limit=2", "").await?;
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            json!({
+                "items": [{ "key": "k0", "value": 0 }, { "key": "k1", "value": 1 }],
+                "next": "k1",
+            })
+        );
+        let (_, body) = http(addr, "GET", "/tables/t1?limit=2&after=k3", "").await?;
+        assert_eq!(
+            body,
+            json!({ "items": [{ "key": "k4", "value": 4 }], "next": null })
+        );
+
+        let batch = json!({
+            "requests": [
+                { "op": "put", "table": "t2", "key": "a", "value": "x" },
+                { "op": "get", "table": "t2", "key": "a" },
+                { "op": "get", "table": "t2", "key": "b" },
+                { "op": "put", "table": "t2", "key": "c", "value": [] },
+                { "op": "delete", "table": "t2", "key": "a" },
+            ]
+        });
+        let (status, body) = http(addr, "POST", "/batch", &batch.to_string()).await?;
+        assert_eq!(status, 200);
+        let responses = body["responses"].as_array().unwrap();
+        assert_eq!(responses[0], json!({ "status": 200, "old_value": null }));
+        assert_eq!(responses[1], json!({ "status": 200, "value": "x" }));
+        assert_eq!(responses[2]["status"], 404);
+        assert_eq!(responses[3]["status"], 400);
+        assert_eq!(responses[4], json!({ "status": 200, "old_value": "x" }));
+
+        let (status, _) = http(addr, "POST", "/batch", r#"{"requests":[{"op":"x"}]}"#).await?;
+        assert_eq!(status, 400);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn requests_on_one_connection_should_share_the_connection_limit() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(RateLimiter::new(RateLimitConfig {
+                per_connection: Some(BucketConfig {
+                    rate: 0.001,
+                    burst: 1,
+                }),
+                ..Default::default()
+            }))
+            .into();
+        let addr = start_server(service.clone()).await?;
+
+        // 同一个连接上的第二个请求被限流
+        let mut stream = TcpStream::connect(addr).await?;
+        let req = "GET /tables/t1/keys/k1 HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let last =
+            "GET /tables/t1/keys/k1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        stream
+            .write_all(format!("{}{}", req, last).as_bytes())
+            .await?;
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).await?;
+        assert!(buf.starts_with("HTTP/1.1 404"));
+        assert!(buf.contains("HTTP/1.1 429"));
+
+        // 新的连接有自己的限额
+        let (status, _) = http(addr, "GET", "/tables/t1/keys/k1", "").await?;
+        assert_eq!(status, 404);
+        let res = service.execute(CommandRequest::new_info(["clients"]));
+        let total = res
+            .pairs
+            .iter()
+            .find(|p| p.key == "clients.total_connections");
+        assert_eq!(total.and_then(|p| p.value.clone()), Some(2.into()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn https_should_work() -> Result<()> {
+        let certs = generate_certs("client1");
+        let acceptor = TlsServerAcceptor::new(&certs.server_cert, &certs.server_key, None)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(serve_http(listener, service, Some(acceptor)));
+
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&certs.ca))?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.with_alpn(&[ALPN_HTTP]).connect(stream).await?;
+        let req = "GET /tables/t1/keys/k1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        stream.write_all(req.as_bytes()).await?;
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).await?;
+        assert!(buf.starts_with("HTTP/1.1 404"));
+        Ok(())
+    }
+
+    async fn start_server(service: Service) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve_http(listener, service, None));
+        Ok(addr)
+    }
+
+    async fn http(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        body: &str,
+    ) -> Result<(u16, serde_json::Value)> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let req = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        );
+        stream.write_all(req.as_bytes()).await?;
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).await?;
+        let status = buf[9..12].parse()?;
+        let (_, body) = buf.split_once("\r\n\r\n").unwrap();
+        Ok((status, serde_json::from_str(body)?))
+    }
+}
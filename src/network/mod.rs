@@ -0,0 +1,643 @@
+mod frame;
+mod grpc;
+mod http;
+mod multiplex;
+mod quic;
+mod resp;
+mod server;
+mod stream;
+mod tls;
+#[cfg(unix)]
+mod unix;
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Instant, SystemTime},
+};
+
+use futures::future;
+use prost::Message;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    CommandRequest, CommandResponse, CompressionConfig, ConnectEvent, DisconnectEvent, ErrorEvent,
+    KvError, MemTable, PubsubMessage, SendEvent, Service, Session, Storage, WatchEvent,
+};
+
+use frame::Frame;
+
+pub use frame::{Compression, FrameCoder, FrameOptions, FrameStream, DEFAULT_MAX_FRAME_SIZE};
+pub use grpc::{serve_grpc, GrpcService, KvClient, KvServer};
+pub use http::serve_http;
+pub use multiplex::{YamuxClient, YamuxServerStream, YamuxStream};
+pub use quic::{bind_quic, serve_quic, QuicClient, QuicResponseStream};
+pub use resp::{serve_resp, RespFrame, RespServerStream};
+pub use server::{shutdown_signal, Server, ServerHandle, ShutdownReport};
+pub use stream::KvStream;
+pub use tls::{peer_identity, TlsClientConnector, TlsServerAcceptor};
+#[cfg(unix)]
+pub use unix::{bind_unix, serve_unix};
+
+/// 处理服务器端的某个 accept 下来的 socket 的读写
+pub struct ProstServerStream<S, Store: Storage = MemTable> {
+    inner: FrameStream<S>,
+    service: Service<Store>,
+    session: Session,
+    /// 客户端请求压缩时允许使用的算法
+    compression: CompressionConfig,
+    /// 服务器关闭时被取消，处理完当前的命令之后通知客户端并断开
+    shutdown: CancellationToken,
+}
+
+/// 处理客户端 socket 的读写
+pub struct ProstClientStream<S> {
+    inner: FrameStream<S>,
+    /// 等待命令的响应时收到的推送消息
+    messages: VecDeque<PubsubMessage>,
+    /// 等待命令的响应时收到的 key 变化事件
+    events: VecDeque<WatchEvent>,
+}
+
+impl<S, Store> ProstServerStream<S, Store>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    Store: Storage + Send + Sync + 'static,
+{
+    pub fn new(stream: S, service: Service<Store>) -> Self {
+        Self {
+            inner: FrameStream::new(stream),
+            service,
+            session: Session::new(None),
+            compression: CompressionConfig::default(),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// 设置客户端请求压缩时允许使用的算法，algorithms 为空时不压缩
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// 设置最大的 frame 大小，超过这个大小的请求会导致连接断开
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.inner = self.inner.with_max_frame_size(max_frame_size);
+        self
+    }
+
+    /// 设置连接的 Session，比如客户端地址，或者 mTLS 证书中的身份
+    pub fn with_session(mut self, session: Session) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// token 被取消时，正在处理的命令仍然会完成并发回响应，然后通知客户端服务器正在关闭并断开连接
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// 不断读取客户端发来的命令，交给 Service 处理后把结果发回去，直到客户端断开
+    pub async fn process(mut self) -> Result<(), KvError> {
+        let peer = self.session.peer;
+        let connected_at = Instant::now();
+        self.service.on_connected(&ConnectEvent {
+            peer,
+            at: SystemTime::now(),
+        });
+
+        let mut requests = 0;
+        // 订阅或者 watch 之后，需要推送给客户端的消息从这里读取
+        let (mut push, mut events) = (None, None);
+        let result = loop {
+            let next = tokio::select! {
+                next = self.inner.read_frame() => Incoming::Request(next),
+                msg = recv_push(&mut push) => Incoming::Push(msg.map(Box::new)),
+                msg = recv_push(&mut events) => Incoming::Push(msg.map(Box::new)),
+                _ = self.shutdown.cancelled() => Incoming::Shutdown,
+            };
+            let next = match next {
+                Incoming::Request(next) => next,
+                Incoming::Push(Some(msg)) => {
+                    if let Err(error) = self.inner.send(&*msg).await {
+                        let event = ErrorEvent {
+                            peer,
+                            error,
+                            elapsed: connected_at.elapsed(),
+                        };
+                        self.service.on_send_error(&event);
+                        break Err(event.error);
+                    }
+                    continue;
+                }
+                Incoming::Shutdown => {
+                    info!("Server is shutting down, disconnecting {:?}", peer);
+                    let error = CommandResponse::from(KvError::ShuttingDown);
+                    self.inner.send(&error).await.ok();
+                    break Ok(());
+                }
+                Incoming::Push(None) => {
+                    warn!(
+                        "Client {:?} is too slow to receive messages, disconnecting",
+                        peer
+                    );
+                    let error = KvError::Internal("Client is too slow to receive messages".into());
+                    self.inner.send(&CommandResponse::from(error)).await.ok();
+                    break Ok(());
+                }
+            };
+            let frame = match next {
+                Ok(Some(frame)) if frame.handshake => {
+                    let (algorithms, threshold) =
+                        (&self.compression.algorithms, self.compression.threshold);
+                    match self
+                        .inner
+                        .accept_handshake(&frame, algorithms, threshold)
+                        .await
+                    {
+                        Ok(compression) => {
+                            info!("Client {:?} negotiated compression {:?}", peer, compression);
+                            continue;
+                        }
+                        Err(error) => break Err(error),
+                    }
+                }
+                Ok(Some(frame)) => frame,
+                Ok(None) => break Ok(()),
+                Err(error) => break Err(self.decode_error(error, connected_at)),
+            };
+            let cmd = match CommandRequest::decode(frame.payload) {
+                Ok(cmd) => cmd,
+                Err(e) => break Err(self.decode_error(e.into(), connected_at)),
+            };
+
+            info!("Got a new command: {}", cmd);
+            requests += 1;
+            let start = Instant::now();
+            let res = self.service.execute_async(cmd, &mut self.session).await;
+            if push.is_none() {
+                push = self.service.pubsub().take_receiver(self.session.id);
+            }
+            if events.is_none() {
+                events = self.service.watcher().take_receiver(self.session.id);
+            }
+            let event = SendEvent {
+                peer,
+                result: self.inner.send(&res).await,
+                elapsed: start.elapsed(),
+            };
+            self.service.on_after_send(&event);
+
+            if let Err(error) = event.result {
+                let event = ErrorEvent {
+                    peer,
+                    error,
+                    elapsed: connected_at.elapsed(),
+                };
+                self.service.on_send_error(&event);
+                break Err(event.error);
+            }
+        };
+
+        self.service.pubsub().remove(self.session.id);
+        self.service.watcher().remove(self.session.id);
+        self.service.on_disconnected(&DisconnectEvent {
+            peer,
+            duration: connected_at.elapsed(),
+            requests,
+        });
+        result
+    }
+
+    fn decode_error(&self, error: KvError, connected_at: Instant) -> KvError {
+        let event = ErrorEvent {
+            peer: self.session.peer,
+            error,
+            elapsed: connected_at.elapsed(),
+        };
+        self.service.on_decode_error(&event);
+        event.error
+    }
+}
+
+impl<S> ProstClientStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    pub fn new(stream: S) -> Self {
+        Self {
+            inner: FrameStream::new(stream),
+            messages: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// 建立连接之后先和服务器协商压缩算法，服务器不支持配置中的任何算法时不压缩
+    pub async fn with_compression(
+        stream: S,
+        compression: &CompressionConfig,
+    ) -> Result<Self, KvError> {
+        let mut client = Self::new(stream);
+        client
+            .inner
+            .handshake(&compression.algorithms, compression.threshold)
+            .await?;
+        Ok(client)
+    }
+
+    /// 设置最大的 frame 大小，超过这个大小的响应会返回错误
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.inner = self.inner.with_max_frame_size(max_frame_size);
+        self
+    }
+
+    /// 协商好的压缩算法
+    pub fn compression(&self) -> Option<Compression> {
+        self.inner.compression()
+    }
+
+    /// 发送一个命令并等待服务器的响应，期间收到的推送消息和事件会缓存起来
+    pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        self.inner.send(&cmd).await?;
+
+        loop {
+            if let Some(res) = self.read().await? {
+                return Ok(res);
+            }
+        }
+    }
+
+    /// 订阅之后，等待服务器推送的下一条消息
+    pub async fn next_message(&mut self) -> Result<PubsubMessage, KvError> {
+        loop {
+            if let Some(msg) = self.messages.pop_front() {
+                return Ok(msg);
+            }
+            if let Some(res) = self.read().await? {
+                return Err(unexpected_response(res));
+            }
+        }
+    }
+
+    /// watch 之后，等待服务器推送的下一个 key 变化事件
+    pub async fn next_event(&mut self) -> Result<WatchEvent, KvError> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            if let Some(res) = self.read().await? {
+                return Err(unexpected_response(res));
+            }
+        }
+    }
+
+    /// 读取一个 frame，推送的消息和事件放到缓存里并返回 None，命令的响应返回 Some
+    async fn read(&mut self) -> Result<Option<CommandResponse>, KvError> {
+        let mut res = match self.inner.recv::<CommandResponse>().await? {
+            Some(res) => res,
+            None => return Err(KvError::Internal("Didn't get any response".into())),
+        };
+        if let Some(msg) = res.pubsub.take() {
+            self.messages.push_back(msg);
+            return Ok(None);
+        }
+        if let Some(event) = res.event.take() {
+            self.events.push_back(event);
+            return Ok(None);
+        }
+        Ok(Some(res))
+    }
+}
+
+/// 等待推送的消息时收到了命令的响应，比如服务器因为客户端太慢而断开前发送的错误
+fn unexpected_response(res: CommandResponse) -> KvError {
+    KvError::Internal(format!(
+        "Expect a pushed message, got response {}: {}",
+        res.status, res.message
+    ))
+}
+
+/// 一个连接上有多个并发请求的协议（HTTP、gRPC、QUIC）用它触发连接的事件
+///
+/// 创建时触发 on_connected，drop 时触发 on_disconnected，每个请求的响应发送之后调用 `sent`。
+pub(crate) struct ConnectionEvents<Store: Storage> {
+    service: Service<Store>,
+    peer: Option<SocketAddr>,
+    connected_at: Instant,
+    requests: AtomicU64,
+}
+
+impl<Store: Storage> ConnectionEvents<Store> {
+    pub fn new(service: Service<Store>, peer: Option<SocketAddr>) -> Self {
+        service.on_connected(&ConnectEvent {
+            peer,
+            at: SystemTime::now(),
+        });
+        Self {
+            service,
+            peer,
+            connected_at: Instant::now(),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    /// 一个请求的响应已经发送（或者发送失败），start 是收到请求的时间；返回发送的结果
+    pub fn sent(&self, result: Result<(), KvError>, start: Instant) -> Result<(), KvError> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let event = SendEvent {
+            peer: self.peer,
+            result,
+            elapsed: start.elapsed(),
+        };
+        self.service.on_after_send(&event);
+        event.result.map_err(|error| {
+            let event = ErrorEvent {
+                peer: self.peer,
+                error,
+                elapsed: self.connected_at.elapsed(),
+            };
+            self.service.on_send_error(&event);
+            event.error
+        })
+    }
+}
+
+impl<Store: Storage> Drop for ConnectionEvents<Store> {
+    fn drop(&mut self) {
+        self.service.on_disconnected(&DisconnectEvent {
+            peer: self.peer,
+            duration: self.connected_at.elapsed(),
+            requests: self.requests.load(Ordering::Relaxed),
+        });
+    }
+}
+
+/// 从连接读到的命令，或者需要推送给客户端的消息
+enum Incoming {
+    Request(Result<Option<Frame>, KvError>),
+    Push(Option<Box<CommandResponse>>),
+    Shutdown,
+}
+
+/// 读取下一条推送的消息，还没有订阅的时候永远不会返回
+async fn recv_push(push: &mut Option<mpsc::Receiver<CommandResponse>>) -> Option<CommandResponse> {
+    match push {
+        Some(rx) => rx.recv().await,
+        None => future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use anyhow::Result;
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::tls::test_utils::*;
+    use super::*;
+    use crate::{assert_res_ok, EventKind, Kvpair, ServiceInner, Value};
+
+    #[tokio::test]
+    async fn client_server_basic_communication_should_work() -> Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute(cmd).await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = client.execute(cmd).await?;
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_over_mtls_should_work() -> Result<()> {
+        let certs = generate_certs("client1");
+        let acceptor =
+            TlsServerAcceptor::new(&certs.server_cert, &certs.server_key, Some(&certs.ca))?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            ProstServerStream::new(stream, service)
+                .process()
+                .await
+                .unwrap();
+        });
+
+        let identity = Some((certs.client_cert.as_str(), certs.client_key.as_str()));
+        let connector = TlsClientConnector::new(DOMAIN, identity, Some(&certs.ca))?;
+        let stream = TcpStream::connect(addr).await?;
+        let stream = connector.connect(stream).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        let res = client
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lifecycle_events_should_be_fired() -> Result<()> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let (tx1, tx2, tx3) = (tx.clone(), tx.clone(), tx.clone());
+        let service: Service = ServiceInner::new(MemTable::new())
+            .fn_connected(move |e| tx1.send(format!("connected {:?}", e.peer)).unwrap())
+            .fn_after_send(move |e| tx2.send(format!("sent {:?}", e.result)).unwrap())
+            .fn_disconnected(move |e| {
+                tx3.send(format!("disconnected {:?} {}", e.peer, e.requests))
+                    .unwrap()
+            })
+            .into();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            ProstServerStream::new(stream, service)
+                .with_session(Session::new(Some(peer)))
+                .process()
+                .await
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let local = stream.local_addr()?;
+        let mut client = ProstClientStream::new(stream);
+        client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        client.execute(CommandRequest::new_hget("t1", "k2")).await?;
+        drop(client);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+            if events.len() == 4 {
+                break;
+            }
+        }
+        assert_eq!(
+            events,
+            vec![
+                format!("connected {:?}", Some(local)),
+                "sent Ok(())".to_string(),
+                "sent Ok(())".to_string(),
+                format!("disconnected {:?} 2", Some(local)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn decode_error_should_be_reported() -> Result<()> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .fn_decode_error(move |e| tx.send(e.error.to_string()).unwrap())
+            .into();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            ProstServerStream::new(stream, service).process().await
+        });
+
+        let mut stream = TcpStream::connect(addr).await?;
+        // 长度为 2 的 frame，内容不是合法的 protobuf
+        stream.write_all(&[0, 0, 0, 2, 0xff, 0xff]).await?;
+
+        assert!(handle.await?.is_err());
+        assert!(rx.recv().await.unwrap().contains("decode protobuf"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscriber_should_receive_published_messages() -> Result<()> {
+        let addr = start_server().await?;
+
+        let mut subscriber = ProstClientStream::new(TcpStream::connect(addr).await?);
+        let cmd = CommandRequest::new_subscribe(["news"], ["weather.*"]);
+        let res = subscriber.execute(cmd).await?;
+        assert_res_ok(res, &[2.into()], &[]);
+
+        let mut publisher = ProstClientStream::new(TcpStream::connect(addr).await?);
+        let cmd = CommandRequest::new_publish("news", "hello".into());
+        assert_res_ok(publisher.execute(cmd).await?, &[1.into()], &[]);
+        let cmd = CommandRequest::new_publish("weather.today", "sunny".into());
+        assert_res_ok(publisher.execute(cmd).await?, &[1.into()], &[]);
+
+        let msg = subscriber.next_message().await?;
+        assert_eq!(msg.channel, "news");
+        assert_eq!(msg.data, Some("hello".into()));
+
+        // 等待响应时收到的推送消息会被缓存下来
+        let res = subscriber
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_eq!(res.status, 404);
+        let msg = subscriber.next_message().await?;
+        assert_eq!(msg.channel, "weather.today");
+        assert_eq!(msg.pattern, "weather.*");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watcher_should_resume_after_reconnect() -> Result<()> {
+        let addr = start_server().await?;
+
+        let mut watcher = ProstClientStream::new(TcpStream::connect(addr).await?);
+        let res = watcher
+            .execute(CommandRequest::new_watch("t1", "", 0))
+            .await?;
+        assert_res_ok(res, &[0.into()], &[]);
+
+        let mut writer = ProstClientStream::new(TcpStream::connect(addr).await?);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        writer.execute(cmd).await?;
+        let event = watcher.next_event().await?;
+        assert_eq!((event.seq, event.key.as_str()), (1, "k1"));
+        drop(watcher);
+
+        // 断开期间的修改在重连之后补发
+        writer.execute(CommandRequest::new_hdel("t1", "k1")).await?;
+        let mut watcher = ProstClientStream::new(TcpStream::connect(addr).await?);
+        let cmd = CommandRequest::new_watch("t1", "k1", event.seq + 1);
+        let res = watcher.execute(cmd).await?;
+        assert_res_ok(res, &[2.into()], &[]);
+        let event = watcher.next_event().await?;
+        assert_eq!(event.kind(), EventKind::Del);
+        assert_eq!(event.old_value, Some("v1".into()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compression_should_be_negotiated_per_connection() -> Result<()> {
+        let addr = start_server().await?;
+
+        for compression in Compression::ALL {
+            let config = CompressionConfig {
+                algorithms: vec![compression],
+                threshold: 100,
+            };
+            let stream = TcpStream::connect(addr).await?;
+            let mut client = ProstClientStream::with_compression(stream, &config).await?;
+            assert_eq!(client.compression(), Some(compression));
+
+            let table = format!("{:?}", compression);
+            let pairs: Vec<_> = (0..100)
+                .map(|i| Kvpair::new(format!("k{:03}", i), Value::from("v".repeat(100))))
+                .collect();
+            let res = client
+                .execute(CommandRequest::new_hmset(&table, pairs.clone()))
+                .await?;
+            assert_eq!(res.status, 200);
+
+            let res = client.execute(CommandRequest::new_hget_all(&table)).await?;
+            assert_res_ok(res, &[], &pairs);
+        }
+
+        // 没有协商压缩的客户端不受影响
+        let mut client = ProstClientStream::new(TcpStream::connect(addr).await?);
+        assert_eq!(client.compression(), None);
+        let res = client.execute(CommandRequest::new_hget_all("Zstd")).await?;
+        assert_eq!(res.pairs.len(), 100);
+        Ok(())
+    }
+
+    async fn start_server() -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let server = ProstServerStream::new(stream, service.clone());
+                tokio::spawn(server.process());
+            }
+        });
+
+        Ok(addr)
+    }
+}
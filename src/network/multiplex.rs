@@ -0,0 +1,248 @@
+use futures::{future, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task::JoinSet,
+};
+use tokio_util::{
+    compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt},
+    sync::CancellationToken,
+};
+use tracing::warn;
+use yamux::{Config, Connection, Control, Mode, WindowUpdateMode};
+
+use super::{ProstClientStream, ProstServerStream};
+use crate::{
+    CompressionConfig, KvError, MemTable, Service, Session, Storage, YamuxConfig,
+    DEFAULT_MAX_FRAME_SIZE,
+};
+
+/// yamux 要求每个 stream 的接收窗口不小于 256KB
+const MIN_RECEIVE_WINDOW: u32 = 256 * 1024;
+
+/// yamux 连接上的一个逻辑 stream，可以像普通连接一样交给 ProstServerStream/ProstClientStream
+pub type YamuxStream = Compat<yamux::Stream>;
+
+/// 在一个连接上使用 yamux 多路复用，每个逻辑 stream 都由同一个 Service 处理
+pub struct YamuxServerStream<S, Store: Storage = MemTable> {
+    conn: Connection<Compat<S>>,
+    service: Service<Store>,
+    session: Session,
+    /// 客户端请求压缩时允许使用的算法
+    compression: CompressionConfig,
+    max_frame_size: usize,
+    /// 服务器关闭时被取消，不再接受新的逻辑 stream，已有的 stream 通知客户端之后断开
+    shutdown: CancellationToken,
+}
+
+impl<S, Store> YamuxServerStream<S, Store>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    Store: Storage + Send + Sync + 'static,
+{
+    pub fn new(stream: S, service: Service<Store>, config: &YamuxConfig) -> Self {
+        Self {
+            conn: Connection::new(stream.compat(), yamux_config(config), Mode::Server),
+            service,
+            session: Session::new(None),
+            compression: CompressionConfig::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// 设置连接的 Session，每个逻辑 stream 使用新的 Session，但是继承其中的客户端地址和身份
+    pub fn with_session(mut self, session: Session) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// 设置每个逻辑 stream 上客户端请求压缩时允许使用的算法
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// 设置每个逻辑 stream 上最大的 frame 大小
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// token 被取消时不再接受新的逻辑 stream，等已有的 stream 处理完当前的命令并断开之后返回
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// 不断接受客户端打开的逻辑 stream，直到连接断开
+    pub async fn process(self) -> Result<(), KvError> {
+        let mut control = self.conn.control();
+        let mut streams = Box::pin(yamux::into_stream(self.conn));
+        let mut tasks = JoinSet::new();
+        let result = loop {
+            let stream = tokio::select! {
+                stream = streams.next() => stream,
+                // 及时清理已经结束的逻辑 stream
+                Some(_) = tasks.join_next() => continue,
+                _ = self.shutdown.cancelled() => break Ok(()),
+            };
+            let stream = match stream {
+                Some(Ok(stream)) => stream,
+                Some(Err(e)) => break Err(KvError::IoError(e.to_string())),
+                None => break Ok(()),
+            };
+            let session = Session::new(self.session.peer).with_user(self.session.user.clone());
+            let server = ProstServerStream::new(stream.compat(), self.service.clone())
+                .with_session(session)
+                .with_compression(self.compression.clone())
+                .with_max_frame_size(self.max_frame_size)
+                .with_shutdown(self.shutdown.clone());
+            tasks.spawn(async move {
+                if let Err(e) = server.process().await {
+                    warn!("Failed to process yamux stream: {}", e);
+                }
+            });
+        };
+        // 逻辑 stream 的数据要靠轮询连接才能收发，所以等它们都结束、连接关闭之后才返回
+        if self.shutdown.is_cancelled() {
+            let drain = async {
+                while tasks.join_next().await.is_some() {}
+                control.close().await.ok();
+            };
+            // 关闭时新打开的逻辑 stream 直接丢弃
+            let poll = async { while let Some(Ok(_)) = streams.next().await {} };
+            tokio::select! {
+                _ = drain => {}
+                _ = poll => {}
+            }
+        }
+        result
+    }
+}
+
+/// yamux 的客户端，在一个连接上打开多个逻辑 stream，比如一个 stream 做 watch，另一个发送普通的命令
+#[derive(Clone)]
+pub struct YamuxClient {
+    control: Control,
+}
+
+impl YamuxClient {
+    pub fn new<S>(stream: S, config: &YamuxConfig) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let conn = Connection::new(stream.compat(), yamux_config(config), Mode::Client);
+        let control = conn.control();
+        // 连接需要一直被 poll 才能收发数据；服务器不会主动打开 stream，收到的直接丢弃
+        tokio::spawn(yamux::into_stream(conn).for_each(|_| future::ready(())));
+        Self { control }
+    }
+
+    /// 打开一个新的逻辑 stream，它的请求和响应与其它 stream 互不影响
+    pub async fn open_stream(&mut self) -> Result<ProstClientStream<YamuxStream>, KvError> {
+        let stream = self
+            .control
+            .open_stream()
+            .await
+            .map_err(|e| KvError::IoError(e.to_string()))?;
+        Ok(ProstClientStream::new(stream.compat()))
+    }
+
+    /// 关闭整个连接，所有的逻辑 stream 都会被关闭
+    pub async fn close(mut self) -> Result<(), KvError> {
+        self.control
+            .close()
+            .await
+            .map_err(|e| KvError::IoError(e.to_string()))
+    }
+}
+
+fn yamux_config(config: &YamuxConfig) -> Config {
+    let mut cfg = Config::default();
+    // 只有数据被读走之后才增加对方的发送窗口，读得慢的 stream 会让对方的写入等待，而不是无限缓存
+    cfg.set_window_update_mode(WindowUpdateMode::OnRead)
+        .set_receive_window(config.receive_window.max(MIN_RECEIVE_WINDOW))
+        .set_max_num_streams(config.max_streams);
+    cfg
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{assert_res_ok, value, CommandRequest, ServiceInner, Value};
+
+    fn start(service: Service) -> YamuxClient {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let config = YamuxConfig::default();
+        tokio::spawn(YamuxServerStream::new(server, service, &config).process());
+        YamuxClient::new(client, &config)
+    }
+
+    #[tokio::test]
+    async fn watch_and_requests_should_share_one_connection() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let mut client = start(service);
+
+        let mut watcher = client.open_stream().await?;
+        let res = watcher
+            .execute(CommandRequest::new_watch("t1", "", 0))
+            .await?;
+        assert_res_ok(res, &[0.into()], &[]);
+
+        let mut writer = client.open_stream().await?;
+        let res = writer
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let event = watcher.next_event().await?;
+        assert_eq!((event.seq, event.key.as_str()), (1, "k1"));
+
+        // 关闭一个逻辑 stream 不影响其它 stream
+        drop(watcher);
+        let res = writer.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        client.close().await?;
+        assert!(writer
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_streams_should_be_flow_controlled() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let client = start(service);
+
+        // 每个 value 都比接收窗口大，需要多次窗口更新才能传完
+        let handles: Vec<_> = (0..8u8)
+            .map(|i| {
+                let mut client = client.clone();
+                tokio::spawn(async move {
+                    let mut stream = client.open_stream().await.unwrap();
+                    let data = Bytes::from(vec![i; 512 * 1024]);
+                    let value = Value {
+                        value: Some(value::Value::Binary(data)),
+                    };
+                    let key = format!("k{}", i);
+                    let cmd = CommandRequest::new_hset("t1", &key, value.clone());
+                    assert_eq!(stream.execute(cmd).await.unwrap().status, 200);
+                    let res = stream
+                        .execute(CommandRequest::new_hget("t1", &key))
+                        .await
+                        .unwrap();
+                    assert_res_ok(res, &[value], &[]);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await?;
+        }
+        Ok(())
+    }
+}
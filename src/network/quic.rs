@@ -0,0 +1,380 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use quinn::{Connection, Endpoint, RecvStream, SendStream};
+use tokio::{
+    io::{self, Join},
+    task::JoinSet,
+};
+use tokio_rustls::rustls::Certificate;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use super::{
+    recv_push,
+    tls::{certificate_common_name, TlsClientConnector, TlsServerAcceptor},
+    ConnectionEvents, FrameStream,
+};
+use crate::{
+    command_request::RequestData, CommandRequest, CommandResponse, KvError, Service, Session,
+    Storage, DEFAULT_MAX_FRAME_SIZE,
+};
+
+/// QUIC 的一个双向 stream，读写和 TCP 连接上完全相同的 frame
+type QuicStream = Join<RecvStream, SendStream>;
+
+/// 按照 TLS 配置在 UDP 地址上创建 QUIC endpoint
+///
+/// QUIC 总是加密的，和 TCP 端口使用同一套证书；配置了 client CA 时同样要求客户端证书。
+pub fn bind_quic(addr: SocketAddr, acceptor: &TlsServerAcceptor) -> Result<Endpoint, KvError> {
+    let config = quinn::ServerConfig::with_crypto(acceptor.config());
+    Ok(Endpoint::server(config, addr)?)
+}
+
+/// 在 QUIC endpoint 上提供服务，每个请求/响应使用连接上一个独立的双向 stream
+///
+/// 一个 stream 丢包只会阻塞这一个请求，不影响同一个连接上的其它请求。
+pub async fn serve_quic<Store>(endpoint: Endpoint, service: Service<Store>) -> Result<(), KvError>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    while let Some(connecting) = endpoint.accept().await {
+        let service = service.clone();
+        tokio::spawn(async move {
+            match connecting.await {
+                Ok(conn) => {
+                    let shutdown = CancellationToken::new();
+                    serve_connection(conn, service, DEFAULT_MAX_FRAME_SIZE, shutdown).await
+                }
+                Err(e) => warn!("Failed to accept QUIC connection: {}", e),
+            }
+        });
+    }
+    Ok(())
+}
+
+/// 同一个连接上的请求共享一个 Session，所以在一个 stream 上认证之后，之后的请求都有效
+///
+/// shutdown 被取消时不再接受新的 stream，等已有的 stream 发回响应之后关闭连接。
+pub(crate) async fn serve_connection<Store>(
+    conn: Connection,
+    service: Service<Store>,
+    max_frame_size: usize,
+    shutdown: CancellationToken,
+) where
+    Store: Storage + Send + Sync + 'static,
+{
+    let session = Session::new(Some(conn.remote_address())).with_user(peer_user(&conn));
+    let events = Arc::new(ConnectionEvents::new(service.clone(), session.peer));
+    let session = Arc::new(Mutex::new(session));
+    let mut tasks = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = conn.accept_bi() => accepted,
+            // 及时清理已经结束的 stream
+            Some(_) = tasks.join_next() => continue,
+            _ = shutdown.cancelled() => break,
+        };
+        let (send, recv) = match accepted {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("QUIC connection {} closed: {}", conn.remote_address(), e);
+                return;
+            }
+        };
+        let stream = FrameStream::new(io::join(recv, send)).with_max_frame_size(max_frame_size);
+        let (service, session, events) = (service.clone(), session.clone(), events.clone());
+        let shutdown = shutdown.clone();
+        tasks.spawn(async move {
+            if let Err(e) = serve_stream(stream, service, session, events, shutdown).await {
+                warn!("Failed to process QUIC stream: {}", e);
+            }
+        });
+    }
+
+    info!(
+        "Server is shutting down, disconnecting {}",
+        conn.remote_address()
+    );
+    while tasks.join_next().await.is_some() {}
+    conn.close(0u32.into(), KvError::ShuttingDown.to_string().as_bytes());
+}
+
+/// 处理一个 stream 上的命令
+///
+/// 订阅和 watch 使用这个 stream 自己的 Session，推送的消息在同一个 stream 上发送，直到客户端关闭它。
+async fn serve_stream<Store>(
+    mut stream: FrameStream<QuicStream>,
+    service: Service<Store>,
+    session: Arc<Mutex<Session>>,
+    events: Arc<ConnectionEvents<Store>>,
+    shutdown: CancellationToken,
+) -> Result<(), KvError>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    let start = Instant::now();
+    let cmd = tokio::select! {
+        cmd = stream.recv::<CommandRequest>() => cmd?,
+        _ = shutdown.cancelled() => {
+            stream.send(&CommandResponse::from(KvError::ShuttingDown)).await.ok();
+            return stream.shutdown().await;
+        }
+    };
+    let cmd = match cmd {
+        Some(cmd) => cmd,
+        None => return Ok(()),
+    };
+    if !matches!(
+        cmd.request_data,
+        Some(RequestData::Subscribe(_) | RequestData::Watch(_))
+    ) {
+        // 执行命令时不持有锁，否则同一个连接上的 stream 会被串行执行；只有 Auth 会改变 session
+        let is_auth = matches!(cmd.request_data, Some(RequestData::Auth(_)));
+        let mut current = session.lock().unwrap().clone();
+        let res = service.execute_async(cmd, &mut current).await;
+        if is_auth {
+            session.lock().unwrap().user = current.user;
+        }
+        send_response(&mut stream, &res, &events, start).await?;
+        return stream.shutdown().await;
+    }
+
+    let mut session = {
+        let session = session.lock().unwrap();
+        Session::new(session.peer).with_user(session.user.clone())
+    };
+    let res = service.execute_with(cmd, &mut session);
+    send_response(&mut stream, &res, &events, start).await?;
+
+    let mut push = service.pubsub().take_receiver(session.id);
+    let mut events = service.watcher().take_receiver(session.id);
+    let result = loop {
+        let msg = tokio::select! {
+            msg = recv_push(&mut push) => msg,
+            msg = recv_push(&mut events) => msg,
+            // 客户端关闭了它那一端，不再需要推送
+            _ = stream.recv::<CommandRequest>() => break Ok(()),
+            _ = shutdown.cancelled() => {
+                stream.send(&CommandResponse::from(KvError::ShuttingDown)).await.ok();
+                break stream.shutdown().await;
+            }
+        };
+        let msg = match msg {
+            Some(msg) => msg,
+            None => {
+                warn!(
+                    "Client {:?} is too slow to receive messages, closing stream",
+                    session.peer
+                );
+                let error = KvError::Internal("Client is too slow to receive messages".into());
+                stream.send(&CommandResponse::from(error)).await.ok();
+                break Ok(());
+            }
+        };
+        if let Err(e) = stream.send(&msg).await {
+            break Err(e);
+        }
+    };
+    service.pubsub().remove(session.id);
+    service.watcher().remove(session.id);
+    result
+}
+
+/// 发送命令的响应，并触发连接的 on_after_send
+async fn send_response<Store: Storage>(
+    stream: &mut FrameStream<QuicStream>,
+    res: &CommandResponse,
+    events: &ConnectionEvents<Store>,
+    start: Instant,
+) -> Result<(), KvError> {
+    events.sent(stream.send(res).await, start)
+}
+
+/// 双向认证时，从客户端证书的 Common Name 中取出客户端的身份
+fn peer_user(conn: &Connection) -> Option<String> {
+    let certs = conn.peer_identity()?.downcast::<Vec<Certificate>>().ok()?;
+    certificate_common_name(certs.first()?)
+}
+
+/// QUIC 客户端，可以 clone 之后在多个 task 中并发地发送请求
+#[derive(Clone)]
+pub struct QuicClient {
+    conn: Connection,
+    max_frame_size: usize,
+}
+
+/// 一个请求的响应，订阅和 watch 之后还会收到推送的消息
+pub struct QuicResponseStream {
+    inner: FrameStream<QuicStream>,
+}
+
+impl QuicClient {
+    /// 使用 TLS 配置连接服务器的 QUIC 端口
+    pub async fn connect(
+        addr: SocketAddr,
+        connector: &TlsClientConnector,
+    ) -> Result<Self, KvError> {
+        let local: SocketAddr = if addr.is_ipv6() {
+            "[::]:0".parse().unwrap()
+        } else {
+            "0.0.0.0:0".parse().unwrap()
+        };
+        let mut endpoint = Endpoint::client(local)?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(connector.config()));
+        let conn = endpoint
+            .connect(addr, connector.domain())
+            .map_err(quic_error)?
+            .await
+            .map_err(quic_error)?;
+        Ok(Self {
+            conn,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        })
+    }
+
+    /// 设置最大的 frame 大小，对发送和接收都有效
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// 在一个新的 stream 上发送命令，返回它的响应
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        match self.stream(cmd).await?.next().await? {
+            Some(res) => Ok(res),
+            None => Err(KvError::Internal("Didn't get any response".into())),
+        }
+    }
+
+    /// 在一个新的 stream 上发送命令，订阅和 watch 之后可以从返回的 stream 中不断读取推送的消息
+    ///
+    /// drop 返回的 stream 即可结束订阅。
+    pub async fn stream(&self, cmd: CommandRequest) -> Result<QuicResponseStream, KvError> {
+        let (send, recv) = self.conn.open_bi().await.map_err(quic_error)?;
+        let mut inner =
+            FrameStream::new(io::join(recv, send)).with_max_frame_size(self.max_frame_size);
+        inner.send(&cmd).await?;
+        Ok(QuicResponseStream { inner })
+    }
+
+    /// 关闭连接，所有还没完成的请求都会失败
+    pub fn close(&self) {
+        self.conn.close(0u32.into(), b"done");
+    }
+}
+
+impl QuicResponseStream {
+    /// 读取下一个响应，服务器关闭 stream 时返回 None
+    pub async fn next(&mut self) -> Result<Option<CommandResponse>, KvError> {
+        self.inner.recv().await
+    }
+}
+
+fn quic_error(e: impl std::error::Error) -> KvError {
+    KvError::IoError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{assert_res_ok, network::tls::test_utils::*, MemTable, ServiceInner, Value};
+
+    #[tokio::test]
+    async fn quic_should_work() -> Result<()> {
+        let certs = generate_certs("client1");
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let addr = start_server(&certs, service.clone())?;
+
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&certs.ca))?;
+        let client = QuicClient::connect(addr, &connector).await?;
+
+        // 同一个连接上并发的请求各自使用一个 stream
+        let handles: Vec<_> = (0..10i64)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i.into());
+                    client.execute(cmd).await
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_res_ok(handle.await??, &[Value::default()], &[]);
+        }
+        let res = client.execute(CommandRequest::new_hget("t1", "k3")).await?;
+        assert_res_ok(res, &[3.into()], &[]);
+
+        let cmd = CommandRequest::new_subscribe(["news"], Vec::<&str>::new());
+        let mut stream = client.stream(cmd).await?;
+        assert_eq!(stream.next().await?.unwrap().status, 200);
+
+        let res = client.execute(CommandRequest::new_publish("news", "hello".into()));
+        assert_res_ok(res.await?, &[1.into()], &[]);
+        let msg = stream.next().await?.unwrap().pubsub.unwrap();
+        assert_eq!(msg.data, Some("hello".into()));
+
+        // 关闭订阅的 stream 之后，订阅会被清理
+        drop(stream);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let res = client.execute(CommandRequest::new_publish("news", "bye".into()));
+        assert_res_ok(res.await?, &[0.into()], &[]);
+        assert_eq!(connected(&service), Some(1.into()));
+
+        client.close();
+        assert!(client
+            .execute(CommandRequest::new_hget("t1", "k3"))
+            .await
+            .is_err());
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(connected(&service), Some(0.into()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quic_with_client_cert_should_work() -> Result<()> {
+        let certs = generate_certs("client1");
+        let acceptor =
+            TlsServerAcceptor::new(&certs.server_cert, &certs.server_key, Some(&certs.ca))?;
+        let endpoint = bind_quic("127.0.0.1:0".parse()?, &acceptor)?;
+        let addr = endpoint.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(serve_quic(endpoint, service));
+
+        let identity = Some((certs.client_cert.as_str(), certs.client_key.as_str()));
+        let connector = TlsClientConnector::new(DOMAIN, identity, Some(&certs.ca))?;
+        let client = QuicClient::connect(addr, &connector).await?;
+        let res = client
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        // 没有客户端证书时握手失败
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&certs.ca))?;
+        let result = match QuicClient::connect(addr, &connector).await {
+            Ok(client) => client.execute(CommandRequest::new_hget("t1", "k1")).await,
+            Err(e) => Err(e),
+        };
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    fn connected(service: &Service) -> Option<Value> {
+        let res = service.execute(CommandRequest::new_info(["clients"]));
+        let connected = res.pairs.into_iter().find(|p| p.key == "clients.connected");
+        connected.and_then(|p| p.value)
+    }
+
+    fn start_server(certs: &TestCerts, service: Service) -> Result<SocketAddr> {
+        let acceptor = TlsServerAcceptor::new(&certs.server_cert, &certs.server_key, None)?;
+        let endpoint = bind_quic("127.0.0.1:0".parse()?, &acceptor)?;
+        let addr = endpoint.local_addr()?;
+        tokio::spawn(serve_quic(endpoint, service));
+        Ok(addr)
+    }
+}
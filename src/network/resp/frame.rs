@@ -0,0 +1,470 @@
+use bytes::{Buf, Bytes, BytesMut};
+
+use crate::KvError;
+
+/// Redis 限制 bulk string 最大 512MB
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+/// 一个 array 或 map 最多的元素个数
+const MAX_ELEMENTS: i64 = 1024 * 1024;
+/// 没有读到 \r\n 时，一行最多缓存这么多字节
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// array 和 map 最多嵌套的层数
+const MAX_DEPTH: usize = 128;
+
+/// RESP2/RESP3 的数据类型，编码时根据协议版本把 RESP3 的类型降级成 RESP2 的类型
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespFrame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Array(Vec<RespFrame>),
+    /// RESP2 中是 null bulk string
+    Null,
+    /// RESP2 中是整数 1/0
+    Boolean(bool),
+    /// RESP2 中是 bulk string
+    Double(f64),
+    /// RESP2 中是 key 和 value 交替的 array
+    Map(Vec<(RespFrame, RespFrame)>),
+}
+
+impl RespFrame {
+    pub fn bulk(data: impl Into<Bytes>) -> Self {
+        Self::Bulk(data.into())
+    }
+
+    pub fn ok() -> Self {
+        Self::Simple("OK".into())
+    }
+
+    /// 按照协议版本编码，proto 是 2 或 3
+    pub fn encode(&self, proto: u8, buf: &mut Vec<u8>) {
+        match self {
+            RespFrame::Simple(s) => line(buf, b'+', s),
+            RespFrame::Error(s) => line(buf, b'-', s),
+            RespFrame::Integer(i) => line(buf, b':', &i.to_string()),
+            RespFrame::Bulk(data) => {
+                line(buf, b'$', &data.len().to_string());
+                buf.extend_from_slice(data);
+                buf.extend_from_slice(b"\r\n");
+            }
+            RespFrame::Array(items) => {
+                line(buf, b'*', &items.len().to_string());
+                for item in items {
+                    item.encode(proto, buf);
+                }
+            }
+            RespFrame::Null if proto >= 3 => buf.extend_from_slice(b"_\r\n"),
+            RespFrame::Null => buf.extend_from_slice(b"$-1\r\n"),
+            RespFrame::Boolean(b) if proto >= 3 => line(buf, b'#', if *b { "t" } else { "f" }),
+            RespFrame::Boolean(b) => RespFrame::Integer(*b as i64).encode(proto, buf),
+            RespFrame::Double(f) if proto >= 3 => line(buf, b',', &format_double(*f)),
+            RespFrame::Double(f) => RespFrame::bulk(format_double(*f)).encode(proto, buf),
+            RespFrame::Map(pairs) => {
+                if proto >= 3 {
+                    line(buf, b'%', &pairs.len().to_string());
+                } else {
+                    line(buf, b'*', &(pairs.len() * 2).to_string());
+                }
+                for (k, v) in pairs {
+                    k.encode(proto, buf);
+                    v.encode(proto, buf);
+                }
+            }
+        }
+    }
+}
+
+/// 增量解析 RESP frame 的解析器，每个连接一个
+///
+/// 已经解析出来的元素会立即从 buf 中移除，没有解析完的 array 和 map 保存在栈里，
+/// 下次读到数据之后从断开的地方继续，不会每次都从 frame 的开头重新解析。
+/// 除了 RESP 的 array，也支持 telnet 之类的工具发送的 inline 命令（空格分隔的一行）。
+#[derive(Debug)]
+pub struct RespParser {
+    /// 还没有收齐元素的 array 和 map，最外层在最前面
+    stack: Vec<Aggregate>,
+    /// 当前 frame 已经从 buf 中移除的字节数
+    consumed: usize,
+    /// buf 中已经查找过、没有 \r\n 的字节数，下次从这里继续查找
+    scanned: usize,
+    max_frame_size: usize,
+}
+
+#[derive(Debug)]
+enum Aggregate {
+    Array {
+        len: usize,
+        items: Vec<RespFrame>,
+    },
+    Map {
+        len: usize,
+        pairs: Vec<(RespFrame, RespFrame)>,
+        key: Option<RespFrame>,
+    },
+}
+
+/// 解析出的一个元素：一个完整的 frame，或者一个 array/map 的开头
+enum Element {
+    Frame(RespFrame),
+    Open(Aggregate),
+}
+
+impl RespParser {
+    /// 一个 frame（包括它所有的元素）最多 max_frame_size 个字节
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            stack: Vec::new(),
+            consumed: 0,
+            scanned: 0,
+            max_frame_size,
+        }
+    }
+
+    /// 从 buf 中解析一个完整的 frame，数据不完整时返回 None，已经解析的部分会从 buf 中移除
+    pub fn parse(&mut self, buf: &mut BytesMut) -> Result<Option<RespFrame>, KvError> {
+        loop {
+            let element = match buf.first() {
+                None => return Ok(None),
+                Some(b) if self.stack.is_empty() && !is_type_byte(*b) => self.inline(buf)?,
+                Some(_) => self.element(buf)?,
+            };
+            let mut frame = match element {
+                Some(Element::Frame(frame)) => frame,
+                Some(Element::Open(aggregate)) => {
+                    if self.stack.len() >= MAX_DEPTH {
+                        return Err(error("nesting is too deep"));
+                    }
+                    self.stack.push(aggregate);
+                    continue;
+                }
+                None => return Ok(None),
+            };
+
+            // 把 frame 放进外层的 array/map，收齐元素的 array/map 继续放进更外一层
+            loop {
+                let aggregate = match self.stack.last_mut() {
+                    Some(aggregate) => aggregate,
+                    None => {
+                        self.consumed = 0;
+                        return Ok(Some(frame));
+                    }
+                };
+                match aggregate.push(frame) {
+                    Some(done) => {
+                        self.stack.pop();
+                        frame = done;
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+
+    fn inline(&mut self, buf: &mut BytesMut) -> Result<Option<Element>, KvError> {
+        let end = match self.find_line(buf)? {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let args = buf[..end]
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|v| !v.is_empty())
+            .map(|v| RespFrame::Bulk(Bytes::copy_from_slice(v)))
+            .collect();
+        self.advance(buf, end + 2)?;
+        Ok(Some(Element::Frame(RespFrame::Array(args))))
+    }
+
+    fn element(&mut self, buf: &mut BytesMut) -> Result<Option<Element>, KvError> {
+        let end = match self.find_line(buf)? {
+            Some(0) => return Err(error("empty line")),
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let (kind, rest) = (buf[0], &buf[1..end]);
+        let element = match kind {
+            b'+' => RespFrame::Simple(String::from_utf8_lossy(rest).into()).into(),
+            b'-' => RespFrame::Error(String::from_utf8_lossy(rest).into()).into(),
+            b':' => RespFrame::Integer(parse_int(rest)?).into(),
+            b'_' => RespFrame::Null.into(),
+            b'#' => match rest {
+                b"t" => RespFrame::Boolean(true).into(),
+                b"f" => RespFrame::Boolean(false).into(),
+                _ => return Err(error("invalid boolean")),
+            },
+            b',' => {
+                let s = std::str::from_utf8(rest).map_err(|_| error("invalid double"))?;
+                RespFrame::Double(s.parse().map_err(|_| error("invalid double"))?).into()
+            }
+            b'$' => match parse_len(rest, MAX_BULK_LEN)? {
+                Some(len) => return self.bulk(buf, end + 2, len),
+                None => RespFrame::Null.into(),
+            },
+            // RESP3 的 set 和 push 当作 array 处理
+            b'*' | b'~' | b'>' => match parse_len(rest, MAX_ELEMENTS)? {
+                Some(0) => RespFrame::Array(vec![]).into(),
+                Some(len) => Element::Open(Aggregate::Array {
+                    len,
+                    items: Vec::with_capacity(len.min(1024)),
+                }),
+                None => RespFrame::Null.into(),
+            },
+            b'%' => match parse_len(rest, MAX_ELEMENTS)?.unwrap_or_default() {
+                0 => RespFrame::Map(vec![]).into(),
+                len => Element::Open(Aggregate::Map {
+                    len,
+                    pairs: Vec::with_capacity(len.min(1024)),
+                    key: None,
+                }),
+            },
+            b => return Err(error(&format!("unexpected type byte '{}'", b as char))),
+        };
+        self.advance(buf, end + 2)?;
+        Ok(Some(element))
+    }
+
+    /// 长度为 len 的 bulk string，数据从 buf 的 start 开始
+    fn bulk(
+        &mut self,
+        buf: &mut BytesMut,
+        start: usize,
+        len: usize,
+    ) -> Result<Option<Element>, KvError> {
+        let total = start + len + 2;
+        // 还没有收到数据就可以知道 frame 会不会太大，不用等数据都缓存下来
+        self.check_size(total)?;
+        if buf.len() < total {
+            return Ok(None);
+        }
+        if &buf[start + len..total] != b"\r\n" {
+            return Err(error("bulk string is not terminated by CRLF"));
+        }
+        let data = Bytes::copy_from_slice(&buf[start..start + len]);
+        self.advance(buf, total)?;
+        Ok(Some(RespFrame::Bulk(data).into()))
+    }
+
+    /// buf 开头第一行的长度（不包括 \r\n），没有完整的一行时返回 None
+    fn find_line(&mut self, buf: &[u8]) -> Result<Option<usize>, KvError> {
+        // 上次查找的最后一个字节可能是 \r，往回退一个字节
+        let start = self.scanned.saturating_sub(1);
+        match buf[start..].windows(2).position(|w| w == b"\r\n") {
+            Some(end) => Ok(Some(start + end)),
+            None if buf.len() > MAX_LINE_LEN => Err(error("line is too long")),
+            None => {
+                self.check_size(buf.len())?;
+                self.scanned = buf.len();
+                Ok(None)
+            }
+        }
+    }
+
+    fn advance(&mut self, buf: &mut BytesMut, n: usize) -> Result<(), KvError> {
+        self.check_size(n)?;
+        buf.advance(n);
+        self.consumed += n;
+        self.scanned = 0;
+        Ok(())
+    }
+
+    /// 当前 frame 再增加 n 个字节之后是否超过限制
+    fn check_size(&self, n: usize) -> Result<(), KvError> {
+        if self.consumed + n > self.max_frame_size {
+            return Err(error(&format!(
+                "frame is larger than {} bytes",
+                self.max_frame_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Aggregate {
+    /// 加入一个元素，收齐所有元素之后返回完整的 frame
+    fn push(&mut self, frame: RespFrame) -> Option<RespFrame> {
+        match self {
+            Aggregate::Array { len, items } => {
+                items.push(frame);
+                (items.len() == *len).then(|| RespFrame::Array(std::mem::take(items)))
+            }
+            Aggregate::Map { len, pairs, key } => match key.take() {
+                None => {
+                    *key = Some(frame);
+                    None
+                }
+                Some(k) => {
+                    pairs.push((k, frame));
+                    (pairs.len() == *len).then(|| RespFrame::Map(std::mem::take(pairs)))
+                }
+            },
+        }
+    }
+}
+
+impl From<RespFrame> for Element {
+    fn from(frame: RespFrame) -> Self {
+        Element::Frame(frame)
+    }
+}
+
+fn is_type_byte(b: u8) -> bool {
+    matches!(
+        b,
+        b'+' | b'-' | b':' | b'$' | b'*' | b'_' | b'#' | b',' | b'%' | b'~' | b'>'
+    )
+}
+
+fn parse_int(data: &[u8]) -> Result<i64, KvError> {
+    std::str::from_utf8(data)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| error("invalid integer"))
+}
+
+/// -1 表示 null，返回 None
+fn parse_len(data: &[u8], max: i64) -> Result<Option<usize>, KvError> {
+    match parse_int(data)? {
+        -1 => Ok(None),
+        len if (0..=max).contains(&len) => Ok(Some(len as usize)),
+        _ => Err(error("invalid length")),
+    }
+}
+
+fn line(buf: &mut Vec<u8>, kind: u8, s: &str) {
+    buf.push(kind);
+    buf.extend_from_slice(s.as_bytes());
+    buf.extend_from_slice(b"\r\n");
+}
+
+fn format_double(f: f64) -> String {
+    if f.is_infinite() {
+        if f > 0.0 { "inf" } else { "-inf" }.into()
+    } else {
+        f.to_string()
+    }
+}
+
+fn error(msg: &str) -> KvError {
+    KvError::ProtocolError(msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(buf: &mut BytesMut) -> Result<Option<RespFrame>, KvError> {
+        RespParser::new(1024).parse(buf)
+    }
+
+    fn parse_all(data: &[u8]) -> Vec<RespFrame> {
+        let mut buf = BytesMut::from(data);
+        let mut parser = RespParser::new(1024);
+        let mut frames = Vec::new();
+        while let Some(frame) = parser.parse(&mut buf).unwrap() {
+            frames.push(frame);
+        }
+        assert!(buf.is_empty());
+        frames
+    }
+
+    fn encode(frame: &RespFrame, proto: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        frame.encode(proto, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn parse_should_handle_arrays_inline_and_partial_frames() {
+        let frames = parse_all(b"*2\r\n$4\r\nHGET\r\n$0\r\n\r\nPING  hello\r\n");
+        assert_eq!(
+            frames,
+            vec![
+                RespFrame::Array(vec![RespFrame::bulk("HGET"), RespFrame::bulk("")]),
+                RespFrame::Array(vec![RespFrame::bulk("PING"), RespFrame::bulk("hello")]),
+            ]
+        );
+
+        // 数据不完整时已经解析的元素从 buf 中移除，收到剩下的数据之后继续解析
+        let data = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nhel";
+        let mut buf = BytesMut::from(&data[..]);
+        let mut parser = RespParser::new(1024);
+        assert_eq!(parser.parse(&mut buf).unwrap(), None);
+        assert_eq!(&buf[..], b"$5\r\nhel");
+        buf.extend_from_slice(b"lo\r");
+        assert_eq!(parser.parse(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"\n");
+        let frame = parser.parse(&mut buf).unwrap();
+        let args = ["SET", "k", "hello"].map(RespFrame::bulk).to_vec();
+        assert_eq!(frame, Some(RespFrame::Array(args)));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"*1\r\n$3\r\nabcde\r\n"[..]);
+        assert!(parse(&mut buf).is_err());
+        let mut buf = BytesMut::from(&b"*1\r\n?x\r\n"[..]);
+        assert!(parse(&mut buf).is_err());
+    }
+
+    #[test]
+    fn parse_should_reject_deep_nesting() {
+        let mut buf = BytesMut::from(&b"*1\r\n".repeat(MAX_DEPTH)[..]);
+        buf.extend_from_slice(b":1\r\n");
+        assert_eq!(parse_all(&buf).len(), 1);
+
+        let mut buf = BytesMut::from(&b"*1\r\n".repeat(MAX_DEPTH + 1)[..]);
+        let err = parse(&mut buf).unwrap_err();
+        assert_eq!(err, KvError::ProtocolError("nesting is too deep".into()));
+    }
+
+    #[test]
+    fn parse_should_limit_frame_size() {
+        // bulk string 的长度超过限制时，不用等数据到达就返回错误
+        let mut buf = BytesMut::from(&b"*1\r\n$2000\r\n"[..]);
+        assert!(matches!(parse(&mut buf), Err(KvError::ProtocolError(_))));
+
+        // 每个元素都不大，但是加起来超过了限制
+        let mut parser = RespParser::new(1024);
+        let mut buf = BytesMut::from(&b"*100\r\n"[..]);
+        let result = (0..100).try_for_each(|_| {
+            buf.extend_from_slice(b"$20\r\naaaaaaaaaaaaaaaaaaaa\r\n");
+            parser.parse(&mut buf).map(|_| ())
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_should_handle_resp3_types() {
+        let frames = parse_all(b"*5\r\n_\r\n#t\r\n,1.5\r\n%1\r\n+k\r\n:1\r\n$-1\r\n");
+        assert_eq!(
+            frames,
+            vec![RespFrame::Array(vec![
+                RespFrame::Null,
+                RespFrame::Boolean(true),
+                RespFrame::Double(1.5),
+                RespFrame::Map(vec![(RespFrame::Simple("k".into()), RespFrame::Integer(1))]),
+                RespFrame::Null,
+            ])]
+        );
+    }
+
+    #[test]
+    fn encode_should_downgrade_resp3_types_for_resp2() {
+        let frame = RespFrame::Map(vec![
+            (RespFrame::bulk("k1"), RespFrame::Null),
+            (RespFrame::bulk("k2"), RespFrame::Boolean(true)),
+        ]);
+        assert_eq!(
+            encode(&frame, 2),
+            b"*4\r\n$2\r\nk1\r\n$-1\r\n$2\r\nk2\r\n:1\r\n".to_vec()
+        );
+        assert_eq!(
+            encode(&frame, 3),
+            b"%2\r\n$2\r\nk1\r\n_\r\n$2\r\nk2\r\n#t\r\n".to_vec()
+        );
+        assert_eq!(
+            encode(&RespFrame::Double(2.5), 2),
+            b"$3\r\n2.5\r\n".to_vec()
+        );
+        assert_eq!(encode(&RespFrame::Double(2.5), 3), b",2.5\r\n".to_vec());
+    }
+}
@@ -0,0 +1,678 @@
+mod frame;
+
+use std::time::{Instant, SystemTime};
+
+use bytes::{Bytes, BytesMut};
+use http::StatusCode;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use super::{tls::accept_stream, TlsServerAcceptor};
+use crate::{
+    value, CommandRequest, CommandResponse, ConnectEvent, DisconnectEvent, ErrorEvent, KvError,
+    Kvpair, MemTable, SendEvent, Service, Session, Storage, Value, DEFAULT_MAX_FRAME_SIZE,
+};
+
+pub use frame::RespFrame;
+use frame::RespParser;
+
+/// 在 listener 上用 RESP 协议提供服务，配置了 TLS 时先完成握手
+///
+/// 双向认证时客户端证书中的身份就是连接的用户，不需要再 AUTH。
+pub async fn serve_resp<Store>(
+    listener: TcpListener,
+    service: Service<Store>,
+    tls: Option<TlsServerAcceptor>,
+) -> Result<(), KvError>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    // Redis 客户端不协商 ALPN
+    let tls = tls.map(|tls| tls.with_alpn(&[]));
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let (service, tls) = (service.clone(), tls.clone());
+        tokio::spawn(async move {
+            let (stream, session) = match accept_stream(stream, addr, tls.as_ref()).await {
+                Ok(conn) => conn,
+                Err(e) => return warn!("Failed to accept RESP client {:?}: {}", addr, e),
+            };
+            if let Err(e) = RespServerStream::new(stream, service)
+                .with_session(session)
+                .process()
+                .await
+            {
+                info!("RESP client {:?} disconnected: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// 用 Redis 的 RESP 协议处理客户端连接，把 Redis 的 hash 命令翻译成 CommandRequest
+///
+/// Redis 的 key 对应 table，field 对应 table 里的 key。缺省使用 RESP2，
+/// 客户端可以用 `HELLO 3` 切换到 RESP3。
+pub struct RespServerStream<S, Store: Storage = MemTable> {
+    stream: S,
+    service: Service<Store>,
+    session: Session,
+    /// 2 或 3
+    proto: u8,
+    buf: BytesMut,
+    parser: RespParser,
+    /// 服务器关闭时被取消，处理完当前的命令之后通知客户端并断开
+    shutdown: CancellationToken,
+}
+
+impl<S, Store> RespServerStream<S, Store>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    Store: Storage + Send + Sync + 'static,
+{
+    pub fn new(stream: S, service: Service<Store>) -> Self {
+        Self {
+            stream,
+            service,
+            session: Session::new(None),
+            proto: 2,
+            buf: BytesMut::with_capacity(4096),
+            parser: RespParser::new(DEFAULT_MAX_FRAME_SIZE),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// 设置一个命令（包括所有参数）最大的字节数，缺省和 prost 协议的 frame 一样
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.parser = RespParser::new(max_frame_size);
+        self
+    }
+
+    /// 设置连接的 Session，比如客户端地址
+    pub fn with_session(mut self, session: Session) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// token 被取消时，正在处理的命令仍然会完成并发回响应，然后通知客户端服务器正在关闭并断开连接
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// 不断读取客户端发来的命令，处理后把结果发回去，直到客户端断开或者发送 QUIT
+    pub async fn process(mut self) -> Result<(), KvError> {
+        let peer = self.session.peer;
+        let connected_at = Instant::now();
+        self.service.on_connected(&ConnectEvent {
+            peer,
+            at: SystemTime::now(),
+        });
+
+        let mut requests = 0;
+        let result = loop {
+            let frame = match self.parser.parse(&mut self.buf) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    let read = tokio::select! {
+                        read = self.stream.read_buf(&mut self.buf) => read,
+                        _ = self.shutdown.cancelled() => {
+                            info!("Server is shutting down, disconnecting {:?}", peer);
+                            let reply = RespFrame::Error(format!("ERR {}", KvError::ShuttingDown));
+                            self.send(&reply).await.ok();
+                            break Ok(());
+                        }
+                    };
+                    match read {
+                        Ok(0) => break Ok(()),
+                        Ok(_) => continue,
+                        Err(e) => break Err(e.into()),
+                    }
+                }
+                Err(error) => {
+                    // 协议错误之后无法找到下一个命令的开始，回复错误后断开
+                    let reply = RespFrame::Error(format!("ERR {}", error));
+                    self.send(&reply).await.ok();
+                    let event = ErrorEvent {
+                        peer,
+                        error,
+                        elapsed: connected_at.elapsed(),
+                    };
+                    self.service.on_decode_error(&event);
+                    break Err(event.error);
+                }
+            };
+            let args = match frame {
+                RespFrame::Array(args) if args.is_empty() => continue,
+                RespFrame::Array(args) => args,
+                _ => vec![],
+            };
+
+            info!("Got a new RESP command: {:?}", args.first());
+            requests += 1;
+            let start = Instant::now();
+            let (reply, quit) = self.handle(args).await;
+            let event = SendEvent {
+                peer,
+                result: self.send(&reply).await,
+                elapsed: start.elapsed(),
+            };
+            self.service.on_after_send(&event);
+
+            if let Err(error) = event.result {
+                let event = ErrorEvent {
+                    peer,
+                    error,
+                    elapsed: connected_at.elapsed(),
+                };
+                self.service.on_send_error(&event);
+                break Err(event.error);
+            }
+            if quit {
+                break Ok(());
+            }
+        };
+
+        self.service.on_disconnected(&DisconnectEvent {
+            peer,
+            duration: connected_at.elapsed(),
+            requests,
+        });
+        result
+    }
+
+    async fn send(&mut self, reply: &RespFrame) -> Result<(), KvError> {
+        let mut buf = Vec::new();
+        reply.encode(self.proto, &mut buf);
+        self.stream.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// 返回回复，以及是否需要断开连接
+    async fn handle(&mut self, args: Vec<RespFrame>) -> (RespFrame, bool) {
+        let args: Option<Vec<Bytes>> = args
+            .into_iter()
+            .map(|arg| match arg {
+                RespFrame::Bulk(data) => Some(data),
+                RespFrame::Simple(s) => Some(s.into()),
+                RespFrame::Integer(i) => Some(i.to_string().into()),
+                _ => None,
+            })
+            .collect();
+        let args = match args {
+            Some(args) if !args.is_empty() => args,
+            _ => {
+                let msg = "ERR Protocol error: expected an array of bulk strings";
+                return (RespFrame::Error(msg.into()), false);
+            }
+        };
+
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+        let reply = self.command(&name, &args[1..]).await.unwrap_or_else(|e| e);
+        (reply, name == "quit")
+    }
+
+    /// 执行一个命令，Err 是需要返回给客户端的错误
+    async fn command(&mut self, name: &str, args: &[Bytes]) -> Result<RespFrame, RespFrame> {
+        match name {
+            "ping" => match args {
+                [] => Ok(RespFrame::Simple("PONG".into())),
+                [msg] => Ok(RespFrame::Bulk(msg.clone())),
+                _ => Err(arity(name)),
+            },
+            "echo" => {
+                check_arity(name, args, 1, Some(1))?;
+                Ok(RespFrame::Bulk(args[0].clone()))
+            }
+            "quit" => Ok(RespFrame::ok()),
+            "select" => match args {
+                [db] if &db[..] == b"0" => Ok(RespFrame::ok()),
+                [_] => Err(RespFrame::Error("ERR DB index is out of range".into())),
+                _ => Err(arity(name)),
+            },
+            // redis-cli 启动时会查询 COMMAND DOCS，客户端库会设置连接的名字
+            "command" => Ok(RespFrame::Array(vec![])),
+            "client" => Ok(RespFrame::ok()),
+            "hello" => self.hello(args).await,
+            "auth" => {
+                check_arity(name, args, 1, Some(2))?;
+                self.auth(args).await?;
+                Ok(RespFrame::ok())
+            }
+            "info" => {
+                let sections = args
+                    .iter()
+                    .map(|s| String::from_utf8_lossy(s).to_ascii_lowercase())
+                    .filter(|s| !matches!(s.as_str(), "all" | "everything" | "default"));
+                let res = self.execute(CommandRequest::new_info(sections))?;
+                Ok(RespFrame::bulk(info_text(&res.pairs)))
+            }
+            "hget" => {
+                check_arity(name, args, 2, Some(2))?;
+                let cmd = CommandRequest::new_hget(string(&args[0])?, string(&args[1])?);
+                let res = self.service.execute_with(cmd, &mut self.session);
+                if res.status == StatusCode::NOT_FOUND.as_u16() as u32 {
+                    return Ok(RespFrame::Null);
+                }
+                let res = check(res)?;
+                Ok(value_frame(res.values.first()))
+            }
+            "hset" | "hmset" => {
+                if args.len() < 3 || args.len().is_multiple_of(2) {
+                    return Err(arity(name));
+                }
+                let pairs = args[1..]
+                    .chunks(2)
+                    .map(|kv| Ok(Kvpair::new(string(&kv[0])?, to_value(&kv[1]))))
+                    .collect::<Result<Vec<_>, RespFrame>>()?;
+                let res = self.execute(CommandRequest::new_hmset(string(&args[0])?, pairs))?;
+                if name == "hmset" {
+                    return Ok(RespFrame::ok());
+                }
+                // HSET 返回新增的 field 数
+                let added = res.values.iter().filter(|v| v.value.is_none()).count();
+                Ok(RespFrame::Integer(added as i64))
+            }
+            "hmget" => {
+                check_arity(name, args, 2, None)?;
+                let keys = strings(&args[1..])?;
+                let res = self.execute(CommandRequest::new_hmget(string(&args[0])?, keys))?;
+                let values = res.values.iter().map(|v| value_frame(Some(v))).collect();
+                Ok(RespFrame::Array(values))
+            }
+            "hdel" => {
+                check_arity(name, args, 2, None)?;
+                let keys = strings(&args[1..])?;
+                let res = self.execute(CommandRequest::new_hmdel(string(&args[0])?, keys))?;
+                let deleted = res.values.iter().filter(|v| v.value.is_some()).count();
+                Ok(RespFrame::Integer(deleted as i64))
+            }
+            "hexists" => {
+                check_arity(name, args, 2, Some(2))?;
+                let cmd = CommandRequest::new_hexist(string(&args[0])?, string(&args[1])?);
+                let res = self.execute(cmd)?;
+                let exists = matches!(
+                    res.values.first().and_then(|v| v.value.as_ref()),
+                    Some(value::Value::Bool(true))
+                );
+                Ok(RespFrame::Integer(exists as i64))
+            }
+            "hgetall" | "hkeys" | "hvals" | "hlen" => {
+                check_arity(name, args, 1, Some(1))?;
+                let res = self.execute(CommandRequest::new_hget_all(string(&args[0])?))?;
+                let pairs = res.pairs.into_iter();
+                Ok(match name {
+                    "hgetall" => RespFrame::Map(
+                        pairs
+                            .map(|p| (RespFrame::bulk(p.key), value_frame(p.value.as_ref())))
+                            .collect(),
+                    ),
+                    "hkeys" => RespFrame::Array(pairs.map(|p| RespFrame::bulk(p.key)).collect()),
+                    "hvals" => {
+                        RespFrame::Array(pairs.map(|p| value_frame(p.value.as_ref())).collect())
+                    }
+                    _ => RespFrame::Integer(pairs.len() as i64),
+                })
+            }
+            "publish" => {
+                check_arity(name, args, 2, Some(2))?;
+                let cmd = CommandRequest::new_publish(string(&args[0])?, to_value(&args[1]));
+                let res = self.execute(cmd)?;
+                match res.values.first().and_then(|v| v.value.as_ref()) {
+                    Some(value::Value::Integer(n)) => Ok(RespFrame::Integer(*n)),
+                    _ => Ok(RespFrame::Integer(0)),
+                }
+            }
+            _ => Err(RespFrame::Error(format!("ERR unknown command '{}'", name))),
+        }
+    }
+
+    /// HELLO [protover [AUTH username password] [SETNAME clientname]]
+    async fn hello(&mut self, args: &[Bytes]) -> Result<RespFrame, RespFrame> {
+        let mut proto = self.proto;
+        let mut rest = args;
+        if let Some((ver, tail)) = args.split_first() {
+            proto = match &ver[..] {
+                b"2" => 2,
+                b"3" => 3,
+                _ => {
+                    let msg = "NOPROTO unsupported protocol version";
+                    return Err(RespFrame::Error(msg.into()));
+                }
+            };
+            rest = tail;
+        }
+        while let Some((opt, tail)) = rest.split_first() {
+            match String::from_utf8_lossy(opt).to_ascii_lowercase().as_str() {
+                "auth" if tail.len() >= 2 => {
+                    self.auth(&tail[..2]).await?;
+                    rest = &tail[2..];
+                }
+                "setname" if !tail.is_empty() => rest = &tail[1..],
+                _ => return Err(RespFrame::Error("ERR syntax error".into())),
+            }
+        }
+        self.proto = proto;
+
+        let field = |k: &str, v: RespFrame| (RespFrame::bulk(k.to_string()), v);
+        Ok(RespFrame::Map(vec![
+            field("server", RespFrame::bulk("kv")),
+            field("version", RespFrame::bulk(env!("CARGO_PKG_VERSION"))),
+            field("proto", RespFrame::Integer(proto as i64)),
+            field("id", RespFrame::Integer(self.session.id as i64)),
+            field("mode", RespFrame::bulk("standalone")),
+            field("role", RespFrame::bulk("master")),
+            field("modules", RespFrame::Array(vec![])),
+        ]))
+    }
+
+    /// AUTH password 使用 token 认证，AUTH username password 使用用户名/密码认证
+    async fn auth(&mut self, args: &[Bytes]) -> Result<(), RespFrame> {
+        let cmd = match args {
+            [token] => CommandRequest::new_auth_token(string(token)?),
+            [user, password] => CommandRequest::new_auth(string(user)?, string(password)?),
+            _ => return Err(arity("auth")),
+        };
+        check(self.service.execute_async(cmd, &mut self.session).await)?;
+        Ok(())
+    }
+
+    fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, RespFrame> {
+        check(self.service.execute_with(cmd, &mut self.session))
+    }
+}
+
+/// 非 200 的状态码转换成 Redis 的错误，错误的第一个单词是 Redis 客户端用来区分错误类型的前缀
+fn check(res: CommandResponse) -> Result<CommandResponse, RespFrame> {
+    let prefix = match StatusCode::from_u16(res.status as u16) {
+        Ok(StatusCode::OK) => return Ok(res),
+        Ok(StatusCode::UNAUTHORIZED) => "NOAUTH",
+        Ok(StatusCode::FORBIDDEN) => "NOPERM",
+        Ok(StatusCode::TEMPORARY_REDIRECT) => "READONLY",
+        _ => "ERR",
+    };
+    let msg = res.message.replace(['\r', '\n'], " ");
+    Err(RespFrame::Error(format!("{} {}", prefix, msg)))
+}
+
+fn check_arity(
+    name: &str,
+    args: &[Bytes],
+    min: usize,
+    max: Option<usize>,
+) -> Result<(), RespFrame> {
+    if args.len() < min || max.is_some_and(|max| args.len() > max) {
+        return Err(arity(name));
+    }
+    Ok(())
+}
+
+fn arity(name: &str) -> RespFrame {
+    RespFrame::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name
+    ))
+}
+
+fn string(data: &Bytes) -> Result<String, RespFrame> {
+    String::from_utf8(data.to_vec())
+        .map_err(|_| RespFrame::Error("ERR keys must be valid UTF-8".into()))
+}
+
+fn strings(args: &[Bytes]) -> Result<Vec<String>, RespFrame> {
+    args.iter().map(string).collect()
+}
+
+/// Redis 的值都是字符串，合法的 UTF-8 保存成 String，否则保存成 Binary
+fn to_value(data: &Bytes) -> Value {
+    let value = match std::str::from_utf8(data) {
+        Ok(s) => value::Value::String(s.into()),
+        Err(_) => value::Value::Binary(data.clone()),
+    };
+    Value { value: Some(value) }
+}
+
+/// 所有类型的 Value 都作为 bulk string 返回，和 Redis 一样；空的 Value 是 null
+fn value_frame(v: Option<&Value>) -> RespFrame {
+    match v.and_then(|v| v.value.as_ref()) {
+        Some(value::Value::String(s)) => RespFrame::bulk(s.clone()),
+        Some(value::Value::Binary(b)) => RespFrame::Bulk(b.clone()),
+        Some(value::Value::Integer(i)) => RespFrame::bulk(i.to_string()),
+        Some(value::Value::Float(f)) => RespFrame::bulk(f.to_string()),
+        Some(value::Value::Bool(b)) => RespFrame::bulk(if *b { "1" } else { "0" }),
+        None => RespFrame::Null,
+    }
+}
+
+/// 按照 Redis INFO 的格式输出：`# Section` 开头，每行一个 `field:value`
+fn info_text(pairs: &[Kvpair]) -> String {
+    let mut text = String::new();
+    let mut current = "";
+    for pair in pairs {
+        let (section, field) = pair.key.split_once('.').unwrap_or(("", &pair.key));
+        if section != current {
+            if !text.is_empty() {
+                text.push_str("\r\n");
+            }
+            let mut chars = section.chars();
+            let title: String = chars
+                .next()
+                .map(|c| c.to_ascii_uppercase())
+                .into_iter()
+                .chain(chars)
+                .collect();
+            text.push_str(&format!("# {}\r\n", title));
+            current = section;
+        }
+        let value = match value_frame(pair.value.as_ref()) {
+            RespFrame::Bulk(data) => String::from_utf8_lossy(&data).into_owned(),
+            _ => String::new(),
+        };
+        text.push_str(&format!("{}:{}\r\n", field, value));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tokio::{
+        io::{duplex, DuplexStream},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::{
+        network::tls::test_utils::{generate_certs, DOMAIN},
+        ServiceInner, TlsClientConnector,
+    };
+
+    #[tokio::test]
+    async fn resp_with_client_cert_should_work() -> Result<()> {
+        let certs = generate_certs("client1");
+        let acceptor =
+            TlsServerAcceptor::new(&certs.server_cert, &certs.server_key, Some(&certs.ca))?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(serve_resp(listener, service, Some(acceptor)));
+
+        let identity = Some((certs.client_cert.as_str(), certs.client_key.as_str()));
+        let connector = TlsClientConnector::new(DOMAIN, identity, Some(&certs.ca))?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.with_alpn(&[]).connect(stream).await?;
+        stream.write_all(b"*1\r\n$4\r\nPING\r\n").await?;
+        let mut buf = [0; 7];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"+PONG\r\n");
+        Ok(())
+    }
+
+    fn start(service: Service) -> DuplexStream {
+        let (client, server) = duplex(4096);
+        tokio::spawn(RespServerStream::new(server, service).process());
+        client
+    }
+
+    /// 发送原始的 RESP 数据，检查返回的原始数据
+    async fn roundtrip(client: &mut DuplexStream, req: &[u8], expected: &[u8]) -> Result<()> {
+        client.write_all(req).await?;
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await?;
+        assert_eq!(
+            String::from_utf8_lossy(&buf),
+            String::from_utf8_lossy(expected)
+        );
+        Ok(())
+    }
+
+    async fn read_frame(client: &mut DuplexStream) -> Result<RespFrame> {
+        let mut buf = BytesMut::new();
+        let mut parser = RespParser::new(DEFAULT_MAX_FRAME_SIZE);
+        loop {
+            if let Some(frame) = parser.parse(&mut buf)? {
+                return Ok(frame);
+            }
+            client.read_buf(&mut buf).await?;
+        }
+    }
+
+    #[tokio::test]
+    async fn resp2_hash_commands_should_work() -> Result<()> {
+        let mut client = start(ServiceInner::new(MemTable::new()).into());
+
+        roundtrip(&mut client, b"*1\r\n$4\r\nPING\r\n", b"+PONG\r\n").await?;
+        roundtrip(&mut client, b"PING\r\n", b"+PONG\r\n").await?;
+        roundtrip(
+            &mut client,
+            b"*6\r\n$4\r\nHSET\r\n$6\r\nuser:1\r\n$4\r\nname\r\n$5\r\nalice\r\n$3\r\nage\r\n$2\r\n30\r\n",
+            b":2\r\n",
+        )
+        .await?;
+        roundtrip(
+            &mut client,
+            b"*3\r\n$4\r\nhget\r\n$6\r\nuser:1\r\n$4\r\nname\r\n",
+            b"$5\r\nalice\r\n",
+        )
+        .await?;
+        roundtrip(
+            &mut client,
+            b"*3\r\n$4\r\nHGET\r\n$6\r\nuser:1\r\n$4\r\nnope\r\n",
+            b"$-1\r\n",
+        )
+        .await?;
+        roundtrip(
+            &mut client,
+            b"*4\r\n$5\r\nHMGET\r\n$6\r\nuser:1\r\n$3\r\nage\r\n$4\r\nnope\r\n",
+            b"*2\r\n$2\r\n30\r\n$-1\r\n",
+        )
+        .await?;
+        roundtrip(
+            &mut client,
+            b"*3\r\n$7\r\nHEXISTS\r\n$6\r\nuser:1\r\n$3\r\nage\r\n",
+            b":1\r\n",
+        )
+        .await?;
+        roundtrip(
+            &mut client,
+            b"*4\r\n$4\r\nHDEL\r\n$6\r\nuser:1\r\n$3\r\nage\r\n$4\r\nnope\r\n",
+            b":1\r\n",
+        )
+        .await?;
+        roundtrip(
+            &mut client,
+            b"*2\r\n$7\r\nHGETALL\r\n$6\r\nuser:1\r\n",
+            b"*2\r\n$4\r\nname\r\n$5\r\nalice\r\n",
+        )
+        .await?;
+        roundtrip(&mut client, b"HLEN user:1\r\n", b":1\r\n").await?;
+
+        roundtrip(
+            &mut client,
+            b"*2\r\n$4\r\nHGET\r\n$6\r\nuser:1\r\n",
+            b"-ERR wrong number of arguments for 'hget' command\r\n",
+        )
+        .await?;
+        roundtrip(
+            &mut client,
+            b"*1\r\n$3\r\nFOO\r\n",
+            b"-ERR unknown command 'foo'\r\n",
+        )
+        .await?;
+        roundtrip(&mut client, b"*1\r\n$4\r\nQUIT\r\n", b"+OK\r\n").await?;
+        assert_eq!(client.read(&mut [0; 16]).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hello_3_should_switch_to_resp3() -> Result<()> {
+        let mut client = start(ServiceInner::new(MemTable::new()).into());
+        roundtrip(
+            &mut client,
+            b"*4\r\n$4\r\nHSET\r\n$1\r\nh\r\n$1\r\nf\r\n$1\r\nv\r\n",
+            b":1\r\n",
+        )
+        .await?;
+
+        client
+            .write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n")
+            .await?;
+        let pairs = match read_frame(&mut client).await? {
+            RespFrame::Map(pairs) => pairs,
+            frame => panic!("expect a map, got {:?}", frame),
+        };
+        assert!(pairs.contains(&(RespFrame::bulk("proto"), RespFrame::Integer(3))));
+
+        roundtrip(
+            &mut client,
+            b"*2\r\n$7\r\nHGETALL\r\n$1\r\nh\r\n",
+            b"%1\r\n$1\r\nf\r\n$1\r\nv\r\n",
+        )
+        .await?;
+        roundtrip(&mut client, b"HGET h nope\r\n", b"_\r\n").await?;
+        roundtrip(
+            &mut client,
+            b"*2\r\n$5\r\nHELLO\r\n$1\r\n4\r\n",
+            b"-NOPROTO unsupported protocol version\r\n",
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn errors_should_map_to_redis_error_prefixes() -> Result<()> {
+        let service = ServiceInner::new(MemTable::new())
+            .follower("127.0.0.1:6379")
+            .into();
+        let mut client = start(service);
+        let res =
+            b"-READONLY This server is a follower, send writes to the leader 127.0.0.1:6379\r\n";
+        roundtrip(&mut client, b"HSET h f v\r\n", res).await?;
+
+        // 协议错误之后断开连接
+        roundtrip(
+            &mut client,
+            b"*1\r\n$3\r\nabcdef\r\n",
+            b"-ERR Protocol error: bulk string is not terminated by CRLF\r\n",
+        )
+        .await?;
+        assert_eq!(client.read(&mut [0; 16]).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deeply_nested_arrays_should_be_rejected() -> Result<()> {
+        let mut client = start(ServiceInner::new(MemTable::new()).into());
+        roundtrip(
+            &mut client,
+            &b"*1\r\n".repeat(1000),
+            b"-ERR Protocol error: nesting is too deep\r\n",
+        )
+        .await?;
+        assert_eq!(client.read(&mut [0; 16]).await?, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,835 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use quinn::Endpoint;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::mpsc,
+    task::JoinSet,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+#[cfg(unix)]
+use super::bind_unix;
+use super::{
+    bind_quic,
+    grpc::{self, ALPN_GRPC},
+    http::{self, ALPN_HTTP},
+    quic,
+    tls::accept_stream,
+    ProstServerStream, RespServerStream, TlsServerAcceptor, YamuxServerStream,
+};
+use crate::{
+    Authenticator, ChangeLog, CompressionConfig, Follower, KvError, MemTable, RaftServer,
+    RateLimiter, ServerConfig, Service, ServiceInner, Session, Storage, UnixSocketConfig,
+    YamuxConfig, DEFAULT_MAX_FRAME_SIZE,
+};
+
+/// 关闭时默认等待正在处理的命令完成的时间
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 一个客户端连接上的全部处理，关闭时由 Server 统一等待或者强制断开
+type Connection = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// 在 TCP 端口上提供服务，并且可以优雅地关闭
+///
+/// 除了 protobuf 协议的 TCP 端口，还可以同时监听 RESP、HTTP、gRPC、Unix domain socket 和 QUIC，
+/// 所有的连接使用同一个关闭的通知。关闭时先停止 accept，正在处理的命令在 deadline 之前完成并发回响应，
+/// 其它客户端收到 503 的通知后断开；超过 deadline 的连接被强制断开。最后 flush 存储和变更日志，
+/// 返回关闭的情况。
+pub struct Server<Store: Storage = MemTable> {
+    listeners: Vec<Listener>,
+    service: Service<Store>,
+    tls: Option<TlsServerAcceptor>,
+    compression: CompressionConfig,
+    max_frame_size: usize,
+    yamux: Option<YamuxConfig>,
+    shutdown: CancellationToken,
+    shutdown_timeout: Duration,
+    /// 复制和 Raft 节点等后台任务，run 返回之前被取消
+    background: CancellationToken,
+}
+
+/// Server 监听的一个端口和它使用的协议
+enum Listener {
+    /// protobuf 协议，配置了 yamux 时在连接上多路复用
+    Prost(TcpListener),
+    Resp(TcpListener),
+    Http(TcpListener),
+    Grpc(TcpListener),
+    /// 和 TCP 端口相同的协议，不使用 TLS
+    #[cfg(unix)]
+    Unix(UnixListener),
+    Quic(Endpoint),
+}
+
+/// 建立连接时需要的配置，所有的监听器共享
+struct Context<Store: Storage> {
+    service: Service<Store>,
+    compression: CompressionConfig,
+    max_frame_size: usize,
+    yamux: Option<YamuxConfig>,
+    shutdown: CancellationToken,
+}
+
+/// 用来关闭 Server，可以 clone 之后在任何地方调用
+#[derive(Clone)]
+pub struct ServerHandle {
+    shutdown: CancellationToken,
+}
+
+/// 服务器关闭的情况
+#[derive(Debug)]
+pub struct ShutdownReport {
+    /// 开始关闭时还连接着的客户端数
+    pub connections: usize,
+    /// 在 deadline 之前正常断开的连接数
+    pub closed: usize,
+    /// 超过 deadline 被强制断开的连接数，它们正在处理的命令可能没有发回响应
+    pub aborted: usize,
+    /// 从开始关闭到所有连接断开花的时间，不包括 flush
+    pub elapsed: Duration,
+    /// flush 存储和变更日志的结果
+    pub flush: Result<(), KvError>,
+}
+
+impl<Store> Server<Store>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    pub fn new(listener: TcpListener, service: Service<Store>) -> Self {
+        Self {
+            listeners: vec![Listener::Prost(listener)],
+            service,
+            tls: None,
+            compression: CompressionConfig::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            yamux: None,
+            shutdown: CancellationToken::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            background: CancellationToken::new(),
+        }
+    }
+
+    /// 按照配置文件创建服务器，监听所有配置的端口
+    ///
+    /// 在 inner 上注册限流和认证的中间件，设置慢命令日志、Pub/Sub、Watch 和变更日志；
+    /// inner 上已经注册的中间件（比如 metrics 和 AuditLog）在它们的外层。
+    /// 配置了 replication 或者 raft 时，复制或者 Raft 节点在后台运行，run 返回时停止。
+    pub async fn from_config(
+        config: &ServerConfig,
+        mut inner: ServiceInner<Store>,
+    ) -> Result<Self, KvError> {
+        if config.replication.is_some() && config.raft.is_some() {
+            let msg = "replication and raft can not be enabled together".into();
+            return Err(KvError::ConfigError(msg));
+        }
+        if let Some(rate_limit) = &config.rate_limit {
+            inner = inner.layer(RateLimiter::new(rate_limit.clone()));
+        }
+        if let Some(auth) = &config.auth {
+            inner = inner.layer(Authenticator::new(auth.clone())?);
+        }
+        if let Some(slowlog) = config.slowlog {
+            inner = inner.slowlog(slowlog);
+        }
+        if let Some(pubsub) = config.pubsub {
+            inner = inner.pubsub(pubsub);
+        }
+        if let Some(watch) = config.watch {
+            inner = inner.watch(watch);
+        }
+        if let Some(cdc) = &config.cdc {
+            inner = inner.changelog(ChangeLog::open(cdc.clone())?);
+        }
+        if let Some(replication) = &config.replication {
+            inner = inner.follower(&replication.leader);
+        }
+        let background = CancellationToken::new();
+        let raft = config
+            .raft
+            .as_ref()
+            .map(|raft| RaftServer::new(raft.clone()).with_shutdown(background.clone()));
+        if let Some(raft) = &raft {
+            inner = inner.raft(raft.handle());
+        }
+        let service: Service<Store> = inner.into();
+
+        let tls = config
+            .tls
+            .as_ref()
+            .map(TlsServerAcceptor::from_config)
+            .transpose()?;
+        let listener = TcpListener::bind(&config.general.addr).await?;
+        info!("Start listening on {}", config.general.addr);
+        let mut server = Server::new(listener, service.clone())
+            .with_max_frame_size(config.general.max_frame_size);
+        server.background = background.clone();
+        if let Some(tls) = &tls {
+            server = server.with_tls(tls.clone());
+        }
+        if let Some(compression) = &config.compression {
+            server = server.with_compression(compression.clone());
+        }
+        if let Some(yamux) = &config.yamux {
+            server = server.with_yamux(yamux.clone());
+        }
+        if let Some(resp) = &config.resp {
+            server = server.with_resp(TcpListener::bind(&resp.addr).await?);
+            info!("Start listening RESP on {}", resp.addr);
+        }
+        if let Some(http) = &config.http {
+            server = server.with_http(TcpListener::bind(&http.addr).await?);
+            info!("Start listening HTTP on {}", http.addr);
+        }
+        if let Some(grpc) = &config.grpc {
+            server = server.with_grpc(TcpListener::bind(&grpc.addr).await?);
+            info!("Start listening gRPC on {}", grpc.addr);
+        }
+        if let Some(unix) = &config.unix {
+            server = server.with_unix_config(unix)?;
+            info!("Start listening on unix://{}", unix.path);
+        }
+        if let Some(quic) = &config.quic {
+            let tls = tls
+                .as_ref()
+                .ok_or_else(|| KvError::ConfigError("QUIC requires [tls]".into()))?;
+            let addr = quic.addr.parse().map_err(|e| {
+                KvError::ConfigError(format!("Invalid QUIC address {}: {}", quic.addr, e))
+            })?;
+            server = server.with_quic(bind_quic(addr, tls)?);
+            info!("Start listening QUIC on {}", quic.addr);
+        }
+
+        // 端口都监听成功之后再启动后台任务
+        if let Some(raft) = raft {
+            raft.start(service.clone()).await?;
+        }
+        if let Some(replication) = &config.replication {
+            let follower = Follower::new(service, replication.clone())?;
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = follower.run() => {}
+                    _ = background.cancelled() => {}
+                }
+            });
+        }
+        Ok(server)
+    }
+
+    #[cfg(unix)]
+    fn with_unix_config(self, config: &UnixSocketConfig) -> Result<Self, KvError> {
+        Ok(self.with_unix(bind_unix(config)?))
+    }
+
+    #[cfg(not(unix))]
+    fn with_unix_config(self, _config: &UnixSocketConfig) -> Result<Self, KvError> {
+        let msg = "Unix domain socket is not supported on this platform".into();
+        Err(KvError::ConfigError(msg))
+    }
+
+    /// TCP、RESP、HTTP 和 gRPC 端口都使用 TLS，配置了 client CA 时要求客户端证书
+    pub fn with_tls(mut self, acceptor: TlsServerAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// 设置客户端请求压缩时允许使用的算法，algorithms 为空时不压缩
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// 设置最大的 frame 大小，protobuf、RESP 和 QUIC 的连接都使用它
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// TCP 端口和 Unix domain socket 上的连接都使用 yamux 多路复用
+    pub fn with_yamux(mut self, config: YamuxConfig) -> Self {
+        self.yamux = Some(config);
+        self
+    }
+
+    /// 在 listener 上使用 Redis 的 RESP 协议
+    pub fn with_resp(mut self, listener: TcpListener) -> Self {
+        self.listeners.push(Listener::Resp(listener));
+        self
+    }
+
+    /// 在 listener 上提供 REST 接口
+    pub fn with_http(mut self, listener: TcpListener) -> Self {
+        self.listeners.push(Listener::Http(listener));
+        self
+    }
+
+    /// 在 listener 上提供 gRPC 服务
+    pub fn with_grpc(mut self, listener: TcpListener) -> Self {
+        self.listeners.push(Listener::Grpc(listener));
+        self
+    }
+
+    /// 在 Unix domain socket 上提供和 TCP 端口相同的协议
+    #[cfg(unix)]
+    pub fn with_unix(mut self, listener: UnixListener) -> Self {
+        self.listeners.push(Listener::Unix(listener));
+        self
+    }
+
+    /// 在 QUIC endpoint 上提供服务，endpoint 由 bind_quic 创建
+    pub fn with_quic(mut self, endpoint: Endpoint) -> Self {
+        self.listeners.push(Listener::Quic(endpoint));
+        self
+    }
+
+    /// 设置关闭时等待正在处理的命令完成的时间
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// 服务器使用的 Service，可以用来在其它端口上提供 metrics 等服务
+    pub fn service(&self) -> Service<Store> {
+        self.service.clone()
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    /// 不断 accept 客户端连接，直到通过 ServerHandle 关闭
+    pub async fn run(self) -> ShutdownReport {
+        let ctx = Arc::new(Context {
+            service: self.service,
+            compression: self.compression,
+            max_frame_size: self.max_frame_size,
+            yamux: self.yamux,
+            shutdown: self.shutdown.clone(),
+        });
+        // 每个监听器在自己的 task 里 accept，建立的连接都交给这里统一管理
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut listeners = JoinSet::new();
+        for listener in self.listeners {
+            let tls = self.tls.as_ref().map(|tls| listener.tls(tls));
+            listeners.spawn(listen(listener, tls, ctx.clone(), tx.clone()));
+        }
+        drop(tx);
+
+        let mut connections = JoinSet::new();
+        loop {
+            // 先检查是否在关闭，否则已经收到通知而断开的连接可能在这里被清理，不计入 ShutdownReport
+            tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => break,
+                // 及时清理已经断开的连接
+                Some(_) = connections.join_next() => {}
+                Some(conn) = rx.recv() => {
+                    connections.spawn(conn);
+                }
+            }
+        }
+        // 关闭所有的监听器，之后不再 accept 新的连接
+        listeners.shutdown().await;
+
+        let start = Instant::now();
+        let total = connections.len();
+        info!(
+            "Shutting down, waiting up to {:?} for {} connections",
+            self.shutdown_timeout, total
+        );
+        let deadline = tokio::time::sleep(self.shutdown_timeout);
+        tokio::pin!(deadline);
+        let mut closed = 0;
+        loop {
+            tokio::select! {
+                next = connections.join_next() => match next {
+                    Some(_) => closed += 1,
+                    None => break,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+        let aborted = connections.len();
+        connections.shutdown().await;
+        let elapsed = start.elapsed();
+
+        let flush = ctx.service.flush();
+        self.background.cancel();
+        let report = ShutdownReport {
+            connections: total,
+            closed,
+            aborted,
+            elapsed,
+            flush,
+        };
+        info!("Server shut down: {:?}", report);
+        report
+    }
+}
+
+/// 不断 accept 连接并交给 Server，直到开始关闭
+async fn listen<Store>(
+    listener: Listener,
+    tls: Option<TlsServerAcceptor>,
+    ctx: Arc<Context<Store>>,
+    tx: mpsc::UnboundedSender<Connection>,
+) where
+    Store: Storage + Send + Sync + 'static,
+{
+    loop {
+        let accepted = tokio::select! {
+            _ = ctx.shutdown.cancelled() => break,
+            accepted = listener.accept(tls.as_ref(), &ctx) => accepted,
+        };
+        match accepted {
+            Ok(Some(conn)) => {
+                if tx.send(conn).is_err() {
+                    break;
+                }
+            }
+            // QUIC endpoint 已经关闭
+            Ok(None) => break,
+            Err(e) => warn!("Failed to accept: {}", e),
+        }
+    }
+}
+
+impl Listener {
+    /// 按照协议的 ALPN 调整 TLS 配置
+    fn tls(&self, tls: &TlsServerAcceptor) -> TlsServerAcceptor {
+        match self {
+            // Redis 客户端不协商 ALPN
+            Listener::Resp(_) => tls.with_alpn(&[]),
+            Listener::Http(_) => tls.with_alpn(&[ALPN_HTTP]),
+            Listener::Grpc(_) => tls.with_alpn(&[ALPN_GRPC]),
+            _ => tls.clone(),
+        }
+    }
+
+    /// accept 一个连接，返回处理这个连接的 future；TLS 握手也在这个 future 里完成
+    async fn accept<Store>(
+        &self,
+        tls: Option<&TlsServerAcceptor>,
+        ctx: &Arc<Context<Store>>,
+    ) -> Result<Option<Connection>, KvError>
+    where
+        Store: Storage + Send + Sync + 'static,
+    {
+        let (ctx, tls) = (ctx.clone(), tls.cloned());
+        let conn: Connection = match self {
+            Listener::Prost(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Box::pin(async move {
+                    match accept_stream(stream, addr, tls.as_ref()).await {
+                        Ok((stream, session)) => ctx.serve(stream, session).await,
+                        Err(e) => warn!("Failed to accept client {:?}: {}", addr, e),
+                    }
+                })
+            }
+            Listener::Resp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Box::pin(async move {
+                    let (stream, session) = match accept_stream(stream, addr, tls.as_ref()).await {
+                        Ok(conn) => conn,
+                        Err(e) => return warn!("Failed to accept RESP client {:?}: {}", addr, e),
+                    };
+                    let server = RespServerStream::new(stream, ctx.service.clone())
+                        .with_session(session)
+                        .with_max_frame_size(ctx.max_frame_size)
+                        .with_shutdown(ctx.shutdown.clone());
+                    if let Err(e) = server.process().await {
+                        info!("RESP client {:?} disconnected: {}", addr, e);
+                    }
+                })
+            }
+            Listener::Http(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Box::pin(async move {
+                    match accept_stream(stream, addr, tls.as_ref()).await {
+                        Ok((stream, session)) => {
+                            let (service, shutdown) = (ctx.service.clone(), ctx.shutdown.clone());
+                            http::serve_connection(stream, service, session, shutdown).await
+                        }
+                        Err(e) => warn!("Failed to accept HTTP client {:?}: {}", addr, e),
+                    }
+                })
+            }
+            Listener::Grpc(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Box::pin(async move {
+                    match accept_stream(stream, addr, tls.as_ref()).await {
+                        Ok((stream, session)) => {
+                            let (service, shutdown) = (ctx.service.clone(), ctx.shutdown.clone());
+                            grpc::serve_connection(stream, service, session, shutdown).await
+                        }
+                        Err(e) => warn!("Failed to accept gRPC client {:?}: {}", addr, e),
+                    }
+                })
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Box::pin(async move { ctx.serve(stream, Session::new(None)).await })
+            }
+            Listener::Quic(endpoint) => match endpoint.accept().await {
+                Some(connecting) => Box::pin(async move {
+                    match connecting.await {
+                        Ok(conn) => {
+                            let (service, shutdown) = (ctx.service.clone(), ctx.shutdown.clone());
+                            quic::serve_connection(conn, service, ctx.max_frame_size, shutdown)
+                                .await
+                        }
+                        Err(e) => warn!("Failed to accept QUIC connection: {}", e),
+                    }
+                }),
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(conn))
+    }
+}
+
+impl<Store> Context<Store>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    /// 在 TCP 或者 Unix domain socket 的连接上处理 protobuf 协议的命令
+    async fn serve<S>(&self, stream: S, session: Session)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let peer = session.peer;
+        let result = match &self.yamux {
+            Some(config) => {
+                YamuxServerStream::new(stream, self.service.clone(), config)
+                    .with_session(session)
+                    .with_compression(self.compression.clone())
+                    .with_max_frame_size(self.max_frame_size)
+                    .with_shutdown(self.shutdown.clone())
+                    .process()
+                    .await
+            }
+            None => {
+                ProstServerStream::new(stream, self.service.clone())
+                    .with_session(session)
+                    .with_compression(self.compression.clone())
+                    .with_max_frame_size(self.max_frame_size)
+                    .with_shutdown(self.shutdown.clone())
+                    .process()
+                    .await
+            }
+        };
+        if let Err(e) = result {
+            warn!("Failed to process client {:?}: {}", peer, e);
+        }
+    }
+}
+
+impl ServerHandle {
+    /// 开始关闭服务器，重复调用没有影响
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// 收到 SIGTERM 或者 SIGINT 时关闭服务器
+    pub fn shutdown_on_signal(&self) {
+        let handle = self.clone();
+        tokio::spawn(async move {
+            match shutdown_signal().await {
+                Ok(()) => handle.shutdown(),
+                Err(e) => warn!("Failed to listen for signals: {}", e),
+            }
+        });
+    }
+}
+
+/// 等待 SIGTERM 或者 SIGINT（Ctrl-C）
+#[cfg(unix)]
+pub async fn shutdown_signal() -> Result<(), KvError> {
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = term.recv() => info!("Received SIGTERM"),
+        _ = int.recv() => info!("Received SIGINT"),
+    }
+    Ok(())
+}
+
+/// 其它平台上没有 SIGTERM，等待 Ctrl-C
+#[cfg(not(unix))]
+pub async fn shutdown_signal() -> Result<(), KvError> {
+    tokio::signal::ctrl_c().await?;
+    info!("Received Ctrl-C");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use anyhow::Result;
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::{
+        assert_res_ok, bind_quic,
+        network::tls::test_utils::{generate_certs, DOMAIN},
+        value, CdcConfig, ChangeLog, CommandRequest, CommandResponse, FrameStream, KvClient,
+        ProstClientStream, QuicClient, RaftServerConfig, SledDb, TlsClientConnector, Value,
+        YamuxClient,
+    };
+
+    #[tokio::test]
+    async fn shutdown_should_notify_clients_and_flush() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cdc = CdcConfig {
+            fsync: false,
+            ..CdcConfig::new(dir.path().join("cdc").to_string_lossy())
+        };
+        let service: Service<SledDb> = ServiceInner::new(SledDb::new(dir.path().join("db")))
+            .changelog(ChangeLog::open(cdc)?)
+            .into();
+        let (addr, handle, report) = start_server(service, Duration::from_secs(5)).await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        let res = client
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let mut idle = FrameStream::new(TcpStream::connect(addr).await?);
+        // 确保连接已经被 accept
+        idle.send(&CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(idle.recv::<CommandResponse>().await?.unwrap().status, 200);
+
+        handle.shutdown();
+        let res: CommandResponse = idle.recv().await?.unwrap();
+        assert_eq!(res.status, 503);
+        assert_eq!(res.message, "Server is shutting down");
+        assert!(idle.recv::<CommandResponse>().await?.is_none());
+
+        let report = report.await?;
+        assert_eq!((report.connections, report.closed), (2, 2));
+        assert_eq!(report.aborted, 0);
+        assert!(report.flush.is_ok());
+        // 不再 accept 新的连接
+        assert!(TcpStream::connect(addr).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_should_abort_connections_after_deadline() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let value = Value {
+            value: Some(value::Value::Binary(Bytes::from(vec![0u8; 4 << 20]))),
+        };
+        service.execute(CommandRequest::new_hset("t1", "big", value));
+        let timeout = Duration::from_millis(200);
+        let (addr, handle, report) = start_server(service, timeout).await?;
+
+        // 客户端一直不读响应，服务器发送响应时被阻塞，没法在 deadline 之前完成
+        let mut slow = FrameStream::new(TcpStream::connect(addr).await?);
+        for _ in 0..16 {
+            slow.send(&CommandRequest::new_hget("t1", "big")).await?;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        handle.shutdown();
+        let report = report.await?;
+        assert_eq!((report.connections, report.aborted), (1, 1));
+        assert_eq!(report.closed, 0);
+        assert!(report.elapsed >= timeout);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_should_drain_resp_http_and_grpc() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let bind = || TcpListener::bind("127.0.0.1:0");
+        let (listener, resp, http, grpc) =
+            (bind().await?, bind().await?, bind().await?, bind().await?);
+        let (resp_addr, http_addr) = (resp.local_addr()?, http.local_addr()?);
+        let grpc_addr = grpc.local_addr()?;
+        let server = Server::new(listener, service)
+            .with_resp(resp)
+            .with_http(http)
+            .with_grpc(grpc);
+        let handle = server.handle();
+        let report = tokio::spawn(server.run());
+
+        let mut resp = TcpStream::connect(resp_addr).await?;
+        resp.write_all(b"PING\r\n").await?;
+        let mut buf = [0; 7];
+        resp.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"+PONG\r\n");
+
+        // keep-alive 的 HTTP 连接
+        let mut http = TcpStream::connect(http_addr).await?;
+        http.write_all(b"GET /tables/t1/keys/k1 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut buf = vec![0; 1024];
+        let n = http.read(&mut buf).await?;
+        assert!(buf[..n].starts_with(b"HTTP/1.1 404"));
+
+        let mut client = KvClient::connect(format!("http://{}", grpc_addr)).await?;
+        let cmd = CommandRequest::new_subscribe(["news"], Vec::<&str>::new());
+        let mut stream = client.stream(cmd).await?.into_inner();
+        assert_eq!(stream.next().await.unwrap()?.status, 200);
+
+        handle.shutdown();
+        let mut buf = String::new();
+        resp.read_to_string(&mut buf).await?;
+        assert_eq!(buf, "-ERR Server is shutting down\r\n");
+        assert_eq!(http.read(&mut [0; 16]).await?, 0);
+        assert_eq!(stream.next().await.unwrap()?.status, 503);
+        assert!(stream.next().await.is_none());
+
+        let report = report.await?;
+        assert_eq!((report.connections, report.closed), (3, 3));
+        assert!(TcpStream::connect(resp_addr).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_should_drain_tls_yamux_and_quic() -> Result<()> {
+        let certs = generate_certs("client1");
+        let acceptor = TlsServerAcceptor::new(&certs.server_cert, &certs.server_key, None)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let endpoint = bind_quic("127.0.0.1:0".parse()?, &acceptor)?;
+        let quic_addr = endpoint.local_addr()?;
+        let config = YamuxConfig::default();
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let server = Server::new(listener, service)
+            .with_tls(acceptor)
+            .with_yamux(config.clone())
+            .with_quic(endpoint);
+        let handle = server.handle();
+        let report = tokio::spawn(server.run());
+
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&certs.ca))?;
+        let stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        let mut yamux = YamuxClient::new(stream, &config);
+        let mut client = yamux.open_stream().await?;
+        let cmd = CommandRequest::new_subscribe(["news"], Vec::<&str>::new());
+        assert_eq!(client.execute(cmd).await?.status, 200);
+
+        let quic = QuicClient::connect(quic_addr, &connector).await?;
+        let cmd = CommandRequest::new_subscribe(["news"], Vec::<&str>::new());
+        let mut stream = quic.stream(cmd).await?;
+        assert_eq!(stream.next().await?.unwrap().status, 200);
+
+        handle.shutdown();
+        let err = client.next_message().await.unwrap_err();
+        assert!(err.to_string().contains("503"));
+        assert_eq!(stream.next().await?.unwrap().status, 503);
+
+        let report = report.await?;
+        assert_eq!((report.connections, report.closed), (2, 2));
+        assert_eq!(report.aborted, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_from_config_should_apply_every_section() -> Result<()> {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:0"
+
+            [rate_limit.per_connection]
+            rate = 0.001
+            burst = 1
+
+            [resp]
+            addr = "127.0.0.1:0"
+
+            [http]
+            addr = "127.0.0.1:0"
+            "#,
+        )?;
+        let server = Server::from_config(&config, ServiceInner::new(MemTable::new())).await?;
+        assert_eq!(server.listeners.len(), 3);
+        let addr = match &server.listeners[0] {
+            Listener::Prost(listener) => listener.local_addr()?,
+            _ => unreachable!(),
+        };
+        let handle = server.handle();
+        let report = tokio::spawn(server.run());
+
+        // 限流中间件生效：同一个连接上的第二个命令被拒绝
+        let mut client = ProstClientStream::new(TcpStream::connect(addr).await?);
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.status, 404);
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.status, 429);
+
+        handle.shutdown();
+        report.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_from_config_should_reject_conflicting_sections() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:0"
+
+            [quic]
+            addr = "127.0.0.1:0"
+            "#,
+        )
+        .unwrap();
+        let res = Server::from_config(&config, ServiceInner::new(MemTable::new())).await;
+        assert!(matches!(res, Err(KvError::ConfigError(_))));
+
+        let mut config: ServerConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:0"
+
+            [replication]
+            leader = "127.0.0.1:9527"
+            "#,
+        )
+        .unwrap();
+        config.raft = Some(RaftServerConfig::new(1, "/tmp/kv-raft", Vec::new()));
+        let res = Server::from_config(&config, ServiceInner::new(MemTable::new())).await;
+        assert!(matches!(res, Err(KvError::ConfigError(_))));
+    }
+
+    async fn start_server<Store: Storage + Send + Sync + 'static>(
+        service: Service<Store>,
+        timeout: Duration,
+    ) -> Result<(
+        SocketAddr,
+        ServerHandle,
+        tokio::task::JoinHandle<ShutdownReport>,
+    )> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = Server::new(listener, service).with_shutdown_timeout(timeout);
+        let handle = server.handle();
+        Ok((addr, handle, tokio::spawn(server.run())))
+    }
+}
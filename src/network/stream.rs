@@ -0,0 +1,86 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::KvError;
+
+/// 客户端地址使用这个前缀时连接 Unix domain socket
+const UNIX_SCHEME: &str = "unix://";
+
+/// 客户端到服务器的连接，可以是 TCP，也可以是 Unix domain socket
+///
+/// 两种连接上的 CommandRequest/CommandResponse 的 frame 完全相同，可以直接交给 ProstClientStream。
+/// 只有 Unix 平台支持 Unix domain socket。
+pub enum KvStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl KvStream {
+    /// 地址是 unix:///path/to/kv.sock 时连接 Unix domain socket，否则当作 TCP 地址
+    pub async fn connect(addr: &str) -> Result<Self, KvError> {
+        match addr.strip_prefix(UNIX_SCHEME) {
+            #[cfg(unix)]
+            Some(path) => Ok(Self::Unix(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Some(_) => Err(KvError::InvalidCommand(
+                "Unix domain socket is not supported on this platform".into(),
+            )),
+            None => Ok(Self::Tcp(TcpStream::connect(addr).await?)),
+        }
+    }
+}
+
+impl AsyncRead for KvStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            KvStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            KvStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for KvStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            KvStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            KvStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            KvStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            KvStream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            KvStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            KvStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
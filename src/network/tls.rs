@@ -0,0 +1,496 @@
+use std::{
+    fs,
+    io::{self, Cursor},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{
+    client,
+    rustls::{
+        server::AllowAnyAuthenticatedClient, Certificate, ClientConfig, OwnedTrustAnchor,
+        PrivateKey, RootCertStore, ServerConfig, ServerName,
+    },
+    server, TlsAcceptor, TlsConnector,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::{ClientTlsConfig, KvError, ServerTlsConfig, Session};
+
+/// KV Server 自己的 ALPN (Application-Layer Protocol Negotiation)
+const ALPN_KV: &str = "kv";
+
+/// 存放 TLS ServerConfig 并提供方法 accept 把底层的协议转换成 TLS
+#[derive(Clone)]
+pub struct TlsServerAcceptor {
+    inner: Arc<ServerConfig>,
+}
+
+/// 存放 TLS Client 并提供方法 connect 把底层的协议转换成 TLS
+#[derive(Clone)]
+pub struct TlsClientConnector {
+    config: Arc<ClientConfig>,
+    domain: Arc<String>,
+}
+
+impl TlsServerAcceptor {
+    /// 加载 server cert / key，如果提供了 client_ca 则要求客户端提供证书（双向认证）
+    pub fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Self, KvError> {
+        let certs = load_certs(cert)?;
+        let key = load_key(key)?;
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match client_ca {
+            Some(ca) => {
+                let roots = load_root_store(ca)?;
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![ALPN_KV.into()];
+
+        Ok(Self {
+            inner: Arc::new(config),
+        })
+    }
+
+    /// 从配置中的 PEM 文件路径加载
+    pub fn from_config(config: &ServerTlsConfig) -> Result<Self, KvError> {
+        let cert = fs::read_to_string(&config.cert)?;
+        let key = fs::read_to_string(&config.key)?;
+        let ca = config.ca.as_ref().map(fs::read_to_string).transpose()?;
+        Self::new(&cert, &key, ca.as_deref())
+    }
+
+    /// 触发 TLS 协议，把底层的 stream 转换成 TLS stream
+    pub async fn accept<S>(&self, stream: S) -> Result<server::TlsStream<S>, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let acceptor = TlsAcceptor::from(self.inner.clone());
+        Ok(acceptor.accept(stream).await?)
+    }
+
+    /// 使用另外一组 ALPN 协议，比如 HTTP 的 http/1.1、gRPC 的 h2，证书和客户端认证不变
+    pub fn with_alpn(&self, protocols: &[&str]) -> Self {
+        let mut config = (*self.inner).clone();
+        config.alpn_protocols = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+        Self {
+            inner: Arc::new(config),
+        }
+    }
+
+    /// QUIC 使用同一份 rustls 配置
+    pub(crate) fn config(&self) -> Arc<ServerConfig> {
+        self.inner.clone()
+    }
+}
+
+/// 服务器 accept 下来的连接，配置了 TLS 时是握手之后的 TLS stream
+pub(crate) enum MaybeTlsStream<S> {
+    Plain(S),
+    Tls(Box<server::TlsStream<S>>),
+}
+
+/// 配置了 TLS 时先完成握手，返回连接和它的 Session；双向认证时 Session 中是客户端证书的身份
+pub(crate) async fn accept_stream<S>(
+    stream: S,
+    peer: SocketAddr,
+    tls: Option<&TlsServerAcceptor>,
+) -> Result<(MaybeTlsStream<S>, Session), KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let session = Session::new(Some(peer));
+    match tls {
+        Some(acceptor) => {
+            let stream = acceptor.accept(stream).await?;
+            let session = session.with_user(peer_identity(&stream));
+            Ok((MaybeTlsStream::Tls(Box::new(stream)), session))
+        }
+        None => Ok((MaybeTlsStream::Plain(stream), session)),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for MaybeTlsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MaybeTlsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+impl TlsClientConnector {
+    /// 加载 client cert / CA cert，生成 ClientConfig
+    pub fn new(
+        domain: impl Into<String>,
+        identity: Option<(&str, &str)>,
+        server_ca: Option<&str>,
+    ) -> Result<Self, KvError> {
+        let roots = match server_ca {
+            Some(ca) => load_root_store(ca)?,
+            None => {
+                let mut roots = RootCertStore::empty();
+                roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        ta.subject,
+                        ta.spki,
+                        ta.name_constraints,
+                    )
+                }));
+                roots
+            }
+        };
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+
+        let mut config = match identity {
+            Some((cert, key)) => {
+                builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![ALPN_KV.into()];
+
+        Ok(Self {
+            config: Arc::new(config),
+            domain: Arc::new(domain.into()),
+        })
+    }
+
+    /// 从配置中的 PEM 文件路径加载
+    pub fn from_config(config: &ClientTlsConfig) -> Result<Self, KvError> {
+        let identity = match &config.identity {
+            Some(id) => Some((fs::read_to_string(&id.cert)?, fs::read_to_string(&id.key)?)),
+            None => None,
+        };
+        let ca = config.ca.as_ref().map(fs::read_to_string).transpose()?;
+        Self::new(
+            config.domain.clone(),
+            identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str())),
+            ca.as_deref(),
+        )
+    }
+
+    /// 使用另外一组 ALPN 协议，比如连接 HTTPS 时使用 http/1.1
+    pub fn with_alpn(&self, protocols: &[&str]) -> Self {
+        let mut config = (*self.config).clone();
+        config.alpn_protocols = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+        Self {
+            config: Arc::new(config),
+            domain: self.domain.clone(),
+        }
+    }
+
+    /// 触发 TLS 协议，把底层的 stream 转换成 TLS stream
+    pub async fn connect<S>(&self, stream: S) -> Result<client::TlsStream<S>, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let connector = TlsConnector::from(self.config.clone());
+        Ok(connector.connect(self.server_name()?, stream).await?)
+    }
+
+    /// QUIC 使用同一份 rustls 配置
+    pub(crate) fn config(&self) -> Arc<ClientConfig> {
+        self.config.clone()
+    }
+
+    pub(crate) fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    fn server_name(&self) -> Result<ServerName, KvError> {
+        ServerName::try_from(self.domain.as_str())
+            .map_err(|_| KvError::ConfigError(format!("Invalid domain: {}", self.domain)))
+    }
+}
+
+/// 双向认证时，从客户端证书的 Common Name 中取出客户端的身份
+pub fn peer_identity<S>(stream: &server::TlsStream<S>) -> Option<String> {
+    let (_, conn) = stream.get_ref();
+    let cert = conn.peer_certificates()?.first()?;
+    certificate_common_name(cert)
+}
+
+pub(crate) fn certificate_common_name(cert: &Certificate) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(&cert.0).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(|s| s.to_owned())
+}
+
+fn load_certs(cert: &str) -> Result<Vec<Certificate>, KvError> {
+    let certs = rustls_pemfile::certs(&mut Cursor::new(cert))
+        .map_err(|_| KvError::CertificateParseError("cert".into()))?;
+    if certs.is_empty() {
+        return Err(KvError::CertificateParseError("cert".into()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(key: &str) -> Result<PrivateKey, KvError> {
+    let items = rustls_pemfile::read_all(&mut Cursor::new(key))
+        .map_err(|_| KvError::CertificateParseError("private key".into()))?;
+
+    // 依次尝试 PKCS8 / RSA / EC 格式的私钥
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| KvError::CertificateParseError("private key".into()))
+}
+
+fn load_root_store(ca: &str) -> Result<RootCertStore, KvError> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca)? {
+        roots
+            .add(&cert)
+            .map_err(|e| KvError::CertificateParseError(format!("CA: {}", e)))?;
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    };
+
+    /// 测试用的一套证书：自签名的 CA，以及由它签发的 server / client 证书
+    pub struct TestCerts {
+        pub ca: String,
+        pub server_cert: String,
+        pub server_key: String,
+        pub client_cert: String,
+        pub client_key: String,
+    }
+
+    pub const DOMAIN: &str = "kvserver.acme.inc";
+
+    pub fn generate_ca(name: &str) -> Certificate {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        Certificate::from_params(params).unwrap()
+    }
+
+    pub fn generate_certs(client_name: &str) -> TestCerts {
+        let ca = generate_ca("Acme KV CA");
+
+        let mut params = CertificateParams::new(vec![DOMAIN.into()]);
+        params.distinguished_name.push(DnType::CommonName, DOMAIN);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let server = Certificate::from_params(params).unwrap();
+
+        let client = generate_client(client_name);
+
+        TestCerts {
+            ca: ca.serialize_pem().unwrap(),
+            server_cert: server.serialize_pem_with_signer(&ca).unwrap(),
+            server_key: server.serialize_private_key_pem(),
+            client_cert: client.serialize_pem_with_signer(&ca).unwrap(),
+            client_key: client.serialize_private_key_pem(),
+        }
+    }
+
+    pub fn generate_client(name: &str) -> Certificate {
+        let mut params = CertificateParams::new(vec![]);
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        Certificate::from_params(params).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use anyhow::Result;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::test_utils::*;
+    use super::*;
+
+    #[tokio::test]
+    async fn tls_should_work() -> Result<()> {
+        let certs = generate_certs("client1");
+        let addr = start_server(&certs, false).await?;
+
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&certs.ca))?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(stream).await?;
+        stream.write_all(b"hello world!").await?;
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_with_client_cert_should_work() -> Result<()> {
+        let certs = generate_certs("client1");
+        let addr = start_server(&certs, true).await?;
+
+        let identity = Some((certs.client_cert.as_str(), certs.client_key.as_str()));
+        let connector = TlsClientConnector::new(DOMAIN, identity, Some(&certs.ca))?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(stream).await?;
+        stream.write_all(b"hello world!").await?;
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_with_bad_domain_should_not_work() -> Result<()> {
+        let certs = generate_certs("client1");
+        let addr = start_server(&certs, false).await?;
+
+        let connector = TlsClientConnector::new("kvserver1.acme.inc", None, Some(&certs.ca))?;
+        let stream = TcpStream::connect(addr).await?;
+        let result = connector.connect(stream).await;
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_without_client_cert_should_be_rejected_by_mtls_server() -> Result<()> {
+        let certs = generate_certs("client1");
+        let addr = start_server(&certs, true).await?;
+
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&certs.ca))?;
+        let stream = TcpStream::connect(addr).await?;
+        // TLS 1.3 下客户端握手会先完成，服务器拒绝后读数据会出错
+        let result = match connector.connect(stream).await {
+            Ok(mut stream) => {
+                stream.write_all(b"hello world!").await?;
+                let mut buf = [0; 12];
+                stream.read_exact(&mut buf).await.map(|_| ())
+            }
+            Err(_) => return Ok(()),
+        };
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_with_untrusted_client_cert_should_be_rejected() -> Result<()> {
+        let certs = generate_certs("client1");
+        let addr = start_server(&certs, true).await?;
+
+        // 由另一个 CA 签发的客户端证书
+        let other_ca = generate_ca("Evil CA");
+        let client = generate_client("mallory");
+        let cert = client.serialize_pem_with_signer(&other_ca)?;
+        let key = client.serialize_private_key_pem();
+
+        let connector = TlsClientConnector::new(DOMAIN, Some((&cert, &key)), Some(&certs.ca))?;
+        let stream = TcpStream::connect(addr).await?;
+        let result = match connector.connect(stream).await {
+            Ok(mut stream) => {
+                stream.write_all(b"hello world!").await?;
+                let mut buf = [0; 12];
+                stream.read_exact(&mut buf).await.map(|_| ())
+            }
+            Err(_) => return Ok(()),
+        };
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn peer_identity_should_come_from_client_cert() -> Result<()> {
+        let certs = generate_certs("tyr");
+        let acceptor =
+            TlsServerAcceptor::new(&certs.server_cert, &certs.server_key, Some(&certs.ca))?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            peer_identity(&stream)
+        });
+
+        let identity = Some((certs.client_cert.as_str(), certs.client_key.as_str()));
+        let connector = TlsClientConnector::new(DOMAIN, identity, Some(&certs.ca))?;
+        let stream = TcpStream::connect(addr).await?;
+        let _stream = connector.connect(stream).await?;
+
+        assert_eq!(handle.await?, Some("tyr".to_string()));
+        Ok(())
+    }
+
+    async fn start_server(certs: &TestCerts, mtls: bool) -> Result<SocketAddr> {
+        let ca = mtls.then_some(certs.ca.as_str());
+        let acceptor = TlsServerAcceptor::new(&certs.server_cert, &certs.server_key, ca)?;
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = echo.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = echo.accept().await.unwrap();
+            let mut stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            let mut buf = [0; 12];
+            if stream.read_exact(&mut buf).await.is_ok() {
+                stream.write_all(&buf).await.unwrap();
+            }
+        });
+
+        Ok(addr)
+    }
+}
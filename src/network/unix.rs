@@ -0,0 +1,128 @@
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::Path,
+};
+
+use tokio::net::UnixListener;
+
+use super::ProstServerStream;
+use crate::{KvError, Service, Storage, UnixSocketConfig};
+
+/// 按照配置监听 Unix domain socket，并设置 socket 文件的权限
+///
+/// 上次没有正常退出时留下的 socket 文件会被替换；如果还有服务器在监听这个文件，或者它不是 socket，返回错误。
+/// socket 先在一个只有自己能访问（0700）的临时目录里创建，设置好权限之后再改名到配置的路径，
+/// 这样其它用户不会在设置权限之前连上来。
+pub fn bind_unix(config: &UnixSocketConfig) -> Result<UnixListener, KvError> {
+    let path = Path::new(&config.path);
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(KvError::ConfigError(format!(
+                "{} exists and is not a socket",
+                config.path
+            )));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(KvError::IoError(format!(
+                "{} is in use by another server",
+                config.path
+            )));
+        }
+    }
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| KvError::ConfigError(format!("{} is not a file path", config.path)))?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp = dir.join(name);
+    let result = UnixListener::bind(&tmp)
+        .map_err(KvError::from)
+        .and_then(|listener| {
+            fs::set_permissions(&tmp, Permissions::from_mode(config.mode))?;
+            fs::rename(&tmp, path)?;
+            Ok(listener)
+        });
+    // 出错时 socket 文件还在临时目录里，一起删除
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+/// 在 Unix domain socket 上提供和 TCP 端口相同的 protobuf 协议
+pub async fn serve_unix<Store>(
+    listener: UnixListener,
+    service: Service<Store>,
+) -> Result<(), KvError>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let service = service.clone();
+        tokio::spawn(async move { ProstServerStream::new(stream, service).process().await });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{
+        assert_res_ok, CommandRequest, KvStream, MemTable, ProstClientStream, ServiceInner, Value,
+    };
+
+    #[tokio::test]
+    async fn unix_socket_should_work() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("kv.sock");
+        let config = UnixSocketConfig {
+            path: path.to_string_lossy().into(),
+            mode: 0o660,
+        };
+        // 残留的 socket 文件会被替换
+        drop(std::os::unix::net::UnixListener::bind(&path)?);
+
+        let listener = bind_unix(&config)?;
+        let mode = fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        // 创建 socket 用的临时目录已经删除
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+        // 已经有服务器在监听时不能再 bind
+        assert!(bind_unix(&config).is_err());
+
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(serve_unix(listener, service));
+
+        let stream = KvStream::connect(&format!("unix://{}", config.path)).await?;
+        assert!(matches!(stream, KvStream::Unix(_)));
+        let mut client = ProstClientStream::new(stream);
+
+        let res = client
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(res, &["v1".into()], &[]);
+        Ok(())
+    }
+
+    #[test]
+    fn bind_unix_should_not_remove_regular_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("kv.sock");
+        fs::write(&path, "data")?;
+        let config = UnixSocketConfig {
+            path: path.to_string_lossy().into(),
+            mode: 0o600,
+        };
+        assert!(matches!(bind_unix(&config), Err(KvError::ConfigError(_))));
+        assert_eq!(fs::read_to_string(&path)?, "data");
+        Ok(())
+    }
+}
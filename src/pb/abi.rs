@@ -1,15 +1,19 @@
 /// 来自客户端的命令请求
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
     #[prost(
         oneof = "command_request::RequestData",
-        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9"
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21"
     )]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
 pub mod command_request {
-    #[derive(PartialOrd, Clone, PartialEq, ::prost::Oneof)]
+    #[derive(PartialOrd)]
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum RequestData {
         #[prost(message, tag = "1")]
         Hget(super::Hget),
@@ -29,9 +33,35 @@ pub mod command_request {
         Hexist(super::Hexist),
         #[prost(message, tag = "9")]
         Hmexist(super::Hmexist),
+        #[prost(message, tag = "10")]
+        Auth(super::Auth),
+        #[prost(message, tag = "11")]
+        Info(super::Info),
+        #[prost(message, tag = "12")]
+        SlowlogGet(super::SlowlogGet),
+        #[prost(message, tag = "13")]
+        SlowlogReset(super::SlowlogReset),
+        #[prost(message, tag = "14")]
+        Subscribe(super::Subscribe),
+        #[prost(message, tag = "15")]
+        Unsubscribe(super::Unsubscribe),
+        #[prost(message, tag = "16")]
+        Publish(super::Publish),
+        #[prost(message, tag = "17")]
+        Watch(super::Watch),
+        #[prost(message, tag = "18")]
+        Unwatch(super::Unwatch),
+        #[prost(message, tag = "19")]
+        ReadChanges(super::ReadChanges),
+        #[prost(message, tag = "20")]
+        Snapshot(super::Snapshot),
+        #[prost(message, tag = "21")]
+        Hcas(super::Hcas),
     }
 }
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandResponse {
     /// 状态码： 复用 HTTP 2xx/4xx/5xx 状态码
     #[prost(uint32, tag = "1")]
@@ -45,16 +75,29 @@ pub struct CommandResponse {
     /// 成功返回的 kv pairs
     #[prost(message, repeated, tag = "4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// 服务器主动推送给订阅者的消息，普通的响应里没有
+    #[prost(message, optional, tag = "5")]
+    pub pubsub: ::core::option::Option<PubsubMessage>,
+    /// 服务器主动推送给 watcher 的 key 变化事件，普通的响应里没有
+    #[prost(message, optional, tag = "6")]
+    pub event: ::core::option::Option<WatchEvent>,
+    /// ReadChanges 返回的变更记录
+    #[prost(message, repeated, tag = "7")]
+    pub changes: ::prost::alloc::vec::Vec<Change>,
 }
 /// 返回的值
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
     #[prost(oneof = "value::Value", tags = "1, 2, 3, 4, 5")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
 pub mod value {
-    #[derive(PartialOrd, Clone, PartialEq, ::prost::Oneof)]
+    #[derive(PartialOrd)]
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum Value {
         #[prost(string, tag = "1")]
         String(::prost::alloc::string::String),
@@ -69,7 +112,9 @@ pub mod value {
     }
 }
 /// 返回的 kvpair
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Kvpair {
     #[prost(string, tag = "1")]
     pub key: ::prost::alloc::string::String,
@@ -77,7 +122,9 @@ pub struct Kvpair {
     pub value: ::core::option::Option<Value>,
 }
 /// 从 table 中获取一个 key，返回 value
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hget {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -85,13 +132,22 @@ pub struct Hget {
     pub key: ::prost::alloc::string::String,
 }
 /// 从 table 中获取所有的 kvpair
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+/// limit 不为 0 时分页读取：按 key 排序，返回 key 大于 after 的最多 limit 个 kvpair
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hgetall {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub after: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "3")]
+    pub limit: u32,
 }
 /// 从 table 中获取一组 key，返回他们的 value
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmget {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -100,7 +156,9 @@ pub struct Hmget {
 }
 /// 往 table 里存一个 kvpair
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hset {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -109,7 +167,9 @@ pub struct Hset {
 }
 /// 往 table 里存一组 kvpair
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmset {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -117,7 +177,9 @@ pub struct Hmset {
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
 }
 /// 从 table 里删除一个 key，返回它之前的值
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hdel {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -125,15 +187,34 @@ pub struct Hdel {
     pub key: ::prost::alloc::string::String,
 }
 /// 从 table 里删除一组 key，返回他们之前的值
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmdel {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
     #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 当 key 当前的值等于 expected 时把它改成 value，返回之前的值
+/// expected 为空表示 key 不存在，value 为空表示删除 key；当前的值不等于 expected 时返回 409，不做修改
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hcas {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub expected: ::core::option::Option<Value>,
+    #[prost(message, optional, tag = "4")]
+    pub value: ::core::option::Option<Value>,
+}
 /// 查看 key 是否存在
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hexist {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -141,10 +222,405 @@ pub struct Hexist {
     pub key: ::prost::alloc::string::String,
 }
 /// 查看一组 key 是否存在
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmexist {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
     #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 认证当前连接，可以使用用户名/密码，也可以使用 token
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Auth {
+    #[prost(string, tag = "1")]
+    pub username: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub password: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub token: ::prost::alloc::string::String,
+}
+/// 查看服务器的状态，返回的 pairs 的 key 是 section.field 的形式，比如 server.version
+/// sections 为空时返回所有 section：server、clients、commands、storage、keyspace
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Info {
+    #[prost(string, repeated, tag = "1")]
+    pub sections: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 获取最近的 count 条慢命令（最新的在前），count 为 0 时返回所有
+/// 返回的 pairs 的 key 是 id.field 的形式，field 包括 timestamp、duration_us、client、command
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SlowlogGet {
+    #[prost(uint32, tag = "1")]
+    pub count: u32,
+}
+/// 清空慢命令日志，返回清除的条数
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SlowlogReset {}
+/// 订阅一组 channel，patterns 中可以使用 `*` 通配符；返回这个连接订阅的总数
+/// 订阅之后，服务器会在 channel 有消息时主动推送带有 pubsub 字段的 CommandResponse
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Subscribe {
+    #[prost(string, repeated, tag = "1")]
+    pub channels: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(string, repeated, tag = "2")]
+    pub patterns: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 取消订阅，channels 和 patterns 都为空时取消所有订阅；返回这个连接剩下的订阅数
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Unsubscribe {
+    #[prost(string, repeated, tag = "1")]
+    pub channels: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(string, repeated, tag = "2")]
+    pub patterns: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 向 channel 发布一条消息，返回收到消息的订阅者个数
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Publish {
+    #[prost(string, tag = "1")]
+    pub channel: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub data: ::core::option::Option<Value>,
+}
+/// 推送给订阅者的消息，通过 pattern 订阅时会带上匹配的 pattern
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PubsubMessage {
+    #[prost(string, tag = "1")]
+    pub channel: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub pattern: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub data: ::core::option::Option<Value>,
+}
+/// 监听 table 中 key 的变化，返回当前最新的 seq
+/// key 不为空时只监听这一个 key，否则监听以 prefix 开头的 key，prefix 也为空时监听整个 table
+/// from_seq 大于 0 时先补发 seq >= from_seq 的历史事件，用于断线重连之后继续监听；
+/// 服务器只保留最近的一部分事件，太旧的 seq 会返回 410
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Watch {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub prefix: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "4")]
+    pub from_seq: u64,
+}
+/// 取消这个连接的所有 watch
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Unwatch {}
+/// key 的变化事件，seq 从 1 开始，在整个服务器内递增
+/// 新设置的 key 没有 old_value，删除的 key 没有 new_value
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WatchEvent {
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+    #[prost(enumeration = "EventKind", tag = "2")]
+    pub kind: i32,
+    #[prost(string, tag = "3")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "4")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "5")]
+    pub old_value: ::core::option::Option<Value>,
+    #[prost(message, optional, tag = "6")]
+    pub new_value: ::core::option::Option<Value>,
+}
+/// 从变更日志（CDC）中读取 offset >= from_offset 的最多 limit 条记录，按 offset 排序
+/// limit 为 0 时使用缺省值 100，最多 1000；from_offset 已经被清理时返回 410
+/// 返回的 values\[0\] 是变更日志中最新的 offset
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReadChanges {
+    #[prost(uint64, tag = "1")]
+    pub from_offset: u64,
+    #[prost(uint32, tag = "2")]
+    pub limit: u32,
+}
+/// 变更日志中的一条记录，offset 从 1 开始连续递增
+/// 变更日志的 segment 文件和快照文件都是 length-delimited（varint 长度前缀）编码的 protobuf 消息：
+/// segment 文件中是连续的 Change；快照文件以 SnapshotHeader 开头，后面是 op 为 PUT 的 Change，
+/// 它们的 offset 都是快照对应的 offset。先加载快照，再按顺序应用 offset 更大的 Change 就可以重建所有数据
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Change {
+    #[prost(uint64, tag = "1")]
+    pub offset: u64,
+    /// unix 时间戳，毫秒
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+    #[prost(enumeration = "ChangeOp", tag = "3")]
+    pub op: i32,
+    #[prost(string, tag = "4")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "5")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "6")]
+    pub value: ::core::option::Option<Value>,
+}
+/// 分页获取所有数据的快照，用于 follower 初始化，需要开启变更日志
+/// 数据按 (table, key) 排序，每页从游标 (table, key) 之后开始，table 为空时从头开始；
+/// 下一页的游标是这一页最后一条记录的 (table, key)。limit 为 0 时使用缺省值 1000，最多 10000，
+/// 每页的数据不超过 4MB。返回的 changes 是 op 为 PUT 的数据，values\[0\] 是读取这一页之前的 offset，
+/// values\[1\] 表示是否还有下一页。各页不是同一时刻的数据，加载完所有页之后，
+/// 从第一页的 offset 开始应用变更日志即可和 leader 保持一致
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Snapshot {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "3")]
+    pub limit: u32,
+}
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SnapshotHeader {
+    /// 快照包含了 offset 以及之前的所有变更
+    #[prost(uint64, tag = "1")]
+    pub offset: u64,
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+}
+/// Raft 需要持久化的 term 和投票，改变之后先写入磁盘，再发出依赖它们的消息
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftHardState {
+    #[prost(uint64, tag = "1")]
+    pub term: u64,
+    /// 0 表示当前 term 还没有投票，节点的 id 从 1 开始
+    #[prost(uint64, tag = "2")]
+    pub voted_for: u64,
+}
+/// Raft 的日志条目，日志文件中是连续的 length-delimited RaftEntry
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftEntry {
+    #[prost(uint64, tag = "1")]
+    pub index: u64,
+    #[prost(uint64, tag = "2")]
+    pub term: u64,
+    #[prost(oneof = "raft_entry::Data", tags = "3, 4, 5, 6")]
+    pub data: ::core::option::Option<raft_entry::Data>,
+}
+/// Nested message and enum types in `RaftEntry`.
+pub mod raft_entry {
+    #[derive(PartialOrd)]
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Data {
+        /// 新的 leader 当选后写入的空条目
+        #[prost(bool, tag = "3")]
+        Noop(bool),
+        /// 修改数据的命令
+        #[prost(message, tag = "4")]
+        Command(super::CommandRequest),
+        /// 增加或者删除一个投票成员
+        #[prost(uint64, tag = "5")]
+        AddNode(u64),
+        #[prost(uint64, tag = "6")]
+        RemoveNode(u64),
+    }
+}
+/// Raft 快照文件以 RaftSnapshotMeta 开头，后面是按 (table, key) 排序、op 为 PUT 的 Change，
+/// 包含 index 以及之前的所有条目应用之后的数据
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftSnapshotMeta {
+    #[prost(uint64, tag = "1")]
+    pub index: u64,
+    #[prost(uint64, tag = "2")]
+    pub term: u64,
+    #[prost(uint64, repeated, tag = "3")]
+    pub voters: ::prost::alloc::vec::Vec<u64>,
+}
+/// Raft 节点之间的消息，term 是发送者当前的 term
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftMessage {
+    #[prost(uint64, tag = "1")]
+    pub from: u64,
+    #[prost(uint64, tag = "2")]
+    pub to: u64,
+    #[prost(uint64, tag = "3")]
+    pub term: u64,
+    #[prost(oneof = "raft_message::Body", tags = "4, 5, 6, 7, 8, 9")]
+    pub body: ::core::option::Option<raft_message::Body>,
+}
+/// Nested message and enum types in `RaftMessage`.
+pub mod raft_message {
+    #[derive(PartialOrd)]
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Body {
+        #[prost(message, tag = "4")]
+        RequestVote(super::RaftRequestVote),
+        #[prost(message, tag = "5")]
+        Vote(super::RaftVote),
+        #[prost(message, tag = "6")]
+        Append(super::RaftAppend),
+        #[prost(message, tag = "7")]
+        AppendResponse(super::RaftAppendResponse),
+        #[prost(message, tag = "8")]
+        InstallSnapshot(super::RaftInstallSnapshot),
+        #[prost(message, tag = "9")]
+        SnapshotAck(super::RaftSnapshotAck),
+    }
+}
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftRequestVote {
+    #[prost(uint64, tag = "1")]
+    pub last_index: u64,
+    #[prost(uint64, tag = "2")]
+    pub last_term: u64,
+}
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftVote {
+    #[prost(bool, tag = "1")]
+    pub granted: bool,
+}
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftAppend {
+    #[prost(uint64, tag = "1")]
+    pub prev_index: u64,
+    #[prost(uint64, tag = "2")]
+    pub prev_term: u64,
+    #[prost(message, repeated, tag = "3")]
+    pub entries: ::prost::alloc::vec::Vec<RaftEntry>,
+    #[prost(uint64, tag = "4")]
+    pub commit: u64,
+    #[prost(uint64, tag = "5")]
+    pub read: u64,
+}
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftAppendResponse {
+    #[prost(bool, tag = "1")]
+    pub success: bool,
+    #[prost(uint64, tag = "2")]
+    pub last_index: u64,
+    #[prost(uint64, tag = "3")]
+    pub read: u64,
+}
+/// 快照文件中从 offset 开始的一段，done 表示这是最后一段
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftInstallSnapshot {
+    #[prost(uint64, tag = "1")]
+    pub index: u64,
+    #[prost(uint64, tag = "2")]
+    pub offset: u64,
+    #[prost(bytes = "bytes", tag = "3")]
+    pub data: ::prost::bytes::Bytes,
+    #[prost(bool, tag = "4")]
+    pub done: bool,
+}
+/// follower 已经收到的快照字节数，leader 从 offset 继续发送
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftSnapshotAck {
+    #[prost(uint64, tag = "1")]
+    pub index: u64,
+    #[prost(uint64, tag = "2")]
+    pub offset: u64,
+}
+/// key 变化的类型，EXPIRE 留给 key 过期使用，目前还不支持 TTL，不会产生这种事件
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum EventKind {
+    Set = 0,
+    Del = 1,
+    Expire = 2,
+}
+impl EventKind {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            EventKind::Set => "SET",
+            EventKind::Del => "DEL",
+            EventKind::Expire => "EXPIRE",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "SET" => Some(Self::Set),
+            "DEL" => Some(Self::Del),
+            "EXPIRE" => Some(Self::Expire),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ChangeOp {
+    /// 设置 key，value 是新的值
+    Put = 0,
+    /// 删除 key，没有 value
+    Remove = 1,
+}
+impl ChangeOp {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            ChangeOp::Put => "PUT",
+            ChangeOp::Remove => "REMOVE",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "PUT" => Some(Self::Put),
+            "REMOVE" => Some(Self::Remove),
+            _ => None,
+        }
+    }
+}
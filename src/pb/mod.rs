@@ -1,3 +1,5 @@
+use std::fmt;
+
 use abi::*;
 use http::StatusCode;
 use prost::Message;
@@ -6,6 +8,11 @@ use crate::{command_request::RequestData, KvError};
 
 pub mod abi;
 
+/// kv.proto 中定义的 gRPC 服务
+pub mod kv {
+    tonic::include_proto!("kv");
+}
+
 impl CommandRequest {
     /// 创建 HSET 命令
     pub fn new_hset(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
@@ -16,6 +23,36 @@ impl CommandRequest {
             })),
         }
     }
+    /// 创建 HMSET 命令
+    pub fn new_hmset(table: impl Into<String>, pairs: Vec<Kvpair>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmset(Hmset {
+                table: table.into(),
+                pairs,
+            })),
+        }
+    }
+    /// 创建 HDEL 命令
+    pub fn new_hdel(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hdel(Hdel {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+    /// 创建 HMDEL 命令
+    pub fn new_hmdel<T: Into<String>>(
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = T>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmdel(Hmdel {
+                table: table.into(),
+                keys: keys.into_iter().map(|v| v.into()).collect(),
+            })),
+        }
+    }
     /// 创建 HGET 命令
     pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
         Self {
@@ -25,14 +62,266 @@ impl CommandRequest {
             })),
         }
     }
+    /// 创建 HMGET 命令
+    pub fn new_hmget<T: Into<String>>(
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = T>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmget(Hmget {
+                table: table.into(),
+                keys: keys.into_iter().map(|v| v.into()).collect(),
+            })),
+        }
+    }
+    /// 创建 HEXIST 命令
+    pub fn new_hexist(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexist(Hexist {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+    /// 创建 HMEXIST 命令
+    pub fn new_hmexist<T: Into<String>>(
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = T>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmexist(Hmexist {
+                table: table.into(),
+                keys: keys.into_iter().map(|v| v.into()).collect(),
+            })),
+        }
+    }
+    /// 创建用户名/密码认证的 AUTH 命令
+    pub fn new_auth(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Auth(Auth {
+                username: username.into(),
+                password: password.into(),
+                ..Default::default()
+            })),
+        }
+    }
+    /// 创建 token 认证的 AUTH 命令
+    pub fn new_auth_token(token: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Auth(Auth {
+                token: token.into(),
+                ..Default::default()
+            })),
+        }
+    }
+    /// 创建 INFO 命令，sections 为空时返回所有 section
+    pub fn new_info<T: Into<String>>(sections: impl IntoIterator<Item = T>) -> Self {
+        Self {
+            request_data: Some(RequestData::Info(Info {
+                sections: sections.into_iter().map(|v| v.into()).collect(),
+            })),
+        }
+    }
+    /// 创建 SLOWLOG GET 命令，count 为 0 时返回所有记录
+    pub fn new_slowlog_get(count: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::SlowlogGet(SlowlogGet { count })),
+        }
+    }
+    /// 创建 SLOWLOG RESET 命令
+    pub fn new_slowlog_reset() -> Self {
+        Self {
+            request_data: Some(RequestData::SlowlogReset(SlowlogReset {})),
+        }
+    }
+    /// 创建 SUBSCRIBE 命令
+    pub fn new_subscribe<T: Into<String>>(
+        channels: impl IntoIterator<Item = T>,
+        patterns: impl IntoIterator<Item = T>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                channels: channels.into_iter().map(|v| v.into()).collect(),
+                patterns: patterns.into_iter().map(|v| v.into()).collect(),
+            })),
+        }
+    }
+    /// 创建 UNSUBSCRIBE 命令，channels 和 patterns 都为空时取消所有订阅
+    pub fn new_unsubscribe<T: Into<String>>(
+        channels: impl IntoIterator<Item = T>,
+        patterns: impl IntoIterator<Item = T>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Unsubscribe(Unsubscribe {
+                channels: channels.into_iter().map(|v| v.into()).collect(),
+                patterns: patterns.into_iter().map(|v| v.into()).collect(),
+            })),
+        }
+    }
+    /// 创建 PUBLISH 命令
+    pub fn new_publish(channel: impl Into<String>, data: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Publish(Publish {
+                channel: channel.into(),
+                data: Some(data),
+            })),
+        }
+    }
+    /// 创建 WATCH 命令，监听 table 中的一个 key，key 为空时监听整个 table
+    pub fn new_watch(table: impl Into<String>, key: impl Into<String>, from_seq: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Watch(Watch {
+                table: table.into(),
+                key: key.into(),
+                prefix: String::new(),
+                from_seq,
+            })),
+        }
+    }
+    /// 创建 WATCH 命令，监听 table 中以 prefix 开头的 key
+    pub fn new_watch_prefix(
+        table: impl Into<String>,
+        prefix: impl Into<String>,
+        from_seq: u64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Watch(Watch {
+                table: table.into(),
+                key: String::new(),
+                prefix: prefix.into(),
+                from_seq,
+            })),
+        }
+    }
+    /// 创建 UNWATCH 命令
+    pub fn new_unwatch() -> Self {
+        Self {
+            request_data: Some(RequestData::Unwatch(Unwatch {})),
+        }
+    }
+    /// 创建 READ CHANGES 命令，limit 为 0 时使用缺省值
+    pub fn new_read_changes(from_offset: u64, limit: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::ReadChanges(ReadChanges { from_offset, limit })),
+        }
+    }
+    /// 创建 SNAPSHOT 命令，读取游标 (table, key) 之后的一页数据
+    pub fn new_snapshot(table: impl Into<String>, key: impl Into<String>, limit: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Snapshot(Snapshot {
+                table: table.into(),
+                key: key.into(),
+                limit,
+            })),
+        }
+    }
     /// 创建 HGETALL 命令
     pub fn new_hget_all(table: impl Into<String>) -> Self {
         Self {
             request_data: Some(RequestData::Hgetall(Hgetall {
                 table: table.into(),
+                ..Default::default()
+            })),
+        }
+    }
+    /// 创建分页的 HGETALL 命令，读取 key 大于 after 的最多 limit 个 kv pair
+    pub fn new_hget_range(table: impl Into<String>, after: impl Into<String>, limit: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetall(Hgetall {
+                table: table.into(),
+                after: after.into(),
+                limit,
             })),
         }
     }
+    /// 创建 HCAS 命令，expected 为 None 表示 key 不存在，value 为 None 表示删除 key
+    pub fn new_hcas(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        expected: Option<Value>,
+        value: Option<Value>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hcas(Hcas {
+                table: table.into(),
+                key: key.into(),
+                expected,
+                value,
+            })),
+        }
+    }
+}
+
+/// 用于日志：Auth 命令的密码和 token 不会被打印出来
+impl fmt::Display for CommandRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.request_data {
+            Some(RequestData::Auth(auth)) => f
+                .debug_struct("Auth")
+                .field("username", &auth.username)
+                .field("password", &"<redacted>")
+                .field("token", &"<redacted>")
+                .finish(),
+            Some(data) => write!(f, "{:?}", data),
+            None => f.write_str("None"),
+        }
+    }
+}
+
+impl RequestData {
+    /// 命令的名字，用于限流、统计等
+    pub fn name(&self) -> &'static str {
+        match self {
+            RequestData::Hget(_) => "hget",
+            RequestData::Hgetall(_) => "hgetall",
+            RequestData::Hmget(_) => "hmget",
+            RequestData::Hset(_) => "hset",
+            RequestData::Hmset(_) => "hmset",
+            RequestData::Hdel(_) => "hdel",
+            RequestData::Hmdel(_) => "hmdel",
+            RequestData::Hexist(_) => "hexist",
+            RequestData::Hmexist(_) => "hmexist",
+            RequestData::Hcas(_) => "hcas",
+            RequestData::Auth(_) => "auth",
+            RequestData::Info(_) => "info",
+            RequestData::SlowlogGet(_) => "slowlog_get",
+            RequestData::SlowlogReset(_) => "slowlog_reset",
+            RequestData::Subscribe(_) => "subscribe",
+            RequestData::Unsubscribe(_) => "unsubscribe",
+            RequestData::Publish(_) => "publish",
+            RequestData::Watch(_) => "watch",
+            RequestData::Unwatch(_) => "unwatch",
+            RequestData::ReadChanges(_) => "read_changes",
+            RequestData::Snapshot(_) => "snapshot",
+        }
+    }
+
+    /// 命令操作的 table，管理命令没有 table
+    pub fn table(&self) -> Option<&str> {
+        match self {
+            RequestData::Hget(v) => Some(&v.table),
+            RequestData::Hgetall(v) => Some(&v.table),
+            RequestData::Hmget(v) => Some(&v.table),
+            RequestData::Hset(v) => Some(&v.table),
+            RequestData::Hmset(v) => Some(&v.table),
+            RequestData::Hdel(v) => Some(&v.table),
+            RequestData::Hmdel(v) => Some(&v.table),
+            RequestData::Hexist(v) => Some(&v.table),
+            RequestData::Hmexist(v) => Some(&v.table),
+            RequestData::Hcas(v) => Some(&v.table),
+            RequestData::Watch(v) => Some(&v.table),
+            RequestData::Auth(_)
+            | RequestData::Info(_)
+            | RequestData::SlowlogGet(_)
+            | RequestData::SlowlogReset(_)
+            | RequestData::Subscribe(_)
+            | RequestData::Unsubscribe(_)
+            | RequestData::Publish(_)
+            | RequestData::Unwatch(_)
+            | RequestData::ReadChanges(_)
+            | RequestData::Snapshot(_) => None,
+        }
+    }
 }
 
 impl Kvpair {
@@ -74,6 +363,15 @@ impl From<i64> for Value {
     }
 }
 
+/// 从 bool 转换成 Value
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Self {
+            value: Some(value::Value::Bool(b)),
+        }
+    }
+}
+
 // as can also be used with the _ placeholder when the destination type can be inferred.
 /// 从 Value 转换成 CommandResponse
 impl From<Value> for CommandResponse {
@@ -92,13 +390,27 @@ impl From<KvError> for CommandResponse {
         let mut result = Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: e.to_string(),
-            values: vec![],
-            pairs: vec![],
+            ..Default::default()
         };
 
         match e {
             KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
             KvError::NotFound(_, _) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
+            KvError::Conflict(_, _) => result.status = StatusCode::CONFLICT.as_u16() as _,
+            KvError::Unauthenticated(_) => result.status = StatusCode::UNAUTHORIZED.as_u16() as _,
+            KvError::PermissionDenied(_) => result.status = StatusCode::FORBIDDEN.as_u16() as _,
+            KvError::HistoryTruncated(_, _) => result.status = StatusCode::GONE.as_u16() as _,
+            KvError::ShuttingDown => result.status = StatusCode::SERVICE_UNAVAILABLE.as_u16() as _,
+            KvError::NotLeader(leader) => {
+                result.status = StatusCode::TEMPORARY_REDIRECT.as_u16() as _;
+                // 把 leader 的地址放在 values 里，方便客户端重定向
+                result.values = vec![leader.into()];
+            }
+            KvError::RateLimited(_, retry_after_ms) => {
+                result.status = StatusCode::TOO_MANY_REQUESTS.as_u16() as _;
+                // 把建议的重试间隔（毫秒）放在 values 里，方便客户端处理
+                result.values = vec![(retry_after_ms as i64).into()];
+            }
             _ => {}
         }
 
@@ -106,6 +418,50 @@ impl From<KvError> for CommandResponse {
     }
 }
 
+/// 把推送给订阅者的消息封装成 CommandResponse
+impl From<PubsubMessage> for CommandResponse {
+    fn from(msg: PubsubMessage) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            pubsub: Some(msg),
+            ..Default::default()
+        }
+    }
+}
+
+/// 把推送给 watcher 的事件封装成 CommandResponse
+impl From<WatchEvent> for CommandResponse {
+    fn from(event: WatchEvent) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            event: Some(event),
+            ..Default::default()
+        }
+    }
+}
+
+/// 从 Vec<Change> 转换成 CommandResponse
+impl From<Vec<Change>> for CommandResponse {
+    fn from(v: Vec<Change>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            changes: v,
+            ..Default::default()
+        }
+    }
+}
+
+/// 从 Vec<Value> 转换成 CommandResponse
+impl From<Vec<Value>> for CommandResponse {
+    fn from(v: Vec<Value>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            values: v,
+            ..Default::default()
+        }
+    }
+}
+
 /// 从 Vec<Kvpair> 转换成 CommandResponse
 impl From<Vec<Kvpair>> for CommandResponse {
     fn from(v: Vec<Kvpair>) -> Self {
@@ -141,3 +497,20 @@ impl TryFrom<Value> for Vec<u8> {
         Ok(buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_command_should_be_redacted_in_display() {
+        let output = CommandRequest::new_auth("alice", "alice-password").to_string();
+        assert!(output.contains("alice"));
+        assert!(!output.contains("alice-password"));
+        let output = CommandRequest::new_auth_token("ci.ci-secret").to_string();
+        assert!(!output.contains("ci-secret"));
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        assert!(cmd.to_string().contains("k1"));
+    }
+}
@@ -0,0 +1,160 @@
+use super::Entry;
+
+/// Raft 日志，快照之前的条目已经被压缩掉了，只保留快照的 index 和 term
+#[derive(Debug, Default)]
+pub(crate) struct RaftLog {
+    /// snapshot_index 之后的条目，index 连续
+    entries: Vec<Entry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+}
+
+impl RaftLog {
+    /// 用快照和日志文件中的条目重建日志，返回的 bool 表示是否丢掉了一些条目
+    ///
+    /// 快照和日志不是同时写入的，只有紧接着快照的条目才能保留：
+    /// 之前的条目已经包含在快照里，和快照冲突或者不连续的条目已经没有用了。
+    pub fn restore(snapshot_index: u64, snapshot_term: u64, entries: Vec<Entry>) -> (Self, bool) {
+        let start = match entries.iter().position(|e| e.index == snapshot_index) {
+            Some(i) if entries[i].term == snapshot_term => i + 1,
+            Some(_) => entries.len(),
+            None => match entries.first() {
+                Some(e) if e.index == snapshot_index + 1 => 0,
+                _ => entries.len(),
+            },
+        };
+        let log = Self {
+            entries: entries.into_iter().skip(start).collect(),
+            snapshot_index,
+            snapshot_term,
+        };
+        (log, start > 0)
+    }
+
+    /// snapshot_index 之后的所有条目
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.entries.last().map_or(self.snapshot_index, |e| e.index)
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.snapshot_term, |e| e.term)
+    }
+
+    /// index 处条目的 term，条目已经被压缩或者还不存在时返回 None
+    pub fn term(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.get(index).map(|e| e.term)
+    }
+
+    pub fn get(&self, index: u64) -> Option<&Entry> {
+        if index <= self.snapshot_index {
+            return None;
+        }
+        self.entries.get((index - self.snapshot_index - 1) as usize)
+    }
+
+    /// 从 index 开始最多 max 个条目，index 必须大于 snapshot_index
+    pub fn entries_from(&self, index: u64, max: usize) -> Vec<Entry> {
+        let start = (index - self.snapshot_index - 1) as usize;
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    pub fn append(&mut self, entry: Entry) {
+        debug_assert_eq!(entry.index, self.last_index() + 1);
+        self.entries.push(entry);
+    }
+
+    /// 删除 index 以及之后的条目
+    pub fn truncate_from(&mut self, index: u64) {
+        let len = index.saturating_sub(self.snapshot_index + 1) as usize;
+        self.entries.truncate(len);
+    }
+
+    /// 删除 index 以及之前的条目，它们已经包含在快照里了
+    pub fn compact(&mut self, index: u64, term: u64) {
+        let len = (index - self.snapshot_index) as usize;
+        self.entries.drain(..len.min(self.entries.len()));
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+    }
+
+    /// 安装快照之后，清空所有条目
+    pub fn reset(&mut self, index: u64, term: u64) {
+        self.entries.clear();
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+    }
+
+    /// candidate 的日志是否至少和自己一样新
+    pub fn is_up_to_date(&self, last_index: u64, last_term: u64) -> bool {
+        last_term > self.last_term()
+            || (last_term == self.last_term() && last_index >= self.last_index())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EntryData;
+
+    fn entry(index: u64, term: u64) -> Entry {
+        Entry {
+            index,
+            term,
+            data: EntryData::Noop,
+        }
+    }
+
+    #[test]
+    fn raft_log_should_truncate_and_compact() {
+        let mut log = RaftLog::default();
+        for (index, term) in [(1, 1), (2, 1), (3, 2), (4, 2)] {
+            log.append(entry(index, term));
+        }
+        assert_eq!((log.last_index(), log.last_term()), (4, 2));
+
+        log.truncate_from(4);
+        assert_eq!(log.last_index(), 3);
+        log.compact(2, 1);
+        assert_eq!(log.term(2), Some(1));
+        assert_eq!(log.term(1), None);
+        assert_eq!(log.get(3).unwrap().term, 2);
+        assert_eq!(log.entries_from(3, 10).len(), 1);
+
+        log.truncate_from(3);
+        assert_eq!((log.last_index(), log.last_term()), (2, 1));
+        assert!(log.is_up_to_date(2, 1));
+        assert!(!log.is_up_to_date(5, 0));
+    }
+
+    #[test]
+    fn raft_log_should_restore_entries_after_snapshot() {
+        let entries = || vec![entry(3, 1), entry(4, 2), entry(5, 2)];
+
+        // 压缩之后还没来得及重写日志
+        let (log, dropped) = RaftLog::restore(4, 2, entries());
+        assert!(dropped);
+        assert_eq!((log.snapshot_index(), log.last_index()), (4, 5));
+
+        let (log, dropped) = RaftLog::restore(2, 1, entries());
+        assert!(!dropped);
+        assert_eq!(log.entries().len(), 3);
+
+        // 安装的快照和本地的条目冲突，或者本地的条目不连续
+        let (log, dropped) = RaftLog::restore(4, 3, entries());
+        assert!(dropped);
+        assert_eq!(log.last_index(), 4);
+        let (log, _) = RaftLog::restore(8, 3, entries());
+        assert_eq!((log.last_index(), log.last_term()), (8, 3));
+    }
+}
@@ -0,0 +1,214 @@
+use bytes::Bytes;
+
+use crate::{
+    raft_entry, raft_message, CommandRequest, KvError, RaftAppend, RaftAppendResponse, RaftEntry,
+    RaftInstallSnapshot, RaftMessage, RaftRequestVote, RaftSnapshotAck, RaftVote,
+};
+
+/// 日志条目的内容
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryData {
+    /// 新的 leader 当选后写入的空条目，用来提交之前 term 的条目
+    Noop,
+    /// 修改数据的命令，提交之后应用到状态机
+    Command(CommandRequest),
+    /// 增加一个投票成员，提交之后生效
+    AddNode(u64),
+    /// 删除一个投票成员，提交之后生效
+    RemoveNode(u64),
+}
+
+/// 一个日志条目，index 从 1 开始
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub index: u64,
+    pub term: u64,
+    pub data: EntryData,
+}
+
+/// 节点之间的消息，由使用者负责在节点之间传递
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub from: u64,
+    pub to: u64,
+    /// 发送者当前的 term
+    pub term: u64,
+    pub body: MessageBody,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageBody {
+    /// candidate 请求投票
+    RequestVote { last_index: u64, last_term: u64 },
+    /// 投票结果
+    Vote { granted: bool },
+    /// leader 复制日志，entries 为空时就是心跳
+    ///
+    /// read 是 read-index 请求的序号，follower 原样返回，用来确认 leader 的身份。
+    Append {
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+        read: u64,
+    },
+    /// 成功时 last_index 是 follower 和 leader 一致的最后一个条目；
+    /// 失败时是 follower 建议 leader 下次从哪个条目之后开始发送
+    AppendResponse {
+        success: bool,
+        last_index: u64,
+        read: u64,
+    },
+    /// follower 需要的条目已经被压缩掉了，分段发送快照文件，done 表示这是最后一段
+    InstallSnapshot {
+        index: u64,
+        offset: u64,
+        data: Bytes,
+        done: bool,
+    },
+    /// follower 已经收到了快照 index 的前 offset 个字节
+    SnapshotAck { index: u64, offset: u64 },
+}
+
+impl From<Entry> for RaftEntry {
+    fn from(entry: Entry) -> Self {
+        let data = match entry.data {
+            EntryData::Noop => raft_entry::Data::Noop(true),
+            EntryData::Command(cmd) => raft_entry::Data::Command(cmd),
+            EntryData::AddNode(id) => raft_entry::Data::AddNode(id),
+            EntryData::RemoveNode(id) => raft_entry::Data::RemoveNode(id),
+        };
+        Self {
+            index: entry.index,
+            term: entry.term,
+            data: Some(data),
+        }
+    }
+}
+
+impl TryFrom<RaftEntry> for Entry {
+    type Error = KvError;
+
+    fn try_from(entry: RaftEntry) -> Result<Self, Self::Error> {
+        let data = match entry.data {
+            Some(raft_entry::Data::Noop(_)) => EntryData::Noop,
+            Some(raft_entry::Data::Command(cmd)) => EntryData::Command(cmd),
+            Some(raft_entry::Data::AddNode(id)) => EntryData::AddNode(id),
+            Some(raft_entry::Data::RemoveNode(id)) => EntryData::RemoveNode(id),
+            None => return Err(KvError::Internal("Raft entry has no data".into())),
+        };
+        Ok(Self {
+            index: entry.index,
+            term: entry.term,
+            data,
+        })
+    }
+}
+
+impl From<Message> for RaftMessage {
+    fn from(msg: Message) -> Self {
+        use raft_message::Body;
+
+        let body = match msg.body {
+            MessageBody::RequestVote {
+                last_index,
+                last_term,
+            } => Body::RequestVote(RaftRequestVote {
+                last_index,
+                last_term,
+            }),
+            MessageBody::Vote { granted } => Body::Vote(RaftVote { granted }),
+            MessageBody::Append {
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+                read,
+            } => Body::Append(RaftAppend {
+                prev_index,
+                prev_term,
+                entries: entries.into_iter().map(Into::into).collect(),
+                commit,
+                read,
+            }),
+            MessageBody::AppendResponse {
+                success,
+                last_index,
+                read,
+            } => Body::AppendResponse(RaftAppendResponse {
+                success,
+                last_index,
+                read,
+            }),
+            MessageBody::InstallSnapshot {
+                index,
+                offset,
+                data,
+                done,
+            } => Body::InstallSnapshot(RaftInstallSnapshot {
+                index,
+                offset,
+                data,
+                done,
+            }),
+            MessageBody::SnapshotAck { index, offset } => {
+                Body::SnapshotAck(RaftSnapshotAck { index, offset })
+            }
+        };
+        Self {
+            from: msg.from,
+            to: msg.to,
+            term: msg.term,
+            body: Some(body),
+        }
+    }
+}
+
+impl TryFrom<RaftMessage> for Message {
+    type Error = KvError;
+
+    fn try_from(msg: RaftMessage) -> Result<Self, Self::Error> {
+        use raft_message::Body;
+
+        let body = match msg.body {
+            Some(Body::RequestVote(v)) => MessageBody::RequestVote {
+                last_index: v.last_index,
+                last_term: v.last_term,
+            },
+            Some(Body::Vote(v)) => MessageBody::Vote { granted: v.granted },
+            Some(Body::Append(a)) => MessageBody::Append {
+                prev_index: a.prev_index,
+                prev_term: a.prev_term,
+                entries: a
+                    .entries
+                    .into_iter()
+                    .map(Entry::try_from)
+                    .collect::<Result<_, _>>()?,
+                commit: a.commit,
+                read: a.read,
+            },
+            Some(Body::AppendResponse(r)) => MessageBody::AppendResponse {
+                success: r.success,
+                last_index: r.last_index,
+                read: r.read,
+            },
+            Some(Body::InstallSnapshot(s)) => MessageBody::InstallSnapshot {
+                index: s.index,
+                offset: s.offset,
+                data: s.data,
+                done: s.done,
+            },
+            Some(Body::SnapshotAck(a)) => MessageBody::SnapshotAck {
+                index: a.index,
+                offset: a.offset,
+            },
+            None => return Err(KvError::Internal("Raft message has no body".into())),
+        };
+        Ok(Self {
+            from: msg.from,
+            to: msg.to,
+            term: msg.term,
+            body,
+        })
+    }
+}
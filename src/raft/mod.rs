@@ -0,0 +1,1302 @@
+mod log;
+mod message;
+mod server;
+mod state_machine;
+mod storage;
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    path::Path,
+};
+
+use http::StatusCode;
+use tracing::{debug, warn};
+
+use crate::{
+    service::Changes, CommandRequest, CommandResponse, KvError, RaftConfig, RaftHardState,
+    RaftSnapshotMeta, Service,
+};
+use log::RaftLog;
+pub use message::*;
+pub use server::{RaftHandle, RaftServer};
+pub use state_machine::StateMachine;
+use storage::{persist, RaftStorage};
+
+/// 节点在 Raft 中的角色
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// leader 记录的每个 follower 的复制进度
+#[derive(Debug, Clone, Copy)]
+struct Progress {
+    /// 下一次从哪个条目开始发送
+    next: u64,
+    /// 已经确认和 leader 一致的最后一个条目
+    matched: u64,
+    /// 正在发送快照时，follower 已经收到的字节数
+    snapshot_offset: u64,
+}
+
+/// 等待确认 leader 身份的读请求（read-index）
+struct ReadRequest {
+    id: u64,
+    /// 发起读请求之后的 Append 消息都会带上 ctx，follower 返回 ctx 就说明它还认可这个 leader
+    ctx: u64,
+    /// 读请求发起时的 commit index，应用到这里之后才能读
+    index: u64,
+    cmd: CommandRequest,
+    acks: HashSet<u64>,
+    confirmed: bool,
+}
+
+/// 一个 Raft 节点，修改数据的命令作为日志条目，多数节点确认之后按顺序应用到状态机
+///
+/// RaftNode 本身不做网络 IO：使用者定期调用 `tick`，把收到的消息交给 `step`，
+/// 然后把 `take_messages` 返回的消息发给对应的节点，`take_results` 返回已经完成的请求。
+/// term、投票、日志和快照保存在 `open` 的目录中，写入磁盘之后才会发出依赖它们的消息。
+/// `RaftServer` 在 TCP 上运行节点，并把它接入 `Service`。
+pub struct RaftNode<SM: StateMachine = Service> {
+    id: u64,
+    config: RaftConfig,
+    sm: SM,
+    storage: RaftStorage,
+
+    term: u64,
+    voted_for: Option<u64>,
+    log: RaftLog,
+    /// 正在接收的快照：(index, 已经收到的字节数)
+    receiving: Option<(u64, u64)>,
+
+    role: Role,
+    leader: Option<u64>,
+    /// 投票成员，成员变更的条目应用之后才会改变
+    voters: BTreeSet<u64>,
+    commit: u64,
+    applied: u64,
+
+    election_elapsed: u64,
+    election_timeout: u64,
+    heartbeat_elapsed: u64,
+    rng: u64,
+
+    /// candidate 收到的投票
+    votes: HashSet<u64>,
+    /// leader 在一个选举超时内收到过回复的节点，不够多数时 leader 主动退位
+    active: HashSet<u64>,
+    progress: HashMap<u64, Progress>,
+    /// 当前 term 第一个条目的 index，它提交之后之前 term 的条目才算提交
+    term_start: u64,
+    /// 还没有应用的成员变更条目，同时只允许一个
+    pending_conf: u64,
+
+    /// 条目 index -> (请求 id, 提交时的 term)
+    proposals: HashMap<u64, (u64, u64)>,
+    reads: VecDeque<ReadRequest>,
+    read_ctx: u64,
+    next_request: u64,
+
+    messages: Vec<Message>,
+    results: Vec<(u64, CommandResponse)>,
+}
+
+impl<SM: StateMachine> RaftNode<SM> {
+    /// 打开 dir 中保存的状态，创建一个节点，voters 是第一次启动时集群的投票成员
+    ///
+    /// 状态机先被替换成快照中的数据，之后提交的条目会重新应用一遍；已经有快照时使用快照中的成员。
+    /// 加入已有集群的新节点传入现有的成员，它不在成员里，所以不会发起选举，
+    /// 等 leader 把 `add_node` 的条目复制过来之后才成为投票成员。
+    pub fn open(
+        id: u64,
+        voters: impl IntoIterator<Item = u64>,
+        sm: SM,
+        config: RaftConfig,
+        dir: impl AsRef<Path>,
+    ) -> Result<Self, KvError> {
+        if id == 0 {
+            let msg = "Raft node id must be greater than 0".into();
+            return Err(KvError::ConfigError(msg));
+        }
+        let (mut storage, state, entries) = RaftStorage::open(dir)?;
+        let (index, term, voters) = match storage.restore_snapshot(&sm)? {
+            Some(meta) => (meta.index, meta.term, meta.voters.into_iter().collect()),
+            None => (0, 0, voters.into_iter().collect()),
+        };
+        let (log, dropped) = RaftLog::restore(index, term, entries);
+        if dropped {
+            storage.rewrite(log.entries())?;
+        }
+
+        let mut node = Self {
+            id,
+            config,
+            sm,
+            storage,
+            term: state.term,
+            voted_for: (state.voted_for != 0).then_some(state.voted_for),
+            log,
+            receiving: None,
+            role: Role::Follower,
+            leader: None,
+            voters,
+            commit: index,
+            applied: index,
+            election_elapsed: 0,
+            election_timeout: 0,
+            heartbeat_elapsed: 0,
+            rng: id.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+            votes: HashSet::new(),
+            active: HashSet::new(),
+            progress: HashMap::new(),
+            term_start: 0,
+            pending_conf: 0,
+            proposals: HashMap::new(),
+            reads: VecDeque::new(),
+            read_ctx: 0,
+            next_request: 0,
+            messages: Vec::new(),
+            results: Vec::new(),
+        };
+        node.reset_election_timeout();
+        Ok(node)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// 当前已知的 leader
+    pub fn leader(&self) -> Option<u64> {
+        self.leader
+    }
+
+    pub fn voters(&self) -> &BTreeSet<u64> {
+        &self.voters
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit
+    }
+
+    pub fn applied_index(&self) -> u64 {
+        self.applied
+    }
+
+    /// 日志中这个 index 以及之前的条目已经被快照替代了
+    pub fn snapshot_index(&self) -> u64 {
+        self.log.snapshot_index()
+    }
+
+    /// 状态机，只应该用来读；写入需要通过 `propose`
+    pub fn state_machine(&self) -> &SM {
+        &self.sm
+    }
+
+    /// 逻辑时钟前进一次，驱动选举超时和心跳
+    pub fn tick(&mut self) {
+        self.election_elapsed += 1;
+        if self.role != Role::Leader {
+            if self.election_elapsed >= self.election_timeout && self.voters.contains(&self.id) {
+                self.campaign();
+            }
+            return;
+        }
+
+        self.heartbeat_elapsed += 1;
+        if self.heartbeat_elapsed >= self.config.heartbeat_ticks {
+            self.heartbeat_elapsed = 0;
+            self.broadcast_append();
+            // 成员减少之后，已有的确认可能已经够多数了
+            self.maybe_commit();
+        }
+
+        // 被隔离在少数派里的 leader 主动退位，这样它上面等待的请求可以尽快失败
+        if self.role == Role::Leader && self.election_elapsed >= self.config.election_ticks {
+            self.election_elapsed = 0;
+            self.active.insert(self.id);
+            let active = std::mem::take(&mut self.active);
+            if !self.is_quorum(&active) {
+                debug!("Leader {} lost the quorum, stepping down", self.id);
+                self.become_follower(self.term, None);
+            }
+        }
+    }
+
+    /// 处理其他节点发来的消息
+    pub fn step(&mut self, msg: Message) {
+        if msg.term > self.term {
+            if let MessageBody::RequestVote { .. } = msg.body {
+                // 最近还收到过 leader 的消息，说明 leader 还活着，忽略投票请求，
+                // 防止被隔离之后 term 变大的节点重新加入时打断集群
+                if self.leader.is_some() && self.election_elapsed < self.config.election_ticks {
+                    return;
+                }
+            }
+            let leader = match msg.body {
+                MessageBody::Append { .. } | MessageBody::InstallSnapshot { .. } => Some(msg.from),
+                _ => None,
+            };
+            self.become_follower(msg.term, leader);
+        } else if msg.term < self.term {
+            // 告诉过期的节点新的 term
+            match msg.body {
+                MessageBody::Append { .. } | MessageBody::InstallSnapshot { .. } => {
+                    let last_index = self.log.last_index();
+                    let body = MessageBody::AppendResponse {
+                        success: false,
+                        last_index,
+                        read: 0,
+                    };
+                    self.send(msg.from, body);
+                }
+                MessageBody::RequestVote { .. } => {
+                    self.send(msg.from, MessageBody::Vote { granted: false })
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        match msg.body {
+            MessageBody::RequestVote {
+                last_index,
+                last_term,
+            } => {
+                let granted = self.voted_for.is_none_or(|id| id == msg.from)
+                    && self.log.is_up_to_date(last_index, last_term);
+                if granted {
+                    if self.voted_for.is_none() {
+                        self.voted_for = Some(msg.from);
+                        self.persist_state();
+                    }
+                    self.election_elapsed = 0;
+                }
+                self.send(msg.from, MessageBody::Vote { granted });
+            }
+            MessageBody::Vote { granted } => {
+                if self.role == Role::Candidate && granted {
+                    self.votes.insert(msg.from);
+                    if self.is_quorum(&self.votes) {
+                        self.become_leader();
+                    }
+                }
+            }
+            MessageBody::Append {
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+                read,
+            } => {
+                self.follow(msg.from);
+                self.handle_append(msg.from, prev_index, prev_term, entries, commit, read);
+            }
+            MessageBody::AppendResponse {
+                success,
+                last_index,
+                read,
+            } => {
+                if self.role == Role::Leader {
+                    self.handle_append_response(msg.from, success, last_index, read);
+                }
+            }
+            MessageBody::InstallSnapshot {
+                index,
+                offset,
+                data,
+                done,
+            } => {
+                self.follow(msg.from);
+                self.receive_snapshot(msg.from, index, offset, &data, done);
+            }
+            MessageBody::SnapshotAck { index, offset } => {
+                if self.role == Role::Leader {
+                    self.handle_snapshot_ack(msg.from, index, offset);
+                }
+            }
+        }
+    }
+
+    /// 提交一个修改数据的命令，返回请求 id，条目应用之后在 `take_results` 中返回执行结果
+    pub fn propose(&mut self, cmd: CommandRequest) -> Result<u64, KvError> {
+        self.check_leader()?;
+        let is_write = cmd
+            .request_data
+            .as_ref()
+            .is_some_and(|data| Changes::from_request(data).is_some());
+        if !is_write {
+            let msg = "Only write commands can be proposed, use read-index for reads".into();
+            return Err(KvError::InvalidCommand(msg));
+        }
+        Ok(self.append_proposal(EntryData::Command(cmd)))
+    }
+
+    /// 线性一致的读：确认自己仍然是 leader，并且应用到发起读请求时的 commit index 之后再执行
+    pub fn read(&mut self, cmd: CommandRequest) -> Result<u64, KvError> {
+        self.check_leader()?;
+        let is_write = cmd
+            .request_data
+            .as_ref()
+            .is_none_or(|data| Changes::from_request(data).is_some());
+        if is_write {
+            let msg = "Only read commands can be served by read-index".into();
+            return Err(KvError::InvalidCommand(msg));
+        }
+
+        let id = self.next_id();
+        self.read_ctx += 1;
+        let acks = HashSet::from([self.id]);
+        let confirmed = self.is_quorum(&acks);
+        self.reads.push_back(ReadRequest {
+            id,
+            ctx: self.read_ctx,
+            // 当前 term 的第一个条目还没有提交时，commit index 可能落后于之前的 leader
+            index: self.commit.max(self.term_start),
+            cmd,
+            acks,
+            confirmed,
+        });
+        self.broadcast_append();
+        self.serve_reads();
+        Ok(id)
+    }
+
+    /// 增加一个投票成员，同时只能有一个成员变更
+    pub fn add_node(&mut self, id: u64) -> Result<u64, KvError> {
+        if self.voters.contains(&id) {
+            return Err(KvError::InvalidCommand(format!(
+                "Node {} is already a voter",
+                id
+            )));
+        }
+        self.propose_conf_change(EntryData::AddNode(id))
+    }
+
+    /// 删除一个投票成员，可以删除 leader 自己，条目应用之后 leader 会退位
+    pub fn remove_node(&mut self, id: u64) -> Result<u64, KvError> {
+        if !self.voters.contains(&id) {
+            return Err(KvError::InvalidCommand(format!(
+                "Node {} is not a voter",
+                id
+            )));
+        }
+        if self.voters.len() == 1 {
+            let msg = "Can't remove the last voter".into();
+            return Err(KvError::InvalidCommand(msg));
+        }
+        self.propose_conf_change(EntryData::RemoveNode(id))
+    }
+
+    /// 取出需要发送给其他节点的消息
+    pub fn take_messages(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.messages)
+    }
+
+    /// 取出已经完成的请求：(请求 id, 执行结果)
+    pub fn take_results(&mut self) -> Vec<(u64, CommandResponse)> {
+        std::mem::take(&mut self.results)
+    }
+
+    fn propose_conf_change(&mut self, data: EntryData) -> Result<u64, KvError> {
+        self.check_leader()?;
+        if self.pending_conf > self.applied {
+            let msg = "Another membership change is in progress".into();
+            return Err(KvError::InvalidCommand(msg));
+        }
+        let id = self.append_proposal(data);
+        self.pending_conf = self.log.last_index();
+        Ok(id)
+    }
+
+    fn append_proposal(&mut self, data: EntryData) -> u64 {
+        let id = self.next_id();
+        let index = self.append(data);
+        self.proposals.insert(index, (id, self.term));
+        self.broadcast_append();
+        self.maybe_commit();
+        id
+    }
+
+    fn append(&mut self, data: EntryData) -> u64 {
+        let index = self.log.last_index() + 1;
+        let term = self.term;
+        self.append_entries(vec![Entry { index, term, data }]);
+        index
+    }
+
+    /// 追加条目，写入磁盘之后才返回，leader 这时才能把它们计入多数，follower 才能回复 leader
+    fn append_entries(&mut self, entries: Vec<Entry>) {
+        persist(self.storage.append(&entries));
+        for entry in entries {
+            self.log.append(entry);
+        }
+    }
+
+    /// 删除 index 以及之后的条目
+    fn truncate_log(&mut self, index: u64) {
+        self.log.truncate_from(index);
+        persist(self.storage.truncate(self.log.entries().len()));
+    }
+
+    /// term 和投票写入磁盘之后才能发出依赖它们的消息，否则重启之后可能在同一个 term 投两次票
+    fn persist_state(&self) {
+        let state = RaftHardState {
+            term: self.term,
+            voted_for: self.voted_for.unwrap_or_default(),
+        };
+        persist(self.storage.save_state(&state));
+    }
+
+    fn campaign(&mut self) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.id);
+        self.persist_state();
+        self.votes = HashSet::from([self.id]);
+        self.reset_election_timeout();
+        debug!("Node {} starts election for term {}", self.id, self.term);
+
+        if self.is_quorum(&self.votes) {
+            self.become_leader();
+            return;
+        }
+        let (last_index, last_term) = (self.log.last_index(), self.log.last_term());
+        for to in self.peers() {
+            self.send(
+                to,
+                MessageBody::RequestVote {
+                    last_index,
+                    last_term,
+                },
+            );
+        }
+    }
+
+    fn become_leader(&mut self) {
+        debug!("Node {} becomes leader of term {}", self.id, self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.election_elapsed = 0;
+        self.heartbeat_elapsed = 0;
+        self.active.clear();
+        let next = self.log.last_index() + 1;
+        self.progress = self
+            .peers()
+            .into_iter()
+            .map(|id| {
+                let progress = Progress {
+                    next,
+                    matched: 0,
+                    snapshot_offset: 0,
+                };
+                (id, progress)
+            })
+            .collect();
+        // 之前的 leader 可能留下了还没有提交的成员变更
+        self.pending_conf = self.log.last_index();
+        self.term_start = self.append(EntryData::Noop);
+        self.broadcast_append();
+        self.maybe_commit();
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<u64>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.persist_state();
+        }
+        if self.role != Role::Follower {
+            self.reset_election_timeout();
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        self.progress.clear();
+
+        // 等待确认的读请求都失败，让客户端去找新的 leader
+        let hint = self.leader_hint();
+        for read in self.reads.drain(..) {
+            let res = KvError::NotLeader(hint.clone()).into();
+            self.results.push((read.id, res));
+        }
+    }
+
+    /// 收到当前 term 的 leader 的消息
+    fn follow(&mut self, leader: u64) {
+        if self.role != Role::Follower {
+            self.become_follower(self.term, Some(leader));
+        }
+        self.leader = Some(leader);
+        self.election_elapsed = 0;
+    }
+
+    fn handle_append(
+        &mut self,
+        from: u64,
+        mut prev_index: u64,
+        mut prev_term: u64,
+        mut entries: Vec<Entry>,
+        commit: u64,
+        read: u64,
+    ) {
+        // 已经提交的条目一定和 leader 一致，跳过它们
+        if prev_index < self.commit {
+            entries.retain(|e| e.index > self.commit);
+            prev_index = self.commit;
+            prev_term = self.log.term(self.commit).unwrap_or_default();
+        }
+
+        if self.log.term(prev_index) != Some(prev_term) {
+            let last_index = self.log.last_index().min(prev_index.saturating_sub(1));
+            let body = MessageBody::AppendResponse {
+                success: false,
+                last_index,
+                read,
+            };
+            self.send(from, body);
+            return;
+        }
+
+        let last_index = prev_index + entries.len() as u64;
+        let mut new = Vec::new();
+        for entry in entries {
+            if new.is_empty() {
+                match self.log.term(entry.index) {
+                    Some(term) if term == entry.term => continue,
+                    // 和 leader 冲突的条目一定还没有提交，删掉它以及之后的条目
+                    Some(_) => self.truncate_log(entry.index),
+                    None => {}
+                }
+            }
+            new.push(entry);
+        }
+        self.append_entries(new);
+
+        let commit = commit.min(last_index);
+        if commit > self.commit {
+            self.commit = commit;
+            self.apply();
+        }
+        let body = MessageBody::AppendResponse {
+            success: true,
+            last_index,
+            read,
+        };
+        self.send(from, body);
+    }
+
+    fn handle_append_response(&mut self, from: u64, success: bool, last_index: u64, read: u64) {
+        self.active.insert(from);
+        self.ack_reads(from, read);
+
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return,
+        };
+        if success {
+            progress.matched = progress.matched.max(last_index);
+            progress.next = progress.next.max(progress.matched + 1);
+            progress.snapshot_offset = 0;
+        } else {
+            // 回退到 follower 建议的位置，但不会回退到已经确认的条目之前
+            progress.next = (last_index + 1)
+                .min(progress.next.saturating_sub(1))
+                .max(progress.matched + 1);
+        }
+        let next = progress.next;
+
+        if success {
+            self.maybe_commit();
+        }
+        if self.role == Role::Leader && next <= self.log.last_index() {
+            self.send_append(from);
+        }
+    }
+
+    fn handle_snapshot_ack(&mut self, from: u64, index: u64, offset: u64) {
+        self.active.insert(from);
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return,
+        };
+        // 重复的确认不需要处理，丢失的数据由心跳重新发送
+        if index != self.log.snapshot_index() || offset == progress.snapshot_offset {
+            return;
+        }
+        progress.snapshot_offset = offset;
+        self.send_append(from);
+    }
+
+    /// 把 leader 发来的一段快照写入磁盘，收完之后安装
+    fn receive_snapshot(&mut self, from: u64, index: u64, offset: u64, data: &[u8], done: bool) {
+        if index <= self.commit {
+            let body = MessageBody::AppendResponse {
+                success: true,
+                last_index: self.commit,
+                read: 0,
+            };
+            self.send(from, body);
+            return;
+        }
+
+        // 不是接着上一段的数据，告诉 leader 从哪里继续，新的快照从头开始
+        let expected = match self.receiving {
+            Some((receiving, received)) if receiving == index => received,
+            _ => 0,
+        };
+        if offset != expected {
+            self.send(
+                from,
+                MessageBody::SnapshotAck {
+                    index,
+                    offset: expected,
+                },
+            );
+            return;
+        }
+        persist(self.storage.receive_snapshot_chunk(offset, data));
+        let received = offset + data.len() as u64;
+        if !done {
+            self.receiving = Some((index, received));
+            self.send(
+                from,
+                MessageBody::SnapshotAck {
+                    index,
+                    offset: received,
+                },
+            );
+            return;
+        }
+        self.receiving = None;
+        self.install_snapshot(from);
+    }
+
+    /// 用收到的快照替换本地的快照、状态机和日志
+    fn install_snapshot(&mut self, from: u64) {
+        persist(self.storage.finish_receive());
+        // 状态机替换到一半失败的话，节点停止，重启之后会从新的快照重新恢复
+        let meta = persist(self.storage.restore_snapshot(&self.sm)).unwrap_or_default();
+        debug!("Node {} installed snapshot {}", self.id, meta.index);
+        // 快照之后的条目和 leader 一致时可以保留
+        match self.log.term(meta.index) {
+            Some(term) if term == meta.term => self.log.compact(meta.index, meta.term),
+            _ => self.log.reset(meta.index, meta.term),
+        }
+        persist(self.storage.rewrite(self.log.entries()));
+        self.commit = meta.index;
+        self.applied = meta.index;
+        self.voters = meta.voters.iter().copied().collect();
+
+        // 被快照覆盖的条目不会再在这个节点上应用，无法知道它们的执行结果
+        let dropped: Vec<u64> = self
+            .proposals
+            .keys()
+            .filter(|index| **index <= meta.index)
+            .copied()
+            .collect();
+        for index in dropped {
+            let (id, _) = self.proposals.remove(&index).unwrap();
+            let msg = "Proposal result is unknown after installing a snapshot".into();
+            self.results.push((id, KvError::Internal(msg).into()));
+        }
+
+        let body = MessageBody::AppendResponse {
+            success: true,
+            last_index: meta.index,
+            read: 0,
+        };
+        self.send(from, body);
+    }
+
+    /// 多数节点都已经复制的条目可以提交，但只能直接提交当前 term 的条目
+    fn maybe_commit(&mut self) {
+        let mut matched: Vec<u64> = self
+            .voters
+            .iter()
+            .map(|id| match self.progress.get(id) {
+                _ if *id == self.id => self.log.last_index(),
+                Some(progress) => progress.matched,
+                None => 0,
+            })
+            .collect();
+        if matched.is_empty() {
+            return;
+        }
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.quorum() - 1];
+        if index > self.commit && self.log.term(index) == Some(self.term) {
+            self.commit = index;
+            self.apply();
+        }
+    }
+
+    /// 按顺序应用已经提交的条目
+    fn apply(&mut self) {
+        while self.applied < self.commit {
+            let entry = match self.log.get(self.applied + 1) {
+                Some(entry) => entry.clone(),
+                None => break,
+            };
+            self.applied = entry.index;
+
+            let res = match entry.data {
+                EntryData::Noop => CommandResponse::default(),
+                EntryData::Command(cmd) => self.sm.apply(cmd),
+                EntryData::AddNode(id) => {
+                    self.add_voter(id);
+                    ok()
+                }
+                EntryData::RemoveNode(id) => {
+                    self.remove_voter(id);
+                    ok()
+                }
+            };
+            if self.pending_conf == entry.index {
+                self.pending_conf = 0;
+            }
+            if let Some((id, term)) = self.proposals.remove(&entry.index) {
+                let res = if term == entry.term {
+                    res
+                } else {
+                    let msg = "Proposal was overwritten by a new leader".into();
+                    KvError::Internal(msg).into()
+                };
+                self.results.push((id, res));
+            }
+        }
+        self.serve_reads();
+        self.maybe_snapshot();
+    }
+
+    fn add_voter(&mut self, id: u64) {
+        self.voters.insert(id);
+        if self.role == Role::Leader && id != self.id {
+            let progress = Progress {
+                next: self.log.last_index() + 1,
+                matched: 0,
+                snapshot_offset: 0,
+            };
+            self.progress.insert(id, progress);
+            self.send_append(id);
+        }
+    }
+
+    fn remove_voter(&mut self, id: u64) {
+        self.voters.remove(&id);
+        self.progress.remove(&id);
+        if id == self.id && self.role == Role::Leader {
+            debug!("Leader {} was removed from the cluster", self.id);
+            self.become_follower(self.term, None);
+        }
+    }
+
+    /// 上次快照之后应用了足够多的条目，生成新的快照并压缩日志
+    ///
+    /// 快照直接从状态机写入磁盘，不在内存中保留数据。
+    fn maybe_snapshot(&mut self) {
+        if self.applied - self.log.snapshot_index() < self.config.snapshot_entries {
+            return;
+        }
+        let term = self.log.term(self.applied).unwrap_or_default();
+        let meta = RaftSnapshotMeta {
+            index: self.applied,
+            term,
+            voters: self.voters.iter().copied().collect(),
+        };
+        if let Err(e) = self.storage.write_snapshot(&meta, &self.sm) {
+            warn!("Failed to take snapshot at {}: {}", self.applied, e);
+            return;
+        }
+        self.log.compact(self.applied, term);
+        persist(self.storage.rewrite(self.log.entries()));
+    }
+
+    fn ack_reads(&mut self, from: u64, ctx: u64) {
+        let voters = &self.voters;
+        let quorum = self.voters.len() / 2 + 1;
+        for read in self.reads.iter_mut().filter(|r| r.ctx <= ctx) {
+            read.acks.insert(from);
+            read.confirmed = voters.iter().filter(|id| read.acks.contains(id)).count() >= quorum;
+        }
+        self.serve_reads();
+    }
+
+    /// 按顺序执行已经确认并且状态机已经追上的读请求
+    fn serve_reads(&mut self) {
+        while let Some(read) = self.reads.front() {
+            if !read.confirmed || read.index > self.applied {
+                break;
+            }
+            let read = self.reads.pop_front().unwrap();
+            let res = self.sm.query(read.cmd);
+            self.results.push((read.id, res));
+        }
+    }
+
+    fn broadcast_append(&mut self) {
+        let peers: Vec<u64> = self.progress.keys().copied().collect();
+        for to in peers {
+            self.send_append(to);
+        }
+    }
+
+    fn send_append(&mut self, to: u64) {
+        let (next, offset) = match self.progress.get(&to) {
+            Some(progress) => (progress.next, progress.snapshot_offset),
+            None => return,
+        };
+        // 需要的条目已经被压缩掉了，从 follower 已经收到的位置继续发送快照文件
+        if next <= self.log.snapshot_index() {
+            let index = self.log.snapshot_index();
+            let max = self.config.snapshot_chunk_bytes;
+            match self.storage.read_snapshot_chunk(offset, max) {
+                Ok((data, done)) => {
+                    let body = MessageBody::InstallSnapshot {
+                        index,
+                        offset,
+                        data,
+                        done,
+                    };
+                    self.send(to, body);
+                }
+                Err(e) => warn!("Failed to read snapshot {} for node {}: {}", index, to, e),
+            }
+            return;
+        }
+
+        let prev_index = next - 1;
+        let body = MessageBody::Append {
+            prev_index,
+            prev_term: self.log.term(prev_index).unwrap_or_default(),
+            entries: self.log.entries_from(next, self.config.max_append_entries),
+            commit: self.commit,
+            read: self.read_ctx,
+        };
+        self.send(to, body);
+    }
+
+    fn send(&mut self, to: u64, body: MessageBody) {
+        self.messages.push(Message {
+            from: self.id,
+            to,
+            term: self.term,
+            body,
+        });
+    }
+
+    fn check_leader(&self) -> Result<(), KvError> {
+        match self.role {
+            Role::Leader => Ok(()),
+            _ => Err(KvError::NotLeader(self.leader_hint())),
+        }
+    }
+
+    fn leader_hint(&self) -> String {
+        self.leader.map(|id| id.to_string()).unwrap_or_default()
+    }
+
+    fn peers(&self) -> Vec<u64> {
+        self.voters
+            .iter()
+            .copied()
+            .filter(|id| *id != self.id)
+            .collect()
+    }
+
+    fn quorum(&self) -> usize {
+        self.voters.len() / 2 + 1
+    }
+
+    fn is_quorum(&self, nodes: &HashSet<u64>) -> bool {
+        let count = self.voters.iter().filter(|id| nodes.contains(id)).count();
+        count >= self.quorum()
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_request += 1;
+        self.next_request
+    }
+
+    /// 选举超时在 [election_ticks, 2 * election_ticks) 之间随机，避免多个节点同时发起选举
+    fn reset_election_timeout(&mut self) {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let ticks = self.config.election_ticks.max(1);
+        self.election_timeout = ticks + self.rng % ticks;
+        self.election_elapsed = 0;
+    }
+}
+
+fn ok() -> CommandResponse {
+    CommandResponse {
+        status: StatusCode::OK.as_u16() as _,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+    use crate::{MemTable, ServiceInner, Storage, Value};
+
+    /// 模拟的网络，在一个线程里按顺序投递消息，可以切断任意两个节点之间的连接
+    struct Cluster {
+        config: RaftConfig,
+        /// 集群第一次启动时的投票成员，节点重启时也使用它，之后的成员变更从快照和日志中恢复
+        voters: Vec<u64>,
+        nodes: BTreeMap<u64, RaftNode>,
+        /// 每个节点保存持久化状态的目录
+        dirs: BTreeMap<u64, TempDir>,
+        /// 被切断的连接 (from, to)
+        cut: HashSet<(u64, u64)>,
+        /// (节点, 请求 id) -> 结果
+        results: HashMap<(u64, u64), CommandResponse>,
+    }
+
+    impl Cluster {
+        fn new(size: u64, config: RaftConfig) -> Self {
+            let mut cluster = Self {
+                config,
+                voters: (1..=size).collect(),
+                nodes: BTreeMap::new(),
+                dirs: BTreeMap::new(),
+                cut: HashSet::new(),
+                results: HashMap::new(),
+            };
+            for id in 1..=size {
+                cluster.add(id, 1..=size);
+            }
+            cluster
+        }
+
+        fn add(&mut self, id: u64, voters: impl IntoIterator<Item = u64>) {
+            let dir = tempdir().unwrap();
+            let node = RaftNode::open(id, voters, service(), self.config, dir.path()).unwrap();
+            self.nodes.insert(id, node);
+            self.dirs.insert(id, dir);
+        }
+
+        /// 模拟节点重启：丢掉内存中的所有状态，用一个新的状态机从磁盘恢复
+        fn restart(&mut self, id: u64, sm: Service) {
+            self.nodes.remove(&id);
+            let dir = self.dirs[&id].path();
+            let voters = self.voters.clone();
+            let node = RaftNode::open(id, voters, sm, self.config, dir).unwrap();
+            self.nodes.insert(id, node);
+        }
+
+        fn node(&mut self, id: u64) -> &mut RaftNode {
+            self.nodes.get_mut(&id).unwrap()
+        }
+
+        fn deliver(&mut self) {
+            loop {
+                let mut messages = Vec::new();
+                for (id, node) in self.nodes.iter_mut() {
+                    messages.extend(node.take_messages());
+                    for (request, res) in node.take_results() {
+                        self.results.insert((*id, request), res);
+                    }
+                }
+                if messages.is_empty() {
+                    break;
+                }
+                for msg in messages {
+                    if self.cut.contains(&(msg.from, msg.to)) {
+                        continue;
+                    }
+                    if let Some(node) = self.nodes.get_mut(&msg.to) {
+                        node.step(msg);
+                    }
+                }
+            }
+        }
+
+        fn tick(&mut self, ticks: usize) {
+            for _ in 0..ticks {
+                for node in self.nodes.values_mut() {
+                    node.tick();
+                }
+                self.deliver();
+            }
+        }
+
+        fn isolate(&mut self, id: u64) {
+            for other in self.nodes.keys().copied().filter(|other| *other != id) {
+                self.cut.insert((id, other));
+                self.cut.insert((other, id));
+            }
+        }
+
+        fn heal(&mut self) {
+            self.cut.clear();
+        }
+
+        /// 等待选出一个不是 exclude 的 leader
+        fn wait_leader(&mut self, exclude: Option<u64>) -> u64 {
+            for _ in 0..200 {
+                let leader = self
+                    .nodes
+                    .values()
+                    .filter(|n| n.role() == Role::Leader && Some(n.id()) != exclude)
+                    .max_by_key(|n| n.term())
+                    .map(|n| n.id());
+                if let Some(leader) = leader {
+                    return leader;
+                }
+                self.tick(1);
+            }
+            panic!("no leader elected");
+        }
+
+        fn wait_result(&mut self, node: u64, id: u64) -> CommandResponse {
+            self.deliver();
+            for _ in 0..100 {
+                if let Some(res) = self.results.remove(&(node, id)) {
+                    return res;
+                }
+                self.tick(1);
+            }
+            panic!("request {} on node {} didn't finish", id, node);
+        }
+
+        fn write(&mut self, node: u64, cmd: CommandRequest) -> CommandResponse {
+            let id = self.node(node).propose(cmd).unwrap();
+            self.wait_result(node, id)
+        }
+
+        fn value(&self, node: u64, key: &str) -> Option<Value> {
+            let sm = self.nodes[&node].state_machine();
+            let res = sm.execute(CommandRequest::new_hget("t1", key));
+            (res.status == 200).then(|| res.values[0].clone())
+        }
+    }
+
+    fn service() -> Service {
+        ServiceInner::new(MemTable::new()).into()
+    }
+
+    fn hset(key: &str, value: &str) -> CommandRequest {
+        CommandRequest::new_hset("t1", key, value.into())
+    }
+
+    #[test]
+    fn leader_should_replicate_writes_to_all_nodes() {
+        let mut cluster = Cluster::new(3, RaftConfig::default());
+        let leader = cluster.wait_leader(None);
+        let res = cluster.write(leader, hset("k1", "v1"));
+        assert_eq!(res.status, 200);
+
+        cluster.tick(5);
+        for id in 1..=3 {
+            assert_eq!(cluster.value(id, "k1"), Some("v1".into()));
+        }
+
+        let follower = (1..=3).find(|id| *id != leader).unwrap();
+        let err = cluster
+            .node(follower)
+            .propose(hset("k1", "v2"))
+            .unwrap_err();
+        assert_eq!(err, KvError::NotLeader(leader.to_string()));
+
+        // 读命令只能通过 read-index 执行
+        let read = CommandRequest::new_hget("t1", "k1");
+        assert!(cluster.node(leader).propose(read).is_err());
+    }
+
+    #[test]
+    fn minority_leader_should_not_commit_writes() {
+        let mut cluster = Cluster::new(3, RaftConfig::default());
+        let old = cluster.wait_leader(None);
+        cluster.isolate(old);
+        let lost = cluster.node(old).propose(hset("k1", "lost")).unwrap();
+
+        let leader = cluster.wait_leader(Some(old));
+        let res = cluster.write(leader, hset("k1", "kept"));
+        assert_eq!(res.status, 200);
+        assert!(!cluster.results.contains_key(&(old, lost)));
+        // 隔离的 leader 收不到多数节点的回复，会主动退位
+        cluster.tick(20);
+        assert_ne!(cluster.node(old).role(), Role::Leader);
+
+        cluster.heal();
+        cluster.tick(50);
+        let res = cluster.results.remove(&(old, lost)).unwrap();
+        assert_eq!(res.status, 500);
+        for id in 1..=3 {
+            assert_eq!(cluster.value(id, "k1"), Some("kept".into()));
+        }
+    }
+
+    #[test]
+    fn read_index_should_not_return_stale_data() {
+        let mut cluster = Cluster::new(3, RaftConfig::default());
+        let old = cluster.wait_leader(None);
+        cluster.write(old, hset("k1", "v1"));
+
+        let id = cluster
+            .node(old)
+            .read(CommandRequest::new_hget("t1", "k1"))
+            .unwrap();
+        let res = cluster.wait_result(old, id);
+        assert_eq!(res.values, vec!["v1".into()]);
+        assert!(cluster.node(old).read(hset("k1", "v2")).is_err());
+
+        // 被隔离的 leader 无法确认自己的身份，读请求不会返回旧的数据
+        cluster.isolate(old);
+        let id = cluster
+            .node(old)
+            .read(CommandRequest::new_hget("t1", "k1"))
+            .unwrap();
+        let leader = cluster.wait_leader(Some(old));
+        cluster.write(leader, hset("k1", "v2"));
+        let res = cluster.wait_result(old, id);
+        assert_eq!(res.status, 307);
+
+        let id = cluster
+            .node(leader)
+            .read(CommandRequest::new_hget("t1", "k1"))
+            .unwrap();
+        let res = cluster.wait_result(leader, id);
+        assert_eq!(res.values, vec!["v2".into()]);
+    }
+
+    #[test]
+    fn snapshot_should_catch_up_lagging_follower() {
+        // 快照分成很多段发送
+        let config = RaftConfig {
+            snapshot_entries: 5,
+            snapshot_chunk_bytes: 16,
+            ..Default::default()
+        };
+        let mut cluster = Cluster::new(3, config);
+        let leader = cluster.wait_leader(None);
+        cluster.write(leader, hset("stale", "v"));
+        cluster.tick(5);
+        let follower = (1..=3).find(|id| *id != leader).unwrap();
+        assert_eq!(cluster.value(follower, "stale"), Some("v".into()));
+
+        cluster.isolate(follower);
+        cluster.write(leader, CommandRequest::new_hdel("t1", "stale"));
+        for i in 0..20 {
+            let res = cluster.write(leader, hset(&format!("key{}", i), "v"));
+            assert_eq!(res.status, 200);
+        }
+        assert!(cluster.node(leader).snapshot_index() > 5);
+
+        cluster.heal();
+        cluster.tick(50);
+        let node = cluster.node(follower);
+        assert!(node.snapshot_index() > 0);
+        assert_eq!(node.applied_index(), cluster.node(leader).applied_index());
+        assert_eq!(cluster.value(follower, "stale"), None);
+        assert_eq!(cluster.value(follower, "key19"), Some("v".into()));
+    }
+
+    #[test]
+    fn membership_changes_should_take_effect_after_commit() {
+        let config = RaftConfig::default();
+        let mut cluster = Cluster::new(3, config);
+        let leader = cluster.wait_leader(None);
+        cluster.write(leader, hset("k1", "v1"));
+
+        // 新节点带着现有的成员启动，等 leader 复制成员变更
+        cluster.add(4, [1, 2, 3]);
+        let id = cluster.node(leader).add_node(4).unwrap();
+        assert!(cluster.node(leader).remove_node(4).is_err());
+        assert_eq!(cluster.wait_result(leader, id).status, 200);
+        cluster.tick(5);
+        assert!(cluster.node(4).voters().contains(&4));
+        assert_eq!(cluster.value(4, "k1"), Some("v1".into()));
+
+        // 删除 leader 自己
+        let id = cluster.node(leader).remove_node(leader).unwrap();
+        assert_eq!(cluster.wait_result(leader, id).status, 200);
+        assert_ne!(cluster.node(leader).role(), Role::Leader);
+
+        let new_leader = cluster.wait_leader(Some(leader));
+        cluster.write(new_leader, hset("k2", "v2"));
+        cluster.tick(5);
+        assert_eq!(cluster.node(new_leader).voters().len(), 3);
+        assert!(!cluster.node(4).voters().contains(&leader));
+        assert_eq!(cluster.value(4, "k2"), Some("v2".into()));
+        assert_eq!(cluster.value(leader, "k2"), None);
+    }
+
+    #[test]
+    fn restarted_node_should_recover_term_vote_and_log() {
+        let mut cluster = Cluster::new(3, RaftConfig::default());
+        let leader = cluster.wait_leader(None);
+        cluster.write(leader, hset("k1", "v1"));
+        cluster.tick(5);
+
+        // 所有节点一起重启，数据只能从日志中恢复
+        let term = cluster.node(leader).term();
+        for id in 1..=3 {
+            cluster.restart(id, service());
+            assert_eq!(cluster.node(id).term(), term);
+            assert_eq!(cluster.value(id, "k1"), None);
+        }
+        // 同一个 term 已经投过票，不会再投给别人
+        let voted = cluster.nodes[&leader].voted_for;
+        assert_eq!(voted, Some(leader));
+
+        let leader = cluster.wait_leader(None);
+        assert!(cluster.node(leader).term() > term);
+        cluster.write(leader, hset("k2", "v2"));
+        cluster.tick(5);
+        for id in 1..=3 {
+            assert_eq!(cluster.value(id, "k1"), Some("v1".into()));
+            assert_eq!(cluster.value(id, "k2"), Some("v2".into()));
+        }
+    }
+
+    #[test]
+    fn restarted_node_should_restore_snapshot_in_place() {
+        let config = RaftConfig {
+            snapshot_entries: 5,
+            ..Default::default()
+        };
+        let mut cluster = Cluster::new(3, config);
+        let leader = cluster.wait_leader(None);
+        for i in 0..10 {
+            cluster.write(leader, hset(&format!("key{}", i), "v"));
+        }
+        cluster.tick(5);
+        let follower = (1..=3).find(|id| *id != leader).unwrap();
+        let snapshot = cluster.node(follower).snapshot_index();
+        assert!(snapshot > 5);
+
+        // 状态机中有快照里没有的数据（比如上次恢复到一半），会被快照替换掉
+        let store = MemTable::new();
+        store.set("t1", "stale", "v").unwrap();
+        store.set("t1", "key0", "stale").unwrap();
+        cluster.restart(follower, ServiceInner::new(store).into());
+        let node = cluster.node(follower);
+        assert_eq!(node.applied_index(), snapshot);
+        assert_eq!(node.voters().len(), 3);
+        assert_eq!(cluster.value(follower, "stale"), None);
+        assert_eq!(cluster.value(follower, "key0"), Some("v".into()));
+
+        cluster.tick(5);
+        assert_eq!(cluster.value(follower, "key9"), Some("v".into()));
+    }
+}
@@ -0,0 +1,432 @@
+use std::{
+    collections::HashMap,
+    sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use http::StatusCode;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use super::{Message, RaftNode, StateMachine};
+use crate::{
+    value, CommandRequest, CommandResponse, FrameStream, KvError, RaftMessage, RaftServerConfig,
+    Service, Storage,
+};
+
+/// 每个节点的发送队列长度，队列满了之后丢弃消息，Raft 会重新发送
+const PEER_QUEUE: usize = 1024;
+
+/// 交给运行 RaftNode 的线程处理的输入
+enum Input {
+    Step(Message),
+    Propose(CommandRequest, SyncSender<CommandResponse>),
+    Read(CommandRequest, SyncSender<CommandResponse>),
+    AddNode(u64, SyncSender<CommandResponse>),
+    RemoveNode(u64, SyncSender<CommandResponse>),
+}
+
+/// Service 把命令交给 Raft 节点的句柄，用 `ServiceInner::raft` 注册
+///
+/// 所有方法都会阻塞到命令完成或者超时，在 async 代码中需要放到 blocking 线程池中调用。
+#[derive(Clone)]
+pub struct RaftHandle {
+    tx: Sender<Input>,
+    timeout: Duration,
+}
+
+impl RaftHandle {
+    /// 提交一个写命令，在多数节点上提交并且应用之后返回执行结果
+    pub fn propose(&self, cmd: CommandRequest) -> CommandResponse {
+        self.call(|tx| Input::Propose(cmd, tx))
+    }
+
+    /// 线性一致地执行一个读命令
+    pub fn read(&self, cmd: CommandRequest) -> CommandResponse {
+        self.call(|tx| Input::Read(cmd, tx))
+    }
+
+    /// 增加一个投票成员，它需要已经配置在所有节点的 peers 中
+    pub fn add_node(&self, id: u64) -> CommandResponse {
+        self.call(|tx| Input::AddNode(id, tx))
+    }
+
+    /// 删除一个投票成员
+    pub fn remove_node(&self, id: u64) -> CommandResponse {
+        self.call(|tx| Input::RemoveNode(id, tx))
+    }
+
+    fn call(&self, input: impl FnOnce(SyncSender<CommandResponse>) -> Input) -> CommandResponse {
+        let (tx, rx) = sync_channel(1);
+        if self.tx.send(input(tx)).is_err() {
+            return KvError::ShuttingDown.into();
+        }
+        match rx.recv_timeout(self.timeout) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => {
+                KvError::Internal("Raft request timed out".into()).into()
+            }
+            Err(RecvTimeoutError::Disconnected) => KvError::ShuttingDown.into(),
+        }
+    }
+}
+
+/// 以 Raft 集群的成员运行 Service：RaftNode 在一个单独的线程里运行（写日志需要 fsync），
+/// 节点之间的消息通过 TCP 上的 `RaftMessage` frame 传递
+///
+/// 先用 `handle` 创建 Service，再用这个 Service 调用 `start`，见 examples/server_with_raft.rs。
+pub struct RaftServer {
+    config: RaftServerConfig,
+    tx: Sender<Input>,
+    rx: Receiver<Input>,
+    shutdown: CancellationToken,
+}
+
+impl RaftServer {
+    pub fn new(config: RaftServerConfig) -> Self {
+        let (tx, rx) = channel();
+        Self {
+            config,
+            tx,
+            rx,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// token 被取消之后停止节点和所有的连接
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// 创建 Service 时传给 `ServiceInner::raft`
+    pub fn handle(&self) -> RaftHandle {
+        RaftHandle {
+            tx: self.tx.clone(),
+            timeout: Duration::from_millis(self.config.request_timeout_ms),
+        }
+    }
+
+    /// 监听 raft_addr，打开节点并从快照恢复 Service 的数据，然后在后台运行
+    pub async fn start<Store>(self, service: Service<Store>) -> Result<(), KvError>
+    where
+        Store: Storage + Send + Sync + 'static,
+    {
+        let config = self.config;
+        let me = config
+            .peers
+            .iter()
+            .find(|p| p.id == config.id)
+            .ok_or_else(|| KvError::ConfigError(format!("Node {} is not in peers", config.id)))?;
+        let listener = TcpListener::bind(&me.raft_addr).await?;
+        info!("Raft node {} listening on {}", config.id, me.raft_addr);
+        tokio::spawn(accept(listener, self.tx, self.shutdown.clone()));
+
+        let tick = Duration::from_millis(config.tick_ms);
+        let retry = tick * config.raft.election_ticks as u32;
+        let mut peers = HashMap::new();
+        for peer in config.peers.iter().filter(|p| p.id != config.id) {
+            let (tx, rx) = mpsc::channel(PEER_QUEUE);
+            let (addr, shutdown) = (peer.raft_addr.clone(), self.shutdown.clone());
+            tokio::spawn(send_to_peer(addr, rx, retry, shutdown));
+            peers.insert(peer.id, tx);
+        }
+
+        let voters = match config.voters.is_empty() {
+            true => config.peers.iter().map(|p| p.id).collect(),
+            false => config.voters.clone(),
+        };
+        let driver = Driver {
+            addrs: config
+                .peers
+                .iter()
+                .map(|p| (p.id, p.addr.clone()))
+                .collect(),
+            peers,
+            waiting: HashMap::new(),
+            timeout: Duration::from_millis(config.request_timeout_ms),
+        };
+        // 从快照恢复数据可能需要很久，也在节点的线程里执行
+        let (opened, result) = oneshot::channel();
+        let shutdown = self.shutdown.clone();
+        let rx = self.rx;
+        thread::Builder::new()
+            .name(format!("raft-{}", config.id))
+            .spawn(move || {
+                let node = RaftNode::open(config.id, voters, service, config.raft, &config.dir);
+                match node {
+                    Ok(node) => {
+                        let _ = opened.send(Ok(()));
+                        driver.run(node, rx, tick, shutdown);
+                    }
+                    Err(e) => {
+                        let _ = opened.send(Err(e));
+                    }
+                }
+            })?;
+
+        let result = result
+            .await
+            .unwrap_or_else(|_| Err(KvError::Internal("Raft node thread panicked".into())));
+        if result.is_err() {
+            self.shutdown.cancel();
+        }
+        result
+    }
+}
+
+/// 运行 RaftNode 的线程的状态
+struct Driver {
+    /// 节点 id -> 客户端访问的地址
+    addrs: HashMap<u64, String>,
+    peers: HashMap<u64, mpsc::Sender<RaftMessage>>,
+    /// 请求 id -> (等待结果的调用者, 调用者放弃等待的时间)
+    waiting: HashMap<u64, (SyncSender<CommandResponse>, Instant)>,
+    timeout: Duration,
+}
+
+impl Driver {
+    fn run<SM: StateMachine>(
+        mut self,
+        mut node: RaftNode<SM>,
+        rx: Receiver<Input>,
+        tick: Duration,
+        shutdown: CancellationToken,
+    ) {
+        let mut next_tick = Instant::now() + tick;
+        while !shutdown.is_cancelled() {
+            match rx.recv_timeout(next_tick.saturating_duration_since(Instant::now())) {
+                Ok(input) => self.handle(&mut node, input),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            let now = Instant::now();
+            if now >= next_tick {
+                node.tick();
+                next_tick = now + tick;
+                // 调用者已经超时的请求不再等待结果
+                self.waiting.retain(|_, (_, deadline)| *deadline > now);
+            }
+            for msg in node.take_messages() {
+                if let Some(peer) = self.peers.get(&msg.to) {
+                    let _ = peer.try_send(msg.into());
+                }
+            }
+            for (id, res) in node.take_results() {
+                if let Some((tx, _)) = self.waiting.remove(&id) {
+                    let _ = tx.try_send(self.redirect(res));
+                }
+            }
+        }
+        debug!("Raft node {} stopped", node.id());
+    }
+
+    fn handle<SM: StateMachine>(&mut self, node: &mut RaftNode<SM>, input: Input) {
+        let (result, tx) = match input {
+            Input::Step(msg) => return node.step(msg),
+            Input::Propose(cmd, tx) => (node.propose(cmd), tx),
+            Input::Read(cmd, tx) => (node.read(cmd), tx),
+            Input::AddNode(id, tx) => (node.add_node(id), tx),
+            Input::RemoveNode(id, tx) => (node.remove_node(id), tx),
+        };
+        match result {
+            Ok(id) => {
+                self.waiting.insert(id, (tx, Instant::now() + self.timeout));
+            }
+            Err(e) => {
+                let _ = tx.try_send(self.redirect(e.into()));
+            }
+        }
+    }
+
+    /// RaftNode 返回的 leader 是节点的 id，换成客户端访问的地址
+    fn redirect(&self, res: CommandResponse) -> CommandResponse {
+        if res.status != StatusCode::TEMPORARY_REDIRECT.as_u16() as u32 {
+            return res;
+        }
+        let addr = match res.values.first().and_then(|v| v.value.as_ref()) {
+            Some(value::Value::String(id)) => id.parse().ok().and_then(|id| self.addrs.get(&id)),
+            _ => None,
+        };
+        match addr {
+            Some(addr) => KvError::NotLeader(addr.clone()).into(),
+            None => res,
+        }
+    }
+}
+
+/// 接收其它节点发来的消息，交给节点的线程处理
+async fn accept(listener: TcpListener, tx: Sender<Input>, shutdown: CancellationToken) {
+    loop {
+        let stream = tokio::select! {
+            _ = shutdown.cancelled() => return,
+            result = listener.accept() => match result {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept raft connection: {}", e);
+                    continue;
+                }
+            },
+        };
+        let (tx, shutdown) = (tx.clone(), shutdown.clone());
+        tokio::spawn(async move {
+            let mut stream = FrameStream::new(stream);
+            loop {
+                let msg = tokio::select! {
+                    _ = shutdown.cancelled() => return,
+                    msg = stream.recv::<RaftMessage>() => msg,
+                };
+                let msg = match msg.and_then(|msg| msg.map(Message::try_from).transpose()) {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => return,
+                    Err(e) => {
+                        warn!("Failed to receive raft message: {}", e);
+                        return;
+                    }
+                };
+                if tx.send(Input::Step(msg)).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// 把消息按顺序发给一个节点，连接断开之后自动重连
+async fn send_to_peer(
+    addr: String,
+    mut rx: mpsc::Receiver<RaftMessage>,
+    retry: Duration,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let stream = match timeout(retry, TcpStream::connect(&addr)).await {
+            Ok(Ok(stream)) => Some(FrameStream::new(stream)),
+            Ok(Err(e)) => {
+                debug!("Failed to connect to raft peer {}: {}", addr, e);
+                None
+            }
+            Err(_) => None,
+        };
+        if let Some(mut stream) = stream {
+            loop {
+                let msg = tokio::select! {
+                    _ = shutdown.cancelled() => return,
+                    msg = rx.recv() => match msg {
+                        Some(msg) => msg,
+                        None => return,
+                    },
+                };
+                if let Err(e) = stream.send(&msg).await {
+                    debug!("Failed to send to raft peer {}: {}", addr, e);
+                    break;
+                }
+            }
+        }
+
+        // 节点不可达时，积压的消息已经没有用了，重连之后 Raft 会发送新的消息
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = sleep(retry) => {}
+        }
+        while rx.try_recv().is_ok() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{
+        assert_res_ok, CdcConfig, ChangeLog, MemTable, RaftConfig, RaftPeer, ServiceInner, Session,
+        Value,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn raft_servers_should_replicate_over_tcp() -> Result<()> {
+        // 先占用端口再释放，得到三个空闲的地址
+        let mut peers = Vec::new();
+        for id in 1..=3 {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+            peers.push(RaftPeer {
+                id,
+                raft_addr: listener.local_addr()?.to_string(),
+                addr: format!("client-{}", id),
+            });
+        }
+
+        let dir = tempdir()?;
+        let shutdown = CancellationToken::new();
+        let mut services = Vec::new();
+        for id in 1..=3 {
+            let path = dir.path().join(id.to_string());
+            let config = RaftServerConfig {
+                tick_ms: 10,
+                raft: RaftConfig {
+                    snapshot_entries: 5,
+                    ..Default::default()
+                },
+                ..RaftServerConfig::new(id, path.join("raft").to_str().unwrap(), peers.clone())
+            };
+            let log = ChangeLog::open(CdcConfig::new(path.join("cdc").to_str().unwrap()))?;
+            let raft = RaftServer::new(config).with_shutdown(shutdown.clone());
+            let service: Service = ServiceInner::new(MemTable::new())
+                .changelog(log)
+                .raft(raft.handle())
+                .into();
+            raft.start(service.clone()).await?;
+            services.push(service);
+        }
+
+        // 等待选出 leader，follower 把写命令重定向到 leader 的客户端地址
+        let mut session = Session::default();
+        let leader = timeout(Duration::from_secs(10), async {
+            loop {
+                for (i, service) in services.iter().enumerate() {
+                    let cmd = CommandRequest::new_hset("t1", "k0", "v0".into());
+                    if service.execute_async(cmd, &mut session).await.status == 200 {
+                        return i;
+                    }
+                }
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await?;
+        for i in 1..=10 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i.into());
+            let res = services[leader].execute_async(cmd, &mut session).await;
+            assert_eq!(res.status, 200);
+        }
+        let cmd = CommandRequest::new_hget("t1", "k10");
+        let res = services[leader].execute_async(cmd, &mut session).await;
+        assert_res_ok(res, &[10.into()], &[]);
+
+        let follower = (leader + 1) % 3;
+        let cmd = CommandRequest::new_hget("t1", "k10");
+        let res = services[follower].execute_async(cmd, &mut session).await;
+        assert_eq!(res.status, 307);
+        let addr = format!("client-{}", leader + 1);
+        assert_eq!(res.values, vec![Value::from(addr)]);
+
+        // 所有节点都通过 Service 应用条目，变更日志里有每一次写入
+        for service in &services {
+            timeout(Duration::from_secs(5), async {
+                while service.changelog().unwrap().last_offset() < 11 {
+                    sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await?;
+        }
+        shutdown.cancel();
+        Ok(())
+    }
+}
@@ -0,0 +1,23 @@
+use crate::{Change, CommandRequest, CommandResponse, KvError};
+
+/// Raft 的状态机，所有节点按相同的顺序应用相同的命令，得到相同的数据
+///
+/// `Service` 实现了这个 trait，应用的命令和普通的写命令一样生成变更日志和 watch 事件。
+pub trait StateMachine {
+    /// 应用一个已经提交的写命令
+    fn apply(&self, cmd: CommandRequest) -> CommandResponse;
+
+    /// 执行一个只读命令，leader 确认了自己的身份并且应用到读请求的 index 之后才会调用
+    fn query(&self, cmd: CommandRequest) -> CommandResponse;
+
+    /// 按 (table, key) 的顺序输出所有数据，用来生成快照
+    fn dump(&self, f: &mut dyn FnMut(Change) -> Result<(), KvError>) -> Result<(), KvError>;
+
+    /// 用快照中的一页替换这一页范围内的数据，after 是上一页最后的 (table, key)，last 表示最后一页
+    fn restore(
+        &self,
+        after: Option<(&str, &str)>,
+        changes: Vec<Change>,
+        last: bool,
+    ) -> Result<(), KvError>;
+}
@@ -0,0 +1,269 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use bytes::Bytes;
+use prost::Message;
+
+use super::{Entry, StateMachine};
+use crate::{service::read_length, Change, KvError, RaftEntry, RaftHardState, RaftSnapshotMeta};
+
+const STATE_FILE: &str = "state";
+const LOG_FILE: &str = "log";
+const SNAPSHOT_FILE: &str = "snapshot";
+/// 正在接收的 leader 的快照，收完之后改名成 SNAPSHOT_FILE
+const RECEIVE_FILE: &str = "snapshot.recv";
+/// 从快照恢复状态机时每页的记录数
+const RESTORE_PAGE: usize = 1000;
+
+/// Raft 节点保存在一个目录中的持久化状态
+///
+/// - state：RaftHardState，先写入临时文件再改名
+/// - log：快照之后的日志条目，每次追加之后 fsync
+/// - snapshot：RaftSnapshotMeta 加上按 (table, key) 排序的数据
+///
+/// 快照和日志分两步更新，中间崩溃的话，重启时丢掉日志中已经包含在快照里的条目。
+pub(crate) struct RaftStorage {
+    dir: PathBuf,
+    log: File,
+    /// log 文件中每个条目结束的位置，用来截断
+    ends: Vec<u64>,
+}
+
+impl RaftStorage {
+    /// 打开目录，返回保存的 term 和投票，以及日志中所有完整的条目
+    pub fn open(dir: impl AsRef<Path>) -> Result<(Self, RaftHardState, Vec<Entry>), KvError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(data) => RaftHardState::decode(data.as_slice())?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => RaftHardState::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let path = dir.join(LOG_FILE);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut buf = data.as_slice();
+        let (mut entries, mut ends) = (Vec::new(), Vec::new());
+        // 最后一个条目可能没有写完，丢掉它以及之后的数据
+        while !buf.is_empty() {
+            let mut next = buf;
+            match RaftEntry::decode_length_delimited(&mut next) {
+                Ok(entry) => entries.push(Entry::try_from(entry)?),
+                Err(_) => break,
+            }
+            buf = next;
+            ends.push((data.len() - buf.len()) as u64);
+        }
+
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        log.set_len(ends.last().copied().unwrap_or_default())?;
+        Ok((Self { dir, log, ends }, state, entries))
+    }
+
+    /// 保存 term 和投票，返回之后才能发出依赖它们的消息
+    pub fn save_state(&self, state: &RaftHardState) -> Result<(), KvError> {
+        let tmp = self.dir.join(format!("{}.tmp", STATE_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&state.encode_to_vec())?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(STATE_FILE))?;
+        sync_dir(&self.dir);
+        Ok(())
+    }
+
+    /// 在日志文件末尾追加条目并 fsync
+    pub fn append(&mut self, entries: &[Entry]) -> Result<(), KvError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let base = self.file_len();
+        let mut buf = Vec::new();
+        let mut ends = Vec::with_capacity(entries.len());
+        for entry in entries {
+            RaftEntry::from(entry.clone()).encode_length_delimited(&mut buf)?;
+            ends.push(base + buf.len() as u64);
+        }
+        if let Err(e) = self.log.write_all(&buf).and_then(|_| self.log.sync_data()) {
+            // 截断没有写完的条目，否则重启之后会读到它们
+            let _ = self.log.set_len(base);
+            return Err(e.into());
+        }
+        self.ends.extend(ends);
+        Ok(())
+    }
+
+    /// 只保留日志文件中前 len 个条目
+    pub fn truncate(&mut self, len: usize) -> Result<(), KvError> {
+        if len >= self.ends.len() {
+            return Ok(());
+        }
+        self.ends.truncate(len);
+        self.log.set_len(self.file_len())?;
+        self.log.sync_data()?;
+        Ok(())
+    }
+
+    /// 压缩日志或者安装快照之后，用剩下的条目重写日志文件
+    pub fn rewrite(&mut self, entries: &[Entry]) -> Result<(), KvError> {
+        let mut buf = Vec::new();
+        let mut ends = Vec::with_capacity(entries.len());
+        for entry in entries {
+            RaftEntry::from(entry.clone()).encode_length_delimited(&mut buf)?;
+            ends.push(buf.len() as u64);
+        }
+
+        let path = self.dir.join(LOG_FILE);
+        let tmp = self.dir.join(format!("{}.tmp", LOG_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        sync_dir(&self.dir);
+
+        self.log = OpenOptions::new().append(true).open(&path)?;
+        self.ends = ends;
+        Ok(())
+    }
+
+    /// 把状态机的所有数据写入快照文件，写完之后才替换原来的快照
+    pub fn write_snapshot(
+        &self,
+        meta: &RaftSnapshotMeta,
+        sm: &impl StateMachine,
+    ) -> Result<(), KvError> {
+        let tmp = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let mut buf = Vec::new();
+        meta.encode_length_delimited(&mut buf)?;
+        writer.write_all(&buf)?;
+        sm.dump(&mut |change: Change| {
+            buf.clear();
+            change.encode_length_delimited(&mut buf)?;
+            Ok(writer.write_all(&buf)?)
+        })?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT_FILE))?;
+        sync_dir(&self.dir);
+        Ok(())
+    }
+
+    /// 用快照文件分页替换状态机中的数据，返回快照的元数据，还没有快照时清空状态机
+    ///
+    /// 每一页只替换自己范围内的数据，中途崩溃的话，重启之后再从快照文件恢复一次即可。
+    pub fn restore_snapshot(
+        &self,
+        sm: &impl StateMachine,
+    ) -> Result<Option<RaftSnapshotMeta>, KvError> {
+        let file = match File::open(self.dir.join(SNAPSHOT_FILE)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                sm.restore(None, Vec::new(), true)?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut reader = BufReader::new(file);
+        let mut buf = Vec::new();
+        let meta: RaftSnapshotMeta = read_message(&mut reader, &mut buf)?
+            .ok_or_else(|| KvError::Internal("Raft snapshot is empty".into()))?;
+
+        let mut cursor: Option<(String, String)> = None;
+        let mut next: Option<Change> = read_message(&mut reader, &mut buf)?;
+        loop {
+            let mut page = Vec::with_capacity(RESTORE_PAGE);
+            while page.len() < RESTORE_PAGE {
+                match next.take() {
+                    Some(change) => page.push(change),
+                    None => break,
+                }
+                next = read_message(&mut reader, &mut buf)?;
+            }
+            let last = next.is_none();
+            let end = page.last().map(|c| (c.table.clone(), c.key.clone()));
+            let after = cursor.as_ref().map(|(t, k)| (t.as_str(), k.as_str()));
+            sm.restore(after, page, last)?;
+            if last {
+                return Ok(Some(meta));
+            }
+            cursor = end;
+        }
+    }
+
+    /// 读取快照文件从 offset 开始最多 max 个字节，返回数据以及是否已经读到结尾
+    pub fn read_snapshot_chunk(&self, offset: u64, max: usize) -> Result<(Bytes, bool), KvError> {
+        let mut file = File::open(self.dir.join(SNAPSHOT_FILE))?;
+        let len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::with_capacity(max.min(len.saturating_sub(offset) as usize));
+        file.take(max as u64).read_to_end(&mut data)?;
+        let done = offset + data.len() as u64 >= len;
+        Ok((data.into(), done))
+    }
+
+    /// 把 leader 发来的一段快照写入临时文件，offset 为 0 时重新开始
+    pub fn receive_snapshot_chunk(&self, offset: u64, data: &[u8]) -> Result<(), KvError> {
+        let path = self.dir.join(RECEIVE_FILE);
+        let mut file = match offset {
+            0 => File::create(&path)?,
+            _ => OpenOptions::new().append(true).open(&path)?,
+        };
+        if file.metadata()?.len() != offset {
+            return Err(KvError::Internal(
+                "Raft snapshot chunk is out of order".into(),
+            ));
+        }
+        file.write_all(data)?;
+        Ok(())
+    }
+
+    /// 收完 leader 的快照之后，用它替换本地的快照
+    pub fn finish_receive(&self) -> Result<(), KvError> {
+        let path = self.dir.join(RECEIVE_FILE);
+        File::open(&path)?.sync_all()?;
+        fs::rename(&path, self.dir.join(SNAPSHOT_FILE))?;
+        sync_dir(&self.dir);
+        Ok(())
+    }
+
+    fn file_len(&self) -> u64 {
+        self.ends.last().copied().unwrap_or_default()
+    }
+}
+
+/// 持久化失败之后，内存中的状态和磁盘已经不一致，继续运行可能违反 Raft 的安全性，只能停止节点
+pub(crate) fn persist<T>(result: Result<T, KvError>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => panic!("Failed to persist raft state: {}", e),
+    }
+}
+
+/// 改名之后 fsync 目录，保证重启之后能看到新的文件；Windows 上不能打开目录，忽略错误
+fn sync_dir(dir: &Path) {
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+/// 读取下一个 length-delimited 的消息，读完时返回 None
+fn read_message<M: Message + Default>(
+    reader: &mut impl Read,
+    buf: &mut Vec<u8>,
+) -> Result<Option<M>, KvError> {
+    let len = match read_length(reader)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    buf.resize(len as usize, 0);
+    reader.read_exact(buf)?;
+    Ok(Some(M::decode(buf.as_slice())?))
+}
@@ -0,0 +1,344 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use http::StatusCode;
+use tokio::{net::TcpStream, time::sleep};
+use tracing::{info, warn};
+
+use crate::{
+    value, CommandRequest, CommandResponse, KvError, MemTable, ProstClientStream,
+    ReplicationConfig, Service, Storage,
+};
+
+/// follower 的复制状态
+#[derive(Debug, Default)]
+pub struct ReplicationStats {
+    /// 是否和 leader 保持着连接
+    pub connected: AtomicBool,
+    /// 最近一次从 leader 得知的最新 offset
+    pub leader_offset: AtomicU64,
+    /// 已经应用的最后一个 offset
+    pub applied_offset: AtomicU64,
+    /// 加载快照的次数，follower 落后太多（leader 已经清理了需要的变更）时会重新加载快照
+    pub snapshots: AtomicU64,
+}
+
+impl ReplicationStats {
+    /// 落后 leader 的变更数
+    pub fn lag(&self) -> u64 {
+        let leader = self.leader_offset.load(Ordering::Relaxed);
+        leader.saturating_sub(self.applied_offset.load(Ordering::Relaxed))
+    }
+}
+
+/// follower 的复制任务：从 leader 获取快照，然后不断读取 leader 的变更日志并应用到本地
+///
+/// 已经应用的 offset 保存在 Service 的 ReplicationStats 里，follower 进程重启之后会重新加载快照。
+pub struct Follower<Store: Storage = MemTable> {
+    service: Service<Store>,
+    config: ReplicationConfig,
+}
+
+impl<Store: Storage> Follower<Store> {
+    /// service 需要用 `ServiceInner::follower` 创建
+    pub fn new(service: Service<Store>, config: ReplicationConfig) -> Result<Self, KvError> {
+        if service.replication().is_none() {
+            let msg = "Service is not created as a follower".into();
+            return Err(KvError::ConfigError(msg));
+        }
+        Ok(Self { service, config })
+    }
+
+    /// 一直运行，和 leader 的连接断开之后会自动重连
+    pub async fn run(self) {
+        let retry = Duration::from_millis(self.config.retry_interval_ms);
+        // 重连之后，如果 leader 还保留着需要的变更，就不需要重新加载快照
+        let mut need_snapshot = self.stats().applied_offset.load(Ordering::Relaxed) == 0;
+        loop {
+            if let Err(e) = self.replicate(&mut need_snapshot).await {
+                warn!("Replication from {} failed: {}", self.config.leader, e);
+            }
+            self.stats().connected.store(false, Ordering::Relaxed);
+            sleep(retry).await;
+        }
+    }
+
+    async fn replicate(&self, need_snapshot: &mut bool) -> Result<(), KvError> {
+        let stream = TcpStream::connect(&self.config.leader).await?;
+        let mut client = ProstClientStream::new(stream);
+        if let (Some(username), Some(password)) = (&self.config.username, &self.config.password) {
+            let res = client
+                .execute(CommandRequest::new_auth(username, password))
+                .await?;
+            check_status(&res)?;
+        }
+        info!("Connected to leader {}", self.config.leader);
+        self.stats().connected.store(true, Ordering::Relaxed);
+
+        let poll = Duration::from_millis(self.config.poll_interval_ms);
+        loop {
+            if *need_snapshot {
+                let offset = self.load_snapshot(&mut client).await?;
+                self.stats().applied_offset.store(offset, Ordering::Relaxed);
+                self.stats().snapshots.fetch_add(1, Ordering::Relaxed);
+                *need_snapshot = false;
+                info!("Loaded snapshot at offset {} from leader", offset);
+            }
+
+            let from = self.stats().applied_offset.load(Ordering::Relaxed) + 1;
+            let cmd = CommandRequest::new_read_changes(from, self.config.batch);
+            let res = client.execute(cmd).await?;
+            if res.status == StatusCode::GONE.as_u16() as u32 {
+                warn!(
+                    "Follower is too far behind: {}, reloading snapshot",
+                    res.message
+                );
+                *need_snapshot = true;
+                continue;
+            }
+            check_status(&res)?;
+            self.stats()
+                .leader_offset
+                .store(offset(&res)?, Ordering::Relaxed);
+
+            let last = match res.changes.last() {
+                Some(change) => change.offset,
+                None => {
+                    sleep(poll).await;
+                    continue;
+                }
+            };
+            self.service.apply_changes(res.changes)?;
+            self.stats().applied_offset.store(last, Ordering::Relaxed);
+        }
+    }
+
+    /// 分页加载 leader 的快照，返回第一页的 offset，之后的变更从这个 offset 开始应用
+    async fn load_snapshot(
+        &self,
+        client: &mut ProstClientStream<TcpStream>,
+    ) -> Result<u64, KvError> {
+        let mut cursor: Option<(String, String)> = None;
+        let mut first = None;
+        loop {
+            let (table, key) = cursor.clone().unwrap_or_default();
+            let cmd = CommandRequest::new_snapshot(table, key, self.config.batch);
+            let res = client.execute(cmd).await?;
+            check_status(&res)?;
+            let offset = *first.get_or_insert(offset(&res)?);
+            let more = matches!(
+                res.values.get(1).and_then(|v| v.value.as_ref()),
+                Some(value::Value::Bool(true))
+            );
+            let next = res.changes.last().map(|c| (c.table.clone(), c.key.clone()));
+            let after = cursor.as_ref().map(|(t, k)| (t.as_str(), k.as_str()));
+            self.service.load_snapshot_page(after, res.changes, !more)?;
+            if !more {
+                return Ok(offset);
+            }
+            cursor = next.or(cursor);
+        }
+    }
+
+    fn stats(&self) -> &ReplicationStats {
+        self.service.replication().unwrap()
+    }
+}
+
+fn check_status(res: &CommandResponse) -> Result<(), KvError> {
+    if res.status == StatusCode::OK.as_u16() as u32 {
+        Ok(())
+    } else {
+        Err(KvError::Internal(format!(
+            "Leader returned {}: {}",
+            res.status, res.message
+        )))
+    }
+}
+
+/// Snapshot 和 ReadChanges 在 values[0] 中返回 offset
+fn offset(res: &CommandResponse) -> Result<u64, KvError> {
+    match res.values.first().and_then(|v| v.value.as_ref()) {
+        Some(value::Value::Integer(offset)) => Ok(*offset as u64),
+        _ => Err(KvError::Internal("Leader didn't return the offset".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use anyhow::Result;
+    use tempfile::{tempdir, TempDir};
+    use tokio::{net::TcpListener, task::JoinHandle, time::timeout};
+
+    use super::*;
+    use crate::{
+        assert_res_ok, CdcConfig, ChangeLog, Metrics, ProstServerStream, ServiceInner, Session,
+        Value,
+    };
+
+    async fn serve(service: Service) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = listener.accept().await.unwrap();
+                let server = ProstServerStream::new(stream, service.clone())
+                    .with_session(Session::new(Some(peer)));
+                tokio::spawn(server.process());
+            }
+        });
+        Ok(addr)
+    }
+
+    async fn start_leader(segment_bytes: u64) -> Result<(Service, SocketAddr, TempDir)> {
+        let dir = tempdir()?;
+        let log = ChangeLog::open(CdcConfig {
+            fsync: false,
+            segment_bytes,
+            retention_bytes: Some(segment_bytes),
+            ..CdcConfig::new(dir.path().to_str().unwrap())
+        })?;
+        let service: Service = ServiceInner::new(MemTable::new()).changelog(log).into();
+        let addr = serve(service.clone()).await?;
+        Ok((service, addr, dir))
+    }
+
+    fn start_follower(leader: SocketAddr, metrics: Arc<Metrics>) -> Result<Service> {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .follower(leader.to_string())
+            .metrics(metrics)
+            .into();
+        spawn_follower(&service, leader)?;
+        Ok(service)
+    }
+
+    fn spawn_follower(service: &Service, leader: SocketAddr) -> Result<JoinHandle<()>> {
+        let config = ReplicationConfig {
+            poll_interval_ms: 10,
+            retry_interval_ms: 10,
+            ..ReplicationConfig::new(leader.to_string())
+        };
+        Ok(tokio::spawn(Follower::new(service.clone(), config)?.run()))
+    }
+
+    /// 等待 follower 追上 leader
+    async fn wait_for(follower: &Service, offset: u64) -> Result<()> {
+        let stats = follower.replication().unwrap();
+        timeout(Duration::from_secs(5), async {
+            while stats.applied_offset.load(Ordering::Relaxed) < offset {
+                sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn follower_should_replicate_snapshot_and_changes() -> Result<()> {
+        let (leader, addr, _dir) = start_leader(4 * 1024 * 1024).await?;
+        leader.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        leader.execute(CommandRequest::new_hset("t2", "k1", 1.into()));
+
+        let metrics = Arc::new(Metrics::new());
+        let follower = start_follower(addr, metrics.clone())?;
+        wait_for(&follower, 2).await?;
+        let res = follower.execute(CommandRequest::new_hget("t2", "k1"));
+        assert_res_ok(res, &[1.into()], &[]);
+
+        leader.execute(CommandRequest::new_hset("t1", "k2", "v2".into()));
+        leader.execute(CommandRequest::new_hdel("t1", "k1"));
+        wait_for(&follower, 4).await?;
+        let res = follower.execute(CommandRequest::new_hget_all("t1"));
+        assert_res_ok(res, &[], &[crate::Kvpair::new("k2", "v2".into())]);
+
+        let stats = follower.replication().unwrap();
+        assert!(stats.connected.load(Ordering::Relaxed));
+        assert_eq!(stats.snapshots.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.lag(), 0);
+        let text = metrics.render(&follower).await?;
+        assert!(text.contains("kv_replication_applied_offset 4"));
+        assert!(text.contains("kv_replication_lag 0"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn follower_should_redirect_writes_to_leader() -> Result<()> {
+        let (_leader, addr, _dir) = start_leader(4 * 1024 * 1024).await?;
+        let follower = start_follower(addr, Arc::new(Metrics::new()))?;
+
+        let res = follower.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        assert_eq!(res.status, 307);
+        assert_eq!(res.values, vec![Value::from(addr.to_string())]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn follower_should_reload_snapshot_when_too_far_behind() -> Result<()> {
+        let (leader, addr, _dir) = start_leader(64).await?;
+        leader.execute(CommandRequest::new_hset("t1", "stale", 0.into()));
+        let follower: Service = ServiceInner::new(MemTable::new())
+            .follower(addr.to_string())
+            .into();
+        let handle = spawn_follower(&follower, addr)?;
+        wait_for(&follower, 1).await?;
+
+        // 模拟 follower 停止了一段时间，leader 已经清理了它需要的变更
+        handle.abort();
+        leader.execute(CommandRequest::new_hdel("t1", "stale"));
+        for i in 0..20 {
+            let cmd = CommandRequest::new_hset("t1", format!("key{}", i), i.into());
+            leader.execute(cmd);
+        }
+        assert!(leader.changelog().unwrap().first_offset() > 2);
+
+        spawn_follower(&follower, addr)?;
+        let last = leader.changelog().unwrap().last_offset();
+        wait_for(&follower, last).await?;
+
+        let stats = follower.replication().unwrap();
+        assert_eq!(stats.snapshots.load(Ordering::Relaxed), 2);
+        let res = follower.execute(CommandRequest::new_hget("t1", "stale"));
+        assert_eq!(res.status, 404);
+        let res = follower.execute(CommandRequest::new_hget("t1", "key19"));
+        assert_res_ok(res, &[19.into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn snapshot_should_be_loaded_in_pages() -> Result<()> {
+        let (leader, addr, _dir) = start_leader(4 * 1024 * 1024).await?;
+        for table in ["t1", "t2", "t3"] {
+            for i in 0..5 {
+                let cmd = CommandRequest::new_hset(table, format!("k{}", i), i.into());
+                leader.execute(cmd);
+            }
+        }
+
+        // follower 本地有一些 leader 没有的旧数据，分布在各页的范围里
+        let store = MemTable::new();
+        for (table, key) in [("t0", "k0"), ("t1", "k2a"), ("t2", "k9"), ("t9", "k0")] {
+            store.set(table, key, 0)?;
+        }
+        let follower: Service = ServiceInner::new(store).follower(addr.to_string()).into();
+        let config = ReplicationConfig {
+            batch: 4,
+            poll_interval_ms: 10,
+            ..ReplicationConfig::new(addr.to_string())
+        };
+        tokio::spawn(Follower::new(follower.clone(), config)?.run());
+        wait_for(&follower, 15).await?;
+
+        for table in ["t0", "t1", "t2", "t3", "t9"] {
+            let mut leader_pairs = leader.execute(CommandRequest::new_hget_all(table)).pairs;
+            let mut pairs = follower.execute(CommandRequest::new_hget_all(table)).pairs;
+            leader_pairs.sort_by(|a, b| a.key.cmp(&b.key));
+            pairs.sort_by(|a, b| a.key.cmp(&b.key));
+            assert_eq!(pairs, leader_pairs, "table {}", table);
+        }
+        Ok(())
+    }
+}
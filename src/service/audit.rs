@@ -0,0 +1,369 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{
+    command_request::RequestData, CommandRequest, CommandResponse, KvError, Middleware, Next,
+    Session,
+};
+
+/// 第一条记录的 prev_hash
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// 审计日志里的一条记录，对应一个修改数据的命令
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// 从 0 开始连续递增，用来发现被删除的记录
+    pub seq: u64,
+    /// unix 时间戳，毫秒
+    pub timestamp: u64,
+    pub user: Option<String>,
+    pub peer: Option<String>,
+    pub command: String,
+    pub table: String,
+    pub keys: Vec<String>,
+    /// 命令执行结果的状态码
+    pub status: u32,
+    /// 上一条记录的 hash
+    pub prev_hash: String,
+    /// 这条记录（hash 字段为空时）的 JSON 的 SHA-256
+    pub hash: String,
+}
+
+impl AuditRecord {
+    fn compute_hash(&self) -> String {
+        let record = AuditRecord {
+            hash: String::new(),
+            ..self.clone()
+        };
+        let data = serde_json::to_vec(&record).unwrap();
+        Sha256::digest(data)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// 校验审计日志时发现的问题，line 从 1 开始
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditIssue {
+    /// 无法解析的行
+    Malformed { line: usize },
+    /// seq 不连续，说明中间的记录被删除了
+    Gap {
+        line: usize,
+        expected: u64,
+        found: u64,
+    },
+    /// prev_hash 和上一条记录的 hash 对不上
+    BrokenChain { line: usize, seq: u64 },
+    /// 记录的内容和 hash 对不上，说明被修改过
+    Tampered { line: usize, seq: u64 },
+}
+
+struct Writer {
+    file: File,
+    next_seq: u64,
+    last_hash: String,
+}
+
+/// 审计日志中间件，把所有修改数据的命令追加到 JSON lines 文件里
+///
+/// 每条记录都包含上一条记录的 hash，修改或者删除中间的任何一条记录都能被 `AuditLog::verify` 发现。
+/// 截掉文件末尾的记录无法从文件本身发现，需要把最后一条记录的 hash 另外保存起来比对。
+///
+/// 注册顺序见 `ServiceInner::layer`。
+pub struct AuditLog {
+    writer: Mutex<Writer>,
+}
+
+impl AuditLog {
+    /// 打开（或者创建）审计日志，已有的日志会接着最后一条记录继续写
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let path = path.as_ref();
+        let (next_seq, last_hash) = match read_last_line(path)? {
+            Some(line) => {
+                let record: AuditRecord = serde_json::from_str(&line).map_err(|e| {
+                    KvError::Internal(format!("Corrupted audit log {:?}: {}", path, e))
+                })?;
+                (record.seq + 1, record.hash)
+            }
+            None => (0, GENESIS_HASH.to_string()),
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: Mutex::new(Writer {
+                file,
+                next_seq,
+                last_hash,
+            }),
+        })
+    }
+
+    /// 检查整个审计日志，返回发现的所有问题；没有问题时返回空的 Vec
+    pub fn verify(path: impl AsRef<Path>) -> Result<Vec<AuditIssue>, KvError> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut issues = Vec::new();
+        // 上一条能解析的记录的 (seq, hash)，遇到无法解析的行之后需要重新同步
+        let mut prev = Some((None, GENESIS_HASH.to_string()));
+
+        for (i, line) in reader.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let record: AuditRecord = match serde_json::from_str(&line) {
+                Ok(v) => v,
+                Err(_) => {
+                    issues.push(AuditIssue::Malformed { line: line_no });
+                    prev = None;
+                    continue;
+                }
+            };
+
+            if let Some((prev_seq, prev_hash)) = &prev {
+                let expected = prev_seq.map_or(0, |s: u64| s + 1);
+                if record.seq != expected {
+                    issues.push(AuditIssue::Gap {
+                        line: line_no,
+                        expected,
+                        found: record.seq,
+                    });
+                } else if record.prev_hash != *prev_hash {
+                    issues.push(AuditIssue::BrokenChain {
+                        line: line_no,
+                        seq: record.seq,
+                    });
+                }
+            }
+            if record.compute_hash() != record.hash {
+                issues.push(AuditIssue::Tampered {
+                    line: line_no,
+                    seq: record.seq,
+                });
+            }
+            prev = Some((Some(record.seq), record.hash));
+        }
+        Ok(issues)
+    }
+
+    fn append(
+        &self,
+        session: &Session,
+        command: &str,
+        table: String,
+        keys: Vec<String>,
+        status: u32,
+    ) -> Result<(), KvError> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let mut writer = self.writer.lock().unwrap();
+        let mut record = AuditRecord {
+            seq: writer.next_seq,
+            timestamp,
+            user: session.user.clone(),
+            peer: session.peer.map(|p| p.to_string()),
+            command: command.into(),
+            table,
+            keys,
+            status,
+            prev_hash: writer.last_hash.clone(),
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash();
+
+        let mut line = serde_json::to_vec(&record).unwrap();
+        line.push(b'\n');
+        writer.file.write_all(&line)?;
+
+        writer.next_seq += 1;
+        writer.last_hash = record.hash;
+        Ok(())
+    }
+}
+
+impl Middleware for AuditLog {
+    fn call(&self, cmd: CommandRequest, session: &mut Session, next: Next) -> CommandResponse {
+        let target = cmd.request_data.as_ref().and_then(mutation);
+        let res = next.run(cmd, session);
+
+        if let Some((command, table, keys)) = target {
+            if let Err(e) = self.append(session, command, table, keys, res.status) {
+                warn!("Failed to write audit log: {}", e);
+            }
+        }
+        res
+    }
+}
+
+/// 修改数据的命令，返回 (命令名, table, keys)；只读命令返回 None
+///
+/// 这里故意不使用通配的分支，新增命令时编译器会提醒我们决定它是否需要审计。
+fn mutation(data: &RequestData) -> Option<(&'static str, String, Vec<String>)> {
+    let name = data.name();
+    match data {
+        RequestData::Hset(v) => {
+            let keys = v.pair.iter().map(|p| p.key.clone()).collect();
+            Some((name, v.table.clone(), keys))
+        }
+        RequestData::Hmset(v) => {
+            let keys = v.pairs.iter().map(|p| p.key.clone()).collect();
+            Some((name, v.table.clone(), keys))
+        }
+        RequestData::Hdel(v) => Some((name, v.table.clone(), vec![v.key.clone()])),
+        RequestData::Hmdel(v) => Some((name, v.table.clone(), v.keys.clone())),
+        RequestData::Hcas(v) => Some((name, v.table.clone(), vec![v.key.clone()])),
+        RequestData::Hget(_)
+        | RequestData::Hgetall(_)
+        | RequestData::Hmget(_)
+        | RequestData::Hexist(_)
+        | RequestData::Hmexist(_)
+        | RequestData::Auth(_)
+        | RequestData::Info(_)
+        | RequestData::SlowlogGet(_)
+        | RequestData::SlowlogReset(_)
+        | RequestData::Subscribe(_)
+        | RequestData::Unsubscribe(_)
+        | RequestData::Publish(_)
+        | RequestData::Watch(_)
+        | RequestData::Unwatch(_)
+        | RequestData::ReadChanges(_)
+        | RequestData::Snapshot(_) => None,
+    }
+}
+
+fn read_last_line(path: &Path) -> Result<Option<String>, KvError> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut last = None;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            last = Some(line);
+        }
+    }
+    Ok(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{service::auth, MemTable, Service, ServiceInner};
+
+    fn read_records(path: &Path) -> Vec<AuditRecord> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    fn write_audit_log(path: &Path) {
+        let audit = AuditLog::open(path).unwrap();
+        let service: Service = ServiceInner::new(MemTable::new()).layer(audit).into();
+        for key in ["k1", "k2", "k3"] {
+            service.execute(CommandRequest::new_hset("t1", key, "v".into()));
+        }
+    }
+
+    #[test]
+    fn audit_log_should_record_mutations_only() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let audit = Arc::new(AuditLog::open(&path).unwrap());
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(audit)
+            .layer(auth::tests::authenticator())
+            .into();
+
+        let mut session = Session::new("127.0.0.1:5000".parse().ok());
+        service.execute_with(
+            CommandRequest::new_auth("alice", "alice-password"),
+            &mut session,
+        );
+        let cmd = CommandRequest::new_hset("user_1", "k1", "v1".into());
+        service.execute_with(cmd, &mut session);
+        service.execute_with(CommandRequest::new_hget("user_1", "k1"), &mut session);
+        // 被 ACL 拒绝的修改也要记录下来
+        let cmd = CommandRequest::new_hset("orders", "k1", "v1".into());
+        service.execute_with(cmd, &mut session);
+
+        let records = read_records(&path);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].seq, 0);
+        assert_eq!(records[0].prev_hash, GENESIS_HASH);
+        assert_eq!(records[0].user.as_deref(), Some("alice"));
+        assert_eq!(records[0].peer.as_deref(), Some("127.0.0.1:5000"));
+        assert_eq!(records[0].command, "hset");
+        assert_eq!(records[0].keys, vec!["k1"]);
+        assert_eq!(records[0].status, 200);
+        assert_eq!(records[1].table, "orders");
+        assert_eq!(records[1].status, 403);
+        assert_eq!(records[1].prev_hash, records[0].hash);
+
+        assert!(AuditLog::verify(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reopened_audit_log_should_continue_the_chain() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        write_audit_log(&path);
+        write_audit_log(&path);
+
+        let records = read_records(&path);
+        assert_eq!(records.len(), 6);
+        assert_eq!(records[3].seq, 3);
+        assert_eq!(records[3].prev_hash, records[2].hash);
+        assert!(AuditLog::verify(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn verify_should_detect_edits_and_gaps() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        write_audit_log(&path);
+        write_audit_log(&path);
+
+        let content = fs::read_to_string(&path).unwrap();
+        let mut lines: Vec<String> = content.lines().map(|l| l.to_string()).collect();
+        // 修改第 2 条记录的 key，删除第 4 条记录，第 6 条记录写坏
+        lines[1] = lines[1].replace("\"k2\"", "\"k9\"");
+        lines.remove(3);
+        lines[4] = "{not json".into();
+        fs::write(&path, lines.join("\n")).unwrap();
+
+        assert_eq!(
+            AuditLog::verify(&path).unwrap(),
+            vec![
+                AuditIssue::Tampered { line: 2, seq: 1 },
+                AuditIssue::Gap {
+                    line: 4,
+                    expected: 3,
+                    found: 4
+                },
+                AuditIssue::Malformed { line: 5 },
+            ]
+        );
+    }
+}
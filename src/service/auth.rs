@@ -0,0 +1,351 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+
+use crate::{
+    command_request::RequestData, Auth, AuthConfig, CommandRequest, CommandResponse, KvError,
+    Middleware, Permission, Session, Value,
+};
+
+/// 根据 AuthConfig 验证用户身份，并根据 ACL 规则检查权限
+///
+/// 验证一次密码需要几十毫秒的 CPU，网络层应该通过 Service::execute_async 执行 Auth 命令。
+#[derive(Debug, Clone)]
+pub struct Authenticator {
+    config: AuthConfig,
+    /// 用户或者 token 不存在时也验证一次这个哈希，让响应时间不会暴露哪些用户存在
+    dummy_hash: String,
+}
+
+impl Authenticator {
+    pub fn new(config: AuthConfig) -> Result<Self, KvError> {
+        // 提前检查所有的哈希值，避免运行时才发现配置错误
+        let hashes = config.users.iter().map(|u| &u.password);
+        let hashes = hashes.chain(config.tokens.iter().map(|t| &t.token));
+        for hash in hashes {
+            PasswordHash::new(hash).map_err(|e| KvError::ConfigError(e.to_string()))?;
+        }
+        for (i, token) in config.tokens.iter().enumerate() {
+            if token.id.is_empty() || token.id.contains(TOKEN_SEPARATOR) {
+                let msg = format!("invalid token id: {:?}", token.id);
+                return Err(KvError::ConfigError(msg));
+            }
+            if config.tokens[..i].iter().any(|t| t.id == token.id) {
+                let msg = format!("duplicate token id: {}", token.id);
+                return Err(KvError::ConfigError(msg));
+            }
+        }
+
+        Ok(Self {
+            config,
+            dummy_hash: hash_password("dummy password")?,
+        })
+    }
+
+    /// 验证 Auth 命令，成功则返回用户名
+    pub fn authenticate(&self, auth: &Auth) -> Result<String, KvError> {
+        if !auth.token.is_empty() {
+            // token 的格式是 id.secret，按 id 找到 token，只需要验证一次哈希
+            let (id, secret) = auth
+                .token
+                .split_once(TOKEN_SEPARATOR)
+                .unwrap_or(("", auth.token.as_str()));
+            let token = self.config.tokens.iter().find(|t| t.id == id);
+            let hash = token.map_or(&self.dummy_hash, |t| &t.token);
+            let ok = verify(secret, hash);
+            return match (token, ok) {
+                (Some(token), true) => Ok(token.user.clone()),
+                _ => Err(KvError::Unauthenticated("invalid token".into())),
+            };
+        }
+
+        // 用户不存在时用 dummy hash 验证，耗时和密码错误时一样
+        let user = self.config.users.iter().find(|u| u.name == auth.username);
+        let hash = user.map_or(&self.dummy_hash, |u| &u.password);
+        let ok = verify(&auth.password, hash);
+        match (user, ok) {
+            (Some(user), true) => Ok(user.name.clone()),
+            _ => Err(KvError::Unauthenticated(
+                "invalid username or password".into(),
+            )),
+        }
+    }
+
+    /// 检查用户对 table 是否有对应的权限
+    pub fn authorize(&self, user: &str, table: &str, perm: Permission) -> Result<(), KvError> {
+        let allowed = self
+            .config
+            .acl
+            .iter()
+            .filter(|rule| glob_match(&rule.user, user) && glob_match(&rule.table, table))
+            .any(|rule| {
+                rule.permissions
+                    .iter()
+                    .any(|p| *p == perm || *p == Permission::Admin)
+            });
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(KvError::PermissionDenied(format!(
+                "user {} has no {:?} permission on table {}",
+                user, perm, table
+            )))
+        }
+    }
+}
+
+/// 作为中间件：处理 Auth 命令，并在 dispatch 之前检查认证和 ACL
+impl Middleware for Authenticator {
+    fn before(&self, cmd: &mut CommandRequest, session: &mut Session) -> Option<CommandResponse> {
+        let data = match &cmd.request_data {
+            Some(RequestData::Auth(auth)) => {
+                return match self.authenticate(auth) {
+                    Ok(user) => {
+                        session.user = Some(user.clone());
+                        Some(Value::from(user).into())
+                    }
+                    Err(e) => {
+                        session.user = None;
+                        Some(e.into())
+                    }
+                };
+            }
+            Some(data) => data,
+            None => return None,
+        };
+
+        let user = match session.user.as_deref() {
+            Some(user) => user,
+            None => {
+                return Some(KvError::Unauthenticated("Please authenticate first".into()).into())
+            }
+        };
+
+        match required_permission(data) {
+            Some((table, perm)) => self.authorize(user, table, perm).err().map(|e| e.into()),
+            None => None,
+        }
+    }
+}
+
+/// 用 argon2 哈希密码或 token，生成的 PHC 字符串可以直接写入配置文件
+pub fn hash_password(password: &str) -> Result<String, KvError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| KvError::Internal(e.to_string()))
+}
+
+fn verify(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// token 中分隔 id 和 secret 的字符
+const TOKEN_SEPARATOR: char = '.';
+
+/// 管理命令不针对某个 table，需要 ACL 中 table 为 `*` 的 admin 规则
+const ADMIN_TABLE: &str = "*";
+
+/// 执行一个命令需要的权限，返回 (table, permission)；Auth 和 Pub/Sub 命令不需要权限
+fn required_permission(data: &RequestData) -> Option<(&str, Permission)> {
+    match data {
+        RequestData::Hget(v) => Some((&v.table, Permission::Read)),
+        RequestData::Hgetall(v) => Some((&v.table, Permission::Read)),
+        RequestData::Hmget(v) => Some((&v.table, Permission::Read)),
+        RequestData::Hexist(v) => Some((&v.table, Permission::Read)),
+        RequestData::Hmexist(v) => Some((&v.table, Permission::Read)),
+        RequestData::Hset(v) => Some((&v.table, Permission::Write)),
+        RequestData::Hmset(v) => Some((&v.table, Permission::Write)),
+        RequestData::Hdel(v) => Some((&v.table, Permission::Write)),
+        RequestData::Hmdel(v) => Some((&v.table, Permission::Write)),
+        RequestData::Hcas(v) => Some((&v.table, Permission::Write)),
+        RequestData::Watch(v) => Some((&v.table, Permission::Read)),
+        // channel 不是 table，不受 ACL 控制，只需要通过认证
+        RequestData::Auth(_)
+        | RequestData::Subscribe(_)
+        | RequestData::Unsubscribe(_)
+        | RequestData::Publish(_)
+        | RequestData::Unwatch(_) => None,
+        // 变更日志包含所有 table 的数据，需要对所有 table 都有读权限
+        RequestData::ReadChanges(_) | RequestData::Snapshot(_) => {
+            Some((ADMIN_TABLE, Permission::Read))
+        }
+        // 管理命令需要对所有 table 都有 admin 权限
+        RequestData::Info(_) | RequestData::SlowlogGet(_) | RequestData::SlowlogReset(_) => {
+            Some((ADMIN_TABLE, Permission::Admin))
+        }
+    }
+}
+
+/// 简单的通配符匹配，`*` 匹配任意长度的字符串
+pub(crate) fn glob_match(pattern: &str, s: &str) -> bool {
+    let (p, s) = (pattern.as_bytes(), s.as_bytes());
+    let (mut pi, mut si) = (0, 0);
+    // 上一个 `*` 的位置，以及它当时对应的 s 的位置
+    let mut star: Option<(usize, usize)> = None;
+
+    while si < s.len() {
+        if pi < p.len() && p[pi] == b'*' {
+            star = Some((pi, si));
+            pi += 1;
+        } else if pi < p.len() && p[pi] == s[si] {
+            pi += 1;
+            si += 1;
+        } else if let Some((sp, ss)) = star {
+            // 回溯：让上一个 `*` 多匹配一个字符
+            pi = sp + 1;
+            si = ss + 1;
+            star = Some((sp, ss + 1));
+        } else {
+            return false;
+        }
+    }
+
+    p[pi..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{AclRule, TokenConfig, UserConfig};
+
+    #[test]
+    fn glob_match_should_work() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("user_*", "user_1"));
+        assert!(glob_match("*_log", "access_log"));
+        assert!(glob_match("a*c*e", "abcde"));
+        assert!(glob_match("t1", "t1"));
+        assert!(!glob_match("t1", "t2"));
+        assert!(!glob_match("user_*", "users"));
+        assert!(!glob_match("a*c", "abcd"));
+    }
+
+    #[test]
+    fn authenticate_should_work() {
+        let auth = authenticator();
+
+        let user = auth.authenticate(&Auth {
+            username: "alice".into(),
+            password: "alice-password".into(),
+            ..Default::default()
+        });
+        assert_eq!(user, Ok("alice".into()));
+
+        let user = auth.authenticate(&Auth {
+            username: "alice".into(),
+            password: "wrong".into(),
+            ..Default::default()
+        });
+        assert!(matches!(user, Err(KvError::Unauthenticated(_))));
+
+        let user = auth.authenticate(&Auth {
+            token: "ci.ci-secret".into(),
+            ..Default::default()
+        });
+        assert_eq!(user, Ok("ci".into()));
+
+        // 不存在的用户和 token id 同样需要验证一次哈希，返回相同的错误
+        let user = auth.authenticate(&Auth {
+            username: "mallory".into(),
+            password: "alice-password".into(),
+            ..Default::default()
+        });
+        assert!(matches!(user, Err(KvError::Unauthenticated(_))));
+        for token in ["ci-secret", "cd.ci-secret", "ci.wrong"] {
+            let user = auth.authenticate(&Auth {
+                token: token.into(),
+                ..Default::default()
+            });
+            assert!(matches!(user, Err(KvError::Unauthenticated(_))));
+        }
+    }
+
+    #[test]
+    fn authorize_should_follow_acl() {
+        let auth = authenticator();
+
+        assert!(auth.authorize("alice", "user_1", Permission::Read).is_ok());
+        assert!(auth.authorize("alice", "user_1", Permission::Write).is_ok());
+        assert!(auth.authorize("alice", "orders", Permission::Read).is_err());
+        assert!(auth.authorize("ci", "orders", Permission::Read).is_ok());
+        assert!(auth.authorize("ci", "orders", Permission::Write).is_err());
+        // admin 权限包含 read 和 write
+        assert!(auth.authorize("root", "orders", Permission::Write).is_ok());
+    }
+
+    #[test]
+    fn invalid_hash_should_be_rejected() {
+        let config = AuthConfig {
+            users: vec![UserConfig {
+                name: "alice".into(),
+                password: "plaintext".into(),
+            }],
+            ..Default::default()
+        };
+        assert!(matches!(
+            Authenticator::new(config),
+            Err(KvError::ConfigError(_))
+        ));
+
+        let token = TokenConfig {
+            id: "ci".into(),
+            user: "ci".into(),
+            token: hash_password("ci-secret").unwrap(),
+        };
+        let config = AuthConfig {
+            tokens: vec![token.clone(), token],
+            ..Default::default()
+        };
+        assert!(matches!(
+            Authenticator::new(config),
+            Err(KvError::ConfigError(_))
+        ));
+    }
+
+    pub(crate) fn authenticator() -> Authenticator {
+        let config = AuthConfig {
+            users: vec![
+                UserConfig {
+                    name: "alice".into(),
+                    password: hash_password("alice-password").unwrap(),
+                },
+                UserConfig {
+                    name: "root".into(),
+                    password: hash_password("root-password").unwrap(),
+                },
+            ],
+            tokens: vec![TokenConfig {
+                id: "ci".into(),
+                user: "ci".into(),
+                token: hash_password("ci-secret").unwrap(),
+            }],
+            acl: vec![
+                AclRule {
+                    user: "alice".into(),
+                    table: "user_*".into(),
+                    permissions: vec![Permission::Read, Permission::Write],
+                },
+                AclRule {
+                    user: "ci".into(),
+                    table: "*".into(),
+                    permissions: vec![Permission::Read],
+                },
+                AclRule {
+                    user: "root".into(),
+                    table: "*".into(),
+                    permissions: vec![Permission::Admin],
+                },
+            ],
+        };
+        Authenticator::new(config).unwrap()
+    }
+}
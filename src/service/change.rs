@@ -0,0 +1,103 @@
+use http::StatusCode;
+
+use crate::{command_request::RequestData, CommandResponse, Value};
+
+/// 写命令修改的 key，在执行之前从命令中提取出来，执行成功之后和返回的旧值一起生成 KeyChange
+pub(crate) struct Changes {
+    table: String,
+    /// key 和新的 value，删除时没有新的 value
+    keys: Vec<(String, Option<Value>)>,
+}
+
+/// 一个 key 的变化，用于生成 watch 事件和变更日志
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct KeyChange {
+    pub table: String,
+    pub key: String,
+    /// 新设置的 key 没有旧值
+    pub old_value: Option<Value>,
+    /// 删除的 key 没有新值
+    pub new_value: Option<Value>,
+}
+
+impl Changes {
+    /// 写命令返回 Changes，只读命令返回 None
+    ///
+    /// 和审计日志一样故意不使用通配的分支，新增命令时编译器会提醒我们决定它是否会修改 key。
+    pub(crate) fn from_request(data: &RequestData) -> Option<Self> {
+        let (table, keys) = match data {
+            RequestData::Hset(v) => {
+                let keys = v
+                    .pair
+                    .iter()
+                    .map(|p| (p.key.clone(), Some(p.value.clone().unwrap_or_default())))
+                    .collect();
+                (&v.table, keys)
+            }
+            RequestData::Hmset(v) => {
+                let keys = v
+                    .pairs
+                    .iter()
+                    .map(|p| (p.key.clone(), Some(p.value.clone().unwrap_or_default())))
+                    .collect();
+                (&v.table, keys)
+            }
+            RequestData::Hdel(v) => (&v.table, vec![(v.key.clone(), None)]),
+            RequestData::Hmdel(v) => (&v.table, v.keys.iter().map(|k| (k.clone(), None)).collect()),
+            RequestData::Hcas(v) => (&v.table, vec![(v.key.clone(), v.value.clone())]),
+            RequestData::Hget(_)
+            | RequestData::Hgetall(_)
+            | RequestData::Hmget(_)
+            | RequestData::Hexist(_)
+            | RequestData::Hmexist(_)
+            | RequestData::Auth(_)
+            | RequestData::Info(_)
+            | RequestData::SlowlogGet(_)
+            | RequestData::SlowlogReset(_)
+            | RequestData::Subscribe(_)
+            | RequestData::Unsubscribe(_)
+            | RequestData::Publish(_)
+            | RequestData::Watch(_)
+            | RequestData::Unwatch(_)
+            | RequestData::ReadChanges(_)
+            | RequestData::Snapshot(_) => return None,
+        };
+        Some(Self {
+            table: table.clone(),
+            keys,
+        })
+    }
+
+    /// 根据命令的执行结果生成每个 key 的变化
+    ///
+    /// 批量写命令中途失败时，前面的 key 已经生效，错误响应的 values 中是它们的旧值，
+    /// 这些 key 的变化同样需要返回，否则变更日志和 watch 会漏掉它们。其它失败的命令没有变化。
+    pub(crate) fn resolve(self, res: &CommandResponse) -> Vec<KeyChange> {
+        let applied = if res.status == StatusCode::OK.as_u16() as u32 {
+            self.keys.len()
+        } else {
+            res.values.len()
+        };
+
+        let table = self.table;
+        self.keys
+            .into_iter()
+            .take(applied)
+            .enumerate()
+            .filter_map(|(i, (key, new_value))| {
+                // 写命令按顺序返回每个 key 之前的 value，没有旧值时是空的 Value
+                let old_value = res.values.get(i).filter(|v| v.value.is_some()).cloned();
+                // 删除不存在的 key 不算修改
+                if new_value.is_none() && old_value.is_none() {
+                    return None;
+                }
+                Some(KeyChange {
+                    table: table.clone(),
+                    key,
+                    old_value,
+                    new_value,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,652 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use prost::Message;
+
+use super::change::KeyChange;
+use crate::{CdcConfig, Change, ChangeOp, CommandResponse, KvError, SnapshotHeader};
+
+/// ReadChanges 没有指定 limit 时返回的记录数
+const DEFAULT_READ_LIMIT: usize = 100;
+/// ReadChanges 一次最多返回的记录数
+const MAX_READ_LIMIT: usize = 1000;
+/// segment 文件的扩展名，文件名是 segment 中第一条记录的 offset
+const SEGMENT_EXT: &str = "log";
+/// 稀疏索引中相邻两项之间至少间隔的字节数
+const INDEX_INTERVAL: u64 = 64 * 1024;
+
+/// 一个 segment 文件
+#[derive(Debug)]
+struct Segment {
+    /// 第一条记录的 offset
+    base: u64,
+    path: PathBuf,
+    /// 已经完整写入的字节数，读取时不会越过它
+    bytes: u64,
+    /// 最后一次写入的时间
+    modified: SystemTime,
+    /// 稀疏索引：(offset, 这条记录在文件中的位置)，按 offset 排序
+    index: Vec<(u64, u64)>,
+}
+
+impl Segment {
+    /// 在 position 写入 offset 开始的记录时，按间隔更新稀疏索引
+    fn index(&mut self, offset: u64, position: u64) {
+        match self.index.last() {
+            Some((_, last)) if position - last < INDEX_INTERVAL => {}
+            _ => self.index.push((offset, position)),
+        }
+    }
+
+    /// 从哪个文件位置开始读，才能读到 offset 这条记录
+    fn position(&self, offset: u64) -> u64 {
+        match self.index.partition_point(|(o, _)| *o <= offset) {
+            0 => 0,
+            i => self.index[i - 1].1,
+        }
+    }
+}
+
+struct Inner {
+    /// 按 base 排序，最后一个是正在写入的 segment
+    segments: VecDeque<Segment>,
+    file: File,
+    next_offset: u64,
+    /// 日志写入失败、撤销修改也失败之后，存储和日志已经不一致，拒绝之后的所有写命令
+    poisoned: Option<String>,
+}
+
+/// 变更日志（CDC），按顺序持久化所有成功的修改，每条记录有一个连续递增的 offset
+///
+/// 日志由一组 segment 文件组成，当前的 segment 写满之后写入新的 segment，
+/// 按大小或者时间清理旧的 segment 时不会删除正在写入的 segment。
+/// 记录的格式见 abi.proto 中的 Change，外部程序可以直接读取 segment 文件。
+pub struct ChangeLog {
+    config: CdcConfig,
+    dir: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+impl ChangeLog {
+    /// 打开（或者创建）变更日志，已有的日志会接着最后一条记录继续写
+    ///
+    /// 崩溃时最后一条记录可能只写了一半，打开时会把它截掉。
+    pub fn open(config: CdcConfig) -> Result<Self, KvError> {
+        let dir = PathBuf::from(&config.path);
+        fs::create_dir_all(&dir)?;
+
+        let mut segments = list_segments(&dir)?;
+        let mut last_offset = None;
+        for segment in segments.iter_mut() {
+            last_offset = scan_segment(segment)?;
+        }
+        let next_offset = match segments.back_mut() {
+            Some(last) => {
+                // scan_segment 已经把 bytes 设置成完整的记录占用的字节数
+                let file = File::options().write(true).open(&last.path)?;
+                if file.metadata()?.len() > last.bytes {
+                    file.set_len(last.bytes)?;
+                }
+                last_offset.map_or(last.base, |offset| offset + 1)
+            }
+            None => {
+                segments.push_back(create_segment(&dir, 1)?);
+                1
+            }
+        };
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&segments.back().unwrap().path)?;
+        let log = Self {
+            config,
+            dir,
+            inner: Mutex::new(Inner {
+                segments,
+                file,
+                next_offset,
+                poisoned: None,
+            }),
+        };
+        log.retain(&mut log.inner.lock().unwrap());
+        Ok(log)
+    }
+
+    /// 最后一条记录的 offset，还没有记录时为 0
+    pub fn last_offset(&self) -> u64 {
+        self.inner.lock().unwrap().next_offset - 1
+    }
+
+    /// 还没有被清理的最早的 offset
+    pub fn first_offset(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.segments.front().map_or(inner.next_offset, |s| s.base)
+    }
+
+    /// 把正在写入的 segment 同步到磁盘，关闭 fsync 时用它保证关闭前写入的记录不会丢失
+    pub fn flush(&self) -> Result<(), KvError> {
+        self.inner.lock().unwrap().file.sync_data()?;
+        Ok(())
+    }
+
+    /// 执行写命令并把修改追加到日志里
+    ///
+    /// 写命令和追加日志在同一个锁里完成，这样日志中记录的顺序和存储中实际修改的顺序一致。
+    /// 日志写入失败时用 undo 撤销存储中的修改并返回 500，这次修改不会被 follower 和 CDC 看到；
+    /// 撤销也失败时存储和日志已经不一致，之后的写命令都会被拒绝。
+    pub(crate) fn write(
+        &self,
+        f: impl FnOnce() -> (CommandResponse, Vec<KeyChange>),
+        undo: impl FnOnce(&[KeyChange]) -> Result<(), KvError>,
+    ) -> (CommandResponse, Vec<KeyChange>) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(e) = &inner.poisoned {
+            let msg = format!("Change log is out of sync with the storage: {}", e);
+            return (KvError::Internal(msg).into(), Vec::new());
+        }
+        let (res, changes) = f();
+        if let Err(e) = self.append(&mut inner, &changes) {
+            let msg = format!("Failed to write change log: {}", e);
+            if let Err(e) = undo(&changes) {
+                tracing::error!("Failed to undo changes not written to change log: {}", e);
+                inner.poisoned = Some(msg.clone());
+                return (KvError::Internal(msg).into(), changes);
+            }
+            return (KvError::Internal(msg).into(), Vec::new());
+        }
+        (res, changes)
+    }
+
+    /// 读取 offset >= from_offset 的最多 limit 条记录，from_offset 为 0 时从最早的记录开始读
+    ///
+    /// 只在加锁时通过稀疏索引找到要读的文件位置，读取文件时不阻塞写命令。
+    pub fn read(&self, from_offset: u64, limit: usize) -> Result<Vec<Change>, KvError> {
+        let limit = match limit {
+            0 => DEFAULT_READ_LIMIT,
+            n => n.min(MAX_READ_LIMIT),
+        };
+
+        let (from_offset, ranges) = {
+            let inner = self.inner.lock().unwrap();
+            let first = inner.segments.front().map_or(inner.next_offset, |s| s.base);
+            if from_offset > 0 && from_offset < first {
+                return Err(KvError::HistoryTruncated(from_offset, first));
+            }
+            let from_offset = from_offset.max(first);
+
+            // 从包含 from_offset 的 segment 开始往后读，只读到加锁时已经完整写入的位置
+            let start = inner
+                .segments
+                .iter()
+                .rposition(|s| s.base <= from_offset)
+                .unwrap_or(0);
+            let ranges: Vec<_> = inner
+                .segments
+                .iter()
+                .skip(start)
+                .map(|s| (s.path.clone(), s.position(from_offset), s.bytes))
+                .collect();
+            (from_offset, ranges)
+        };
+
+        let mut result = Vec::new();
+        for (path, start, end) in ranges {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                // 解锁之后 segment 被清理了
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(KvError::HistoryTruncated(from_offset, self.first_offset()));
+                }
+                Err(e) => return Err(e.into()),
+            };
+            read_records(file, start..end, from_offset, limit, &mut result)?;
+            if result.len() == limit {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    /// 快照对应的 offset，只在读取 offset 时加锁
+    ///
+    /// 之后读取数据时不阻塞写命令，读取期间的修改都在 offset 之后的日志里，重放时会覆盖快照中的值。
+    pub(crate) fn snapshot_header(&self) -> SnapshotHeader {
+        SnapshotHeader {
+            offset: self.last_offset(),
+            timestamp: now_millis(),
+        }
+    }
+
+    /// 把 dump 输出的 Change 逐条写入快照文件，先写入临时文件再改名，避免留下不完整的快照
+    pub(crate) fn write_snapshot(
+        path: &Path,
+        header: &SnapshotHeader,
+        dump: impl FnOnce(&mut dyn FnMut(Change) -> Result<(), KvError>) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        let tmp = path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        let mut buf = Vec::new();
+        header.encode_length_delimited(&mut buf)?;
+        file.write_all(&buf)?;
+        dump(&mut |change| {
+            buf.clear();
+            change.encode_length_delimited(&mut buf)?;
+            file.write_all(&buf)?;
+            Ok(())
+        })?;
+
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// 读取快照文件
+    pub fn read_snapshot(path: impl AsRef<Path>) -> Result<(SnapshotHeader, Vec<Change>), KvError> {
+        let data = fs::read(path)?;
+        let mut buf = data.as_slice();
+        let header = SnapshotHeader::decode_length_delimited(&mut buf)?;
+        let mut changes = Vec::new();
+        while !buf.is_empty() {
+            changes.push(Change::decode_length_delimited(&mut buf)?);
+        }
+        Ok((header, changes))
+    }
+
+    fn append(&self, inner: &mut Inner, changes: &[KeyChange]) -> Result<(), KvError> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let timestamp = now_millis();
+        let mut buf = Vec::new();
+        for (i, change) in changes.iter().enumerate() {
+            let op = match change.new_value {
+                Some(_) => ChangeOp::Put,
+                None => ChangeOp::Remove,
+            };
+            Change {
+                offset: inner.next_offset + i as u64,
+                timestamp,
+                op: op as i32,
+                table: change.table.clone(),
+                key: change.key.clone(),
+                value: change.new_value.clone(),
+            }
+            .encode_length_delimited(&mut buf)?;
+        }
+
+        let fsync = self.config.fsync;
+        let written = inner.file.write_all(&buf).and_then(|_| {
+            if fsync {
+                inner.file.sync_data()
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = written {
+            // 截掉可能写了一半的记录，否则之后追加的记录都无法被读取
+            let bytes = inner.segments.back().unwrap().bytes;
+            if let Err(e) = inner.file.set_len(bytes) {
+                tracing::warn!("Failed to truncate change log segment: {}", e);
+            }
+            return Err(e.into());
+        }
+        let offset = inner.next_offset;
+        inner.next_offset += changes.len() as u64;
+        let segment = inner.segments.back_mut().unwrap();
+        segment.index(offset, segment.bytes);
+        segment.bytes += buf.len() as u64;
+        segment.modified = SystemTime::now();
+
+        if segment.bytes >= self.config.segment_bytes {
+            let segment = create_segment(&self.dir, inner.next_offset)?;
+            inner.file = OpenOptions::new().append(true).open(&segment.path)?;
+            inner.segments.push_back(segment);
+        }
+        self.retain(inner);
+        Ok(())
+    }
+
+    /// 按配置清理旧的 segment，正在写入的 segment 不会被删除
+    fn retain(&self, inner: &mut Inner) {
+        let max_age = self.config.retention_secs.map(Duration::from_secs);
+        let now = SystemTime::now();
+        let mut total: u64 = inner.segments.iter().map(|s| s.bytes).sum();
+
+        while inner.segments.len() > 1 {
+            let oldest = &inner.segments[0];
+            let too_big = matches!(self.config.retention_bytes, Some(max) if total > max);
+            let too_old = match max_age {
+                Some(max_age) => now.duration_since(oldest.modified).unwrap_or_default() > max_age,
+                None => false,
+            };
+            if !too_big && !too_old {
+                break;
+            }
+            if let Err(e) = fs::remove_file(&oldest.path) {
+                tracing::warn!(
+                    "Failed to remove change log segment {:?}: {}",
+                    oldest.path,
+                    e
+                );
+                break;
+            }
+            total -= oldest.bytes;
+            inner.segments.pop_front();
+        }
+    }
+}
+
+pub(super) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn segment_path(dir: &Path, base: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", base, SEGMENT_EXT))
+}
+
+fn create_segment(dir: &Path, base: u64) -> Result<Segment, KvError> {
+    let path = segment_path(dir, base);
+    File::create(&path)?;
+    Ok(Segment {
+        base,
+        path,
+        bytes: 0,
+        modified: SystemTime::now(),
+        index: Vec::new(),
+    })
+}
+
+fn list_segments(dir: &Path) -> Result<VecDeque<Segment>, KvError> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) {
+            continue;
+        }
+        let base = match path.file_stem().and_then(|s| s.to_str()?.parse().ok()) {
+            Some(base) => base,
+            None => continue,
+        };
+        let metadata = fs::metadata(&path)?;
+        segments.push(Segment {
+            base,
+            path,
+            bytes: metadata.len(),
+            modified: metadata.modified()?,
+            index: Vec::new(),
+        });
+    }
+    segments.sort_by_key(|s| s.base);
+    Ok(segments.into())
+}
+
+/// 扫描 segment 中的所有记录建立稀疏索引，返回最后一条记录的 offset
+///
+/// bytes 被设置成完整的记录占用的字节数，之后的部分是没有写完的记录。
+fn scan_segment(segment: &mut Segment) -> Result<Option<u64>, KvError> {
+    let data = fs::read(&segment.path)?;
+    let mut buf = data.as_slice();
+    let mut last_offset = None;
+    // 最后一条没有写完的记录解析失败时，buf 停留在它的开头
+    while !buf.is_empty() {
+        let mut next = buf;
+        match Change::decode_length_delimited(&mut next) {
+            Ok(change) => {
+                segment.index(change.offset, (data.len() - buf.len()) as u64);
+                last_offset = Some(change.offset);
+            }
+            Err(_) => break,
+        }
+        buf = next;
+    }
+    segment.bytes = (data.len() - buf.len()) as u64;
+    Ok(last_offset)
+}
+
+/// 读取文件中 range 范围内 offset >= from_offset 的记录，追加到 result 中直到有 limit 条
+fn read_records(
+    mut file: File,
+    range: Range<u64>,
+    from_offset: u64,
+    limit: usize,
+    result: &mut Vec<Change>,
+) -> Result<(), KvError> {
+    file.seek(SeekFrom::Start(range.start))?;
+    let mut reader = BufReader::new(file.take(range.end - range.start));
+    let mut buf = Vec::new();
+    while result.len() < limit {
+        let len = match read_length(&mut reader)? {
+            Some(len) => len,
+            None => break,
+        };
+        if len > range.end - range.start {
+            return Err(KvError::Internal("Corrupted change log record".into()));
+        }
+        buf.resize(len as usize, 0);
+        reader.read_exact(&mut buf)?;
+        let change = Change::decode(buf.as_slice())?;
+        if change.offset >= from_offset {
+            result.push(change);
+        }
+    }
+    Ok(())
+}
+
+/// 读取记录前面 varint 编码的长度，已经读完时返回 None
+pub(crate) fn read_length(reader: &mut impl Read) -> Result<Option<u64>, KvError> {
+    let mut len = 0;
+    for i in 0..10 {
+        let mut byte = [0u8];
+        if reader.read(&mut byte)? == 0 {
+            return match i {
+                0 => Ok(None),
+                _ => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            };
+        }
+        len |= ((byte[0] & 0x7f) as u64) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(len));
+        }
+    }
+    Err(KvError::Internal("Corrupted change log record".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{assert_res_ok, CommandRequest, Kvpair, MemTable, Service, ServiceInner, Value};
+
+    fn config(dir: &Path) -> CdcConfig {
+        CdcConfig {
+            fsync: false,
+            ..CdcConfig::new(dir.to_str().unwrap())
+        }
+    }
+
+    fn new_service(log: ChangeLog) -> Service {
+        ServiceInner::new(MemTable::new()).changelog(log).into()
+    }
+
+    #[test]
+    fn changes_should_be_read_by_offset() {
+        let dir = tempdir().unwrap();
+        let service = new_service(ChangeLog::open(config(dir.path())).unwrap());
+        service.execute(CommandRequest::new_hset("t1", "k1", 1.into()));
+        service.execute(CommandRequest::new_hset("t1", "k2", 2.into()));
+        // 删除不存在的 key 不会产生记录
+        service.execute(CommandRequest::new_hmdel("t1", ["k1", "k3"]));
+
+        let log = service.changelog().unwrap();
+        assert_eq!(log.last_offset(), 3);
+        let changes = log.read(0, 0).unwrap();
+        let offsets: Vec<_> = changes.iter().map(|c| c.offset).collect();
+        assert_eq!(offsets, vec![1, 2, 3]);
+        assert_eq!(changes[1].value, Some(2.into()));
+        assert_eq!(
+            (changes[2].op(), changes[2].value.clone()),
+            (ChangeOp::Remove, None)
+        );
+
+        let changes = log.read(2, 1).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].key, "k2");
+        assert!(log.read(4, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn changes_should_be_read_through_sparse_index() {
+        let dir = tempdir().unwrap();
+        let service = new_service(ChangeLog::open(config(dir.path())).unwrap());
+        let value = Value::from("v".repeat(100));
+        for i in 0..2000 {
+            service.execute(CommandRequest::new_hset(
+                "t1",
+                format!("k{}", i),
+                value.clone(),
+            ));
+        }
+
+        let check = |log: &ChangeLog| {
+            assert!(log.inner.lock().unwrap().segments[0].index.len() > 2);
+            for from in [1, 999, 1500, 2000] {
+                let changes = log.read(from, 3).unwrap();
+                let offsets: Vec<_> = changes.iter().map(|c| c.offset).collect();
+                let expected: Vec<_> = (from..=2000).take(3).collect();
+                assert_eq!(offsets, expected);
+                assert_eq!(changes[0].key, format!("k{}", from - 1));
+            }
+        };
+        check(service.changelog().unwrap());
+        drop(service);
+        // 重新打开时扫描 segment 重建索引
+        check(&ChangeLog::open(config(dir.path())).unwrap());
+    }
+
+    #[test]
+    fn reopened_log_should_continue_offsets_and_drop_partial_record() {
+        let dir = tempdir().unwrap();
+        let service = new_service(ChangeLog::open(config(dir.path())).unwrap());
+        service.execute(CommandRequest::new_hset("t1", "k1", 1.into()));
+        service.execute(CommandRequest::new_hset("t1", "k2", 2.into()));
+        drop(service);
+
+        // 模拟写到一半时崩溃
+        let path = segment_path(dir.path(), 1);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[20, 1, 2]).unwrap();
+
+        let service = new_service(ChangeLog::open(config(dir.path())).unwrap());
+        service.execute(CommandRequest::new_hset("t1", "k3", 3.into()));
+        let changes = service.changelog().unwrap().read(0, 0).unwrap();
+        let keys: Vec<_> = changes.iter().map(|c| (c.offset, c.key.as_str())).collect();
+        assert_eq!(keys, vec![(1, "k1"), (2, "k2"), (3, "k3")]);
+    }
+
+    #[test]
+    fn old_segments_should_be_removed_by_size() {
+        let dir = tempdir().unwrap();
+        let log = ChangeLog::open(CdcConfig {
+            segment_bytes: 64,
+            retention_bytes: Some(128),
+            ..config(dir.path())
+        })
+        .unwrap();
+        let service = new_service(log);
+        for i in 0..20 {
+            let cmd = CommandRequest::new_hset("t1", format!("key{}", i), "value".into());
+            service.execute(cmd);
+        }
+
+        let log = service.changelog().unwrap();
+        let first = log.first_offset();
+        assert!(first > 1);
+        assert_eq!(
+            log.read(1, 10).unwrap_err(),
+            KvError::HistoryTruncated(1, first)
+        );
+        let changes = log.read(first, 100).unwrap();
+        assert_eq!(changes.len() as u64, log.last_offset() - first + 1);
+
+        let res = service.execute(CommandRequest::new_read_changes(1, 10));
+        assert_eq!(res.status, 410);
+    }
+
+    #[test]
+    fn failed_append_should_undo_the_write() {
+        let dir = tempdir().unwrap();
+        let service = new_service(ChangeLog::open(config(&dir.path().join("log"))).unwrap());
+        service.execute(CommandRequest::new_hset("t1", "k1", 1.into()));
+
+        // 换成只读的文件，追加日志会失败
+        let log = service.changelog().unwrap();
+        {
+            let mut inner = log.inner.lock().unwrap();
+            let path = inner.segments.back().unwrap().path.clone();
+            inner.file = File::open(path).unwrap();
+        }
+        let pairs = vec![Kvpair::new("k1", 2.into()), Kvpair::new("k2", "new".into())];
+        let res = service.execute(CommandRequest::new_hmset("t1", pairs));
+        assert_eq!(res.status, 500);
+
+        // 存储中的修改被撤销，和日志保持一致
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_ok(res, &[1.into()], &[]);
+        let res = service.execute(CommandRequest::new_hget("t1", "k2"));
+        assert_eq!(res.status, 404);
+        assert_eq!(log.last_offset(), 1);
+    }
+
+    #[test]
+    fn snapshot_and_log_should_rebuild_all_data() {
+        let dir = tempdir().unwrap();
+        let service = new_service(ChangeLog::open(config(&dir.path().join("log"))).unwrap());
+        service.execute(CommandRequest::new_hset("t1", "k1", 1.into()));
+        service.execute(CommandRequest::new_hset("t2", "k1", "v".into()));
+
+        let path = dir.path().join("snapshot");
+        assert_eq!(service.snapshot(&path).unwrap(), 2);
+        service.execute(CommandRequest::new_hset("t1", "k1", 10.into()));
+        service.execute(CommandRequest::new_hdel("t2", "k1"));
+        service.execute(CommandRequest::new_hset("t2", "k2", "yes".into()));
+
+        // 外部消费者：先加载快照，再应用 offset 更大的记录
+        let (header, snapshot) = ChangeLog::read_snapshot(&path).unwrap();
+        assert_eq!(header.offset, 2);
+        let mut state = BTreeMap::new();
+        for change in snapshot {
+            state.insert((change.table, change.key), change.value.unwrap());
+        }
+        let log = service.changelog().unwrap();
+        for change in log.read(header.offset + 1, 0).unwrap() {
+            let key = (change.table.clone(), change.key.clone());
+            match change.op() {
+                ChangeOp::Put => state.insert(key, change.value.unwrap()),
+                ChangeOp::Remove => state.remove(&key),
+            };
+        }
+
+        let expected: BTreeMap<(String, String), Value> = [
+            (("t1".into(), "k1".into()), 10.into()),
+            (("t2".into(), "k2".into()), "yes".into()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(state, expected);
+    }
+}
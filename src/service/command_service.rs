@@ -1,6 +1,6 @@
 use crate::{
-    dispatch, CommandRequest, CommandResponse, CommandService, Hget, Hgetall, Hset, KvError,
-    Kvpair, MemTable, Storage, Value,
+    CommandResponse, CommandService, Hcas, Hdel, Hexist, Hget, Hgetall, Hmdel, Hmexist, Hmget,
+    Hmset, Hset, KvError, Storage, Value,
 };
 
 impl CommandService for Hget {
@@ -8,20 +8,62 @@ impl CommandService for Hget {
         match store.get(&self.table, &self.key) {
             Ok(Some(v)) => v.into(),
             Ok(None) => KvError::NotFound(self.table, self.key).into(),
-            Err(_) => todo!(),
+            Err(e) => e.into(),
         }
     }
 }
 
 impl CommandService for Hgetall {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get_all(&self.table) {
+        let result = match self.limit {
+            0 => store.get_all(&self.table),
+            limit => {
+                let after = Some(self.after.as_str()).filter(|v| !v.is_empty());
+                store.get_range(&self.table, after, limit as usize)
+            }
+        };
+        match result {
             Ok(v) => v.into(),
             Err(e) => e.into(),
         }
     }
 }
 
+impl CommandService for Hmget {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in &self.keys {
+            match store.get(&self.table, key) {
+                Ok(v) => values.push(v.unwrap_or_default()),
+                Err(e) => return e.into(),
+            }
+        }
+        values.into()
+    }
+}
+
+impl CommandService for Hexist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.contains(&self.table, &self.key) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmexist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mut values: Vec<Value> = Vec::with_capacity(self.keys.len());
+        for key in &self.keys {
+            match store.contains(&self.table, key) {
+                Ok(v) => values.push(v.into()),
+                Err(e) => return e.into(),
+            }
+        }
+        values.into()
+    }
+}
+
 impl CommandService for Hset {
     fn execute(self, store: &impl Storage) -> CommandResponse {
         match self.pair {
@@ -35,11 +77,69 @@ impl CommandService for Hset {
     }
 }
 
+/// 逐个写入，中途失败时已经写入的 key 不会回滚，错误响应的 values 中是这些 key 的旧值
+impl CommandService for Hmset {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.pairs.len());
+        for pair in self.pairs {
+            match store.set(&self.table, pair.key, pair.value.unwrap_or_default()) {
+                Ok(v) => values.push(v.unwrap_or_default()),
+                Err(e) => return partial_error(e, values),
+            }
+        }
+        values.into()
+    }
+}
+
+impl CommandService for Hdel {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.del(&self.table, &self.key) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => Value::default().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// 和 Hmset 一样，中途失败时错误响应的 values 中是已经删除的 key 的旧值
+impl CommandService for Hmdel {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in &self.keys {
+            match store.del(&self.table, key) {
+                Ok(v) => values.push(v.unwrap_or_default()),
+                Err(e) => return partial_error(e, values),
+            }
+        }
+        values.into()
+    }
+}
+
+/// 成功时返回之前的值，也就是 expected；失败时不返回 values，这样不会生成变更日志和 watch 事件
+impl CommandService for Hcas {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.compare_and_set(&self.table, &self.key, self.expected.as_ref(), self.value) {
+            Ok(true) => self.expected.unwrap_or_default().into(),
+            Ok(false) => KvError::Conflict(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// 批量写命令中途失败的响应，带上已经生效的那部分 key 的旧值，用来生成变更日志和 watch 事件
+fn partial_error(e: KvError, applied: Vec<Value>) -> CommandResponse {
+    let mut res = CommandResponse::from(e);
+    res.values = applied;
+    res
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{assert_res_error, assert_res_ok};
-
     use super::*;
+    use crate::{
+        assert_res_error, assert_res_ok, dispatch, service::Changes, CommandRequest, Kvpair,
+        MemTable, StorageStats,
+    };
 
     #[test]
     fn hset_should_work() {
@@ -92,4 +192,159 @@ mod tests {
 
         assert_res_ok(res, &[], &pairs);
     }
+
+    #[test]
+    fn hget_all_with_limit_should_return_one_page() {
+        let store = MemTable::new();
+        for key in ["k3", "k1", "k2"] {
+            dispatch(CommandRequest::new_hset("t1", key, key.into()), &store);
+        }
+
+        let res = dispatch(CommandRequest::new_hget_range("t1", "", 2), &store);
+        let pairs = [
+            Kvpair::new("k1", "k1".into()),
+            Kvpair::new("k2", "k2".into()),
+        ];
+        assert_res_ok(res, &[], &pairs);
+        let res = dispatch(CommandRequest::new_hget_range("t1", "k2", 2), &store);
+        assert_res_ok(res, &[], &[Kvpair::new("k3", "k3".into())]);
+    }
+
+    #[test]
+    fn hcas_should_only_write_when_value_matches() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hcas("t1", "k1", None, Some(1.into()));
+        let changes = Changes::from_request(cmd.request_data.as_ref().unwrap());
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res.clone(), &[Value::default()], &[]);
+        assert_eq!(changes.unwrap().resolve(&res).len(), 1);
+
+        // 值已经变化时返回 409，不生成变化
+        let cmd = CommandRequest::new_hcas("t1", "k1", Some(2.into()), None);
+        let changes = Changes::from_request(cmd.request_data.as_ref().unwrap());
+        let res = dispatch(cmd, &store);
+        assert!(changes.unwrap().resolve(&res).is_empty());
+        assert_res_error(res, 409, "Value has changed");
+
+        let cmd = CommandRequest::new_hcas("t1", "k1", Some(1.into()), None);
+        assert_res_ok(dispatch(cmd, &store), &[1.into()], &[]);
+        let res = dispatch(CommandRequest::new_hexist("t1", "k1"), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+    }
+
+    #[test]
+    fn hmset_should_return_old_values() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", 1.into()), &store);
+        let pairs = vec![Kvpair::new("k1", 10.into()), Kvpair::new("k2", 20.into())];
+        let res = dispatch(CommandRequest::new_hmset("t1", pairs), &store);
+        assert_res_ok(res, &[1.into(), Value::default()], &[]);
+
+        let res = dispatch(CommandRequest::new_hget("t1", "k2"), &store);
+        assert_res_ok(res, &[20.into()], &[]);
+    }
+
+    #[test]
+    fn hdel_and_hmdel_should_return_old_values() {
+        let store = MemTable::new();
+        for key in ["k1", "k2", "k3"] {
+            dispatch(CommandRequest::new_hset("t1", key, key.into()), &store);
+        }
+
+        let res = dispatch(CommandRequest::new_hdel("t1", "k1"), &store);
+        assert_res_ok(res, &["k1".into()], &[]);
+        let res = dispatch(CommandRequest::new_hdel("t1", "k1"), &store);
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = dispatch(CommandRequest::new_hmdel("t1", ["k2", "k4"]), &store);
+        assert_res_ok(res, &["k2".into(), Value::default()], &[]);
+        let res = dispatch(CommandRequest::new_hget_all("t1"), &store);
+        assert_res_ok(res, &[], &[Kvpair::new("k3", "k3".into())]);
+    }
+
+    #[test]
+    fn hmget_and_hexist_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", 1.into()), &store);
+
+        let res = dispatch(CommandRequest::new_hmget("t1", ["k1", "k2"]), &store);
+        assert_res_ok(res, &[1.into(), Value::default()], &[]);
+        let res = dispatch(CommandRequest::new_hexist("t1", "k1"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_hmexist("t1", ["k2", "k1"]), &store);
+        assert_res_ok(res, &[false.into(), true.into()], &[]);
+    }
+
+    #[test]
+    fn partial_hmset_should_report_applied_changes() {
+        let store = FailingStore(MemTable::new());
+        dispatch(CommandRequest::new_hset("t1", "k1", 1.into()), &store);
+        let pairs = vec![
+            Kvpair::new("k1", 10.into()),
+            Kvpair::new("k2", 20.into()),
+            Kvpair::new("bad", 30.into()),
+            Kvpair::new("k3", 40.into()),
+        ];
+        let cmd = CommandRequest::new_hmset("t1", pairs);
+        let changes = cmd.request_data.as_ref().and_then(Changes::from_request);
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.status, 500);
+        assert_eq!(res.values, &[1.into(), Value::default()]);
+
+        // 已经写入的 k1、k2 需要出现在变化里，没有写入的 bad、k3 不能出现
+        let changes = changes.unwrap().resolve(&res);
+        let keys: Vec<_> = changes.iter().map(|c| c.key.as_str()).collect();
+        assert_eq!(keys, ["k1", "k2"]);
+        assert_eq!(changes[0].old_value, Some(1.into()));
+
+        let res = dispatch(CommandRequest::new_hmdel("t1", ["k1", "bad", "k2"]), &store);
+        assert_eq!(res.values, &[10.into()]);
+    }
+
+    /// 写入或者删除 key 为 bad 时失败的存储
+    struct FailingStore(MemTable);
+
+    impl Storage for FailingStore {
+        fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            self.0.get(table, key)
+        }
+        fn set(
+            &self,
+            table: &str,
+            key: impl Into<String>,
+            value: impl Into<Value>,
+        ) -> Result<Option<Value>, KvError> {
+            match key.into() {
+                key if key == "bad" => Err(KvError::Internal("disk is full".into())),
+                key => self.0.set(table, key, value),
+            }
+        }
+        fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+            self.0.contains(table, key)
+        }
+        fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            match key {
+                "bad" => Err(KvError::Internal("disk is full".into())),
+                key => self.0.del(table, key),
+            }
+        }
+        fn compare_and_set(
+            &self,
+            table: &str,
+            key: &str,
+            expected: Option<&Value>,
+            value: Option<Value>,
+        ) -> Result<bool, KvError> {
+            self.0.compare_and_set(table, key, expected, value)
+        }
+        fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+            self.0.get_all(table)
+        }
+        fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+            self.0.get_iter(table)
+        }
+        fn stats(&self) -> Result<StorageStats, KvError> {
+            self.0.stats()
+        }
+    }
 }
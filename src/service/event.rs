@@ -0,0 +1,41 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+use crate::KvError;
+
+/// 客户端连接上来
+#[derive(Debug)]
+pub struct ConnectEvent {
+    pub peer: Option<SocketAddr>,
+    pub at: SystemTime,
+}
+
+/// 客户端断开连接
+#[derive(Debug)]
+pub struct DisconnectEvent {
+    pub peer: Option<SocketAddr>,
+    /// 连接持续的时间
+    pub duration: Duration,
+    /// 这个连接上处理的命令个数
+    pub requests: u64,
+}
+
+/// 一个响应发送完毕（或者发送失败）
+#[derive(Debug)]
+pub struct SendEvent {
+    pub peer: Option<SocketAddr>,
+    pub result: Result<(), KvError>,
+    /// 从收到命令到响应发送完毕的时间
+    pub elapsed: Duration,
+}
+
+/// 连接上发生的错误，比如解码失败或者发送失败
+#[derive(Debug)]
+pub struct ErrorEvent {
+    pub peer: Option<SocketAddr>,
+    pub error: KvError,
+    /// 出错时连接已经持续的时间
+    pub elapsed: Duration,
+}
@@ -0,0 +1,209 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use dashmap::DashMap;
+
+use crate::{Info, KvError, Kvpair, StorageStats, Value};
+
+/// INFO 命令支持的 section
+const SECTIONS: [&str; 5] = ["server", "clients", "commands", "storage", "keyspace"];
+
+/// 服务器运行时的统计数据，用于 INFO 命令
+#[derive(Debug)]
+pub(crate) struct ServerStats {
+    started: Instant,
+    started_at: SystemTime,
+    connected: AtomicU64,
+    total_connections: AtomicU64,
+    commands: DashMap<&'static str, AtomicU64>,
+}
+
+impl ServerStats {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            connected: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            commands: DashMap::new(),
+        }
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.connected.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.connected.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn command_received(&self, name: &'static str) {
+        self.commands
+            .entry(name)
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 按照 INFO 命令要求的 section 生成结果，存储相关的 section 才需要读取 StorageStats
+    pub(crate) fn info(
+        &self,
+        info: &Info,
+        storage: impl FnOnce() -> Result<StorageStats, KvError>,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let sections: Vec<&str> = if info.sections.is_empty() {
+            SECTIONS.to_vec()
+        } else {
+            info.sections.iter().map(|s| s.as_str()).collect()
+        };
+        if let Some(s) = sections.iter().find(|s| !SECTIONS.contains(s)) {
+            return Err(KvError::InvalidCommand(format!(
+                "Unknown info section: {}",
+                s
+            )));
+        }
+
+        let need_storage = sections.iter().any(|s| *s == "storage" || *s == "keyspace");
+        let stats = if need_storage { Some(storage()?) } else { None };
+
+        let mut pairs = Vec::new();
+        for section in sections {
+            let mut add = |field: &str, value: Value| {
+                pairs.push(Kvpair::new(format!("{}.{}", section, field), value))
+            };
+            match section {
+                "server" => {
+                    let started_at = self
+                        .started_at
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default();
+                    add("version", env!("CARGO_PKG_VERSION").into());
+                    add("started_at", (started_at.as_secs() as i64).into());
+                    add(
+                        "uptime_seconds",
+                        (self.started.elapsed().as_secs() as i64).into(),
+                    );
+                }
+                "clients" => {
+                    add("connected", load(&self.connected).into());
+                    add("total_connections", load(&self.total_connections).into());
+                }
+                "commands" => {
+                    let mut commands: Vec<_> = self
+                        .commands
+                        .iter()
+                        .map(|v| (*v.key(), load(v.value())))
+                        .collect();
+                    commands.sort();
+                    add("total", commands.iter().map(|(_, n)| n).sum::<i64>().into());
+                    for (name, n) in commands {
+                        add(name, n.into());
+                    }
+                }
+                "storage" => {
+                    let stats = stats.as_ref().unwrap();
+                    add("backend", stats.backend.into());
+                    add("tables", (stats.tables.len() as i64).into());
+                    if let Some(v) = stats.disk_bytes {
+                        add("disk_bytes", (v as i64).into());
+                    }
+                    if let Some(v) = stats.memory_bytes {
+                        add("memory_bytes", (v as i64).into());
+                    }
+                    for (k, v) in &stats.config {
+                        add(&format!("config.{}", k), v.as_str().into());
+                    }
+                }
+                "keyspace" => {
+                    for table in &stats.as_ref().unwrap().tables {
+                        add(&format!("{}.keys", table.name), (table.keys as i64).into());
+                        add(
+                            &format!("{}.bytes", table.name),
+                            (table.bytes as i64).into(),
+                        );
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+        Ok(pairs)
+    }
+}
+
+fn load(v: &AtomicU64) -> i64 {
+    v.load(Ordering::Relaxed) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TableStats;
+
+    fn storage() -> Result<StorageStats, KvError> {
+        Ok(StorageStats {
+            backend: "memtable",
+            tables: vec![TableStats {
+                name: "t1".into(),
+                keys: 2,
+                bytes: 10,
+            }],
+            memory_bytes: Some(10),
+            ..Default::default()
+        })
+    }
+
+    fn get<'a>(pairs: &'a [Kvpair], key: &str) -> Option<&'a Value> {
+        pairs
+            .iter()
+            .find(|p| p.key == key)
+            .and_then(|p| p.value.as_ref())
+    }
+
+    #[test]
+    fn info_should_return_all_sections() {
+        let stats = ServerStats::new();
+        stats.connection_opened();
+        stats.connection_opened();
+        stats.connection_closed();
+        stats.command_received("hget");
+        stats.command_received("hget");
+        stats.command_received("hset");
+
+        let pairs = stats.info(&Info::default(), storage).unwrap();
+        assert_eq!(
+            get(&pairs, "server.version"),
+            Some(&env!("CARGO_PKG_VERSION").into())
+        );
+        assert_eq!(get(&pairs, "clients.connected"), Some(&1.into()));
+        assert_eq!(get(&pairs, "clients.total_connections"), Some(&2.into()));
+        assert_eq!(get(&pairs, "commands.total"), Some(&3.into()));
+        assert_eq!(get(&pairs, "commands.hget"), Some(&2.into()));
+        assert_eq!(get(&pairs, "storage.backend"), Some(&"memtable".into()));
+        assert_eq!(get(&pairs, "storage.memory_bytes"), Some(&10.into()));
+        assert_eq!(get(&pairs, "keyspace.t1.keys"), Some(&2.into()));
+        assert_eq!(get(&pairs, "keyspace.t1.bytes"), Some(&10.into()));
+    }
+
+    #[test]
+    fn info_should_only_return_requested_sections() {
+        let stats = ServerStats::new();
+        let info = Info {
+            sections: vec!["clients".into()],
+        };
+        // 不需要存储相关的 section 时，不应该读取 StorageStats
+        let pairs = stats
+            .info(&info, || panic!("storage should not be read"))
+            .unwrap();
+        assert!(pairs.iter().all(|p| p.key.starts_with("clients.")));
+
+        let info = Info {
+            sections: vec!["unknown".into()],
+        };
+        assert!(matches!(
+            stats.info(&info, storage),
+            Err(KvError::InvalidCommand(_))
+        ));
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::Arc;
+
+use crate::{CommandRequest, CommandResponse, Session};
+
+/// Service 的中间件，包裹在 dispatch 外面
+///
+/// 中间件按注册顺序调用 before，按相反的顺序调用 after，就像洋葱一样一层层包裹住 dispatch。
+/// 中间件可以持有自己的状态（计数器、缓存、配置等），认证、缓存、限流都可以用它来实现。
+pub trait Middleware: Send + Sync + 'static {
+    /// 在 dispatch 之前调用，可以改写请求；返回 Some(res) 会直接短路，不再调用后续的中间件和 dispatch
+    fn before(&self, _cmd: &mut CommandRequest, _session: &mut Session) -> Option<CommandResponse> {
+        None
+    }
+
+    /// 在 dispatch（或者短路）之后调用，可以改写响应
+    fn after(&self, _session: &Session, _res: &mut CommandResponse) {}
+
+    /// 包裹住后续的中间件和 dispatch，缺省实现依次调用 before / next / after
+    ///
+    /// 需要同时看到请求和响应的中间件（比如统计耗时）可以重载这个方法。
+    fn call(&self, mut cmd: CommandRequest, session: &mut Session, next: Next) -> CommandResponse {
+        if let Some(res) = self.before(&mut cmd, session) {
+            return res;
+        }
+        let mut res = next.run(cmd, session);
+        self.after(session, &mut res);
+        res
+    }
+}
+
+/// 中间件链条中剩下的部分
+pub struct Next<'a> {
+    layers: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Fn(CommandRequest, &Session) -> CommandResponse,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        layers: &'a [Box<dyn Middleware>],
+        handler: &'a dyn Fn(CommandRequest, &Session) -> CommandResponse,
+    ) -> Self {
+        Self { layers, handler }
+    }
+
+    /// 交给下一个中间件处理，没有中间件了就调用 dispatch
+    pub fn run(self, cmd: CommandRequest, session: &mut Session) -> CommandResponse {
+        match self.layers.split_first() {
+            Some((layer, rest)) => layer.call(cmd, session, Next::new(rest, self.handler)),
+            None => (self.handler)(cmd, session),
+        }
+    }
+}
+
+/// 用 Arc 包裹的中间件，这样在把它交给 Service 之后，还可以读取它的状态（比如统计数据）
+impl<T: Middleware> Middleware for Arc<T> {
+    fn call(&self, cmd: CommandRequest, session: &mut Session, next: Next) -> CommandResponse {
+        self.as_ref().call(cmd, session, next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{assert_res_ok, Value};
+
+    struct Recorder {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        stop: bool,
+    }
+
+    impl Middleware for Recorder {
+        fn before(
+            &self,
+            _cmd: &mut CommandRequest,
+            _session: &mut Session,
+        ) -> Option<CommandResponse> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} before", self.name));
+            self.stop.then(|| Value::from(self.name).into())
+        }
+
+        fn after(&self, _session: &Session, _res: &mut CommandResponse) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} after", self.name));
+        }
+    }
+
+    fn recorder(
+        name: &'static str,
+        log: &Arc<Mutex<Vec<String>>>,
+        stop: bool,
+    ) -> Box<dyn Middleware> {
+        Box::new(Recorder {
+            name,
+            log: log.clone(),
+            stop,
+        })
+    }
+
+    #[test]
+    fn layers_should_wrap_handler_like_onion() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let layers = vec![recorder("a", &log, false), recorder("b", &log, false)];
+
+        let handler = |_, _: &Session| {
+            log.lock().unwrap().push("handler".into());
+            Value::from("handler").into()
+        };
+        let next = Next::new(&layers, &handler);
+        let res = next.run(CommandRequest::default(), &mut Session::default());
+
+        assert_res_ok(res, &["handler".into()], &[]);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["a before", "b before", "handler", "b after", "a after"]
+        );
+    }
+
+    #[test]
+    fn layer_should_short_circuit() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let layers = vec![
+            recorder("a", &log, false),
+            recorder("b", &log, true),
+            recorder("c", &log, false),
+        ];
+
+        let handler = |_, _: &Session| {
+            log.lock().unwrap().push("handler".into());
+            Value::from("handler").into()
+        };
+        let next = Next::new(&layers, &handler);
+        let res = next.run(CommandRequest::default(), &mut Session::default());
+
+        assert_res_ok(res, &["b".into()], &[]);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["a before", "b before", "a after"]
+        );
+    }
+}
@@ -1,14 +1,70 @@
+mod audit;
+mod auth;
+mod change;
+mod changelog;
 mod command_service;
+mod event;
+mod info;
+mod middleware;
+mod pubsub;
+mod rate_limit;
+mod session;
+mod slowlog;
+mod watch;
 
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Instant, SystemTime},
+};
+
+use http::StatusCode;
+use prost::Message;
+use tokio::sync::Semaphore;
+use tracing::{debug, field, info_span, Span};
 
-use tracing::debug;
+pub(crate) use change::{Changes, KeyChange};
+pub(crate) use changelog::read_length;
+use info::ServerStats;
 
 use crate::{
-    command_request::RequestData, CommandRequest, CommandResponse, KvError, Kvpair, MemTable,
-    Storage, Value,
+    command_request::RequestData, Change, ChangeOp, CommandRequest, CommandResponse, Info, KvError,
+    MemTable, Metrics, PubsubConfig, RaftHandle, ReadChanges, ReplicationStats, SlowlogConfig,
+    Snapshot, StateMachine, Storage, StorageStats, Subscribe, Value, Watch, WatchConfig,
 };
 
+#[cfg(test)]
+use crate::Kvpair;
+pub use audit::{AuditIssue, AuditLog, AuditRecord};
+pub use auth::{hash_password, Authenticator};
+pub use changelog::ChangeLog;
+pub use event::{ConnectEvent, DisconnectEvent, ErrorEvent, SendEvent};
+pub use middleware::{Middleware, Next};
+pub use pubsub::{PubSub, PubSubStats};
+pub use rate_limit::{RateLimitStats, RateLimiter};
+pub use session::Session;
+pub use slowlog::{Slowlog, SlowlogEntry};
+pub use watch::{KeyspaceWatcher, WatchStats};
+
+/// Snapshot 没有指定 limit 时每页返回的记录数
+const SNAPSHOT_DEFAULT_LIMIT: usize = 1000;
+/// Snapshot 每页最多返回的记录数
+const SNAPSHOT_MAX_LIMIT: usize = 10000;
+/// Snapshot 每页的数据超过这个大小之后就不再继续读取，避免超过 frame 的大小限制
+const SNAPSHOT_CHUNK_BYTES: usize = 4 * 1024 * 1024;
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// follower 的 leader 地址和复制状态
+struct Replica {
+    leader: String,
+    stats: ReplicationStats,
+}
+
 /// 对 Command 的处理的抽象
 pub trait CommandService {
     // 处理 Command，返回 Response
@@ -25,7 +81,13 @@ pub trait NotifyMut<Arg> {
     fn notify(&self, arg: &mut Arg);
 }
 
-impl<Arg> Notify<Arg> for Vec<fn(&Arg)> {
+/// 事件回调，可以是捕获了状态（比如 metrics、logger、配置）的闭包
+pub type Hook<Arg> = Box<dyn Fn(&Arg) + Send + Sync>;
+
+/// 可以修改参数的事件回调
+pub type HookMut<Arg> = Box<dyn Fn(&mut Arg) + Send + Sync>;
+
+impl<Arg> Notify<Arg> for Vec<Hook<Arg>> {
     #[inline]
     fn notify(&self, arg: &Arg) {
         for f in self {
@@ -34,7 +96,7 @@ impl<Arg> Notify<Arg> for Vec<fn(&Arg)> {
     }
 }
 
-impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg)> {
+impl<Arg> NotifyMut<Arg> for Vec<HookMut<Arg>> {
     #[inline]
     fn notify(&self, arg: &mut Arg) {
         for f in self {
@@ -43,12 +105,23 @@ impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg)> {
     }
 }
 
-// 从 Request 中得到 Response，目前处理 HGET/HGETALL/HSET
+// 从 Request 中得到 Response，目前处理 HGET/HGETALL/HSET/HMSET/HDEL/HMDEL
 pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
     match cmd.request_data {
         Some(RequestData::Hget(param)) => param.execute(store),
         Some(RequestData::Hgetall(param)) => param.execute(store),
+        Some(RequestData::Hmget(param)) => param.execute(store),
+        Some(RequestData::Hexist(param)) => param.execute(store),
+        Some(RequestData::Hmexist(param)) => param.execute(store),
         Some(RequestData::Hset(param)) => param.execute(store),
+        Some(RequestData::Hmset(param)) => param.execute(store),
+        Some(RequestData::Hdel(param)) => param.execute(store),
+        Some(RequestData::Hmdel(param)) => param.execute(store),
+        Some(RequestData::Hcas(param)) => param.execute(store),
+        // Auth 命令由 Authenticator 中间件处理，到达这里说明没有开启认证
+        Some(RequestData::Auth(_)) => {
+            KvError::InvalidCommand("Authentication is not enabled".into()).into()
+        }
         None => KvError::InvalidCommand("Request has no data".into()).into(),
         _ => KvError::Internal("Not implemented".into()).into(),
     }
@@ -68,10 +141,84 @@ impl<Store: Storage> Clone for Service<Store> {
 }
 
 impl<Store: Storage> Service<Store> {
+    /// 在一个匿名的 Session 中执行命令，开启认证后会被拒绝
     pub fn execute(&self, cmd: CommandRequest) -> CommandResponse {
-        debug!("cmd is: {:?}", cmd);
+        self.execute_with(cmd, &mut Session::default())
+    }
+
+    /// 在某个连接的 Session 中执行命令，请求会依次经过所有的中间件再 dispatch
+    pub fn execute_with(&self, cmd: CommandRequest, session: &mut Session) -> CommandResponse {
+        let span = request_span(&cmd, session);
+        let _enter = span.enter();
+        debug!("cmd is: {}", cmd);
         self.inner.on_received.notify(&cmd);
-        let mut res = dispatch(cmd, &self.inner.store);
+        if let Some(data) = &cmd.request_data {
+            self.inner.stats.command_received(data.name());
+        }
+        // 管理命令需要读取 Service 自己的状态，其它命令交给 dispatch
+        let handler = |cmd: CommandRequest, session: &Session| match cmd.request_data {
+            Some(RequestData::Info(info)) => self.info(&info),
+            Some(RequestData::SlowlogGet(param)) => {
+                slowlog::to_pairs(&self.inner.slowlog.get(param.count as usize)).into()
+            }
+            Some(RequestData::SlowlogReset(_)) => {
+                Value::from(self.inner.slowlog.reset() as i64).into()
+            }
+            Some(RequestData::Subscribe(param)) => self.subscribe(param, session),
+            Some(RequestData::Unsubscribe(param)) => {
+                let pubsub = &self.inner.pubsub;
+                let count = pubsub.unsubscribe(session.id, param.channels, param.patterns);
+                Value::from(count as i64).into()
+            }
+            Some(RequestData::Publish(param)) => {
+                let data = param.data.unwrap_or_default();
+                let count = self.inner.pubsub.publish(&param.channel, data);
+                Value::from(count as i64).into()
+            }
+            Some(RequestData::Watch(param)) => self.watch(param, session),
+            Some(RequestData::Unwatch(_)) => {
+                self.inner.watcher.unwatch(session.id);
+                Value::default().into()
+            }
+            Some(RequestData::ReadChanges(param)) => self.read_changes(param),
+            Some(RequestData::Snapshot(param)) => match self.snapshot_page(param) {
+                Ok((offset, more, changes)) => {
+                    let mut res = CommandResponse::from(changes);
+                    res.values = vec![Value::from(offset as i64), Value::from(more)];
+                    res
+                }
+                Err(e) => e.into(),
+            },
+            request_data => {
+                let cmd = CommandRequest { request_data };
+                let changes = cmd.request_data.as_ref().and_then(Changes::from_request);
+                match (changes, &self.inner.replica, &self.inner.raft) {
+                    // follower 的数据只能来自 leader
+                    (Some(_), Some(replica), _) => {
+                        KvError::NotLeader(replica.leader.clone()).into()
+                    }
+                    // Raft 集群中的命令经过 leader 的中间件之后，写命令提交到日志，读命令走 read-index
+                    (Some(_), None, Some(raft)) => raft.propose(cmd),
+                    (None, _, Some(raft)) => raft.read(cmd),
+                    (Some(changes), None, None) => self.write(cmd, changes),
+                    (None, _, None) => dispatch(cmd, &self.inner.store),
+                }
+            }
+        };
+
+        let summary = self
+            .inner
+            .slowlog
+            .is_enabled()
+            .then(|| slowlog::summary(&cmd));
+        let (at, start) = (SystemTime::now(), Instant::now());
+        let mut res = Next::new(&self.inner.layers, &handler).run(cmd, session);
+        if let Some(summary) = summary {
+            let elapsed = start.elapsed();
+            self.inner
+                .slowlog
+                .record(summary, session.peer, at, elapsed);
+        }
         debug!("cmd dispatch result is: {:?}", res);
         self.inner.on_executed.notify(&res);
 
@@ -80,43 +227,567 @@ impl<Store: Storage> Service<Store> {
         if !self.inner.on_before_send.is_empty() {
             debug!("Modified response: {:?}", res);
         }
+
+        // Auth 命令会改变 session 中的用户，所以在最后才记录
+        if let Some(user) = &session.user {
+            span.record("user", user.as_str());
+        }
+        span.record("status", res.status);
         res
     }
+
+    fn subscribe(&self, param: Subscribe, session: &Session) -> CommandResponse {
+        // 本地调用没有连接，收不到推送的消息
+        if session.id == 0 {
+            let msg = "Subscribe is only available on a connection";
+            return KvError::InvalidCommand(msg.into()).into();
+        }
+        let pubsub = &self.inner.pubsub;
+        let count = pubsub.subscribe(session.id, param.channels, param.patterns);
+        Value::from(count as i64).into()
+    }
+
+    /// 在网络层的 async task 中执行命令
+    ///
+    /// Auth 命令要验证 argon2 哈希，会占用几十毫秒的 CPU，所以放到 blocking 线程池中执行，
+    /// 同时执行的数量不超过 CPU 数，大量的认证请求不会阻塞 tokio 的 worker 线程。
+    /// 开启了 Raft 之后，命令需要等待多数节点确认，也放到 blocking 线程池中执行。
+    pub async fn execute_async(&self, cmd: CommandRequest, session: &mut Session) -> CommandResponse
+    where
+        Store: Send + Sync + 'static,
+    {
+        let is_auth = matches!(cmd.request_data, Some(RequestData::Auth(_)));
+        if !is_auth && self.inner.raft.is_none() {
+            return self.execute_with(cmd, session);
+        }
+        // Semaphore 不会被 close，acquire 总是成功
+        let _permit = match is_auth {
+            true => Some(self.inner.auth_permits.acquire().await),
+            false => None,
+        };
+        let (service, mut inner) = (self.clone(), session.clone());
+        let result = tokio::task::spawn_blocking(move || {
+            let res = service.execute_with(cmd, &mut inner);
+            (res, inner)
+        })
+        .await;
+        match result {
+            Ok((res, inner)) => {
+                *session = inner;
+                res
+            }
+            Err(e) => KvError::Internal(e.to_string()).into(),
+        }
+    }
+
+    /// 执行写命令，成功之后统一生成变更日志和 key 的变化事件
+    fn write(&self, cmd: CommandRequest, changes: Changes) -> CommandResponse {
+        let run = || {
+            let res = dispatch(cmd, &self.inner.store);
+            let changes = changes.resolve(&res);
+            (res, changes)
+        };
+        // 存储、变更日志和 watch 事件的顺序需要一致，所以都在 watcher 的锁里完成
+        self.inner.watcher.write(|| match &self.inner.changelog {
+            Some(log) => log.write(run, |changes| self.undo(changes)),
+            None => run(),
+        })
+    }
+
+    /// 变更日志写入失败时，按相反的顺序把修改过的 key 恢复成旧值
+    fn undo(&self, changes: &[KeyChange]) -> Result<(), KvError> {
+        let store = &self.inner.store;
+        for change in changes.iter().rev() {
+            match &change.old_value {
+                Some(value) => store.set(&change.table, change.key.clone(), value.clone())?,
+                None => store.del(&change.table, &change.key)?,
+            };
+        }
+        Ok(())
+    }
+
+    /// 应用从 leader 复制过来的变更，和普通的写命令一样会生成变更日志和 watch 事件
+    pub(crate) fn apply_changes(&self, changes: Vec<Change>) -> Result<(), KvError> {
+        for change in changes {
+            let cmd = match change.op() {
+                ChangeOp::Put => {
+                    let value = change.value.unwrap_or_default();
+                    CommandRequest::new_hset(change.table, change.key, value)
+                }
+                ChangeOp::Remove => CommandRequest::new_hdel(change.table, change.key),
+            };
+            let changes = cmd.request_data.as_ref().and_then(Changes::from_request);
+            let res = self.write(cmd, changes.unwrap());
+            if res.status != StatusCode::OK.as_u16() as u32 {
+                return Err(KvError::Internal(format!(
+                    "Failed to apply change {}: {}",
+                    change.offset, res.message
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// 用 leader 快照中的一页替换这一页范围内的数据：删除本地有、快照中没有的 key，再写入这一页
+    ///
+    /// 这一页的范围是游标 after 之后到这一页的最后一个 key，最后一页一直到所有数据的结尾。
+    pub(crate) fn load_snapshot_page(
+        &self,
+        after: Option<(&str, &str)>,
+        changes: Vec<Change>,
+        last: bool,
+    ) -> Result<(), KvError> {
+        let end = match changes.last() {
+            _ if last => None,
+            Some(c) => Some((c.table.as_str(), c.key.as_str())),
+            // 不是最后一页时总有数据，这里只是防御
+            None => return Ok(()),
+        };
+        let keys: HashSet<(&str, &str)> = changes
+            .iter()
+            .map(|c| (c.table.as_str(), c.key.as_str()))
+            .collect();
+
+        // 通常一页在同一个 table 里，不需要遍历所有的 table
+        let tables = match (after, end) {
+            (Some((from, _)), Some((to, _))) if from == to => vec![from.to_string()],
+            _ => self
+                .inner
+                .store
+                .tables()?
+                .into_iter()
+                .filter(|t| after.is_none_or(|(from, _)| t.as_str() >= from))
+                .filter(|t| end.is_none_or(|(to, _)| t.as_str() <= to))
+                .collect(),
+        };
+
+        let mut removed = Vec::new();
+        for table in tables {
+            let mut cursor = after
+                .filter(|(t, _)| *t == table)
+                .map(|(_, k)| k.to_string());
+            'table: loop {
+                let pairs = self.inner.store.get_range(
+                    &table,
+                    cursor.as_deref(),
+                    SNAPSHOT_DEFAULT_LIMIT,
+                )?;
+                for pair in &pairs {
+                    if end.is_some_and(|end| (table.as_str(), pair.key.as_str()) > end) {
+                        break 'table;
+                    }
+                    if !keys.contains(&(table.as_str(), pair.key.as_str())) {
+                        removed.push(Change {
+                            op: ChangeOp::Remove as i32,
+                            table: table.clone(),
+                            key: pair.key.clone(),
+                            ..Default::default()
+                        });
+                    }
+                }
+                match pairs.last() {
+                    Some(pair) if pairs.len() == SNAPSHOT_DEFAULT_LIMIT => {
+                        cursor = Some(pair.key.clone())
+                    }
+                    _ => break,
+                }
+            }
+        }
+        self.apply_changes(removed)?;
+        self.apply_changes(changes)
+    }
+
+    /// follower 的复制状态，leader 为 None
+    pub fn replication(&self) -> Option<&ReplicationStats> {
+        self.inner.replica.as_ref().map(|r| &r.stats)
+    }
+
+    /// Raft 节点的句柄，可以用来变更集群成员；没有开启 Raft 时为 None
+    pub fn raft(&self) -> Option<&RaftHandle> {
+        self.inner.raft.as_ref()
+    }
+
+    fn read_changes(&self, param: ReadChanges) -> CommandResponse {
+        let log = match &self.inner.changelog {
+            Some(log) => log,
+            None => return KvError::InvalidCommand("Change log is not enabled".into()).into(),
+        };
+        match log.read(param.from_offset, param.limit as usize) {
+            Ok(changes) => {
+                let mut res = CommandResponse::from(changes);
+                res.values = vec![Value::from(log.last_offset() as i64)];
+                res
+            }
+            Err(e) => e.into(),
+        }
+    }
+
+    fn watch(&self, param: Watch, session: &Session) -> CommandResponse {
+        if session.id == 0 {
+            let msg = "Watch is only available on a connection";
+            return KvError::InvalidCommand(msg.into()).into();
+        }
+        match self.inner.watcher.watch(session.id, param) {
+            Ok(seq) => Value::from(seq as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+
+    fn info(&self, info: &Info) -> CommandResponse {
+        match self.inner.stats.info(info, || self.storage_stats()) {
+            Ok(pairs) => pairs.into(),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// Pub/Sub 消息中心，服务器端的代码也可以通过它发布消息
+    pub fn pubsub(&self) -> &PubSub {
+        &self.inner.pubsub
+    }
+
+    /// key 变化事件的分发中心
+    pub fn watcher(&self) -> &KeyspaceWatcher {
+        &self.inner.watcher
+    }
+
+    /// 变更日志，没有开启时为 None
+    pub fn changelog(&self) -> Option<&ChangeLog> {
+        self.inner.changelog.as_ref()
+    }
+
+    /// 把所有数据写入快照文件，返回快照对应的变更日志 offset
+    ///
+    /// 外部消费者加载快照之后，从 offset + 1 开始读取变更日志就可以得到完整的数据。
+    /// 数据按页读取并直接写入文件，生成快照时不阻塞写命令。
+    pub fn snapshot(&self, path: impl AsRef<Path>) -> Result<u64, KvError> {
+        let log = self
+            .changelog()
+            .ok_or_else(|| KvError::InvalidCommand("Change log is not enabled".into()))?;
+        let header = log.snapshot_header();
+        ChangeLog::write_snapshot(path.as_ref(), &header, |write| {
+            self.dump(&mut |change| {
+                write(Change {
+                    offset: header.offset,
+                    timestamp: header.timestamp,
+                    ..change
+                })
+            })
+        })?;
+        Ok(header.offset)
+    }
+
+    /// 读取快照中游标之后的一页，返回读取之前的 offset、是否还有下一页，以及这一页的数据
+    ///
+    /// 先取 offset 再读取数据，这样读取期间的修改都在 offset 之后的变更日志里。
+    fn snapshot_page(&self, param: Snapshot) -> Result<(u64, bool, Vec<Change>), KvError> {
+        let log = self
+            .changelog()
+            .ok_or_else(|| KvError::InvalidCommand("Change log is not enabled".into()))?;
+        let offset = log.last_offset();
+        let limit = match param.limit {
+            0 => SNAPSHOT_DEFAULT_LIMIT,
+            n => (n as usize).min(SNAPSHOT_MAX_LIMIT),
+        };
+        let timestamp = changelog::now_millis();
+
+        let store = &self.inner.store;
+        let (mut table, mut after) = match param.table.is_empty() {
+            true => (None, None),
+            false => (Some(param.table), Some(param.key)),
+        };
+        let (mut changes, mut bytes) = (Vec::new(), 0);
+        loop {
+            if let Some(name) = &table {
+                let pairs = store.get_range(name, after.as_deref(), limit - changes.len())?;
+                let done = pairs.len() < limit - changes.len();
+                for pair in pairs {
+                    let change = Change {
+                        offset,
+                        timestamp,
+                        op: ChangeOp::Put as i32,
+                        table: name.clone(),
+                        key: pair.key,
+                        value: pair.value,
+                    };
+                    bytes += change.encoded_len();
+                    changes.push(change);
+                    if bytes >= SNAPSHOT_CHUNK_BYTES {
+                        return Ok((offset, true, changes));
+                    }
+                }
+                if !done {
+                    return Ok((offset, true, changes));
+                }
+            }
+            // 当前的 table 已经读完，找下一个 table
+            let next = store
+                .tables()?
+                .into_iter()
+                .find(|t| table.as_ref().is_none_or(|table| t > table));
+            match next {
+                Some(next) => (table, after) = (Some(next), None),
+                None => return Ok((offset, false, changes)),
+            }
+        }
+    }
+
+    /// 慢命令日志
+    pub fn slowlog(&self) -> &Slowlog {
+        &self.inner.slowlog
+    }
+
+    /// 存储的统计信息，比如每个 table 的 key 数量和大小
+    pub fn storage_stats(&self) -> Result<StorageStats, KvError> {
+        self.inner.store.stats()
+    }
+
+    /// 持久化存储和变更日志中还没写到磁盘的数据
+    pub fn flush(&self) -> Result<(), KvError> {
+        self.inner.store.flush()?;
+        match self.changelog() {
+            Some(log) => log.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// 客户端连接上来时，由网络层调用
+    pub fn on_connected(&self, event: &ConnectEvent) {
+        self.inner.stats.connection_opened();
+        self.inner.on_connected.notify(event);
+    }
+
+    /// 客户端断开时，由网络层调用
+    pub fn on_disconnected(&self, event: &DisconnectEvent) {
+        self.inner.stats.connection_closed();
+        self.inner.on_disconnected.notify(event);
+    }
+
+    /// 响应发送之后（无论成功与否），由网络层调用
+    pub fn on_after_send(&self, event: &SendEvent) {
+        self.inner.on_after_send.notify(event);
+    }
+
+    /// 无法解码客户端发来的数据时，由网络层调用
+    pub fn on_decode_error(&self, event: &ErrorEvent) {
+        self.inner.on_decode_error.notify(event);
+    }
+
+    /// 响应发送失败时，由网络层调用
+    pub fn on_send_error(&self, event: &ErrorEvent) {
+        self.inner.on_send_error.notify(event);
+    }
+}
+
+impl<Store: Storage> StateMachine for Service<Store> {
+    fn apply(&self, cmd: CommandRequest) -> CommandResponse {
+        match cmd.request_data.as_ref().and_then(Changes::from_request) {
+            Some(changes) => self.write(cmd, changes),
+            None => KvError::InvalidCommand("Only write commands can be applied".into()).into(),
+        }
+    }
+
+    fn query(&self, cmd: CommandRequest) -> CommandResponse {
+        dispatch(cmd, &self.inner.store)
+    }
+
+    fn dump(&self, f: &mut dyn FnMut(Change) -> Result<(), KvError>) -> Result<(), KvError> {
+        let store = &self.inner.store;
+        for table in store.tables()? {
+            let mut after = None;
+            loop {
+                let pairs = store.get_range(&table, after.as_deref(), SNAPSHOT_DEFAULT_LIMIT)?;
+                let done = pairs.len() < SNAPSHOT_DEFAULT_LIMIT;
+                after = pairs.last().map(|p| p.key.clone());
+                for pair in pairs {
+                    f(Change {
+                        op: ChangeOp::Put as i32,
+                        table: table.clone(),
+                        key: pair.key,
+                        value: pair.value,
+                        ..Default::default()
+                    })?;
+                }
+                if done {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn restore(
+        &self,
+        after: Option<(&str, &str)>,
+        changes: Vec<Change>,
+        last: bool,
+    ) -> Result<(), KvError> {
+        self.load_snapshot_page(after, changes, last)
+    }
+}
+
+/// 每个请求的 span，hook、中间件和存储里产生的日志和 span 都在它下面
+fn request_span(cmd: &CommandRequest, session: &Session) -> Span {
+    let data = cmd.request_data.as_ref();
+    let client = session
+        .peer
+        .map_or_else(|| "local".to_string(), |p| p.to_string());
+    info_span!(
+        "request",
+        request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+        command = data.map_or("unknown", |v| v.name()),
+        table = data.and_then(|v| v.table()).unwrap_or_default(),
+        client = client.as_str(),
+        session = session.id,
+        user = field::Empty,
+        status = field::Empty,
+    )
 }
 
 pub struct ServiceInner<Store> {
     store: Store,
-    on_received: Vec<fn(&CommandRequest)>,
-    on_executed: Vec<fn(&CommandResponse)>,
-    on_before_send: Vec<fn(&mut CommandResponse)>,
-    on_after_send: Vec<fn()>,
+    stats: ServerStats,
+    slowlog: Slowlog,
+    pubsub: PubSub,
+    watcher: KeyspaceWatcher,
+    changelog: Option<ChangeLog>,
+    replica: Option<Replica>,
+    raft: Option<RaftHandle>,
+    layers: Vec<Box<dyn Middleware>>,
+    auth_permits: Semaphore,
+    on_received: Vec<Hook<CommandRequest>>,
+    on_executed: Vec<Hook<CommandResponse>>,
+    on_before_send: Vec<HookMut<CommandResponse>>,
+    on_after_send: Vec<Hook<SendEvent>>,
+    on_connected: Vec<Hook<ConnectEvent>>,
+    on_disconnected: Vec<Hook<DisconnectEvent>>,
+    on_decode_error: Vec<Hook<ErrorEvent>>,
+    on_send_error: Vec<Hook<ErrorEvent>>,
 }
 
 impl<Store: Storage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
         Self {
             store,
+            stats: ServerStats::new(),
+            slowlog: Slowlog::default(),
+            pubsub: PubSub::default(),
+            watcher: KeyspaceWatcher::default(),
+            changelog: None,
+            replica: None,
+            raft: None,
+            layers: Vec::new(),
+            auth_permits: Semaphore::new(
+                std::thread::available_parallelism().map_or(1, |n| n.get()),
+            ),
             on_received: Vec::new(),
             on_executed: Vec::new(),
             on_before_send: Vec::new(),
             on_after_send: Vec::new(),
+            on_connected: Vec::new(),
+            on_disconnected: Vec::new(),
+            on_decode_error: Vec::new(),
+            on_send_error: Vec::new(),
         }
     }
 
-    pub fn fn_received(mut self, f: fn(&CommandRequest)) -> Self {
-        self.on_received.push(f);
+    /// 添加一个中间件，比如 Authenticator 开启认证和 ACL 检查
+    ///
+    /// 中间件按注册顺序从外到里包裹 dispatch，内置的中间件需要按下面的顺序注册：
+    ///
+    /// 1. `metrics`：最外层，所有请求（包括被后面的中间件拒绝的）都会被统计
+    /// 2. AuditLog：被限流、认证或者 ACL 拒绝的修改请求也会被记录
+    /// 3. RateLimiter：在验证密码之前限流，暴力破解密码的请求会被拒绝
+    /// 4. Authenticator：最后检查身份和 ACL，自定义的中间件放在它后面
+    pub fn layer(mut self, layer: impl Middleware) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    /// 设置慢命令日志的阈值和容量，容量为 0 时关闭慢命令日志
+    pub fn slowlog(mut self, config: SlowlogConfig) -> Self {
+        self.slowlog = Slowlog::new(config);
+        self
+    }
+
+    /// 设置每个订阅者的消息缓存大小，以及缓存满了之后的处理策略
+    pub fn pubsub(mut self, config: PubsubConfig) -> Self {
+        self.pubsub = PubSub::new(config);
         self
     }
-    pub fn fn_executed(mut self, f: fn(&CommandResponse)) -> Self {
-        self.on_executed.push(f);
+
+    /// 设置每个 watcher 的事件缓存大小、保留的历史事件数，以及缓存满了之后的处理策略
+    pub fn watch(mut self, config: WatchConfig) -> Self {
+        self.watcher = KeyspaceWatcher::new(config);
+        self
+    }
+
+    /// 把所有成功的修改记录到变更日志（CDC）里
+    pub fn changelog(mut self, log: ChangeLog) -> Self {
+        self.changelog = Some(log);
+        self
+    }
+
+    /// 作为 leader 的 follower 运行：拒绝所有写命令，数据由 Follower 从 leader 复制过来
+    pub fn follower(mut self, leader: impl Into<String>) -> Self {
+        self.replica = Some(Replica {
+            leader: leader.into(),
+            stats: ReplicationStats::default(),
+        });
+        self
+    }
+
+    /// 作为 Raft 集群的成员运行：写命令提交到 Raft 日志，多数节点确认之后应用，读命令经过 read-index
+    ///
+    /// handle 来自 `RaftServer::handle`，Service 创建好之后再用它启动 RaftServer。
+    /// follower 收到的命令会被重定向到 leader，中间件（认证、ACL、审计）都在 leader 上执行。
+    pub fn raft(mut self, handle: RaftHandle) -> Self {
+        self.raft = Some(handle);
+        self
+    }
+
+    /// 收集 Prometheus metrics：命令次数、耗时、状态码、连接数和流量
+    ///
+    /// 它是一个中间件，注册顺序见 `layer`。
+    pub fn metrics(self, metrics: Arc<Metrics>) -> Self {
+        let (opened, closed) = (metrics.clone(), metrics.clone());
+        self.layer(metrics)
+            .fn_connected(move |_| opened.connection_opened())
+            .fn_disconnected(move |_| closed.connection_closed())
+    }
+
+    pub fn fn_received(mut self, f: impl Fn(&CommandRequest) + Send + Sync + 'static) -> Self {
+        self.on_received.push(Box::new(f));
         self
     }
-    pub fn fn_before_send(mut self, f: fn(&mut CommandResponse)) -> Self {
-        self.on_before_send.push(f);
+    pub fn fn_executed(mut self, f: impl Fn(&CommandResponse) + Send + Sync + 'static) -> Self {
+        self.on_executed.push(Box::new(f));
         self
     }
-    pub fn fn_after_send(mut self, f: fn()) -> Self {
-        self.on_after_send.push(f);
+    pub fn fn_before_send(
+        mut self,
+        f: impl Fn(&mut CommandResponse) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_before_send.push(Box::new(f));
+        self
+    }
+    pub fn fn_after_send(mut self, f: impl Fn(&SendEvent) + Send + Sync + 'static) -> Self {
+        self.on_after_send.push(Box::new(f));
+        self
+    }
+    pub fn fn_connected(mut self, f: impl Fn(&ConnectEvent) + Send + Sync + 'static) -> Self {
+        self.on_connected.push(Box::new(f));
+        self
+    }
+    pub fn fn_disconnected(mut self, f: impl Fn(&DisconnectEvent) + Send + Sync + 'static) -> Self {
+        self.on_disconnected.push(Box::new(f));
+        self
+    }
+    pub fn fn_decode_error(mut self, f: impl Fn(&ErrorEvent) + Send + Sync + 'static) -> Self {
+        self.on_decode_error.push(Box::new(f));
+        self
+    }
+    pub fn fn_send_error(mut self, f: impl Fn(&ErrorEvent) + Send + Sync + 'static) -> Self {
+        self.on_send_error.push(Box::new(f));
         self
     }
 }
@@ -129,14 +800,44 @@ impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
     }
 }
 
+// 需要 pub 才能让这个方法被 command_service 调用
+#[cfg(test)]
+pub fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
+    res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    assert_eq!(res.status, 200);
+    assert_eq!(res.message, "");
+    assert_eq!(res.values, values);
+    assert_eq!(res.pairs, pairs);
+}
+
+#[cfg(test)]
+pub fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
+    assert_eq!(res.status, code);
+    assert!(res.message.contains(msg));
+    assert_eq!(res.values, &[]);
+    assert_eq!(res.pairs, &[]);
+}
+
 #[cfg(test)]
 mod tests {
     use http::StatusCode;
-    use std::thread;
-    use tracing::info;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::{collections::HashMap, sync::Mutex, thread};
+    use tracing::{
+        field::{Field, Visit},
+        info,
+        span::{Attributes, Id, Record},
+        Subscriber,
+    };
+    use tracing_subscriber::{
+        layer::{Context, SubscriberExt},
+        registry::LookupSpan,
+        Layer, Registry,
+    };
 
     use super::*;
-    use crate::{MemTable, Value};
+    use crate::{MemTable, Value, WatchEvent};
 
     #[test]
     fn service_should_works() {
@@ -170,8 +871,8 @@ mod tests {
             res.status = StatusCode::CREATED.as_u16() as _;
         }
 
-        fn e() {
-            info!("Data is send.");
+        fn e(event: &SendEvent) {
+            info!("Data is send: {:?}", event.result);
         }
 
         let service: Service = ServiceInner::new(MemTable::default())
@@ -183,27 +884,330 @@ mod tests {
             .into();
 
         let res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
-        assert_eq!(res.status, StatusCode::CREATED.as_u16() as _);
+        assert_eq!(res.status, StatusCode::CREATED.as_u16() as u32);
         assert_eq!(res.message, "");
         assert_eq!(res.values, vec![Value::default()]);
     }
-}
 
-// 需要 pub 才能让这个方法被 command_service 调用
-#[cfg(test)]
-pub fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
-    res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    #[test]
+    fn auth_should_be_required_when_enabled() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .layer(auth::tests::authenticator())
+            .into();
+        let mut session = Session::default();
 
-    assert_eq!(res.status, 200);
-    assert_eq!(res.message, "");
-    assert_eq!(res.values, values);
-    assert_eq!(res.pairs, pairs);
-}
+        let res = service.execute_with(CommandRequest::new_hget("user_1", "k1"), &mut session);
+        assert_res_error(res, 401, "Unauthenticated");
 
-#[cfg(test)]
-pub fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
-    assert_eq!(res.status, code);
-    assert!(res.message.contains(msg));
-    assert_eq!(res.values, &[]);
-    assert_eq!(res.pairs, &[]);
+        let res = service.execute_with(CommandRequest::new_auth("alice", "wrong"), &mut session);
+        assert_res_error(res, 401, "invalid username or password");
+        assert!(!session.is_authenticated());
+
+        let res = service.execute_with(
+            CommandRequest::new_auth("alice", "alice-password"),
+            &mut session,
+        );
+        assert_res_ok(res, &["alice".into()], &[]);
+        assert_eq!(session.user.as_deref(), Some("alice"));
+
+        let cmd = CommandRequest::new_hset("user_1", "k1", "v1".into());
+        let res = service.execute_with(cmd, &mut session);
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = service.execute_with(CommandRequest::new_hget("orders", "k1"), &mut session);
+        assert_res_error(res, 403, "Permission denied");
+    }
+
+    #[test]
+    fn read_only_token_should_not_write() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .layer(auth::tests::authenticator())
+            .into();
+        let mut session = Session::default();
+
+        let res =
+            service.execute_with(CommandRequest::new_auth_token("ci.ci-secret"), &mut session);
+        assert_res_ok(res, &["ci".into()], &[]);
+
+        let cmd = CommandRequest::new_hset("orders", "k1", "v1".into());
+        let res = service.execute_with(cmd, &mut session);
+        assert_res_error(res, 403, "Permission denied");
+
+        let res = service.execute_with(CommandRequest::new_hget("orders", "k1"), &mut session);
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn session_identity_from_client_cert_should_be_trusted() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .layer(auth::tests::authenticator())
+            .into();
+        let mut session = Session::default().with_user(Some("alice".into()));
+
+        let cmd = CommandRequest::new_hset("user_1", "k1", "v1".into());
+        let res = service.execute_with(cmd, &mut session);
+        assert_res_ok(res, &[Value::default()], &[]);
+    }
+
+    #[test]
+    fn info_should_report_service_and_storage() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        service.execute(CommandRequest::new_hget("t1", "k1"));
+
+        let res = service.execute(CommandRequest::new_info(["commands", "keyspace"]));
+        assert_eq!(res.status, 200);
+        let pairs: Vec<_> = res.pairs.iter().map(|p| p.key.as_str()).collect();
+        assert_eq!(
+            pairs,
+            vec![
+                "commands.total",
+                "commands.hget",
+                "commands.hset",
+                "commands.info",
+                "keyspace.t1.keys",
+                "keyspace.t1.bytes"
+            ]
+        );
+        assert_eq!(res.pairs[0].value, Some(3.into()));
+
+        let res = service.execute(CommandRequest::new_info(["nothing"]));
+        assert_res_error(res, 400, "Unknown info section");
+    }
+
+    #[test]
+    fn info_should_require_admin() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .layer(auth::tests::authenticator())
+            .into();
+
+        let mut session = Session::default().with_user(Some("ci".into()));
+        let res = service.execute_with(CommandRequest::new_info(["server"]), &mut session);
+        assert_res_error(res, 403, "Permission denied");
+
+        let mut session = Session::default().with_user(Some("root".into()));
+        let res = service.execute_with(CommandRequest::new_info(["server"]), &mut session);
+        assert_eq!(res.status, 200);
+        assert_eq!(res.pairs[0].key, "server.version");
+    }
+
+    #[test]
+    fn publish_should_reach_subscribed_sessions() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+
+        // 本地调用没有连接，不能订阅
+        let cmd = CommandRequest::new_subscribe(["news"], []);
+        let res = service.execute(cmd.clone());
+        assert_res_error(res, 400, "only available on a connection");
+
+        let mut session = Session::new(None);
+        let res = service.execute_with(cmd, &mut session);
+        assert_res_ok(res, &[1.into()], &[]);
+        let mut rx = service.pubsub().take_receiver(session.id).unwrap();
+
+        let res = service.execute(CommandRequest::new_publish("news", "hello".into()));
+        assert_res_ok(res, &[1.into()], &[]);
+        let msg = rx.try_recv().unwrap().pubsub.unwrap();
+        assert_eq!(msg.channel, "news");
+        assert_eq!(msg.data, Some("hello".into()));
+
+        let cmd = CommandRequest::new_unsubscribe(Vec::<String>::new(), Vec::<String>::new());
+        let res = service.execute_with(cmd, &mut session);
+        assert_res_ok(res, &[0.into()], &[]);
+    }
+
+    #[test]
+    fn watch_events_should_follow_storage_order() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .watch(WatchConfig {
+                buffer: 1000,
+                ..Default::default()
+            })
+            .into();
+        let mut session = Session::new(None);
+        let res = service.execute_with(CommandRequest::new_watch("t1", "k1", 0), &mut session);
+        assert_eq!(res.status, 200);
+        let mut rx = service.watcher().take_receiver(session.id).unwrap();
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let service = service.clone();
+                thread::spawn(move || {
+                    for j in 0..100 {
+                        let cmd = CommandRequest::new_hset("t1", "k1", (i * 100 + j).into());
+                        service.execute(cmd);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // seq 连续递增，最后一个事件的值就是存储中的值
+        let mut last: Option<WatchEvent> = None;
+        while let Ok(res) = rx.try_recv() {
+            let event = res.event.unwrap();
+            if let Some(prev) = &last {
+                assert_eq!(event.seq, prev.seq + 1);
+            }
+            last = Some(event);
+        }
+        let last = last.unwrap();
+        assert_eq!(last.seq, 400);
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_ok(res, &[last.new_value.unwrap()], &[]);
+    }
+
+    #[test]
+    fn slowlog_should_record_commands_over_threshold() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .slowlog(SlowlogConfig {
+                threshold_us: 0,
+                capacity: 2,
+            })
+            .into();
+        let mut session = Session::new("127.0.0.1:5000".parse().ok());
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        service.execute_with(cmd, &mut session);
+        service.execute_with(CommandRequest::new_hget_all("t1"), &mut session);
+
+        let res = service.execute_with(CommandRequest::new_slowlog_get(1), &mut session);
+        assert_eq!(res.status, 200);
+        let pairs: Vec<_> = res.pairs.iter().map(|p| p.key.as_str()).collect();
+        assert_eq!(
+            pairs,
+            vec!["1.timestamp", "1.duration_us", "1.client", "1.command"]
+        );
+        assert_eq!(res.pairs[2].value, Some("127.0.0.1:5000".into()));
+        assert_eq!(res.pairs[3].value, Some("hgetall t1".into()));
+
+        // SLOWLOG GET 自己也被记录了，容量为 2，所以只剩最近的两条
+        let res = service.execute(CommandRequest::new_slowlog_reset());
+        assert_res_ok(res, &[2.into()], &[]);
+        assert_eq!(service.slowlog().len(), 1);
+    }
+
+    #[test]
+    fn hooks_should_capture_state() {
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        let prefix = String::from("altered");
+
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_received(move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .fn_before_send(move |res| res.message = prefix.clone())
+            .into();
+
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+
+        assert_eq!(received.load(Ordering::Relaxed), 2);
+        assert_eq!(res.message, "altered");
+    }
+
+    /// 把写入的 table 放到某个命名空间下
+    struct Namespace(&'static str);
+
+    impl Middleware for Namespace {
+        fn before(&self, cmd: &mut CommandRequest, _: &mut Session) -> Option<CommandResponse> {
+            if let Some(RequestData::Hset(v)) = cmd.request_data.as_mut() {
+                v.table = format!("{}:{}", self.0, v.table);
+            }
+            None
+        }
+    }
+
+    #[test]
+    fn middleware_should_rewrite_request() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .layer(Namespace("tenant1"))
+            .into();
+
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let res = service.execute(CommandRequest::new_hget("tenant1:t1", "k1"));
+        assert_res_ok(res, &["v1".into()], &[]);
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_error(res, 404, "Not found");
+    }
+
+    /// 记录所有 span 的名字、父 span 和字段
+    #[derive(Clone, Default)]
+    struct SpanRecorder(Arc<Mutex<Vec<RecordedSpan>>>);
+
+    #[derive(Debug, Default)]
+    struct RecordedSpan {
+        name: &'static str,
+        parent: Option<&'static str>,
+        fields: HashMap<String, String>,
+    }
+
+    impl Visit for RecordedSpan {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.fields.insert(field.name().into(), value.into());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.fields
+                .insert(field.name().into(), format!("{:?}", value));
+        }
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanRecorder {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            let mut recorded = RecordedSpan {
+                name: span.name(),
+                parent: span.parent().map(|p| p.name()),
+                ..Default::default()
+            };
+            attrs.record(&mut recorded);
+            self.0.lock().unwrap().push(recorded);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            let name = ctx.span(id).unwrap().name();
+            let mut spans = self.0.lock().unwrap();
+            if let Some(span) = spans.iter_mut().rev().find(|s| s.name == name) {
+                values.record(span);
+            }
+        }
+    }
+
+    #[test]
+    fn request_should_be_traced_with_storage_spans() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .layer(auth::tests::authenticator())
+            .into();
+        let recorder = SpanRecorder::default();
+        let subscriber = Registry::default().with(recorder.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut session =
+                Session::new("127.0.0.1:5000".parse().ok()).with_user(Some("alice".into()));
+            let cmd = CommandRequest::new_hset("user_1", "k1", "v1".into());
+            service.execute_with(cmd, &mut session);
+        });
+
+        let spans = recorder.0.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        let request = &spans[0];
+        assert_eq!(request.name, "request");
+        assert_eq!(request.parent, None);
+        assert_eq!(request.fields["command"], "hset");
+        assert_eq!(request.fields["table"], "user_1");
+        assert_eq!(request.fields["client"], "127.0.0.1:5000");
+        assert_eq!(request.fields["user"], "alice");
+        assert_eq!(request.fields["status"], "200");
+        assert!(request.fields.contains_key("request_id"));
+
+        let storage = &spans[1];
+        assert_eq!(storage.name, "storage.set");
+        assert_eq!(storage.parent, Some("request"));
+        assert_eq!(storage.fields["backend"], "memtable");
+        assert_eq!(storage.fields["table"], "user_1");
+    }
 }
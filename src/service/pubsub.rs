@@ -0,0 +1,330 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::warn;
+
+use super::auth::glob_match;
+use crate::{CommandResponse, PubsubConfig, PubsubMessage, SlowConsumerPolicy, Value};
+
+/// Pub/Sub 的统计数据
+#[derive(Debug, Default)]
+pub struct PubSubStats {
+    pub published: AtomicU64,
+    pub delivered: AtomicU64,
+    /// 订阅者的缓存满了而被丢弃的消息
+    pub dropped: AtomicU64,
+    /// 处理太慢而被断开的订阅者
+    pub evicted: AtomicU64,
+}
+
+struct Subscriber {
+    tx: mpsc::Sender<CommandResponse>,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+}
+
+impl Subscriber {
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// key 是订阅者所在连接的 session id
+    subscribers: HashMap<u64, Subscriber>,
+    channels: HashMap<String, HashSet<u64>>,
+    patterns: HashMap<String, HashSet<u64>>,
+    /// 新订阅者的接收端，等待网络层取走
+    receivers: HashMap<u64, mpsc::Receiver<CommandResponse>>,
+}
+
+impl State {
+    fn remove_subscriber(&mut self, id: u64) {
+        if let Some(sub) = self.subscribers.remove(&id) {
+            unindex(&mut self.channels, sub.channels, id);
+            unindex(&mut self.patterns, sub.patterns, id);
+        }
+        self.receivers.remove(&id);
+    }
+}
+
+/// Pub/Sub 的消息中心
+///
+/// 每个订阅者（连接）有一个有界的消息缓存，网络层从中取出消息推送给客户端。
+/// 缓存满了之后按照 SlowConsumerPolicy 丢弃消息或者断开订阅者，发布消息永远不会被慢的订阅者阻塞。
+pub struct PubSub {
+    config: PubsubConfig,
+    state: RwLock<State>,
+    stats: PubSubStats,
+}
+
+impl PubSub {
+    pub fn new(config: PubsubConfig) -> Self {
+        Self {
+            config,
+            state: RwLock::new(State::default()),
+            stats: PubSubStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &PubSubStats {
+        &self.stats
+    }
+
+    /// 订阅 channel 和 pattern，返回这个连接订阅的总数
+    pub fn subscribe(&self, id: u64, channels: Vec<String>, patterns: Vec<String>) -> usize {
+        let mut state = self.state.write().unwrap();
+        let state = &mut *state;
+        let sub = state.subscribers.entry(id).or_insert_with(|| {
+            let (tx, rx) = mpsc::channel(self.config.buffer.max(1));
+            state.receivers.insert(id, rx);
+            Subscriber {
+                tx,
+                channels: HashSet::new(),
+                patterns: HashSet::new(),
+            }
+        });
+
+        for channel in channels {
+            state
+                .channels
+                .entry(channel.clone())
+                .or_default()
+                .insert(id);
+            sub.channels.insert(channel);
+        }
+        for pattern in patterns {
+            state
+                .patterns
+                .entry(pattern.clone())
+                .or_default()
+                .insert(id);
+            sub.patterns.insert(pattern);
+        }
+        sub.count()
+    }
+
+    /// 取消订阅，channels 和 patterns 都为空时取消所有订阅，返回这个连接剩下的订阅数
+    ///
+    /// 取消所有订阅之后，连接的推送通道仍然保留，再次订阅时可以继续使用。
+    pub fn unsubscribe(&self, id: u64, channels: Vec<String>, patterns: Vec<String>) -> usize {
+        let mut state = self.state.write().unwrap();
+        let state = &mut *state;
+        let sub = match state.subscribers.get_mut(&id) {
+            Some(v) => v,
+            None => return 0,
+        };
+
+        let (channels, patterns) = if channels.is_empty() && patterns.is_empty() {
+            (
+                sub.channels.drain().collect(),
+                sub.patterns.drain().collect(),
+            )
+        } else {
+            sub.channels.retain(|c| !channels.contains(c));
+            sub.patterns.retain(|p| !patterns.contains(p));
+            (channels, patterns)
+        };
+        unindex(&mut state.channels, channels, id);
+        unindex(&mut state.patterns, patterns, id);
+        sub.count()
+    }
+
+    /// 向 channel 发布消息，返回收到消息的订阅者个数
+    pub fn publish(&self, channel: &str, data: Value) -> usize {
+        self.stats.published.fetch_add(1, Ordering::Relaxed);
+
+        let mut delivered = 0;
+        let mut evicted = Vec::new();
+        {
+            let state = self.state.read().unwrap();
+            let exact = state
+                .channels
+                .get(channel)
+                .into_iter()
+                .flatten()
+                .map(|id| (*id, String::new()));
+            let matched = state
+                .patterns
+                .iter()
+                .filter(|(pattern, _)| glob_match(pattern, channel))
+                .flat_map(|(pattern, ids)| ids.iter().map(move |id| (*id, pattern.clone())));
+
+            for (id, pattern) in exact.chain(matched) {
+                let sub = match state.subscribers.get(&id) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let msg = PubsubMessage {
+                    channel: channel.into(),
+                    pattern,
+                    data: Some(data.clone()),
+                };
+                match sub.tx.try_send(msg.into()) {
+                    Ok(_) => delivered += 1,
+                    Err(TrySendError::Full(_)) => {
+                        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                        if self.config.slow_consumer == SlowConsumerPolicy::Disconnect {
+                            evicted.push(id);
+                        }
+                    }
+                    // 连接已经断开，但还没来得及清理
+                    Err(TrySendError::Closed(_)) => evicted.push(id),
+                }
+            }
+        }
+
+        if !evicted.is_empty() {
+            let mut state = self.state.write().unwrap();
+            for id in evicted {
+                if state.subscribers.contains_key(&id) {
+                    warn!("Subscriber {} is too slow or gone, removed", id);
+                    self.stats.evicted.fetch_add(1, Ordering::Relaxed);
+                    state.remove_subscriber(id);
+                }
+            }
+        }
+
+        self.stats
+            .delivered
+            .fetch_add(delivered as u64, Ordering::Relaxed);
+        delivered
+    }
+
+    /// 取走新订阅的连接的推送通道，由网络层在执行完命令之后调用
+    ///
+    /// 通道关闭（recv 返回 None）说明订阅者处理太慢被断开了。
+    pub fn take_receiver(&self, id: u64) -> Option<mpsc::Receiver<CommandResponse>> {
+        self.state.write().unwrap().receivers.remove(&id)
+    }
+
+    /// 连接断开时清理它的所有订阅
+    pub fn remove(&self, id: u64) {
+        self.state.write().unwrap().remove_subscriber(id);
+    }
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        Self::new(PubsubConfig::default())
+    }
+}
+
+fn unindex(
+    index: &mut HashMap<String, HashSet<u64>>,
+    names: impl IntoIterator<Item = String>,
+    id: u64,
+) {
+    for name in names {
+        if let Some(ids) = index.get_mut(&name) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                index.remove(&name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channels(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn recv(rx: &mut mpsc::Receiver<CommandResponse>) -> PubsubMessage {
+        rx.try_recv().unwrap().pubsub.unwrap()
+    }
+
+    #[test]
+    fn publish_should_reach_channel_and_pattern_subscribers() {
+        let hub = PubSub::default();
+        assert_eq!(hub.subscribe(1, channels(&["news"]), vec![]), 1);
+        assert_eq!(hub.subscribe(2, vec![], channels(&["n*"])), 1);
+        let mut rx1 = hub.take_receiver(1).unwrap();
+        let mut rx2 = hub.take_receiver(2).unwrap();
+        assert!(hub.take_receiver(1).is_none());
+
+        assert_eq!(hub.publish("news", "hello".into()), 2);
+        assert_eq!(hub.publish("weather", "sunny".into()), 0);
+
+        let msg = recv(&mut rx1);
+        assert_eq!(msg.channel, "news");
+        assert_eq!(msg.pattern, "");
+        assert_eq!(msg.data, Some("hello".into()));
+        let msg = recv(&mut rx2);
+        assert_eq!(msg.pattern, "n*");
+        assert!(rx1.try_recv().is_err());
+    }
+
+    #[test]
+    fn unsubscribe_should_work() {
+        let hub = PubSub::default();
+        hub.subscribe(1, channels(&["a", "b"]), channels(&["c*"]));
+        let mut rx = hub.take_receiver(1).unwrap();
+
+        assert_eq!(hub.unsubscribe(1, channels(&["a"]), vec![]), 2);
+        assert_eq!(hub.publish("a", 1.into()), 0);
+        assert_eq!(hub.publish("b", 2.into()), 1);
+
+        // 取消所有订阅之后推送通道仍然可用
+        assert_eq!(hub.unsubscribe(1, vec![], vec![]), 0);
+        assert_eq!(hub.publish("c1", 3.into()), 0);
+        assert_eq!(hub.subscribe(1, channels(&["d"]), vec![]), 1);
+        assert!(hub.take_receiver(1).is_none());
+        hub.publish("d", 4.into());
+
+        assert_eq!(recv(&mut rx).data, Some(2.into()));
+        assert_eq!(recv(&mut rx).data, Some(4.into()));
+    }
+
+    #[test]
+    fn slow_consumer_should_be_disconnected() {
+        let hub = PubSub::new(PubsubConfig {
+            buffer: 2,
+            slow_consumer: SlowConsumerPolicy::Disconnect,
+        });
+        hub.subscribe(1, channels(&["a"]), vec![]);
+        let mut rx = hub.take_receiver(1).unwrap();
+
+        assert_eq!(hub.publish("a", 1.into()), 1);
+        assert_eq!(hub.publish("a", 2.into()), 1);
+        assert_eq!(hub.publish("a", 3.into()), 0);
+        assert_eq!(hub.publish("a", 4.into()), 0);
+
+        // 缓存中的消息仍然可以读到，之后通道关闭
+        assert_eq!(recv(&mut rx).data, Some(1.into()));
+        assert_eq!(recv(&mut rx).data, Some(2.into()));
+        assert!(matches!(
+            rx.try_recv(),
+            Err(mpsc::error::TryRecvError::Disconnected)
+        ));
+        assert_eq!(hub.stats().evicted.load(Ordering::Relaxed), 1);
+        assert_eq!(hub.stats().dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn slow_consumer_should_miss_messages_with_drop_policy() {
+        let hub = PubSub::new(PubsubConfig {
+            buffer: 1,
+            slow_consumer: SlowConsumerPolicy::Drop,
+        });
+        hub.subscribe(1, channels(&["a"]), vec![]);
+        let mut rx = hub.take_receiver(1).unwrap();
+
+        assert_eq!(hub.publish("a", 1.into()), 1);
+        assert_eq!(hub.publish("a", 2.into()), 0);
+        assert_eq!(recv(&mut rx).data, Some(1.into()));
+        assert_eq!(hub.publish("a", 3.into()), 1);
+        assert_eq!(recv(&mut rx).data, Some(3.into()));
+        assert_eq!(hub.stats().dropped.load(Ordering::Relaxed), 1);
+    }
+}
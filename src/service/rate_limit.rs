@@ -0,0 +1,344 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+use crate::{
+    BucketConfig, CommandRequest, CommandResponse, KvError, Middleware, RateLimitConfig, Session,
+};
+
+/// 每处理这么多个请求，清理一次已经回满的令牌桶，避免断开的连接一直占用内存
+const SWEEP_INTERVAL: u64 = 1024;
+
+/// 令牌桶
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: &BucketConfig, now: Instant) -> Self {
+        Self {
+            rate: config.rate,
+            capacity: config.burst as f64,
+            tokens: config.burst as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// 还需要等待多久才能拿到一个令牌，None 表示现在就可以拿
+    fn wait_time(&self) -> Option<Duration> {
+        if self.tokens >= 1.0 {
+            None
+        } else if self.rate <= 0.0 {
+            Some(Duration::MAX)
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.capacity
+    }
+}
+
+/// 限流的统计数据，可以导出给监控系统
+#[derive(Debug, Default)]
+pub struct RateLimitStats {
+    pub allowed: AtomicU64,
+    pub throttled_global: AtomicU64,
+    pub throttled_connection: AtomicU64,
+    pub throttled_user: AtomicU64,
+    pub throttled_command: AtomicU64,
+}
+
+impl RateLimitStats {
+    pub fn throttled(&self) -> u64 {
+        self.throttled_global.load(Ordering::Relaxed)
+            + self.throttled_connection.load(Ordering::Relaxed)
+            + self.throttled_user.load(Ordering::Relaxed)
+            + self.throttled_command.load(Ordering::Relaxed)
+    }
+}
+
+/// 基于令牌桶的限流中间件，被限流的请求返回 429
+///
+/// 一个请求需要同时从全局、命令、连接、用户四类桶里各拿到一个令牌，只要有一个桶不够就会被拒绝，
+/// 被拒绝的请求不会消耗任何令牌。
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    global: Option<Mutex<TokenBucket>>,
+    commands: HashMap<String, Mutex<TokenBucket>>,
+    connections: DashMap<u64, TokenBucket>,
+    users: DashMap<String, TokenBucket>,
+    stats: RateLimitStats,
+    requests: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let now = Instant::now();
+        let global = config
+            .global
+            .as_ref()
+            .map(|c| Mutex::new(TokenBucket::new(c, now)));
+        let commands = config
+            .per_command
+            .iter()
+            .map(|(name, c)| (name.to_lowercase(), Mutex::new(TokenBucket::new(c, now))))
+            .collect();
+
+        Self {
+            config,
+            global,
+            commands,
+            connections: DashMap::new(),
+            users: DashMap::new(),
+            stats: RateLimitStats::default(),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> &RateLimitStats {
+        &self.stats
+    }
+
+    /// 检查并消耗令牌，被限流时返回的错误里带有需要等待的时间
+    fn acquire(&self, command: Option<&str>, session: &Session) -> Result<(), KvError> {
+        let now = Instant::now();
+
+        let mut global = self.global.as_ref().map(|b| b.lock().unwrap());
+        let mut command = command
+            .and_then(|name| self.commands.get(name))
+            .map(|b| b.lock().unwrap());
+        let mut connection = self.config.per_connection.as_ref().map(|c| {
+            self.connections
+                .entry(session.id)
+                .or_insert_with(|| TokenBucket::new(c, now))
+        });
+        let mut user = match (&self.config.per_user, &session.user) {
+            (Some(c), Some(user)) => Some(
+                self.users
+                    .entry(user.clone())
+                    .or_insert_with(|| TokenBucket::new(c, now)),
+            ),
+            _ => None,
+        };
+
+        // 先检查所有的桶，都有令牌才一起扣除
+        let buckets: [(Option<&mut TokenBucket>, &str, &AtomicU64); 4] = [
+            (
+                global.as_deref_mut(),
+                "global",
+                &self.stats.throttled_global,
+            ),
+            (
+                command.as_deref_mut(),
+                "command",
+                &self.stats.throttled_command,
+            ),
+            (
+                connection.as_deref_mut(),
+                "connection",
+                &self.stats.throttled_connection,
+            ),
+            (user.as_deref_mut(), "user", &self.stats.throttled_user),
+        ];
+        let mut buckets: Vec<_> = buckets
+            .into_iter()
+            .filter_map(|(b, scope, counter)| b.map(|b| (b, scope, counter)))
+            .collect();
+
+        for (bucket, scope, counter) in buckets.iter_mut() {
+            bucket.refill(now);
+            if let Some(wait) = bucket.wait_time() {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let ms = wait.as_millis().max(1).min(u64::MAX as u128) as u64;
+                return Err(KvError::RateLimited(scope.to_string(), ms));
+            }
+        }
+
+        for (bucket, _, _) in buckets {
+            bucket.tokens -= 1.0;
+        }
+        self.stats.allowed.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn sweep(&self) {
+        let requests = self.requests.fetch_add(1, Ordering::Relaxed);
+        if !requests.is_multiple_of(SWEEP_INTERVAL) {
+            return;
+        }
+        let now = Instant::now();
+        // 回满的桶和新建的桶没有区别，可以直接删掉
+        let active = |bucket: &mut TokenBucket| {
+            bucket.refill(now);
+            !bucket.is_full()
+        };
+        self.connections.retain(|_, b| active(b));
+        self.users.retain(|_, b| active(b));
+    }
+}
+
+impl Middleware for RateLimiter {
+    fn before(&self, cmd: &mut CommandRequest, session: &mut Session) -> Option<CommandResponse> {
+        self.sweep();
+        let command = cmd.request_data.as_ref().map(|data| data.name());
+        self.acquire(command, session).err().map(|e| e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+    use crate::{assert_res_ok, MemTable, Service, ServiceInner, Value};
+
+    fn bucket(rate: f64, burst: u32) -> Option<BucketConfig> {
+        Some(BucketConfig { rate, burst })
+    }
+
+    #[test]
+    fn token_bucket_should_refill() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(
+            &BucketConfig {
+                rate: 10.0,
+                burst: 2,
+            },
+            now,
+        );
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.wait_time(), Some(Duration::from_millis(100)));
+
+        bucket.refill(now + Duration::from_millis(150));
+        assert_eq!(bucket.wait_time(), None);
+
+        bucket.refill(now + Duration::from_secs(10));
+        assert!(bucket.is_full());
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[test]
+    fn per_connection_limit_should_return_429() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            per_connection: bucket(0.001, 2),
+            ..Default::default()
+        });
+        let (mut s1, mut s2) = (Session::new(None), Session::new(None));
+        let mut cmd = CommandRequest::new_hget("t1", "k1");
+
+        assert!(limiter.before(&mut cmd, &mut s1).is_none());
+        assert!(limiter.before(&mut cmd, &mut s1).is_none());
+        let res = limiter.before(&mut cmd, &mut s1).unwrap();
+        assert_eq!(res.status, 429);
+        assert!(res.message.contains("connection"));
+        assert_eq!(res.values.len(), 1);
+
+        // 其它连接不受影响
+        assert!(limiter.before(&mut cmd, &mut s2).is_none());
+
+        assert_eq!(limiter.stats().allowed.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            limiter.stats().throttled_connection.load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn per_user_limit_should_be_shared_by_connections() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            per_user: bucket(0.001, 1),
+            ..Default::default()
+        });
+        let mut s1 = Session::new(None).with_user(Some("alice".into()));
+        let mut s2 = Session::new(None).with_user(Some("alice".into()));
+        let mut cmd = CommandRequest::new_hget("t1", "k1");
+
+        assert!(limiter.before(&mut cmd, &mut s1).is_none());
+        let res = limiter.before(&mut cmd, &mut s2).unwrap();
+        assert_eq!(res.status, 429);
+        assert!(res.message.contains("user"));
+    }
+
+    #[test]
+    fn per_command_limit_should_only_affect_that_command() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            per_command: [(
+                "hgetall".to_string(),
+                BucketConfig {
+                    rate: 0.001,
+                    burst: 1,
+                },
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        });
+        let mut session = Session::new(None);
+
+        let mut cmd = CommandRequest::new_hget_all("t1");
+        assert!(limiter.before(&mut cmd, &mut session).is_none());
+        assert_eq!(limiter.before(&mut cmd, &mut session).unwrap().status, 429);
+
+        let mut cmd = CommandRequest::new_hget("t1", "k1");
+        assert!(limiter.before(&mut cmd, &mut session).is_none());
+    }
+
+    #[test]
+    fn throttled_request_should_not_consume_tokens() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            global: bucket(0.001, 2),
+            per_connection: bucket(0.001, 1),
+            ..Default::default()
+        });
+        let (mut s1, mut s2) = (Session::new(None), Session::new(None));
+        let mut cmd = CommandRequest::new_hget("t1", "k1");
+
+        assert!(limiter.before(&mut cmd, &mut s1).is_none());
+        // 被连接限流拒绝，不应该扣除全局的令牌
+        assert!(limiter.before(&mut cmd, &mut s1).is_some());
+        assert!(limiter.before(&mut cmd, &mut s2).is_none());
+    }
+
+    #[test]
+    fn rate_limiter_should_work_as_service_layer() {
+        let limiter = Arc::new(RateLimiter::new(RateLimitConfig {
+            global: bucket(1000.0, 1),
+            ..Default::default()
+        }));
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(limiter.clone())
+            .into();
+
+        let res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        assert_res_ok(res, &[Value::default()], &[]);
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_eq!(res.status, 429);
+
+        // 等待令牌补充
+        thread::sleep(Duration::from_millis(5));
+        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        assert_eq!(limiter.stats().allowed.load(Ordering::Relaxed), 2);
+        assert_eq!(limiter.stats().throttled(), 1);
+    }
+}
@@ -0,0 +1,37 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// 一个客户端连接的上下文，在这个连接的所有命令之间共享
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Session {
+    /// 连接的唯一 id，本地调用（Session::default）时为 0
+    pub id: u64,
+    /// 客户端地址，本地调用时没有
+    pub peer: Option<SocketAddr>,
+    /// 通过认证后的用户名（也可能来自 mTLS 客户端证书）
+    pub user: Option<String>,
+}
+
+impl Session {
+    pub fn new(peer: Option<SocketAddr>) -> Self {
+        Self {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            peer,
+            user: None,
+        }
+    }
+
+    /// 设置已认证的身份，比如 mTLS 客户端证书中的 Common Name
+    pub fn with_user(mut self, user: Option<String>) -> Self {
+        self.user = user;
+        self
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.user.is_some()
+    }
+}
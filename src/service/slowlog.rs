@@ -0,0 +1,274 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{command_request::RequestData, CommandRequest, Kvpair, SlowlogConfig, Value};
+
+/// 命令摘要的最大长度，超出的部分会被截断
+const MAX_SUMMARY_LEN: usize = 128;
+
+/// 一条慢命令记录
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowlogEntry {
+    /// 递增的 id，reset 之后也不会重复
+    pub id: u64,
+    /// 命令开始执行的时间
+    pub at: SystemTime,
+    /// 执行命令花费的时间
+    pub duration: Duration,
+    /// 客户端地址，本地调用时没有
+    pub peer: Option<SocketAddr>,
+    /// 截断后的命令摘要，不包含 value 和密码
+    pub command: String,
+}
+
+impl SlowlogEntry {
+    fn to_pairs(&self) -> impl Iterator<Item = Kvpair> + '_ {
+        let at = self.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let client = self.peer.map(|p| p.to_string()).unwrap_or_default();
+        [
+            ("timestamp", Value::from(at.as_secs() as i64)),
+            ("duration_us", Value::from(self.duration.as_micros() as i64)),
+            ("client", client.into()),
+            ("command", self.command.as_str().into()),
+        ]
+        .into_iter()
+        .map(move |(field, value)| Kvpair::new(format!("{}.{}", self.id, field), value))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    next_id: u64,
+    entries: VecDeque<SlowlogEntry>,
+}
+
+/// 记录执行时间超过阈值的命令，只保留最近的 capacity 条
+#[derive(Debug)]
+pub struct Slowlog {
+    threshold: Duration,
+    capacity: usize,
+    inner: Mutex<Entries>,
+}
+
+impl Slowlog {
+    pub fn new(config: SlowlogConfig) -> Self {
+        Self {
+            threshold: Duration::from_micros(config.threshold_us),
+            capacity: config.capacity,
+            inner: Mutex::new(Entries::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// 如果命令执行的时间超过了阈值，就记录下来
+    ///
+    /// 命令在执行时会被消耗掉，所以需要调用者事先用 summary 生成摘要。
+    pub fn record(
+        &self,
+        command: String,
+        peer: Option<SocketAddr>,
+        at: SystemTime,
+        duration: Duration,
+    ) {
+        if duration < self.threshold || !self.is_enabled() {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        if inner.entries.len() == self.capacity {
+            inner.entries.pop_front();
+        }
+        inner.entries.push_back(SlowlogEntry {
+            id,
+            at,
+            duration,
+            peer,
+            command,
+        });
+    }
+
+    /// 最近的 count 条记录，最新的在前；count 为 0 时返回所有记录
+    pub fn get(&self, count: usize) -> Vec<SlowlogEntry> {
+        let inner = self.inner.lock().unwrap();
+        let count = if count == 0 { usize::MAX } else { count };
+        inner.entries.iter().rev().take(count).cloned().collect()
+    }
+
+    /// 清空所有记录，返回清除的条数
+    pub fn reset(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let len = inner.entries.len();
+        inner.entries.clear();
+        len
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for Slowlog {
+    fn default() -> Self {
+        Self::new(SlowlogConfig::default())
+    }
+}
+
+/// 把一组记录转换成 SlowlogGet 的返回值
+pub(crate) fn to_pairs(entries: &[SlowlogEntry]) -> Vec<Kvpair> {
+    entries.iter().flat_map(|e| e.to_pairs()).collect()
+}
+
+/// 生成命令的摘要：命令名 + table + key，不包含 value（可能很大）和密码
+pub(crate) fn summary(cmd: &CommandRequest) -> String {
+    let data = match &cmd.request_data {
+        Some(v) => v,
+        None => return "unknown".into(),
+    };
+
+    let args: Vec<&str> = match data {
+        RequestData::Hget(v) => vec![&v.table, &v.key],
+        RequestData::Hgetall(v) => vec![&v.table],
+        RequestData::Hmget(v) => with_keys(&v.table, &v.keys),
+        RequestData::Hset(v) => {
+            let key = v.pair.as_ref().map(|p| p.key.as_str()).unwrap_or_default();
+            vec![&v.table, key]
+        }
+        RequestData::Hmset(v) => std::iter::once(v.table.as_str())
+            .chain(v.pairs.iter().map(|p| p.key.as_str()))
+            .collect(),
+        RequestData::Hdel(v) => vec![&v.table, &v.key],
+        RequestData::Hmdel(v) => with_keys(&v.table, &v.keys),
+        RequestData::Hexist(v) => vec![&v.table, &v.key],
+        RequestData::Hmexist(v) => with_keys(&v.table, &v.keys),
+        RequestData::Hcas(v) => vec![&v.table, &v.key],
+        RequestData::Auth(v) => vec![&v.username],
+        RequestData::Info(v) => v.sections.iter().map(|s| s.as_str()).collect(),
+        RequestData::Subscribe(v) => channels(&v.channels, &v.patterns),
+        RequestData::Unsubscribe(v) => channels(&v.channels, &v.patterns),
+        RequestData::Publish(v) => vec![&v.channel],
+        RequestData::Watch(v) => [&v.table, &v.key, &v.prefix]
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.as_str())
+            .collect(),
+        RequestData::SlowlogGet(_)
+        | RequestData::SlowlogReset(_)
+        | RequestData::Unwatch(_)
+        | RequestData::ReadChanges(_)
+        | RequestData::Snapshot(_) => vec![],
+    };
+
+    let mut summary = data.name().to_string();
+    for arg in args {
+        summary.push(' ');
+        summary.push_str(arg);
+    }
+    truncate(summary)
+}
+
+fn with_keys<'a>(table: &'a str, keys: &'a [String]) -> Vec<&'a str> {
+    std::iter::once(table)
+        .chain(keys.iter().map(|k| k.as_str()))
+        .collect()
+}
+
+fn channels<'a>(channels: &'a [String], patterns: &'a [String]) -> Vec<&'a str> {
+    channels
+        .iter()
+        .chain(patterns)
+        .map(|s| s.as_str())
+        .collect()
+}
+
+fn truncate(mut s: String) -> String {
+    if s.len() <= MAX_SUMMARY_LEN {
+        return s;
+    }
+    let mut end = MAX_SUMMARY_LEN;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    let more = s.len() - end;
+    s.truncate(end);
+    s.push_str(&format!("... ({} more bytes)", more));
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slowlog(threshold_us: u64, capacity: usize) -> Slowlog {
+        Slowlog::new(SlowlogConfig {
+            threshold_us,
+            capacity,
+        })
+    }
+
+    #[test]
+    fn slowlog_should_only_record_slow_commands() {
+        let log = slowlog(1000, 10);
+        let cmd = summary(&CommandRequest::new_hget_all("t1"));
+        let now = SystemTime::now();
+
+        log.record(cmd.clone(), None, now, Duration::from_micros(999));
+        assert!(log.is_empty());
+
+        let peer = "127.0.0.1:5000".parse().ok();
+        log.record(cmd, peer, now, Duration::from_millis(2));
+        let entries = log.get(0);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].command, "hgetall t1");
+        assert_eq!(entries[0].peer, peer);
+        assert_eq!(entries[0].duration, Duration::from_millis(2));
+    }
+
+    #[test]
+    fn slowlog_should_be_bounded() {
+        let log = slowlog(0, 2);
+        let now = SystemTime::now();
+        for key in ["k1", "k2", "k3"] {
+            let cmd = summary(&CommandRequest::new_hget("t1", key));
+            log.record(cmd, None, now, Duration::ZERO);
+        }
+
+        let entries = log.get(0);
+        let commands: Vec<_> = entries.iter().map(|e| e.command.as_str()).collect();
+        assert_eq!(commands, vec!["hget t1 k3", "hget t1 k2"]);
+        assert_eq!(entries[0].id, 2);
+        assert_eq!(log.get(1).len(), 1);
+
+        assert_eq!(log.reset(), 2);
+        assert!(log.is_empty());
+        // reset 之后 id 继续递增
+        log.record("hgetall t1".into(), None, now, Duration::ZERO);
+        assert_eq!(log.get(0)[0].id, 3);
+    }
+
+    #[test]
+    fn summary_should_hide_secrets_and_be_truncated() {
+        let cmd = CommandRequest::new_auth("alice", "secret");
+        assert_eq!(summary(&cmd), "auth alice");
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "a very large value".into());
+        assert_eq!(summary(&cmd), "hset t1 k1");
+
+        let cmd = CommandRequest::new_hget("t1", "测".repeat(100));
+        let s = summary(&cmd);
+        assert!(s.ends_with("... (180 more bytes)"));
+        assert!(s.len() < MAX_SUMMARY_LEN + 32);
+    }
+}
@@ -0,0 +1,368 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::warn;
+
+use super::change::KeyChange;
+use crate::{
+    CommandResponse, EventKind, KvError, SlowConsumerPolicy, Watch, WatchConfig, WatchEvent,
+};
+
+/// Watch 的统计数据
+#[derive(Debug, Default)]
+pub struct WatchStats {
+    pub events: AtomicU64,
+    pub delivered: AtomicU64,
+    /// watcher 的缓存满了而被丢弃的事件
+    pub dropped: AtomicU64,
+    /// 处理太慢而被断开的 watcher
+    pub evicted: AtomicU64,
+}
+
+/// 一个 watch 的范围：整个 table、table 中以 prefix 开头的 key，或者一个 key
+#[derive(Debug, Clone)]
+struct Scope {
+    table: String,
+    key: Option<String>,
+    prefix: String,
+}
+
+impl Scope {
+    fn matches(&self, event: &WatchEvent) -> bool {
+        if self.table != event.table {
+            return false;
+        }
+        match &self.key {
+            Some(key) => *key == event.key,
+            None => event.key.starts_with(&self.prefix),
+        }
+    }
+}
+
+impl From<Watch> for Scope {
+    fn from(v: Watch) -> Self {
+        Self {
+            table: v.table,
+            key: (!v.key.is_empty()).then_some(v.key),
+            prefix: v.prefix,
+        }
+    }
+}
+
+struct Watcher {
+    tx: mpsc::Sender<CommandResponse>,
+    scopes: Vec<Scope>,
+}
+
+struct State {
+    /// 下一个事件的 seq，从 1 开始
+    next_seq: u64,
+    /// 最近的事件，用于补发
+    backlog: VecDeque<WatchEvent>,
+    /// key 是 watcher 所在连接的 session id
+    watchers: HashMap<u64, Watcher>,
+    /// 新 watcher 的接收端，等待网络层取走
+    receivers: HashMap<u64, mpsc::Receiver<CommandResponse>>,
+}
+
+/// key 变化事件的分发中心
+///
+/// 所有成功的写命令在 Service 中统一生成事件，和具体的存储无关。每个事件有一个全局递增的 seq，
+/// 最近的 backlog 个事件会保留下来，watcher 断线重连之后可以通过 from_seq 补齐错过的事件。
+/// 和 Pub/Sub 一样，每个 watcher 有一个有界的缓存，写命令不会被慢的 watcher 阻塞。
+pub struct KeyspaceWatcher {
+    config: WatchConfig,
+    state: Mutex<State>,
+    stats: WatchStats,
+}
+
+impl KeyspaceWatcher {
+    pub fn new(config: WatchConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State {
+                next_seq: 1,
+                backlog: VecDeque::new(),
+                watchers: HashMap::new(),
+                receivers: HashMap::new(),
+            }),
+            stats: WatchStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &WatchStats {
+        &self.stats
+    }
+
+    /// 最新的事件的 seq，还没有事件时为 0
+    pub fn seq(&self) -> u64 {
+        self.state.lock().unwrap().next_seq - 1
+    }
+
+    /// 开始监听，返回最新的事件的 seq
+    ///
+    /// from_seq 大于 0 时先补发 seq >= from_seq 的事件；这些事件已经不在 backlog 里时返回错误，
+    /// 客户端需要重新读取数据之后再从返回的 seq 开始监听。seq 在服务器重启之后从 1 开始，
+    /// 所以 from_seq 超过了下一个事件的 seq 时同样返回这个错误。
+    pub fn watch(&self, id: u64, param: Watch) -> Result<u64, KvError> {
+        let from_seq = param.from_seq;
+        let scope = Scope::from(param);
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if from_seq > 0 {
+            let oldest = state.backlog.front().map_or(state.next_seq, |e| e.seq);
+            if from_seq < oldest {
+                return Err(KvError::HistoryTruncated(from_seq, oldest));
+            }
+            if from_seq > state.next_seq {
+                return Err(KvError::HistoryTruncated(from_seq, state.next_seq));
+            }
+        }
+
+        let watcher = state.watchers.entry(id).or_insert_with(|| {
+            let (tx, rx) = mpsc::channel(self.config.buffer.max(1));
+            state.receivers.insert(id, rx);
+            Watcher {
+                tx,
+                scopes: Vec::new(),
+            }
+        });
+        let replay: Vec<_> = state
+            .backlog
+            .iter()
+            .filter(|e| from_seq > 0 && e.seq >= from_seq && scope.matches(e))
+            .collect();
+        // 补发的事件超出了缓存的大小，客户端需要重新读取数据
+        if replay.len() > watcher.tx.capacity() {
+            return Err(KvError::HistoryTruncated(from_seq, state.next_seq));
+        }
+        for event in replay {
+            watcher.tx.try_send(event.clone().into()).ok();
+            self.stats.delivered.fetch_add(1, Ordering::Relaxed);
+        }
+        watcher.scopes.push(scope);
+        Ok(state.next_seq - 1)
+    }
+
+    /// 取消这个连接的所有 watch，推送通道仍然保留
+    pub fn unwatch(&self, id: u64) {
+        if let Some(watcher) = self.state.lock().unwrap().watchers.get_mut(&id) {
+            watcher.scopes.clear();
+        }
+    }
+
+    /// 执行写操作，并为它修改的每个 key 生成事件
+    ///
+    /// 写操作在锁里执行，这样事件的 seq 和存储中实际修改的顺序一致。
+    pub(crate) fn write<T>(&self, f: impl FnOnce() -> (T, Vec<KeyChange>)) -> T {
+        let mut state = self.state.lock().unwrap();
+        let (res, changes) = f();
+        self.notify(&mut state, &changes);
+        res
+    }
+
+    fn notify(&self, state: &mut State, changes: &[KeyChange]) {
+        let mut evicted = Vec::new();
+        for change in changes {
+            let kind = match change.new_value {
+                Some(_) => EventKind::Set,
+                None => EventKind::Del,
+            };
+            let event = WatchEvent {
+                seq: state.next_seq,
+                kind: kind as i32,
+                table: change.table.clone(),
+                key: change.key.clone(),
+                old_value: change.old_value.clone(),
+                new_value: change.new_value.clone(),
+            };
+            state.next_seq += 1;
+            self.stats.events.fetch_add(1, Ordering::Relaxed);
+
+            for (id, watcher) in state.watchers.iter() {
+                if !watcher.scopes.iter().any(|s| s.matches(&event)) {
+                    continue;
+                }
+                match watcher.tx.try_send(event.clone().into()) {
+                    Ok(_) => {
+                        self.stats.delivered.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(TrySendError::Full(_)) => {
+                        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                        if self.config.slow_consumer == SlowConsumerPolicy::Disconnect {
+                            evicted.push(*id);
+                        }
+                    }
+                    // 连接已经断开，但还没来得及清理
+                    Err(TrySendError::Closed(_)) => evicted.push(*id),
+                }
+            }
+
+            if self.config.backlog > 0 {
+                if state.backlog.len() == self.config.backlog {
+                    state.backlog.pop_front();
+                }
+                state.backlog.push_back(event);
+            }
+        }
+
+        for id in evicted {
+            if state.watchers.remove(&id).is_some() {
+                warn!("Watcher {} is too slow or gone, removed", id);
+                self.stats.evicted.fetch_add(1, Ordering::Relaxed);
+                state.receivers.remove(&id);
+            }
+        }
+    }
+
+    /// 取走新 watcher 的推送通道，由网络层在执行完命令之后调用
+    ///
+    /// 通道关闭（recv 返回 None）说明 watcher 处理太慢被断开了。
+    pub fn take_receiver(&self, id: u64) -> Option<mpsc::Receiver<CommandResponse>> {
+        self.state.lock().unwrap().receivers.remove(&id)
+    }
+
+    /// 连接断开时清理它的所有 watch
+    pub fn remove(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.watchers.remove(&id);
+        state.receivers.remove(&id);
+    }
+}
+
+impl Default for KeyspaceWatcher {
+    fn default() -> Self {
+        Self::new(WatchConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dispatch, service::change::Changes, CommandRequest, Kvpair, MemTable};
+
+    fn write(hub: &KeyspaceWatcher, store: &MemTable, cmd: CommandRequest) {
+        let changes = cmd.request_data.as_ref().and_then(Changes::from_request);
+        hub.write(|| {
+            let res = dispatch(cmd, store);
+            ((), changes.map(|c| c.resolve(&res)).unwrap_or_default())
+        });
+    }
+
+    fn recv(rx: &mut mpsc::Receiver<CommandResponse>) -> WatchEvent {
+        rx.try_recv().unwrap().event.unwrap()
+    }
+
+    fn watch(table: &str, key: &str, prefix: &str, from_seq: u64) -> Watch {
+        Watch {
+            table: table.into(),
+            key: key.into(),
+            prefix: prefix.into(),
+            from_seq,
+        }
+    }
+
+    #[test]
+    fn events_should_carry_old_and_new_values() {
+        let (hub, store) = (KeyspaceWatcher::default(), MemTable::new());
+        assert_eq!(hub.watch(1, watch("t1", "", "", 0)).unwrap(), 0);
+        let mut rx = hub.take_receiver(1).unwrap();
+
+        write(&hub, &store, CommandRequest::new_hset("t1", "k1", 1.into()));
+        write(&hub, &store, CommandRequest::new_hset("t1", "k1", 2.into()));
+        write(&hub, &store, CommandRequest::new_hset("t2", "k1", 3.into()));
+        write(&hub, &store, CommandRequest::new_hmdel("t1", ["k1", "k2"]));
+
+        let event = recv(&mut rx);
+        assert_eq!((event.seq, event.kind()), (1, EventKind::Set));
+        assert_eq!((event.old_value, event.new_value), (None, Some(1.into())));
+        let event = recv(&mut rx);
+        assert_eq!(event.seq, 2);
+        assert_eq!(event.old_value, Some(1.into()));
+        // t2 的事件和删除不存在的 k2 都不会推送
+        let event = recv(&mut rx);
+        assert_eq!((event.seq, event.kind()), (4, EventKind::Del));
+        assert_eq!((event.old_value, event.new_value), (Some(2.into()), None));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(hub.seq(), 4);
+    }
+
+    #[test]
+    fn watch_should_filter_by_key_and_prefix() {
+        let (hub, store) = (KeyspaceWatcher::default(), MemTable::new());
+        hub.watch(1, watch("t1", "user:1", "", 0)).unwrap();
+        hub.watch(2, watch("t1", "", "order:", 0)).unwrap();
+        let mut rx1 = hub.take_receiver(1).unwrap();
+        let mut rx2 = hub.take_receiver(2).unwrap();
+
+        let pairs = vec![
+            Kvpair::new("user:1", 1.into()),
+            Kvpair::new("user:2", 2.into()),
+            Kvpair::new("order:1", 3.into()),
+        ];
+        write(&hub, &store, CommandRequest::new_hmset("t1", pairs));
+
+        assert_eq!(recv(&mut rx1).key, "user:1");
+        assert!(rx1.try_recv().is_err());
+        assert_eq!(recv(&mut rx2).key, "order:1");
+        assert!(rx2.try_recv().is_err());
+
+        hub.unwatch(1);
+        write(&hub, &store, CommandRequest::new_hdel("t1", "user:1"));
+        assert!(rx1.try_recv().is_err());
+    }
+
+    #[test]
+    fn watch_should_resume_from_seq() {
+        let hub = KeyspaceWatcher::new(WatchConfig {
+            backlog: 3,
+            ..Default::default()
+        });
+        let store = MemTable::new();
+        for i in 0..5 {
+            write(&hub, &store, CommandRequest::new_hset("t1", "k1", i.into()));
+        }
+
+        // 只保留了 seq 3..=5
+        let err = hub.watch(1, watch("t1", "", "", 2)).unwrap_err();
+        assert_eq!(err, KvError::HistoryTruncated(2, 3));
+        // 服务器重启之后 seq 重新从 1 开始，比下一个 seq 更大的 from_seq 同样需要重新读取数据
+        let err = hub.watch(1, watch("t1", "", "", 7)).unwrap_err();
+        assert_eq!(err, KvError::HistoryTruncated(7, 6));
+
+        assert_eq!(hub.watch(1, watch("t1", "k1", "", 4)).unwrap(), 5);
+        let mut rx = hub.take_receiver(1).unwrap();
+        assert_eq!(recv(&mut rx).seq, 4);
+        assert_eq!(recv(&mut rx).seq, 5);
+        write(&hub, &store, CommandRequest::new_hset("t1", "k1", 9.into()));
+        assert_eq!(recv(&mut rx).seq, 6);
+    }
+
+    #[test]
+    fn slow_watcher_should_be_disconnected() {
+        let hub = KeyspaceWatcher::new(WatchConfig {
+            buffer: 1,
+            ..Default::default()
+        });
+        let store = MemTable::new();
+        hub.watch(1, watch("t1", "", "", 0)).unwrap();
+        let mut rx = hub.take_receiver(1).unwrap();
+
+        write(&hub, &store, CommandRequest::new_hset("t1", "k1", 1.into()));
+        write(&hub, &store, CommandRequest::new_hset("t1", "k2", 2.into()));
+
+        assert_eq!(recv(&mut rx).key, "k1");
+        assert!(matches!(
+            rx.try_recv(),
+            Err(mpsc::error::TryRecvError::Disconnected)
+        ));
+        assert_eq!(hub.stats().evicted.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,552 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use futures::future::try_join_all;
+use http::StatusCode;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::info;
+
+use crate::{
+    command_request::RequestData, CommandRequest, CommandResponse, KvError, Kvpair,
+    ProstClientStream, Value,
+};
+
+/// 迁移 key 时每页读取的 kv pair 数
+const REBALANCE_PAGE: u32 = 1000;
+
+/// 一致性哈希环，每个节点在环上有 vnodes 个虚拟节点
+///
+/// key 属于它在环上顺时针方向的第一个虚拟节点。增加或删除一个节点时，
+/// 只有这个节点相邻区间里的 key 需要迁移。
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    vnodes: usize,
+    ring: BTreeMap<u64, String>,
+    nodes: BTreeSet<String>,
+}
+
+impl HashRing {
+    pub fn new(vnodes: usize) -> Self {
+        Self {
+            vnodes: vnodes.max(1),
+            ring: BTreeMap::new(),
+            nodes: BTreeSet::new(),
+        }
+    }
+
+    pub fn add_node(&mut self, node: impl Into<String>) {
+        let node = node.into();
+        for i in 0..self.vnodes {
+            let hash = hash(format!("{}#{}", node, i).as_bytes());
+            self.ring.insert(hash, node.clone());
+        }
+        self.nodes.insert(node);
+    }
+
+    pub fn remove_node(&mut self, node: &str) {
+        self.ring.retain(|_, v| v != node);
+        self.nodes.remove(node);
+    }
+
+    pub fn contains(&self, node: &str) -> bool {
+        self.nodes.contains(node)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &str> {
+        self.nodes.iter().map(|v| v.as_str())
+    }
+
+    /// key 所在的节点，环上没有节点时返回 None
+    pub fn node_for(&self, table: &str, key: &str) -> Option<&str> {
+        let mut data = Vec::with_capacity(table.len() + key.len() + 1);
+        data.extend_from_slice(table.as_bytes());
+        data.push(0);
+        data.extend_from_slice(key.as_bytes());
+        let hash = hash(&data);
+        self.ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+}
+
+/// 分片客户端：按 table + key 的一致性哈希把命令发给对应的服务器
+///
+/// 多个 key 的命令（Hmget/Hmset/Hmdel/Hmexist）按服务器拆分成子命令并发执行，
+/// 然后按原来的顺序合并结果；Hgetall 发给所有服务器，合并所有的 kv pair。
+pub struct ShardedClient<S> {
+    ring: HashRing,
+    /// 连接包括已经从哈希环上删除、但是数据还没有迁移走的节点
+    conns: HashMap<String, ProstClientStream<S>>,
+}
+
+impl<S> ShardedClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    pub fn new(vnodes: usize) -> Self {
+        Self {
+            ring: HashRing::new(vnodes),
+            conns: HashMap::new(),
+        }
+    }
+
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// 加入一个节点，之后需要调用 `rebalance` 把属于它的 key 迁移过来
+    pub fn add_node(&mut self, addr: impl Into<String>, stream: S) {
+        let addr = addr.into();
+        self.ring.add_node(addr.clone());
+        self.conns.insert(addr, ProstClientStream::new(stream));
+    }
+
+    /// 从哈希环上删除一个节点，新的请求不再发给它；
+    /// 连接会保留到 `rebalance` 把它上面的 key 都迁移走为止
+    pub fn remove_node(&mut self, addr: &str) {
+        self.ring.remove_node(addr);
+    }
+
+    pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let data = match cmd.request_data {
+            Some(data) => data,
+            None => return Err(KvError::InvalidCommand("Request has no data".into())),
+        };
+        match data {
+            RequestData::Hget(ref v) => self.execute_on(&v.table, &v.key, data.clone()).await,
+            RequestData::Hexist(ref v) => self.execute_on(&v.table, &v.key, data.clone()).await,
+            RequestData::Hdel(ref v) => self.execute_on(&v.table, &v.key, data.clone()).await,
+            RequestData::Hcas(ref v) => self.execute_on(&v.table, &v.key, data.clone()).await,
+            RequestData::Hset(ref v) => {
+                let key = v.pair.as_ref().map(|p| p.key.as_str()).unwrap_or_default();
+                self.execute_on(&v.table, key, data.clone()).await
+            }
+            RequestData::Hmget(v) => {
+                let table = v.table.clone();
+                let build = |keys| CommandRequest::new_hmget(table.clone(), keys);
+                self.execute_split(&v.table, v.keys, |k| k, build).await
+            }
+            RequestData::Hmexist(v) => {
+                let table = v.table.clone();
+                let build = |keys| CommandRequest::new_hmexist(table.clone(), keys);
+                self.execute_split(&v.table, v.keys, |k| k, build).await
+            }
+            RequestData::Hmdel(v) => {
+                let table = v.table.clone();
+                let build = |keys| CommandRequest::new_hmdel(table.clone(), keys);
+                self.execute_split(&v.table, v.keys, |k| k, build).await
+            }
+            RequestData::Hmset(v) => {
+                let table = v.table.clone();
+                let build = |pairs| CommandRequest::new_hmset(table.clone(), pairs);
+                self.execute_split(&v.table, v.pairs, |p: &Kvpair| &p.key, build)
+                    .await
+            }
+            // 分页的 HGETALL 在每个节点上有各自的游标，没法合并
+            RequestData::Hgetall(ref v) if v.limit > 0 => Err(KvError::InvalidCommand(
+                "Paged hgetall is not supported by the sharded client".into(),
+            )),
+            RequestData::Hgetall(_) | RequestData::Auth(_) => self.execute_all(data).await,
+            data => Err(KvError::InvalidCommand(format!(
+                "{} is not supported by the sharded client",
+                data.name()
+            ))),
+        }
+    }
+
+    /// 把不属于所在节点的 key 迁移到哈希环上对应的节点，返回迁移的 key 数
+    ///
+    /// 每个 table 用分页的 HGETALL 按 key 的顺序读取，每页最多 REBALANCE_PAGE 个 key。
+    /// 每个 key 用 HCAS 迁移：新的节点上还没有这个 key 时才写入，旧的节点上的值没有变化时才删除，
+    /// 所以迁移期间按新的哈希环写入的值不会被旧值覆盖。迁移之前所有客户端需要已经切换到新的哈希环，
+    /// 仍然写到旧节点上的 key 会留在旧节点上。
+    pub async fn rebalance(&mut self) -> Result<u64, KvError> {
+        let mut moved = 0;
+        let addrs: Vec<String> = self.conns.keys().cloned().collect();
+        for addr in &addrs {
+            for table in self.tables(addr).await? {
+                let mut after = String::new();
+                loop {
+                    let cmd = CommandRequest::new_hget_range(&table, &after, REBALANCE_PAGE);
+                    let res = check(self.conn(addr)?.execute(cmd).await?)?;
+                    let done = res.pairs.len() < REBALANCE_PAGE as usize;
+                    if let Some(last) = res.pairs.last() {
+                        after = last.key.clone();
+                    }
+
+                    let mut count = 0;
+                    for pair in res.pairs {
+                        let owner = self.owner(&table, &pair.key)?;
+                        if owner != *addr && self.move_key(&table, pair, addr, &owner).await? {
+                            count += 1;
+                        }
+                    }
+                    if count > 0 {
+                        info!("Moved {} keys of {} from {}", count, table, addr);
+                        moved += count;
+                    }
+                    if done {
+                        break;
+                    }
+                }
+            }
+        }
+
+        // 已经删除的节点上的数据都迁移走了，可以断开
+        let ring = &self.ring;
+        self.conns.retain(|addr, _| ring.contains(addr));
+        Ok(moved)
+    }
+
+    /// 把一个 key 从 from 迁移到 to，返回是否从 from 上删除了它
+    ///
+    /// to 上已经有这个 key 时，它是迁移开始之后写入的新值，不再写入旧值；
+    /// from 上的值在读取之后被修改了，就保留在 from 上。
+    async fn move_key(
+        &mut self,
+        table: &str,
+        pair: Kvpair,
+        from: &str,
+        to: &str,
+    ) -> Result<bool, KvError> {
+        let cmd = CommandRequest::new_hcas(table, &pair.key, None, pair.value.clone());
+        swapped(self.conn(to)?.execute(cmd).await?)?;
+        let cmd = CommandRequest::new_hcas(table, pair.key, pair.value, None);
+        swapped(self.conn(from)?.execute(cmd).await?)
+    }
+
+    async fn execute_on(
+        &mut self,
+        table: &str,
+        key: &str,
+        data: RequestData,
+    ) -> Result<CommandResponse, KvError> {
+        let owner = self.owner(table, key)?;
+        let cmd = CommandRequest {
+            request_data: Some(data),
+        };
+        self.conn(&owner)?.execute(cmd).await
+    }
+
+    /// 按 key 所在的节点拆分命令，并发执行之后按原来的顺序合并 values
+    async fn execute_split<T, K, B>(
+        &mut self,
+        table: &str,
+        items: Vec<T>,
+        key: K,
+        build: B,
+    ) -> Result<CommandResponse, KvError>
+    where
+        K: Fn(&T) -> &String,
+        B: Fn(Vec<T>) -> CommandRequest,
+    {
+        let total = items.len();
+        // 节点 -> (原来的位置, 子命令的参数)
+        let mut groups: HashMap<String, (Vec<usize>, Vec<T>)> = HashMap::new();
+        for (i, item) in items.into_iter().enumerate() {
+            let owner = self.owner(table, key(&item))?;
+            let group = groups.entry(owner).or_default();
+            group.0.push(i);
+            group.1.push(item);
+        }
+
+        let mut tasks = Vec::with_capacity(groups.len());
+        for (addr, conn) in self.conns.iter_mut() {
+            if let Some((positions, items)) = groups.remove(addr) {
+                let cmd = build(items);
+                tasks.push(async move { Ok::<_, KvError>((positions, conn.execute(cmd).await?)) });
+            }
+        }
+        if let Some(addr) = groups.keys().next() {
+            return Err(KvError::Internal(format!("Not connected to {}", addr)));
+        }
+
+        let mut values = vec![Value::default(); total];
+        for (positions, res) in try_join_all(tasks).await? {
+            if res.status != StatusCode::OK.as_u16() as u32 {
+                return Ok(res);
+            }
+            for (i, value) in positions.into_iter().zip(res.values) {
+                values[i] = value;
+            }
+        }
+        Ok(values.into())
+    }
+
+    /// 发给哈希环上的所有节点，kv pair 合并在一起，有一个失败就返回它的结果
+    async fn execute_all(&mut self, data: RequestData) -> Result<CommandResponse, KvError> {
+        let ring = &self.ring;
+        let tasks = self
+            .conns
+            .iter_mut()
+            .filter(|(addr, _)| ring.contains(addr))
+            .map(|(_, conn)| {
+                let cmd = CommandRequest {
+                    request_data: Some(data.clone()),
+                };
+                conn.execute(cmd)
+            });
+
+        let mut merged = CommandResponse {
+            status: StatusCode::OK.as_u16() as _,
+            ..Default::default()
+        };
+        for res in try_join_all(tasks).await? {
+            if res.status != StatusCode::OK.as_u16() as u32 {
+                return Ok(res);
+            }
+            merged.values.extend(res.values);
+            merged.pairs.extend(res.pairs);
+        }
+        Ok(merged)
+    }
+
+    /// 从 INFO keyspace 中获取节点上所有的 table
+    async fn tables(&mut self, addr: &str) -> Result<Vec<String>, KvError> {
+        let cmd = CommandRequest::new_info(["keyspace"]);
+        let res = check(self.conn(addr)?.execute(cmd).await?)?;
+        Ok(res
+            .pairs
+            .iter()
+            .filter_map(|p| p.key.strip_prefix("keyspace.")?.strip_suffix(".keys"))
+            .map(|v| v.to_string())
+            .collect())
+    }
+
+    fn owner(&self, table: &str, key: &str) -> Result<String, KvError> {
+        self.ring
+            .node_for(table, key)
+            .map(|v| v.to_string())
+            .ok_or_else(|| KvError::Internal("No node in the hash ring".into()))
+    }
+
+    fn conn(&mut self, addr: &str) -> Result<&mut ProstClientStream<S>, KvError> {
+        self.conns
+            .get_mut(addr)
+            .ok_or_else(|| KvError::Internal(format!("Not connected to {}", addr)))
+    }
+}
+
+fn check(res: CommandResponse) -> Result<CommandResponse, KvError> {
+    if res.status == StatusCode::OK.as_u16() as u32 {
+        Ok(res)
+    } else {
+        Err(KvError::Internal(format!(
+            "Server returned {}: {}",
+            res.status, res.message
+        )))
+    }
+}
+
+/// HCAS 的结果：修改成功返回 true，值已经变化（409）返回 false，其它错误返回 Err
+fn swapped(res: CommandResponse) -> Result<bool, KvError> {
+    if res.status == StatusCode::CONFLICT.as_u16() as u32 {
+        return Ok(false);
+    }
+    check(res).map(|_| true)
+}
+
+/// sha256 的前 8 个字节，保证不同版本、不同平台的客户端算出相同的位置
+fn hash(data: &[u8]) -> u64 {
+    let digest = Sha256::digest(data);
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use anyhow::Result;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::{assert_res_ok, MemTable, ProstServerStream, Service, ServiceInner};
+
+    async fn start_server() -> Result<(Service, SocketAddr)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let server = service.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(ProstServerStream::new(stream, server.clone()).process());
+            }
+        });
+        Ok((service, addr))
+    }
+
+    async fn start_cluster(n: usize) -> Result<(ShardedClient<TcpStream>, Vec<(Service, String)>)> {
+        let mut client = ShardedClient::new(64);
+        let mut servers = Vec::new();
+        for _ in 0..n {
+            let (service, addr) = start_server().await?;
+            client.add_node(addr.to_string(), TcpStream::connect(addr).await?);
+            servers.push((service, addr.to_string()));
+        }
+        Ok((client, servers))
+    }
+
+    fn keys(service: &Service) -> usize {
+        let stats = service.storage_stats().unwrap();
+        stats.tables.iter().map(|t| t.keys as usize).sum()
+    }
+
+    fn pairs(n: usize) -> Vec<Kvpair> {
+        (0..n)
+            .map(|i| Kvpair::new(format!("key{}", i), (i as i64).into()))
+            .collect()
+    }
+
+    #[test]
+    fn hash_ring_should_move_few_keys_when_node_added() {
+        let mut ring = HashRing::new(160);
+        for node in ["a", "b", "c"] {
+            ring.add_node(node);
+        }
+        let keys: Vec<String> = (0..3000).map(|i| format!("key{}", i)).collect();
+        let before: Vec<String> = keys
+            .iter()
+            .map(|k| ring.node_for("t1", k).unwrap().to_string())
+            .collect();
+        for node in ["a", "b", "c"] {
+            let count = before.iter().filter(|n| *n == node).count();
+            assert!(count > 700, "node {} only has {} keys", node, count);
+        }
+
+        ring.add_node("d");
+        let mut moved = 0;
+        for (key, old) in keys.iter().zip(&before) {
+            let new = ring.node_for("t1", key).unwrap();
+            if new != old {
+                // 只会迁移到新的节点
+                assert_eq!(new, "d");
+                moved += 1;
+            }
+        }
+        assert!(moved > 500 && moved < 1200, "moved {} keys", moved);
+
+        ring.remove_node("d");
+        for (key, old) in keys.iter().zip(&before) {
+            assert_eq!(ring.node_for("t1", key).unwrap(), old);
+        }
+    }
+
+    #[tokio::test]
+    async fn sharded_client_should_split_and_merge_commands() -> Result<()> {
+        let (mut client, servers) = start_cluster(3).await?;
+
+        let res = client
+            .execute(CommandRequest::new_hmset("t1", pairs(30)))
+            .await?;
+        assert_res_ok(res, &vec![Value::default(); 30], &[]);
+        for (service, _) in &servers {
+            assert!(keys(service) > 0);
+        }
+        assert_eq!(servers.iter().map(|(s, _)| keys(s)).sum::<usize>(), 30);
+
+        let keys = ["key29", "missing", "key0", "key7"];
+        let res = client
+            .execute(CommandRequest::new_hmget("t1", keys))
+            .await?;
+        let expected = [29.into(), Value::default(), 0.into(), 7.into()];
+        assert_res_ok(res, &expected, &[]);
+        let res = client
+            .execute(CommandRequest::new_hmexist("t1", keys))
+            .await?;
+        let expected = [true.into(), false.into(), true.into(), true.into()];
+        assert_res_ok(res, &expected, &[]);
+
+        let res = client
+            .execute(CommandRequest::new_hget("t1", "key3"))
+            .await?;
+        assert_res_ok(res, &[3.into()], &[]);
+        let res = client.execute(CommandRequest::new_hget_all("t1")).await?;
+        let mut expected = pairs(30);
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_res_ok(res, &[], &expected);
+
+        let res = client
+            .execute(CommandRequest::new_hmdel("t1", ["key1", "key2"]))
+            .await?;
+        assert_res_ok(res, &[1.into(), 2.into()], &[]);
+
+        let cmd = CommandRequest::new_slowlog_reset();
+        assert!(client.execute(cmd).await.is_err());
+        let cmd = CommandRequest::new_hget_range("t1", "", 10);
+        assert!(client.execute(cmd).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rebalance_should_not_overwrite_newer_values() -> Result<()> {
+        let (mut client, mut servers) = start_cluster(1).await?;
+        client
+            .execute(CommandRequest::new_hmset("t1", pairs(50)))
+            .await?;
+
+        let (service, addr) = start_server().await?;
+        client.add_node(addr.to_string(), TcpStream::connect(addr).await?);
+        servers.push((service, addr.to_string()));
+
+        // 加入新节点之后、迁移之前，按新的哈希环写入了一个 key
+        let key = (0..50)
+            .map(|i| format!("key{}", i))
+            .find(|k| client.ring().node_for("t1", k) == Some(addr.to_string().as_str()))
+            .unwrap();
+        client
+            .execute(CommandRequest::new_hset("t1", &key, "new".into()))
+            .await?;
+
+        let moved = client.rebalance().await?;
+        assert_eq!(keys(&servers[1].0) as u64, moved);
+        let res = client.execute(CommandRequest::new_hget("t1", &key)).await?;
+        assert_res_ok(res, &["new".into()], &[]);
+        // 旧节点上的旧值也删除了
+        let res = servers[0].0.execute(CommandRequest::new_hexist("t1", &key));
+        assert_res_ok(res, &[false.into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rebalance_should_migrate_keys_to_owners() -> Result<()> {
+        let (mut client, mut servers) = start_cluster(2).await?;
+        client
+            .execute(CommandRequest::new_hmset("t1", pairs(50)))
+            .await?;
+        client
+            .execute(CommandRequest::new_hmset("t2", pairs(50)))
+            .await?;
+
+        let (service, addr) = start_server().await?;
+        client.add_node(addr.to_string(), TcpStream::connect(addr).await?);
+        servers.push((service, addr.to_string()));
+        let moved = client.rebalance().await?;
+        assert!(moved > 0 && moved < 100, "moved {} keys", moved);
+        assert_eq!(keys(&servers[2].0) as u64, moved);
+
+        // 删除第一个节点，它上面的 key 都迁移到其他节点
+        client.remove_node(&servers[0].1);
+        client.rebalance().await?;
+        assert_eq!(keys(&servers[0].0), 0);
+
+        for (service, addr) in &servers[1..] {
+            // 每个 key 都在哈希环指定的节点上
+            for table in ["t1", "t2"] {
+                let owned: Vec<String> = (0..50)
+                    .map(|i| format!("key{}", i))
+                    .filter(|k| client.ring().node_for(table, k) == Some(addr.as_str()))
+                    .collect();
+                let res = service.execute(CommandRequest::new_hget_all(table));
+                assert_eq!(res.pairs.len(), owned.len());
+                let res = service.execute(CommandRequest::new_hmexist(table, owned.clone()));
+                assert_res_ok(res, &vec![true.into(); owned.len()], &[]);
+            }
+        }
+        let res = client.execute(CommandRequest::new_hget_all("t2")).await?;
+        assert_eq!(res.pairs.len(), 50);
+        Ok(())
+    }
+}
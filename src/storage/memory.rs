@@ -1,5 +1,10 @@
-use crate::{Kvpair, Storage, StorageIter, Value};
-use dashmap::{mapref::one::Ref, DashMap};
+use crate::{Kvpair, Storage, StorageIter, StorageStats, TableStats, Value};
+use dashmap::{
+    mapref::{entry::Entry, one::Ref},
+    DashMap,
+};
+use prost::Message;
+use tracing::instrument;
 
 /// 使用 DashMap 构建的 MemTable，实现了 Storage trait
 #[derive(Default)]
@@ -13,7 +18,7 @@ impl MemTable {
         Self::default()
     }
 
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    fn get_or_create_table(&self, name: &str) -> Ref<'_, String, DashMap<String, Value>> {
         match self.tables.get(name) {
             Some(table) => table,
             None => {
@@ -25,11 +30,23 @@ impl MemTable {
 }
 
 impl Storage for MemTable {
+    #[instrument(
+        name = "storage.get",
+        level = "debug",
+        skip_all,
+        fields(backend = "memtable", table = %table, key = %key)
+    )]
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, crate::KvError> {
         let table = self.get_or_create_table(table);
         Ok(table.get(key).map(|v| v.value().clone()))
     }
 
+    #[instrument(
+        name = "storage.set",
+        level = "debug",
+        skip_all,
+        fields(backend = "memtable", table = %table)
+    )]
     fn set(
         &self,
         table: &str,
@@ -40,16 +57,71 @@ impl Storage for MemTable {
         Ok(table.insert(key.into(), value.into()))
     }
 
+    #[instrument(
+        name = "storage.contains",
+        level = "debug",
+        skip_all,
+        fields(backend = "memtable", table = %table, key = %key)
+    )]
     fn contains(&self, table: &str, key: &str) -> Result<bool, crate::KvError> {
         let table = self.get_or_create_table(table);
         Ok(table.contains_key(key))
     }
 
+    #[instrument(
+        name = "storage.del",
+        level = "debug",
+        skip_all,
+        fields(backend = "memtable", table = %table, key = %key)
+    )]
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, crate::KvError> {
         let table = self.get_or_create_table(table);
         Ok(table.remove(key).map(|(_k, v)| v))
     }
 
+    #[instrument(
+        name = "storage.compare_and_set",
+        level = "debug",
+        skip_all,
+        fields(backend = "memtable", table = %table, key = %key)
+    )]
+    fn compare_and_set(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Option<Value>,
+    ) -> Result<bool, crate::KvError> {
+        let table = self.get_or_create_table(table);
+        // entry 持有 key 所在分片的写锁，比较和修改之间不会有其它写入
+        let swapped = match (table.entry(key.into()), expected, value) {
+            (Entry::Occupied(e), Some(expected), value) if e.get() == expected => {
+                match value {
+                    Some(value) => {
+                        e.replace_entry(value);
+                    }
+                    None => {
+                        e.remove();
+                    }
+                }
+                true
+            }
+            (Entry::Vacant(e), None, Some(value)) => {
+                e.insert(value);
+                true
+            }
+            (Entry::Vacant(_), None, None) => true,
+            _ => false,
+        };
+        Ok(swapped)
+    }
+
+    #[instrument(
+        name = "storage.get_all",
+        level = "debug",
+        skip_all,
+        fields(backend = "memtable", table = %table)
+    )]
     fn get_all(&self, table: &str) -> Result<Vec<crate::Kvpair>, crate::KvError> {
         let table = self.get_or_create_table(table);
         Ok(table
@@ -58,6 +130,12 @@ impl Storage for MemTable {
             .collect())
     }
 
+    #[instrument(
+        name = "storage.get_iter",
+        level = "debug",
+        skip_all,
+        fields(backend = "memtable", table = %table)
+    )]
     fn get_iter(
         &self,
         table: &str,
@@ -70,6 +148,48 @@ impl Storage for MemTable {
 
         Ok(Box::new(iter))
     }
+
+    #[instrument(
+        name = "storage.tables",
+        level = "debug",
+        skip_all,
+        fields(backend = "memtable")
+    )]
+    fn tables(&self) -> Result<Vec<String>, crate::KvError> {
+        let mut tables: Vec<_> = self.tables.iter().map(|t| t.key().clone()).collect();
+        tables.sort();
+        Ok(tables)
+    }
+
+    #[instrument(
+        name = "storage.stats",
+        level = "debug",
+        skip_all,
+        fields(backend = "memtable")
+    )]
+    fn stats(&self) -> Result<StorageStats, crate::KvError> {
+        let mut tables: Vec<_> = self
+            .tables
+            .iter()
+            .map(|table| TableStats {
+                name: table.key().clone(),
+                keys: table.len() as u64,
+                bytes: table
+                    .iter()
+                    .map(|v| (v.key().len() + v.value().encoded_len()) as u64)
+                    .sum(),
+            })
+            .collect();
+        tables.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(StorageStats {
+            backend: "memtable",
+            memory_bytes: Some(tables.iter().map(|t| t.bytes).sum()),
+            tables,
+            disk_bytes: None,
+            config: Vec::new(),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -19,10 +19,72 @@ pub trait Storage {
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError>;
     /// 从 HashTable 中删除一个 key
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+    /// key 当前的 value 等于 expected 时把它改成 value，返回是否修改成功
+    ///
+    /// expected 为 None 表示 key 不存在，value 为 None 表示删除 key。比较和修改是原子的。
+    fn compare_and_set(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Option<Value>,
+    ) -> Result<bool, KvError>;
     /// 遍历 HashTable，返回所有 kv pair（这个接口不好）
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
     /// 遍历 HashTable，返回 kv pair 的 Iterator
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+    /// 按 key 排序，返回 key 大于 after 的最多 limit 个 kv pair，after 为 None 时从第一个 key 开始
+    ///
+    /// 缺省实现遍历整个 HashTable 再排序，key 本身有序的存储应该重载它。
+    fn get_range(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let mut pairs: Vec<_> = self
+            .get_iter(table)?
+            .filter(|p| after.is_none_or(|after| p.key.as_str() > after))
+            .collect();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+    /// 按名字排序的所有 table
+    ///
+    /// 缺省实现从 stats 中取出 table 的名字，stats 需要遍历所有数据的存储应该重载它。
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        Ok(self.stats()?.tables.into_iter().map(|t| t.name).collect())
+    }
+    /// 存储的统计信息：每个 table 的 key 数量和大小，以及后端自己的资源占用
+    fn stats(&self) -> Result<StorageStats, KvError>;
+    /// 把还在缓存中的写入持久化，服务器关闭前调用；内存存储不需要做任何事
+    fn flush(&self) -> Result<(), KvError> {
+        Ok(())
+    }
+}
+
+/// 存储的统计信息
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StorageStats {
+    /// 存储后端的名字，比如 "memtable"、"sled"
+    pub backend: &'static str,
+    /// 按 table 名字排序
+    pub tables: Vec<TableStats>,
+    /// 占用的磁盘空间，只有持久化的存储才有
+    pub disk_bytes: Option<u64>,
+    /// 占用的内存（估算值），只有内存存储才有
+    pub memory_bytes: Option<u64>,
+    /// 存储后端的配置，比如 sled 的数据目录
+    pub config: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableStats {
+    pub name: String,
+    pub keys: u64,
+    /// key 和 value 编码后的大小之和（估算值）
+    pub bytes: u64,
 }
 
 pub struct StorageIter<T> {
@@ -89,6 +151,67 @@ mod tests {
         )
     }
 
+    #[test]
+    fn memtable_get_range_should_work() {
+        test_get_range(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_get_range_should_work() {
+        let dir = tempdir().unwrap();
+        test_get_range(SledDb::new(dir.path()));
+    }
+
+    fn test_get_range(store: impl Storage) {
+        for key in ["k3", "k1", "k2", "k10"] {
+            store.set("t1", key, key).unwrap();
+        }
+        store.set("t2", "k0", "v0").unwrap();
+
+        let keys = |pairs: Vec<Kvpair>| pairs.into_iter().map(|p| p.key).collect::<Vec<_>>();
+        assert_eq!(keys(store.get_range("t1", None, 2).unwrap()), ["k1", "k10"]);
+        assert_eq!(
+            keys(store.get_range("t1", Some("k10"), 10).unwrap()),
+            ["k2", "k3"]
+        );
+        assert!(store.get_range("t1", Some("k3"), 10).unwrap().is_empty());
+        let pairs = store.get_range("t1", Some("k1"), 1).unwrap();
+        assert_eq!(pairs, vec![Kvpair::new("k10", "k10".into())]);
+    }
+
+    #[test]
+    fn memtable_compare_and_set_should_work() {
+        test_compare_and_set(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_compare_and_set_should_work() {
+        let dir = tempdir().unwrap();
+        test_compare_and_set(SledDb::new(dir.path()));
+    }
+
+    fn test_compare_and_set(store: impl Storage) {
+        let (v1, v2): (Value, Value) = ("v1".into(), "v2".into());
+        // key 不存在时只有 expected 为 None 才能写入
+        assert!(!store
+            .compare_and_set("t1", "k1", Some(&v1), Some(v2.clone()))
+            .unwrap());
+        assert!(store
+            .compare_and_set("t1", "k1", None, Some(v1.clone()))
+            .unwrap());
+        assert!(!store
+            .compare_and_set("t1", "k1", None, Some(v2.clone()))
+            .unwrap());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(v1.clone()));
+
+        assert!(store
+            .compare_and_set("t1", "k1", Some(&v1), Some(v2.clone()))
+            .unwrap());
+        assert!(!store.compare_and_set("t1", "k1", Some(&v1), None).unwrap());
+        assert!(store.compare_and_set("t1", "k1", Some(&v2), None).unwrap());
+        assert!(!store.contains("t1", "k1").unwrap());
+    }
+
     fn test_get_all(store: impl Storage) {
         store.set("t2", "k1", "v1").unwrap();
         store.set("t2", "k2", "v2").unwrap();
@@ -103,6 +226,63 @@ mod tests {
         )
     }
 
+    #[test]
+    fn memtable_stats_should_work() {
+        let store = MemTable::new();
+        let stats = test_stats(store);
+        assert_eq!(stats.backend, "memtable");
+        assert_eq!(
+            stats.memory_bytes,
+            Some(stats.tables.iter().map(|t| t.bytes).sum())
+        );
+    }
+
+    fn test_stats(store: impl Storage) -> StorageStats {
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t1", "k2", "v2").unwrap();
+        store.set("t2", "k1", 10).unwrap();
+        store.set("t1-x", "k1", 10).unwrap();
+
+        let stats = store.stats().unwrap();
+        let tables: Vec<_> = stats
+            .tables
+            .iter()
+            .map(|t| (t.name.as_str(), t.keys))
+            .collect();
+        assert_eq!(tables, vec![("t1", 2), ("t1-x", 1), ("t2", 1)]);
+        assert!(stats.tables.iter().all(|t| t.bytes > 0));
+        stats
+    }
+
+    #[test]
+    fn memtable_tables_should_work() {
+        test_tables(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_tables_should_work() {
+        let dir = tempdir().unwrap();
+        test_tables(SledDb::new(dir));
+    }
+
+    fn test_tables(store: impl Storage) {
+        store.set("t2", "k1", "v1").unwrap();
+        store.set("t1", "k1", "v1").unwrap();
+        store.set("t1", "k2", "v2").unwrap();
+        store.set("t1-x", "k1", "v1").unwrap();
+        assert_eq!(store.tables().unwrap(), vec!["t1", "t1-x", "t2"]);
+    }
+
+    #[test]
+    fn sleddb_stats_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        let stats = test_stats(store);
+        assert_eq!(stats.backend, "sled");
+        assert!(stats.disk_bytes.is_some());
+        assert_eq!(stats.config[0].0, "path");
+    }
+
     #[test]
     fn sleddb_basic_interface_should_work() {
         let dir = tempdir().unwrap();
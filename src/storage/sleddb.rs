@@ -1,16 +1,18 @@
 use sled::{Db, IVec};
 use std::convert::TryInto;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str;
+use tracing::instrument;
 
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
+use crate::{KvError, Kvpair, Storage, StorageIter, StorageStats, TableStats, Value};
 
 #[derive(Debug)]
-pub struct SledDb(Db);
+pub struct SledDb(Db, PathBuf);
 
 impl SledDb {
     pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+        let path = path.as_ref().to_path_buf();
+        Self(sled::open(&path).unwrap(), path)
     }
 
     fn get_full_key(table: &str, key: &str) -> String {
@@ -37,6 +39,12 @@ fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
 }
 
 impl Storage for SledDb {
+    #[instrument(
+        name = "storage.get",
+        level = "debug",
+        skip_all,
+        fields(backend = "sled", table = %table, key = %key)
+    )]
     fn get(&self, table: &str, key: &str) -> Result<Option<crate::Value>, KvError> {
         let name = SledDb::get_full_key(table, key);
         let result = self.0.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
@@ -44,6 +52,12 @@ impl Storage for SledDb {
         flip(result)
     }
 
+    #[instrument(
+        name = "storage.set",
+        level = "debug",
+        skip_all,
+        fields(backend = "sled", table = %table)
+    )]
     fn set(
         &self,
         table: &str,
@@ -58,12 +72,24 @@ impl Storage for SledDb {
         flip(result)
     }
 
+    #[instrument(
+        name = "storage.contains",
+        level = "debug",
+        skip_all,
+        fields(backend = "sled", table = %table, key = %key)
+    )]
     fn contains(&self, table: &str, key: &str) -> Result<bool, crate::KvError> {
         let name = SledDb::get_full_key(table, key);
 
         Ok(self.0.contains_key(name)?)
     }
 
+    #[instrument(
+        name = "storage.del",
+        level = "debug",
+        skip_all,
+        fields(backend = "sled", table = %table, key = %key)
+    )]
     fn del(&self, table: &str, key: &str) -> Result<Option<crate::Value>, crate::KvError> {
         let name = SledDb::get_full_key(table, key);
         let result = self.0.remove(name)?.map(|v| v.as_ref().try_into());
@@ -71,12 +97,44 @@ impl Storage for SledDb {
         flip(result)
     }
 
+    #[instrument(
+        name = "storage.compare_and_set",
+        level = "debug",
+        skip_all,
+        fields(backend = "sled", table = %table, key = %key)
+    )]
+    fn compare_and_set(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Option<Value>,
+    ) -> Result<bool, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        // 同一个 Value 的编码是确定的，比较编码之后的字节即可
+        let expected: Option<Vec<u8>> = expected.cloned().map(TryInto::try_into).transpose()?;
+        let value: Option<Vec<u8>> = value.map(TryInto::try_into).transpose()?;
+        Ok(self.0.compare_and_swap(name, expected, value)?.is_ok())
+    }
+
+    #[instrument(
+        name = "storage.get_all",
+        level = "debug",
+        skip_all,
+        fields(backend = "sled", table = %table)
+    )]
     fn get_all(&self, table: &str) -> Result<Vec<crate::Kvpair>, crate::KvError> {
         let prefix = SledDb::get_table_prefix(table);
         let result = self.0.scan_prefix(prefix).map(|v| v.into()).collect();
         Ok(result)
     }
 
+    #[instrument(
+        name = "storage.get_iter",
+        level = "debug",
+        skip_all,
+        fields(backend = "sled", table = %table)
+    )]
     fn get_iter(
         &self,
         table: &str,
@@ -85,6 +143,109 @@ impl Storage for SledDb {
         let iter = StorageIter::new(self.0.scan_prefix(prefix));
         Ok(Box::new(iter))
     }
+
+    #[instrument(
+        name = "storage.get_range",
+        level = "debug",
+        skip_all,
+        fields(backend = "sled", table = %table)
+    )]
+    fn get_range(
+        &self,
+        table: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        let prefix = SledDb::get_table_prefix(table);
+        // sled 中的 key 按字节排序，比 after 大的最小的 key 是 after 后面加上 \0
+        let start = match after {
+            Some(after) => format!("{}{}\0", prefix, after),
+            None => prefix.clone(),
+        };
+        let mut pairs = Vec::new();
+        for item in self.0.range(start..) {
+            let (k, v) = item?;
+            if pairs.len() == limit || !k.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            pairs.push(Ok((k, v)).into());
+        }
+        Ok(pairs)
+    }
+
+    #[instrument(
+        name = "storage.tables",
+        level = "debug",
+        skip_all,
+        fields(backend = "sled")
+    )]
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        // 每找到一个 table 就跳过它所有的 key，只需要读取每个 table 的第一个 key
+        let mut tables = Vec::new();
+        let mut start = Vec::new();
+        while let Some((k, _)) = self.0.range(start.clone()..).next().transpose()? {
+            match str::from_utf8(&k).ok().and_then(|s| s.split_once(':')) {
+                Some((table, _)) => {
+                    // 'table;' 是所有 'table:' 开头的 key 之后的第一个位置
+                    start = format!("{};", table).into_bytes();
+                    tables.push(table.to_string());
+                }
+                None => {
+                    start = k.to_vec();
+                    start.push(0);
+                }
+            }
+        }
+        tables.sort();
+        Ok(tables)
+    }
+
+    #[instrument(
+        name = "storage.stats",
+        level = "debug",
+        skip_all,
+        fields(backend = "sled")
+    )]
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        // sled 里所有 table 共用一个 keyspace，key 的格式是 table:key，按前缀统计即可
+        let mut tables: Vec<TableStats> = Vec::new();
+        for item in self.0.iter() {
+            let (k, v) = item?;
+            let table = match str::from_utf8(&k).ok().and_then(|s| s.split_once(':')) {
+                Some((table, _)) => table,
+                None => continue,
+            };
+            match tables.last_mut() {
+                Some(t) if t.name == table => {
+                    t.keys += 1;
+                    t.bytes += (k.len() + v.len()) as u64;
+                }
+                _ => tables.push(TableStats {
+                    name: table.into(),
+                    keys: 1,
+                    bytes: (k.len() + v.len()) as u64,
+                }),
+            }
+        }
+        // key 按字节排序，名字中有比 ':' 小的字符时，table 的顺序和名字的顺序不同
+        tables.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(StorageStats {
+            backend: "sled",
+            tables,
+            disk_bytes: Some(self.0.size_on_disk()?),
+            memory_bytes: None,
+            config: vec![
+                ("path".into(), self.1.display().to_string()),
+                ("recovered".into(), self.0.was_recovered().to_string()),
+            ],
+        })
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.0.flush()?;
+        Ok(())
+    }
 }
 
 impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
@@ -0,0 +1,126 @@
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    runtime,
+    trace::{self, Tracer, TracerProvider},
+    Resource,
+};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::KvError;
+
+/// 通过 OTLP/HTTP 把 tracing 的 span 导出到 OpenTelemetry collector
+///
+/// span 先缓存在内存里，由后台任务批量发送，所以需要在 tokio runtime 中创建。
+/// drop 的时候会把剩下的 span 都发送出去。
+pub struct OtlpExporter {
+    provider: TracerProvider,
+}
+
+impl OtlpExporter {
+    /// endpoint 是 collector 的地址，比如 http://localhost:4318，span 会发送到 endpoint/v1/traces
+    pub fn new(
+        endpoint: impl Into<String>,
+        service_name: impl Into<String>,
+    ) -> Result<Self, KvError> {
+        let exporter = opentelemetry_otlp::new_exporter()
+            .http()
+            .with_endpoint(endpoint)
+            .build_span_exporter()
+            .map_err(|e| KvError::Internal(format!("Failed to create OTLP exporter: {}", e)))?;
+
+        let resource = Resource::new(vec![KeyValue::new("service.name", service_name.into())]);
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_config(trace::config().with_resource(resource))
+            .build();
+
+        Ok(Self { provider })
+    }
+
+    /// 生成一个 tracing layer，把它加入 tracing_subscriber 之后，所有的 span 都会被导出
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, Tracer>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.provider.tracer("kv"))
+    }
+
+    /// 立即发送缓存的 span，会阻塞当前线程，不要在单线程的 runtime 中调用
+    pub fn flush(&self) -> Result<(), KvError> {
+        for result in self.provider.force_flush() {
+            result.map_err(|e| KvError::Internal(format!("Failed to export spans: {}", e)))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, time::Duration};
+
+    use anyhow::Result;
+    use hyper::{
+        body::{to_bytes, Bytes},
+        server::conn::Http,
+        service::service_fn,
+        Body, Request, Response,
+    };
+    use tokio::{net::TcpListener, sync::mpsc, time::timeout};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+    use crate::{CommandRequest, MemTable, Service, ServiceInner};
+
+    /// 模拟 collector，把收到的请求路径和内容发送到 channel 里
+    async fn start_collector() -> Result<(String, mpsc::UnboundedReceiver<(String, Bytes)>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = format!("http://{}", listener.local_addr()?);
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let handler = service_fn(move |req: Request<Body>| {
+                        let tx = tx.clone();
+                        async move {
+                            let path = req.uri().path().to_string();
+                            let body = to_bytes(req.into_body()).await.unwrap();
+                            tx.send((path, body)).unwrap();
+                            Ok::<_, Infallible>(Response::new(Body::empty()))
+                        }
+                    });
+                    Http::new().serve_connection(stream, handler).await.ok();
+                });
+            }
+        });
+        Ok((endpoint, rx))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_should_be_exported_to_collector() -> Result<()> {
+        let (endpoint, mut rx) = start_collector().await?;
+        let exporter = OtlpExporter::new(endpoint, "kv-test")?;
+        let subscriber = Registry::default().with(exporter.layer());
+
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tracing::subscriber::with_default(subscriber, || {
+            service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        });
+        exporter.flush()?;
+
+        let (path, body) = timeout(Duration::from_secs(5), rx.recv()).await?.unwrap();
+        assert_eq!(path, "/v1/traces");
+        // protobuf 里的字符串是原样编码的，直接在 body 里查找
+        let contains = |s: &str| body.windows(s.len()).any(|w| w == s.as_bytes());
+        assert!(contains("kv-test"));
+        assert!(contains("request"));
+        assert!(contains("storage.set"));
+        assert!(contains("hset"));
+        Ok(())
+    }
+}